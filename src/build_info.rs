@@ -0,0 +1,6 @@
+/// The crate version, from `Cargo.toml`
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The short git SHA this was built from (set by `build.rs`), or `unknown` if it couldn't be
+/// determined at build time
+pub const COMMIT: &str = env!("PETRONEL_GIT_COMMIT");
@@ -0,0 +1,97 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+/// A token for signalling spawned tasks to stop. Clones share the same state, so cancelling any
+/// clone cancels all of them.
+///
+/// This is a minimal version of `tokio_util::sync::CancellationToken`, which isn't available for
+/// tokio 0.2.
+#[derive(Clone, Debug)]
+pub struct CancellationToken {
+    tx: Arc<watch::Sender<bool>>,
+    rx: watch::Receiver<bool>,
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        let (tx, rx) = watch::channel(false);
+        Self {
+            tx: Arc::new(tx),
+            rx,
+        }
+    }
+
+    pub fn cancel(&self) {
+        let _ = self.tx.broadcast(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// Completes once the token has been cancelled
+    pub async fn cancelled(&self) {
+        let mut rx = self.rx.clone();
+        while !*rx.borrow() {
+            // The sender is owned by `self`, so it can't be dropped while waiting
+            if rx.recv().await.is_none() {
+                return;
+            }
+        }
+    }
+
+    /// Runs the future until it completes, or until the token is cancelled, whichever is first.
+    /// Returns `None` if cancelled.
+    pub async fn run_until_cancelled<F: Future>(&self, future: F) -> Option<F::Output> {
+        // Checked first, since `select!` picks randomly if both are ready
+        if self.is_cancelled() {
+            return None;
+        }
+
+        tokio::select! {
+            _ = self.cancelled() => None,
+            output = future => Some(output),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn cancel() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        assert!(!clone.is_cancelled());
+
+        let task = tokio::spawn({
+            let token = token.clone();
+            async move {
+                token
+                    .run_until_cancelled(tokio::time::delay_for(Duration::from_secs(60)))
+                    .await
+            }
+        });
+
+        clone.cancel();
+        assert!(token.is_cancelled());
+        assert_eq!(task.await.unwrap(), None);
+
+        // Already-cancelled tokens complete immediately
+        token.cancelled().await;
+        assert_eq!(token.run_until_cancelled(async { 1 }).await, None);
+
+        let other = CancellationToken::new();
+        assert_eq!(other.run_until_cancelled(async { 1 }).await, Some(1));
+    }
+}
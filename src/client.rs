@@ -0,0 +1,115 @@
+use std::io;
+use std::task::{Context, Poll};
+
+use http::header::{HeaderValue, USER_AGENT};
+use http::{Request, Response, Uri};
+use hyper::client::{HttpConnector, ResponseFuture};
+use hyper::Body;
+use hyper_proxy::{Intercept, Proxy, ProxyConnector};
+use hyper_tls::HttpsConnector;
+
+type HttpsClient = hyper::Client<ProxyConnector<HttpsConnector<HttpConnector>>>;
+
+pub const DEFAULT_USER_AGENT: &str = concat!("petronel-graphql/", env!("CARGO_PKG_VERSION"));
+
+/// A `hyper::Client` that sets a `User-Agent` header on all outbound requests
+#[derive(Clone, Debug)]
+pub struct HttpClient {
+    inner: HttpsClient,
+    user_agent: HeaderValue,
+}
+
+impl HttpClient {
+    /// Creates a client that connects through the given proxy, if any
+    pub fn new(user_agent: HeaderValue, proxy: Option<Uri>) -> io::Result<Self> {
+        let connector = HttpsConnector::new();
+        let connector = match proxy {
+            Some(uri) => ProxyConnector::from_proxy(connector, Proxy::new(Intercept::All, uri))?,
+            None => ProxyConnector::new(connector)?,
+        };
+
+        Ok(Self {
+            inner: hyper::Client::builder().build(connector),
+            user_agent,
+        })
+    }
+
+    pub fn get(&self, uri: Uri) -> ResponseFuture {
+        let request = Request::get(uri)
+            .body(Body::empty())
+            .expect("invalid GET request");
+        self.request(request)
+    }
+
+    /// Sends the request, adding a `User-Agent` header if one isn't already set
+    pub fn request(&self, mut request: Request<Body>) -> ResponseFuture {
+        request
+            .headers_mut()
+            .entry(USER_AGENT)
+            .or_insert_with(|| self.user_agent.clone());
+        self.inner.request(request)
+    }
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        Self::new(HeaderValue::from_static(DEFAULT_USER_AGENT), None)
+            .expect("failed to initialize TLS connector")
+    }
+}
+
+// Used by `twitter_stream` for the Twitter API v1.1 stream
+impl tower_service::Service<Request<Body>> for HttpClient {
+    type Response = Response<Body>;
+    type Error = hyper::Error;
+    type Future = ResponseFuture;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        self.request(request)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn proxy() {
+        // Mock proxy server that records the requested URIs
+        let uris = Arc::new(Mutex::new(Vec::new()));
+        let make_service = make_service_fn({
+            let uris = uris.clone();
+            move |_| {
+                let uris = uris.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        uris.lock().unwrap().push(req.uri().to_string());
+                        async { Ok::<_, Infallible>(Response::new(Body::empty())) }
+                    }))
+                }
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let proxy_uri = format!("http://{}", server.local_addr()).parse().unwrap();
+        tokio::spawn(server);
+
+        let client = HttpClient::new(HeaderValue::from_static("test"), Some(proxy_uri)).unwrap();
+        let resp = client
+            .get("http://example.invalid/image.jpg".parse().unwrap())
+            .await
+            .unwrap();
+
+        assert!(resp.status().is_success());
+        assert_eq!(
+            *uris.lock().unwrap(),
+            vec!["http://example.invalid/image.jpg".to_owned()]
+        );
+    }
+}
@@ -15,14 +15,155 @@ pub enum Error {
     Hyper(#[from] hyper::Error),
     #[error("failed to load image: {0}")]
     Image(#[from] image::error::ImageError),
+    #[error("unsupported image format: {0}")]
+    UnsupportedImageFormat(String),
     #[error("failed to parse URI: {0}")]
     InvalidUri(#[from] http::uri::InvalidUri),
     #[error("stream was closed by receiver")]
     StreamClosed,
-    #[error("invalid bind address: {0}")]
-    BindPort(#[from] std::net::AddrParseError),
+    #[error("invalid bind IP `{ip}`, expected an IPv4 or IPv6 address like `127.0.0.1` or `::1`")]
+    BindIp {
+        ip: String,
+        #[source]
+        source: std::net::AddrParseError,
+    },
+    #[error("invalid image hash size {size}x{small_size}, expected a small size from 2 to 11, and a size from the small size to 256")]
+    ImageHashSize { size: u32, small_size: u32 },
+    #[error("invalid broadcast capacity {0}, expected at least 1")]
+    BroadcastCapacity(usize),
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
+    #[error("invalid snapshot: {0}")]
+    InvalidSnapshot(String),
     #[error("Redis error: {0}")]
     Redis(#[from] redis::RedisError),
+    #[error("request to {uri} failed: {source}")]
+    Request {
+        uri: String,
+        #[source]
+        source: Box<Error>,
+    },
+    #[error("{message}")]
+    Shared {
+        message: String,
+        status: Option<StatusCode>,
+        retryable: bool,
+    },
+}
+
+impl Error {
+    /// Attach the URI of the request that caused this error
+    pub fn with_uri(self, uri: impl ToString) -> Self {
+        Error::Request {
+            uri: uri.to_string(),
+            source: Box::new(self),
+        }
+    }
+
+    /// A copy of this error, for reporting the same failure to multiple callers. Errors can't be
+    /// cloned, so only the message, status code, and retryability are kept.
+    pub fn to_shared(&self) -> Self {
+        Error::Shared {
+            message: self.to_string(),
+            status: self.status_code(),
+            retryable: self.is_retryable(),
+        }
+    }
+
+    /// The HTTP status code of the response that caused this error, if any
+    pub fn status_code(&self) -> Option<StatusCode> {
+        match self {
+            Error::Http(status) => Some(*status),
+            Error::Request { source, .. } => source.status_code(),
+            Error::Shared { status, .. } => *status,
+            _ => None,
+        }
+    }
+
+    /// Whether the operation that caused this error might succeed if attempted again.
+    /// E.g., a failed download is retryable, but an invalid image is not.
+    pub fn is_retryable(&self) -> bool {
+        use image::error::ImageError;
+
+        match self {
+            Error::Http(status) => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            Error::Twitter(_) | Error::Hyper(_) | Error::Io(_) => true,
+            Error::Image(ImageError::IoError(_)) => true,
+            Error::Image(_) => false,
+            Error::Redis(e) => e.is_io_error() || e.is_timeout(),
+            Error::Request { source, .. } => source.is_retryable(),
+            Error::Shared { retryable, .. } => *retryable,
+            Error::Json(_)
+            | Error::UnsupportedImageFormat(_)
+            | Error::InvalidUri(_)
+            | Error::StreamClosed
+            | Error::InvalidSnapshot(_)
+            | Error::BindIp { .. }
+            | Error::ImageHashSize { .. }
+            | Error::BroadcastCapacity(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn is_retryable() {
+        assert!(Error::Http(StatusCode::INTERNAL_SERVER_ERROR).is_retryable());
+        assert!(Error::Http(StatusCode::SERVICE_UNAVAILABLE).is_retryable());
+        assert!(Error::Http(StatusCode::TOO_MANY_REQUESTS).is_retryable());
+        assert!(!Error::Http(StatusCode::NOT_FOUND).is_retryable());
+        assert!(!Error::Http(StatusCode::FORBIDDEN).is_retryable());
+
+        let io_error = || std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        assert!(Error::Io(io_error()).is_retryable());
+        assert!(Error::Image(image::error::ImageError::IoError(io_error())).is_retryable());
+
+        let decode_error = image::load_from_memory(b"not an image").unwrap_err();
+        assert!(!Error::Image(decode_error).is_retryable());
+
+        let json_error = serde_json::from_str::<i32>("not json").unwrap_err();
+        assert!(!Error::Json(json_error).is_retryable());
+
+        let uri_error = "not a uri".parse::<http::Uri>().unwrap_err();
+        assert!(!Error::InvalidUri(uri_error).is_retryable());
+
+        assert!(!Error::StreamClosed.is_retryable());
+        assert!(!Error::UnsupportedImageFormat("GIF".into()).is_retryable());
+
+        let with_uri = Error::Http(StatusCode::BAD_GATEWAY).with_uri("http://example.com");
+        assert!(with_uri.is_retryable());
+    }
+
+    #[test]
+    fn with_uri() {
+        let error = Error::Http(StatusCode::NOT_FOUND).with_uri("http://example.com/image.png");
+
+        assert_eq!(
+            error.to_string(),
+            "request to http://example.com/image.png failed: HTTP error: 404 Not Found"
+        );
+        assert_eq!(error.status_code(), Some(StatusCode::NOT_FOUND));
+
+        let source = std::error::Error::source(&error).expect("missing error source");
+        assert_eq!(source.to_string(), "HTTP error: 404 Not Found");
+    }
+
+    #[test]
+    fn to_shared() {
+        let error = Error::Http(StatusCode::BAD_GATEWAY).with_uri("http://example.com/image.png");
+        let shared = error.to_shared();
+
+        assert_eq!(shared.to_string(), error.to_string());
+        assert_eq!(shared.status_code(), Some(StatusCode::BAD_GATEWAY));
+        assert!(shared.is_retryable());
+
+        let shared = Error::UnsupportedImageFormat("GIF".into()).to_shared();
+        assert_eq!(shared.status_code(), None);
+        assert!(!shared.is_retryable());
+    }
 }
@@ -0,0 +1,36 @@
+use std::sync::Arc;
+use warp::{Filter, Rejection};
+
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Requires an `Authorization: Bearer <token>` header matching the admin token.
+///
+/// If no admin token is configured, requests are rejected as if the route doesn't exist.
+pub fn admin(token: Option<String>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let expected = token.map(|token| Arc::new(format!("Bearer {}", token)));
+
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let expected = expected.clone();
+            async move {
+                match (expected, header) {
+                    (None, _) => Err(warp::reject::not_found()),
+                    (Some(expected), Some(header))
+                        if constant_time_eq(header.as_bytes(), expected.as_bytes()) =>
+                    {
+                        Ok(())
+                    }
+                    (Some(_), _) => Err(warp::reject::custom(Unauthorized)),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+// Avoid leaking the token through response timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
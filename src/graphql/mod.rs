@@ -1,60 +1,386 @@
+mod auth;
+mod rejection;
 mod relay;
 mod schema;
 
+pub use relay::PageSizeLimit;
+
+use rejection::{Draining, ExecutionFailed, TooManyConnections};
+
+use crate::build_info;
+use crate::cancellation::CancellationToken;
 use crate::metrics::{Metric, MetricFactory};
-use crate::raid_handler::RaidHandler;
-use futures::FutureExt;
-use juniper::{EmptyMutation, RootNode};
+use crate::raid_handler::{BossSnapshot, RaidHandler};
+use bytes::Bytes;
+use futures::stream::Stream;
+use futures::{FutureExt, StreamExt};
+use juniper::http::{GraphQLBatchRequest, GraphQLRequest};
+use juniper::{DefaultScalarValue, EmptyMutation, InputValue, RootNode, SubscriptionCoordinator};
 use juniper_subscriptions::Coordinator;
 use juniper_warp::subscriptions::graphql_subscriptions;
+use serde::Deserialize;
+use std::convert::Infallible;
 use std::sync::Arc;
-use warp::{http::Response, Filter};
+use warp::http::{Response, StatusCode};
+use warp::sse::ServerSentEvent;
+use warp::{Filter, Rejection, Reply};
 
-type Schema = RootNode<'static, schema::Query, EmptyMutation<RaidHandler>, schema::Subscription>;
+pub(crate) type Schema =
+    RootNode<'static, schema::Query, EmptyMutation<schema::Context>, schema::Subscription>;
 
-fn schema() -> Schema {
+pub(crate) fn schema() -> Schema {
     Schema::new(
         schema::Query,
-        EmptyMutation::<RaidHandler>::new(),
+        EmptyMutation::<schema::Context>::new(),
         schema::Subscription,
     )
 }
 
-pub fn routes(handler: RaidHandler) -> impl Filter<Extract = impl warp::Reply> + Clone {
+type SchemaCoordinator = Coordinator<
+    'static,
+    schema::Query,
+    EmptyMutation<schema::Context>,
+    schema::Subscription,
+    schema::Context,
+    DefaultScalarValue,
+>;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GetQuery {
+    query: String,
+    operation_name: Option<String>,
+    variables: Option<String>,
+}
+
+impl GetQuery {
+    // Fails with a `400 Bad Request` reply if `variables` isn't valid JSON
+    fn into_request(self) -> Result<GraphQLRequest, warp::reply::Response> {
+        let variables = match self
+            .variables
+            .as_deref()
+            .map(serde_json::from_str::<InputValue>)
+        {
+            None => None,
+            Some(Ok(variables)) => Some(variables),
+            Some(Err(e)) => {
+                let message = format!("Invalid variables: {}", e);
+                return Err(
+                    rejection::error_reply(StatusCode::BAD_REQUEST, message).into_response()
+                );
+            }
+        };
+
+        Ok(GraphQLRequest::new(
+            self.query,
+            self.operation_name,
+            variables,
+        ))
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum MetricsFormat {
+    Prometheus,
+    Json,
+}
+
+#[derive(Deserialize)]
+struct MetricsQuery {
+    format: Option<MetricsFormat>,
+}
+
+#[derive(Deserialize)]
+struct ImportQuery {
+    /// Whether to import boss histories, in addition to the bosses themselves
+    #[serde(default)]
+    history: bool,
+}
+
+// Snapshots are much larger than GraphQL requests, so they have a separate limit
+const MAX_IMPORT_BODY_BYTES: u64 = 64 * 1024 * 1024;
+
+// Counts an open websocket connection until dropped, so the gauge stays accurate
+// even if the connection's task panics or is aborted. Event streams from `/graphql/stream` hold
+// a subscription open in the same way, so they're counted as websocket connections too.
+struct WebsocketConnection {
+    handler: RaidHandler,
+}
+
+impl WebsocketConnection {
+    /// Returns `None` if there are already `max` open connections
+    fn try_new(handler: RaidHandler, max: Option<usize>) -> Option<Self> {
+        let gauge = handler.metric_factory().websocket_connections_gauge();
+        if gauge.inc_up_to(max.unwrap_or(usize::MAX)) {
+            Some(Self { handler })
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for WebsocketConnection {
+    fn drop(&mut self) {
+        self.handler
+            .metric_factory()
+            .websocket_connections_gauge()
+            .dec();
+    }
+}
+
+// Field errors are returned alongside partial data, so `is_ok` alone doesn't catch them
+fn has_errors(response: &serde_json::Value) -> bool {
+    response.get("errors").is_some()
+}
+
+// JSON request bodies may contain a batch of requests. Other requests are executed as a
+// `GraphQLBatchRequest::Single`.
+fn execute_batch(
+    schema: &Schema,
+    ctx: &schema::Context,
+    request: GraphQLBatchRequest,
+) -> warp::reply::Response {
+    let response = request.execute_sync(schema, ctx);
+    let status = if response.is_ok() {
+        StatusCode::OK
+    } else {
+        StatusCode::BAD_REQUEST
+    };
+
+    let body = serde_json::to_value(&response).unwrap_or_default();
+    let metric_factory = ctx.handler.metric_factory();
+    match (&request, &body) {
+        (GraphQLBatchRequest::Single(req), _) => {
+            metric_factory.record_graphql_request(req.operation_name(), has_errors(&body));
+        }
+        (GraphQLBatchRequest::Batch(reqs), serde_json::Value::Array(resps)) => {
+            for (req, resp) in reqs.iter().zip(resps) {
+                metric_factory.record_graphql_request(req.operation_name(), has_errors(resp));
+            }
+        }
+        _ => {}
+    }
+
+    warp::reply::with_status(warp::reply::json(&body), status).into_response()
+}
+
+// Runs `execute_batch` on the blocking thread pool, so that a large query or batch doesn't stall
+// the websockets, event streams, and Twitter stream sharing the same worker thread
+async fn execute_batch_blocking(
+    schema: Arc<Schema>,
+    ctx: schema::Context,
+    request: GraphQLBatchRequest,
+) -> Result<warp::reply::Response, Rejection> {
+    tokio::task::spawn_blocking(move || execute_batch(&schema, &ctx, request))
+        .await
+        .map_err(|_| warp::reject::custom(ExecutionFailed))
+}
+
+// How many responses can be buffered for a server-sent event stream. Once the buffer is full,
+// the subscription isn't polled until the client catches up, so its raid streams lag (reported
+// as `missedCount`) instead of buffering without limit.
+const EVENT_STREAM_BUFFER_SIZE: usize = 16;
+
+// Stops the task driving an event stream's subscription when the stream is dropped (e.g., when
+// the client disconnects), and counts the connection until then
+struct EventStreamGuard {
+    cancel: CancellationToken,
+    _connection: WebsocketConnection,
+}
+
+impl Drop for EventStreamGuard {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+// Runs a subscription, sending each response as a server-sent event. If the request is invalid,
+// a single event containing the errors is sent.
+fn subscription_events(
+    coordinator: Arc<SchemaCoordinator>,
+    ctx: schema::Context,
+    request: GraphQLRequest,
+    connection: WebsocketConnection,
+) -> impl Stream<Item = Result<impl ServerSentEvent, Infallible>> {
+    let (mut tx, rx) = tokio::sync::mpsc::channel(EVENT_STREAM_BUFFER_SIZE);
+    let guard = EventStreamGuard {
+        cancel: CancellationToken::new(),
+        _connection: connection,
+    };
+
+    // The subscription borrows the request and context, so it's driven by a task that owns them
+    let cancel = guard.cancel.clone();
+    tokio::spawn(async move {
+        let forward = async {
+            match coordinator.subscribe(&request, &ctx).await {
+                Ok(mut responses) => {
+                    while let Some(response) = responses.next().await {
+                        let value = serde_json::to_value(&response).unwrap_or_default();
+                        // The client disconnected
+                        if tx.send(value).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+                Err(e) => {
+                    let _ = tx.send(serde_json::json!({ "errors": e })).await;
+                }
+            }
+        };
+
+        cancel.run_until_cancelled(forward).await;
+    });
+
+    rx.map(move |value| {
+        let _guard = &guard;
+        Ok((warp::sse::json(value),))
+    })
+}
+
+pub fn routes(
+    handler: RaidHandler,
+    page_size_limit: PageSizeLimit,
+    max_body_bytes: u64,
+    admin_token: Option<String>,
+    enable_admin_ui: bool,
+    enable_debug_events: bool,
+    max_websocket_connections: Option<usize>,
+) -> impl Filter<Extract = impl warp::Reply> + Clone {
     let graphql_context = {
+        let ctx = schema::Context {
+            handler: handler.clone(),
+            page_size_limit,
+        };
+        warp::any().map(move || ctx.clone())
+    };
+
+    let shared_coordinator = {
+        let coordinator = Arc::new(SchemaCoordinator::new(schema()));
+        warp::any().map(move || coordinator.clone())
+    };
+
+    // The connection is counted before upgrading (or before starting an event stream), so that
+    // connections past the limit can be rejected with an error response
+    let websocket_connection = {
         let handler = handler.clone();
-        warp::any().map(move || handler.clone())
+        warp::any().and_then(move || {
+            let connection = if handler.is_draining() {
+                Err(warp::reject::custom(Draining))
+            } else {
+                WebsocketConnection::try_new(handler.clone(), max_websocket_connections)
+                    .ok_or_else(|| warp::reject::custom(TooManyConnections))
+            };
+            async move { connection }
+        })
     };
 
-    let coordinator = Arc::new(juniper_subscriptions::Coordinator::new(schema()));
     let websocket_graphql = warp::path!("graphql")
         .and(warp::ws())
+        .and(websocket_connection.clone())
         .and(graphql_context.clone())
-        .and(warp::any().map(move || coordinator.clone()))
+        .and(shared_coordinator.clone())
         .map(
             |ws: warp::ws::Ws,
-             ctx: RaidHandler,
-             coordinator: Arc<Coordinator<'static, _, _, _, _, _>>| {
+             connection: WebsocketConnection,
+             ctx: schema::Context,
+             coordinator: Arc<SchemaCoordinator>| {
                 ws.on_upgrade(move |websocket| {
-                    ctx.metric_factory().websocket_connections_gauge().inc();
-
-                    graphql_subscriptions(websocket, coordinator, ctx.clone()).map(move |_r| {
-                        ctx.metric_factory().websocket_connections_gauge().dec();
+                    graphql_subscriptions(websocket, coordinator, ctx).map(move |_r| {
+                        drop(connection);
                     })
                 })
             },
         )
         .map(|reply| warp::reply::with_header(reply, "Sec-WebSocket-Protocol", "graphql-ws"));
 
+    let shared_schema = {
+        let schema = Arc::new(schema());
+        warp::any().map(move || schema.clone())
+    };
+
+    // `POST /graphql` with a JSON body, containing a single request or a batch of requests
     let post_graphql = warp::path!("graphql")
+        .and(warp::post())
         .and(warp::header::exact_ignore_case(
             "accept",
             "application/json",
         ))
-        .and(juniper_warp::make_graphql_filter_sync(
-            schema(),
-            graphql_context.boxed(),
-        ));
+        .and(warp::body::content_length_limit(max_body_bytes))
+        .and(warp::body::json())
+        .and(graphql_context.clone())
+        .and(shared_schema.clone())
+        .and_then(
+            |request: GraphQLBatchRequest, ctx: schema::Context, schema: Arc<Schema>| {
+                execute_batch_blocking(schema, ctx, request)
+            },
+        );
+
+    // `GET /graphql?query=...`, with optional `operationName` and JSON-encoded `variables`
+    let get_graphql = warp::path!("graphql")
+        .and(warp::get())
+        .and(warp::query::<GetQuery>())
+        .and(graphql_context.clone())
+        .and(shared_schema.clone())
+        .and_then(
+            |query: GetQuery, ctx: schema::Context, schema: Arc<Schema>| async move {
+                match query.into_request() {
+                    Ok(request) => {
+                        let request = GraphQLBatchRequest::Single(request);
+                        execute_batch_blocking(schema, ctx, request).await
+                    }
+                    Err(reply) => Ok(reply),
+                }
+            },
+        );
+
+    // `GET /graphql/stream?query=...`, which runs a subscription and sends the results as
+    // server-sent events, for clients that can't use websockets
+    let get_graphql_stream = warp::path!("graphql" / "stream")
+        .and(warp::get())
+        .and(warp::query::<GetQuery>())
+        .and(websocket_connection)
+        .and(graphql_context.clone())
+        .and(shared_coordinator)
+        .map(
+            |query: GetQuery,
+             connection: WebsocketConnection,
+             ctx: schema::Context,
+             coordinator: Arc<SchemaCoordinator>| match query.into_request() {
+                Ok(request) => {
+                    let events = subscription_events(coordinator, ctx, request, connection);
+                    warp::sse::reply(warp::sse::keep_alive().stream(events)).into_response()
+                }
+                Err(reply) => reply,
+            },
+        );
+
+    // `POST /graphql` with the query as the body, using the `application/graphql` content type
+    let post_graphql_query = warp::path!("graphql")
+        .and(warp::post())
+        .and(warp::header::exact_ignore_case(
+            "content-type",
+            "application/graphql",
+        ))
+        .and(warp::body::content_length_limit(max_body_bytes))
+        .and(warp::body::bytes())
+        .and(graphql_context)
+        .and(shared_schema)
+        .and_then(
+            |body: Bytes, ctx: schema::Context, schema: Arc<Schema>| async move {
+                let query = match std::str::from_utf8(&body) {
+                    Ok(query) => query.to_owned(),
+                    Err(_) => {
+                        let message = "Request body must be valid UTF-8";
+                        return Ok(rejection::error_reply(StatusCode::BAD_REQUEST, message)
+                            .into_response());
+                    }
+                };
+
+                let request = GraphQLRequest::new(query, None, None);
+                execute_batch_blocking(schema, ctx, GraphQLBatchRequest::Single(request)).await
+            },
+        );
 
     // TODO: Configurable
     let get_graphiql = warp::path!("graphiql").and(warp::get()).map(|| {
@@ -63,25 +389,944 @@ pub fn routes(handler: RaidHandler) -> impl Filter<Extract = impl warp::Reply> +
             .body(include_str!("graphiql.html"))
     });
 
-    // TODO: Configurable
-    let get_metrics = warp::path!("metrics").and(warp::get()).map(move || {
-        Response::builder()
-            .header("content-type", "text/plain; version=0.0.4")
-            .body(handler.metrics())
+    // Status page for operators, which reads from `/metrics.json`
+    let get_admin_ui = warp::path!("admin")
+        .and(warp::get())
+        .and_then(move || async move {
+            if enable_admin_ui {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+        .map(|| {
+            Response::builder()
+                .header("content-type", "text/html")
+                .body(include_str!("admin.html"))
+        });
+
+    // Server-sent events for changes to bosses, for debugging
+    let get_debug_events = {
+        let handler = handler.clone();
+        warp::path!("debug" / "events")
+            .and(warp::get())
+            .and_then(move || async move {
+                if enable_debug_events {
+                    Ok(())
+                } else {
+                    Err(warp::reject::not_found())
+                }
+            })
+            .untuple_one()
+            .map(move || {
+                let events = handler.subscribe_events().map(|event| {
+                    let name = warp::sse::event(event.name());
+                    Ok::<_, std::convert::Infallible>((name, warp::sse::json(event)))
+                });
+                warp::sse::reply(warp::sse::keep_alive().stream(events))
+            })
+    };
+
+    // Admin routes, which require `Authorization: Bearer <admin token>`
+    let delete_bosses = {
+        let handler = handler.clone();
+        warp::path!("admin" / "bosses")
+            .and(warp::delete())
+            .and(auth::admin(admin_token.clone()))
+            .map(move || {
+                handler.clear();
+                StatusCode::NO_CONTENT
+            })
+    };
+
+    // Stops accepting new websocket connections, ahead of shutting down
+    let post_drain = {
+        let handler = handler.clone();
+        warp::path!("drain")
+            .and(warp::post())
+            .and(auth::admin(admin_token.clone()))
+            .map(move || {
+                handler.drain();
+                StatusCode::NO_CONTENT
+            })
+    };
+
+    // Every boss and its history, as a JSON array that can be loaded with
+    // `RaidHandler::from_snapshot`. Bosses are serialized one at a time as the response is
+    // streamed, so the whole state isn't buffered in memory.
+    let get_export = {
+        let handler = handler.clone();
+        warp::path!("export")
+            .and(warp::get())
+            .and(auth::admin(admin_token.clone()))
+            .map(move || {
+                let bosses = Vec::clone(&handler.bosses());
+                let snapshots =
+                    futures::stream::iter(bosses.into_iter().enumerate()).map(|(index, entry)| {
+                        let mut chunk = if index == 0 {
+                            Vec::new()
+                        } else {
+                            b",".to_vec()
+                        };
+                        serde_json::to_writer(&mut chunk, &entry.snapshot())?;
+                        Ok::<_, serde_json::Error>(chunk)
+                    });
+                let body = futures::stream::once(async { Ok(b"[".to_vec()) })
+                    .chain(snapshots)
+                    .chain(futures::stream::once(async { Ok(b"]".to_vec()) }));
+
+                Response::builder()
+                    .header("content-type", "application/json")
+                    .header(
+                        "content-disposition",
+                        "attachment; filename=\"petronel-export.json\"",
+                    )
+                    .body(hyper::Body::wrap_stream(body))
+            })
+    };
+
+    // Merges a snapshot from `/export` into the current state (see `RaidHandler::import`)
+    let post_import = {
+        let handler = handler.clone();
+        warp::path!("import")
+            .and(warp::post())
+            .and(auth::admin(admin_token))
+            .and(warp::query::<ImportQuery>())
+            .and(warp::body::content_length_limit(MAX_IMPORT_BODY_BYTES))
+            .and(warp::body::json())
+            .map(move |query: ImportQuery, snapshot: Vec<BossSnapshot>| {
+                match handler.import(snapshot, query.history) {
+                    Ok(imported) => warp::reply::json(&serde_json::json!({ "imported": imported }))
+                        .into_response(),
+                    Err(e) => rejection::error_reply(StatusCode::BAD_REQUEST, e).into_response(),
+                }
+            })
+    };
+
+    let get_metrics_json = {
+        let handler = handler.clone();
+        warp::path!("metrics.json")
+            .and(warp::get())
+            .map(move || warp::reply::json(&handler.metrics_json()))
+    };
+
+    // For load balancers, which should stop routing new connections here while draining
+    let get_health = {
+        let handler = handler.clone();
+        warp::path!("health").and(warp::get()).map(move || {
+            let (status, body) = if handler.is_draining() {
+                (StatusCode::SERVICE_UNAVAILABLE, "draining")
+            } else {
+                (StatusCode::OK, "ok")
+            };
+            warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({ "status": body })),
+                status,
+            )
+        })
+    };
+
+    let get_version = warp::path!("version").and(warp::get()).map(|| {
+        warp::reply::json(&serde_json::json!({
+            "version": build_info::VERSION,
+            "commit": build_info::COMMIT,
+        }))
     });
 
+    // TODO: Configurable
+    let get_metrics = warp::path!("metrics")
+        .and(warp::get())
+        .and(warp::query::<MetricsQuery>())
+        .map(move |query: MetricsQuery| match query.format {
+            Some(MetricsFormat::Json) => warp::reply::json(&handler.metrics_json()).into_response(),
+            Some(MetricsFormat::Prometheus) | None => Response::builder()
+                .header("content-type", "text/plain; version=0.0.4")
+                .body({
+                    let mut out = String::new();
+                    handler.write_metrics(&mut out);
+                    out
+                })
+                .into_response(),
+        });
+
     // TODO: Configurable
     let cors = warp::cors()
         .allow_any_origin()
         .allow_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"])
-        .allow_headers(vec!["accept", "content-type"])
+        .allow_headers(vec!["accept", "authorization", "content-type"])
         .max_age(86400);
 
     let routes = post_graphql
+        .or(post_graphql_query)
         .or(websocket_graphql)
+        .or(get_graphql)
+        .or(get_graphql_stream)
         .or(get_graphiql)
+        .or(get_admin_ui)
+        .or(get_debug_events)
         .or(get_metrics)
+        .or(get_metrics_json)
+        .or(get_version)
+        .or(get_health)
+        .or(delete_bosses)
+        .or(post_drain)
+        .or(get_export)
+        .or(post_import)
+        .recover(rejection::handle_rejection)
         .with(cors);
 
     routes
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::PrometheusMetricFactory;
+    use crate::model::Language;
+    use crate::raid_handler::{HistoryLimit, MergeOptions, RaidFilter};
+    use warp::http::StatusCode;
+
+    fn test_routes() -> impl Filter<Extract = impl warp::Reply> + Clone {
+        test_routes_with_admin_token(None)
+    }
+
+    fn test_routes_with_admin_token(
+        admin_token: Option<&str>,
+    ) -> impl Filter<Extract = impl warp::Reply> + Clone {
+        test_routes_with_options(admin_token, false, false)
+    }
+
+    fn test_handler() -> RaidHandler {
+        RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(50),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        )
+    }
+
+    fn test_routes_with_options(
+        admin_token: Option<&str>,
+        enable_admin_ui: bool,
+        enable_debug_events: bool,
+    ) -> impl Filter<Extract = impl warp::Reply> + Clone {
+        routes(
+            test_handler(),
+            PageSizeLimit {
+                max: 100,
+                strict: false,
+            },
+            1024,
+            admin_token.map(String::from),
+            enable_admin_ui,
+            enable_debug_events,
+            None,
+        )
+    }
+
+    fn error_body(body: &[u8]) -> serde_json::Value {
+        serde_json::from_slice(body).expect("invalid JSON error body")
+    }
+
+    #[tokio::test]
+    async fn unknown_path() {
+        let resp = warp::test::request()
+            .method("GET")
+            .path("/unknown")
+            .reply(&test_routes())
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            error_body(resp.body()),
+            serde_json::json!({ "error": { "message": "Not found", "status": 404 } })
+        );
+    }
+
+    #[tokio::test]
+    async fn wrong_method() {
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/metrics.json")
+            .reply(&test_routes())
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        let body = error_body(resp.body());
+        assert_eq!(body["error"]["status"], 405);
+        assert!(body["error"]["message"].is_string());
+    }
+
+    #[tokio::test]
+    async fn body_too_large() {
+        let query = format!(
+            r#"{{"query": "{{ bosses(first: 1) {{ nodes {{ level }} }} }}", "padding": "{}"}}"#,
+            "a".repeat(2048)
+        );
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/graphql")
+            .header("accept", "application/json")
+            .header("content-type", "application/json")
+            .body(query)
+            .reply(&test_routes())
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(error_body(resp.body())["error"]["status"], 413);
+    }
+
+    #[tokio::test]
+    async fn get_query() {
+        let resp = warp::test::request()
+            .method("GET")
+            .path("/graphql?query=%7B%20bosses(first%3A%2010)%20%7B%20nodes%20%7B%20level%20%7D%20%7D%20%7D")
+            .reply(&test_routes())
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "data": { "bosses": { "nodes": [] } } })
+        );
+    }
+
+    #[tokio::test]
+    async fn get_query_with_variables() {
+        // `first` is read from the variables, and negative values are rejected by the resolver
+        let resp = warp::test::request()
+            .method("GET")
+            .path("/graphql?query=query%20Q(%24n%3A%20Int)%20%7B%20bosses(first%3A%20%24n)%20%7B%20nodes%20%7B%20level%20%7D%20%7D%20%7D&variables=%7B%22n%22%3A%20-1%7D")
+            .reply(&test_routes())
+            .await;
+
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(
+            body["errors"][0]["message"],
+            "`first` and `last` must be non-negative"
+        );
+
+        // Malformed variables
+        let resp = warp::test::request()
+            .method("GET")
+            .path("/graphql?query=%7B%20bosses%20%7B%20nodes%20%7B%20level%20%7D%20%7D%20%7D&variables=%7B")
+            .reply(&test_routes())
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error_body(resp.body())["error"]["status"], 400);
+    }
+
+    #[tokio::test]
+    async fn post_application_graphql() {
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/graphql")
+            .header("content-type", "application/graphql")
+            .body("{ bosses(first: 10) { nodes { level } } }")
+            .reply(&test_routes())
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "data": { "bosses": { "nodes": [] } } })
+        );
+    }
+
+    #[tokio::test]
+    async fn admin_auth() {
+        let routes = test_routes_with_admin_token(Some("hunter2"));
+        let request = || warp::test::request().method("DELETE").path("/admin/bosses");
+
+        let resp = request().reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(error_body(resp.body())["error"]["status"], 401);
+
+        let resp = request()
+            .header("authorization", "Bearer hunter3")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = request()
+            .header("authorization", "Bearer hunter2")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        // Public routes don't require auth
+        let resp = warp::test::request()
+            .method("GET")
+            .path("/metrics.json")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn admin_routes_disabled_without_token() {
+        let resp = warp::test::request()
+            .method("DELETE")
+            .path("/admin/bosses")
+            .header("authorization", "Bearer hunter2")
+            .reply(&test_routes())
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn graphql_request_metrics() {
+        let routes = test_routes();
+        let query = |body: &str| {
+            warp::test::request()
+                .method("POST")
+                .path("/graphql")
+                .header("accept", "application/json")
+                .header("content-type", "application/json")
+                .body(body.to_owned())
+                .reply(&routes)
+        };
+        let metrics = || async {
+            let resp = warp::test::request()
+                .method("GET")
+                .path("/metrics.json")
+                .reply(&routes)
+                .await;
+            serde_json::from_slice::<serde_json::Value>(resp.body()).unwrap()
+        };
+
+        let resp = query(r#"{"query": "query Bosses { bosses(first: 1) { nodes { level } } }", "operationName": "Bosses"}"#).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = query(r#"{"query": "{ bosses(first: -1) { nodes { level } } }"}"#).await;
+        assert_eq!(resp.status(), StatusCode::OK); // Resolver errors still return data
+
+        let body = metrics().await;
+        assert_eq!(body["graphqlRequestsTotal"], 2);
+        assert_eq!(body["graphqlErrorsTotal"], 1);
+
+        let resp = warp::test::request()
+            .method("GET")
+            .path("/metrics")
+            .reply(&routes)
+            .await;
+        let body = std::str::from_utf8(resp.body()).unwrap();
+        assert!(body.contains(r#"petronel_graphql_requests_total{operation="Bosses"} 1"#));
+        assert!(body.contains(r#"petronel_graphql_requests_total{operation="anonymous"} 1"#));
+        assert!(body.contains("petronel_graphql_errors_total 1"));
+    }
+
+    #[tokio::test]
+    async fn admin_ui() {
+        let request = || warp::test::request().method("GET").path("/admin");
+
+        let resp = request()
+            .reply(&test_routes_with_options(None, true, false))
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], "text/html");
+        let body = std::str::from_utf8(resp.body()).unwrap();
+        assert!(body.contains("/metrics.json"));
+
+        let resp = request().reply(&test_routes()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn subscription_stream() {
+        use crate::cancellation::CancellationToken;
+        use crate::model::{Raid, Region};
+        use hyper::body::HttpBody;
+
+        let handler = test_handler();
+        let routes = routes(
+            handler.clone(),
+            PageSizeLimit {
+                max: 100,
+                strict: false,
+            },
+            1024,
+            None,
+            false,
+            false,
+            None,
+        );
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        // `subscription { raids { raidId } }`
+        let uri = format!(
+            "http://{}/graphql/stream?query=subscription%20%7B%20raids%20%7B%20raidId%20%7D%20%7D",
+            addr
+        );
+        let mut resp = hyper::Client::new()
+            .get(uri.parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], "text/event-stream");
+
+        // The stream is counted as a connection until the client disconnects
+        let gauge = {
+            let handler = handler.clone();
+            move || handler.metric_factory().websocket_connections_gauge().get()
+        };
+        assert_eq!(gauge(), 1);
+
+        // Keep pushing raids, since the subscription may not have started yet
+        let cancel = CancellationToken::new();
+        let pusher = async move {
+            for tweet_id in 1.. {
+                handler.push(Raid {
+                    id: format!("{:08X}", tweet_id),
+                    tweet_id,
+                    user_name: "walfieee".into(),
+                    user_image: None,
+                    boss_name: "Lv60 オオゾラッコ".into(),
+                    created_at: chrono::Utc::now().into(),
+                    text: None,
+                    language: Language::Japanese,
+                    region: Region::Jp,
+                    image_url: None,
+                    extra_image_urls: Default::default(),
+                });
+                tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+            }
+        };
+        tokio::spawn({
+            let cancel = cancel.clone();
+            async move { cancel.run_until_cancelled(pusher).await }
+        });
+
+        let mut body = String::new();
+        while body.matches("\n\n").count() < 2 {
+            let chunk = resp.body_mut().data().await.unwrap().unwrap();
+            body.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+
+        let events = body
+            .split("\n\n")
+            .take(2)
+            .map(|event| {
+                let data = event.trim_start_matches("data:");
+                serde_json::from_str::<serde_json::Value>(data).unwrap()
+            })
+            .collect::<Vec<_>>();
+        for event in &events {
+            assert!(event["data"]["raids"]["raidId"].is_string());
+        }
+        assert_ne!(events[0], events[1]);
+
+        // The disconnect is noticed when the next raid is sent
+        drop(resp);
+        for _ in 0..100 {
+            if gauge() == 0 {
+                break;
+            }
+            tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(gauge(), 0);
+        cancel.cancel();
+    }
+
+    #[tokio::test]
+    async fn debug_events_disabled() {
+        let resp = warp::test::request()
+            .method("GET")
+            .path("/debug/events")
+            .reply(&test_routes())
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn metrics_formats() {
+        let routes = test_routes();
+        let request = |path| warp::test::request().method("GET").path(path);
+
+        for path in &["/metrics", "/metrics?format=prometheus"] {
+            let resp = request(path).reply(&routes).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.headers()["content-type"], "text/plain; version=0.0.4");
+            let body = std::str::from_utf8(resp.body()).unwrap();
+            assert!(body.contains("# TYPE petronel_websocket_connections gauge"));
+        }
+
+        let resp = request("/metrics?format=json").reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], "application/json");
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["websocketConnections"], 0);
+
+        let resp = request("/metrics?format=xml").reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn version() {
+        let resp = warp::test::request()
+            .method("GET")
+            .path("/version")
+            .reply(&test_routes())
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(body["commit"], build_info::COMMIT);
+    }
+
+    #[tokio::test]
+    async fn websocket_connection_gauge() {
+        let handler = test_handler();
+        let gauge = || handler.metric_factory().websocket_connections_gauge().get();
+
+        // Connection task panics
+        let task = tokio::spawn({
+            let handler = handler.clone();
+            async move {
+                let _connection = WebsocketConnection::try_new(handler, None).unwrap();
+                panic!("connection task panicked");
+            }
+        });
+        assert!(task.await.unwrap_err().is_panic());
+        assert_eq!(gauge(), 0);
+
+        // Connection future is dropped before completing
+        let connection = WebsocketConnection::try_new(handler.clone(), None).unwrap();
+        let future = futures::future::pending::<()>().map(move |()| drop(connection));
+        assert_eq!(gauge(), 1);
+        drop(future);
+        assert_eq!(gauge(), 0);
+    }
+
+    #[tokio::test]
+    async fn export() {
+        use crate::model::{Raid, Region};
+        use crate::raid_handler::BossSnapshot;
+
+        let handler = test_handler();
+        let routes = routes(
+            handler.clone(),
+            PageSizeLimit {
+                max: 100,
+                strict: false,
+            },
+            1024,
+            Some("hunter2".to_owned()),
+            false,
+            false,
+            None,
+        );
+        let request = || {
+            warp::test::request()
+                .method("GET")
+                .path("/export")
+                .header("authorization", "Bearer hunter2")
+        };
+
+        let resp = request().reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.body().as_ref(), b"[]");
+
+        let raid = |tweet_id, boss_name: &str, language| Raid {
+            id: format!("{:08X}", tweet_id),
+            tweet_id,
+            user_name: "walfieee".into(),
+            user_image: None,
+            boss_name: boss_name.into(),
+            created_at: chrono::Utc::now().into(),
+            text: None,
+            language,
+            region: Region::from(language),
+            image_url: None,
+            extra_image_urls: Default::default(),
+        };
+        handler.push(raid(1, "Lv60 オオゾラッコ", Language::Japanese));
+        handler.push(raid(2, "Lv60 オオゾラッコ", Language::Japanese));
+        handler.push(raid(3, "Lvl 60 Ozorotter", Language::English));
+
+        let resp = request().reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], "application/json");
+        let snapshot: Vec<BossSnapshot> = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(snapshot, handler.snapshot());
+
+        // The export can be loaded back into a new handler
+        let restored = RaidHandler::from_snapshot(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            snapshot,
+            HistoryLimit::new(50),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        assert_eq!(restored.snapshot(), handler.snapshot());
+
+        let resp = warp::test::request()
+            .method("GET")
+            .path("/export")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn import() {
+        use crate::model::{Boss, Raid, Region};
+
+        let handler = test_handler();
+        let routes = routes(
+            handler.clone(),
+            PageSizeLimit {
+                max: 100,
+                strict: false,
+            },
+            1024,
+            Some("hunter2".to_owned()),
+            false,
+            false,
+            None,
+        );
+        let request = |path: &str, body: &serde_json::Value| {
+            warp::test::request()
+                .method("POST")
+                .path(path)
+                .header("authorization", "Bearer hunter2")
+                .json(body)
+        };
+
+        let raid = Raid {
+            id: "ABCD1234".to_owned(),
+            tweet_id: 1,
+            user_name: "walfieee".into(),
+            user_image: None,
+            boss_name: "Lvl 60 Ozorotter".into(),
+            created_at: chrono::Utc::now().into(),
+            text: None,
+            language: Language::English,
+            region: Region::En,
+            image_url: None,
+            extra_image_urls: Default::default(),
+        };
+        let snapshot = serde_json::json!([{
+            "boss": Boss::from(&raid),
+            "history": [raid],
+        }]);
+
+        let resp = request("/import?history=true", &snapshot)
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body, serde_json::json!({ "imported": 1 }));
+
+        let boss = handler.boss(&"Lvl 60 Ozorotter".into()).unwrap();
+        assert_eq!(boss.history().read().len(), 1);
+
+        // Importing the same snapshot again doesn't replace the boss, since it isn't newer
+        let resp = request("/import", &snapshot).reply(&routes).await;
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body, serde_json::json!({ "imported": 0 }));
+
+        // Raids in a boss's history must be for that boss
+        let mut invalid = snapshot.clone();
+        invalid[0]["history"][0]["bossName"] = "Lvl 100 Huanglong".into();
+        let resp = request("/import?history=true", &invalid)
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/import")
+            .json(&snapshot)
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    // Returns the stream, to keep the connection open, and the response status line
+    async fn websocket_handshake(addr: std::net::SocketAddr) -> (tokio::net::TcpStream, String) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"GET /graphql HTTP/1.1\r\n\
+                  Host: localhost\r\n\
+                  Connection: Upgrade\r\n\
+                  Upgrade: websocket\r\n\
+                  Sec-WebSocket-Version: 13\r\n\
+                  Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                  Sec-WebSocket-Protocol: graphql-ws\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let mut buf = vec![0; 1024];
+        let len = stream.read(&mut buf).await.unwrap();
+        let response = String::from_utf8_lossy(&buf[..len]).into_owned();
+        let status = response.lines().next().unwrap_or_default().to_owned();
+        (stream, status)
+    }
+
+    #[tokio::test]
+    async fn max_websocket_connections() {
+        let handler = test_handler();
+        let gauge = || handler.metric_factory().websocket_connections_gauge().get();
+        let routes = routes(
+            handler.clone(),
+            PageSizeLimit {
+                max: 100,
+                strict: false,
+            },
+            1024,
+            None,
+            false,
+            false,
+            Some(2),
+        );
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let connect = || websocket_handshake(addr);
+
+        let (first, status) = connect().await;
+        assert_eq!(status, "HTTP/1.1 101 Switching Protocols");
+        let (_second, status) = connect().await;
+        assert_eq!(status, "HTTP/1.1 101 Switching Protocols");
+        assert_eq!(gauge(), 2);
+
+        let (_third, status) = connect().await;
+        assert_eq!(status, "HTTP/1.1 503 Service Unavailable");
+        assert_eq!(gauge(), 2);
+
+        // Closing a connection frees up a slot
+        drop(first);
+        for _ in 0..100 {
+            if gauge() < 2 {
+                break;
+            }
+            tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(gauge(), 1);
+        let (_fourth, status) = connect().await;
+        assert_eq!(status, "HTTP/1.1 101 Switching Protocols");
+        assert_eq!(gauge(), 2);
+    }
+
+    #[tokio::test]
+    async fn drain() {
+        use tokio::io::AsyncReadExt;
+
+        let handler = test_handler();
+        let routes = routes(
+            handler.clone(),
+            PageSizeLimit {
+                max: 100,
+                strict: false,
+            },
+            1024,
+            Some("secret".to_owned()),
+            false,
+            false,
+            None,
+        );
+
+        let health = |routes| async move {
+            let resp = warp::test::request()
+                .method("GET")
+                .path("/health")
+                .reply(routes)
+                .await;
+            (
+                resp.status(),
+                serde_json::from_slice::<serde_json::Value>(resp.body()).unwrap(),
+            )
+        };
+        assert_eq!(
+            health(&routes).await,
+            (StatusCode::OK, serde_json::json!({ "status": "ok" }))
+        );
+
+        let (addr, server) = warp::serve(routes.clone()).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let (mut existing, status) = websocket_handshake(addr).await;
+        assert_eq!(status, "HTTP/1.1 101 Switching Protocols");
+
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/drain")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(!handler.is_draining());
+
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/drain")
+            .header("authorization", "Bearer secret")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(handler.is_draining());
+
+        assert_eq!(
+            health(&routes).await,
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                serde_json::json!({ "status": "draining" })
+            )
+        );
+
+        // New connections are refused, including event streams
+        let (_new, status) = websocket_handshake(addr).await;
+        assert_eq!(status, "HTTP/1.1 503 Service Unavailable");
+        let resp = warp::test::request()
+            .method("GET")
+            .path("/graphql/stream?query=subscription%20%7B%20raids%20%7B%20raidId%20%7D%20%7D")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // The existing connection is left open
+        let mut buf = vec![0; 1024];
+        let timeout = std::time::Duration::from_millis(50);
+        match tokio::time::timeout(timeout, existing.read(&mut buf)).await {
+            Err(_elapsed) => {}
+            Ok(result) => assert_ne!(result.unwrap(), 0, "connection was closed"),
+        }
+        assert_eq!(
+            handler.metric_factory().websocket_connections_gauge().get(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn tls() {
+        let testdata = concat!(env!("CARGO_MANIFEST_DIR"), "/src/graphql/testdata");
+        let (addr, server) = warp::serve(test_routes())
+            .tls()
+            .cert_path(format!("{}/cert.pem", testdata))
+            .key_path(format!("{}/key.pem", testdata))
+            .bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        // The certificate is self-signed, so this only checks that the server is listening
+        tokio::net::TcpStream::connect(addr)
+            .await
+            .expect("failed to connect to TLS server");
+    }
+}
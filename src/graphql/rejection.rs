@@ -0,0 +1,89 @@
+use crate::graphql::auth::Unauthorized;
+use serde::Serialize;
+use std::convert::Infallible;
+use warp::http::StatusCode;
+use warp::{reject, Rejection, Reply};
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: ErrorBody,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    message: String,
+    status: u16,
+}
+
+/// Rejects websocket upgrades once `--max-websocket-connections` is reached
+#[derive(Debug)]
+pub struct TooManyConnections;
+
+impl reject::Reject for TooManyConnections {}
+
+/// Rejects websocket upgrades while the server is draining
+#[derive(Debug)]
+pub struct Draining;
+
+impl reject::Reject for Draining {}
+
+/// The blocking task executing a GraphQL request panicked
+#[derive(Debug)]
+pub struct ExecutionFailed;
+
+impl reject::Reject for ExecutionFailed {}
+
+pub fn error_reply(status: StatusCode, message: impl ToString) -> impl Reply {
+    let body = ErrorResponse {
+        error: ErrorBody {
+            message: message.to_string(),
+            status: status.as_u16(),
+        },
+    };
+
+    warp::reply::with_status(warp::reply::json(&body), status)
+}
+
+/// Converts rejections into JSON error responses
+pub async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Infallible> {
+    let (status, message) = if rejection.is_not_found() {
+        (StatusCode::NOT_FOUND, "Not found".to_owned())
+    } else if rejection.find::<Unauthorized>().is_some() {
+        (StatusCode::UNAUTHORIZED, "Unauthorized".to_owned())
+    } else if rejection.find::<TooManyConnections>().is_some() {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Too many websocket connections".to_owned(),
+        )
+    } else if rejection.find::<Draining>().is_some() {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Server is draining".to_owned(),
+        )
+    } else if let Some(e) = rejection.find::<reject::PayloadTooLarge>() {
+        (StatusCode::PAYLOAD_TOO_LARGE, e.to_string())
+    } else if let Some(e) = rejection.find::<reject::LengthRequired>() {
+        (StatusCode::LENGTH_REQUIRED, e.to_string())
+    } else if let Some(e) = rejection.find::<reject::UnsupportedMediaType>() {
+        (StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string())
+    } else if let Some(e) = rejection.find::<warp::body::BodyDeserializeError>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if let Some(e) = rejection.find::<reject::InvalidQuery>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if let Some(e) = rejection.find::<reject::MissingHeader>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if let Some(e) = rejection.find::<reject::InvalidHeader>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if let Some(e) = rejection.find::<reject::MethodNotAllowed>() {
+        // Checked last, since other routes may have matched the path but rejected the request
+        // for a more specific reason
+        (StatusCode::METHOD_NOT_ALLOWED, e.to_string())
+    } else {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Internal server error".to_owned(),
+        )
+    };
+
+    Ok(error_reply(status, message))
+}
@@ -5,6 +5,7 @@ use juniper::{FieldResult, IntoFieldResult};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::borrow::Borrow;
+use std::collections::VecDeque;
 
 #[derive(Debug, Clone, PartialEq, juniper::GraphQLObject)]
 pub struct PageInfo {
@@ -14,6 +15,30 @@ pub struct PageInfo {
     pub end_cursor: Option<String>,
 }
 
+/// Upper bound on the number of edges that can be requested in a single page
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PageSizeLimit {
+    pub max: usize,
+    /// If true, requests above the limit are rejected instead of clamped
+    pub strict: bool,
+}
+
+impl PageSizeLimit {
+    /// Applies the limit to a `first` or `last` argument
+    pub fn apply(&self, count: Option<i32>) -> FieldResult<Option<i32>> {
+        match count {
+            Some(n) if n > 0 && n as usize > self.max => {
+                if self.strict {
+                    Err(format!("Page size must be at most {}", self.max)).into_result()
+                } else {
+                    Ok(Some(self.max as i32))
+                }
+            }
+            other => Ok(other),
+        }
+    }
+}
+
 pub trait Cursor: Serialize + DeserializeOwned {
     type Edge;
 
@@ -106,20 +131,21 @@ pub trait Cursor: Serialize + DeserializeOwned {
                 edges.skip(skip_n).map(map_fn).collect::<Vec<Out>>()
             }
             (FirstOrLast::Last(count), Some(before)) => {
-                // This is highly inefficient. TODO: Maybe use reversible iterator
-                let mut out = edges
-                    .take_while(|edge| !before.matches_edge(edge.as_ref()))
-                    .map(map_fn)
-                    .collect::<Vec<Out>>();
-
-                if out.len() <= count {
-                    out
-                } else {
-                    let skip_n = out.len() - count;
-                    skipped += skip_n;
-
-                    out.split_off(skip_n)
+                // Keep a sliding window of the last `count` edges before the cursor, so that
+                // only the returned page is allocated
+                let mut window = VecDeque::with_capacity(count);
+
+                for edge in edges.take_while(|edge| !before.matches_edge(edge.as_ref())) {
+                    if window.len() == count {
+                        skipped += 1;
+                        if window.pop_front().is_none() {
+                            continue;
+                        }
+                    }
+                    window.push_back(edge);
                 }
+
+                window.into_iter().map(map_fn).collect::<Vec<Out>>()
             }
         };
 
@@ -569,4 +595,39 @@ mod test {
             )
         );
     }
+
+    #[test]
+    fn page_size_limit() {
+        let clamp = PageSizeLimit {
+            max: 20,
+            strict: false,
+        };
+        let strict = PageSizeLimit {
+            max: 20,
+            strict: true,
+        };
+
+        for limit in &[clamp, strict] {
+            assert_eq!(limit.apply(None).unwrap(), None);
+            assert_eq!(limit.apply(Some(10)).unwrap(), Some(10));
+            assert_eq!(limit.apply(Some(20)).unwrap(), Some(20));
+            // Negative values are left for `paginate` to reject
+            assert_eq!(limit.apply(Some(-1)).unwrap(), Some(-1));
+        }
+
+        assert_eq!(clamp.apply(Some(1000000)).unwrap(), Some(20));
+        assert!(strict.apply(Some(21)).is_err());
+
+        // Clamped requests paginate as if the max had been requested
+        let page = TestCase {
+            first: None,
+            after: None,
+            last: clamp.apply(Some(1000000)).unwrap(),
+            before: None,
+        }
+        .run()
+        .unwrap();
+        assert_eq!(page.0, (81..=100).collect::<Vec<_>>());
+        assert!(page.1.has_previous_page);
+    }
 }
@@ -1,11 +1,13 @@
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::pin::Pin;
 use std::str;
 use std::sync::Arc;
 
-use crate::graphql::relay::{BossCursor, Cursor, PageInfo, TweetCursor};
+use crate::graphql::relay::{BossCursor, Cursor, PageInfo, PageSizeLimit, TweetCursor};
+use crate::metrics::MetricFactory;
 use crate::model::*;
-use crate::raid_handler::{BossEntry, RaidHandler};
+use crate::raid_handler::{BossEntry, LanguageStats, RaidHandler, RaidUpdate};
 
 use futures::future::ready;
 use futures::stream::Stream;
@@ -13,6 +15,7 @@ use juniper::{
     Arguments, BoxFuture, DefaultScalarValue, ExecutionResult, Executor, FieldResult, GraphQLType,
     Selection,
 };
+use tokio::stream::StreamExt;
 
 #[derive(juniper::GraphQLScalarValue)]
 #[graphql(transparent, name = "ID")]
@@ -23,12 +26,219 @@ pub struct Id(String);
 /// An ISO-8601 encoded UTC date string.
 pub struct GraphQlDateTime(String);
 
+#[derive(juniper::GraphQLScalarValue)]
+#[graphql(transparent, name = "Duration")]
+/// A length of time, in seconds.
+pub struct GraphQlDuration(i32);
+
+#[derive(juniper::GraphQLEnum)]
+#[graphql(name = "Region")]
+/// The game region that a tweet was posted from
+pub enum GraphQlRegion {
+    /// Japanese
+    Jp,
+    /// English
+    En,
+}
+
+impl From<Region> for GraphQlRegion {
+    fn from(region: Region) -> Self {
+        match region {
+            Region::Jp => Self::Jp,
+            Region::En => Self::En,
+        }
+    }
+}
+
+#[derive(juniper::GraphQLEnum)]
+#[graphql(name = "Language")]
+/// The language of a tweet
+pub enum GraphQlLanguage {
+    /// Japanese
+    Japanese,
+    /// English
+    English,
+}
+
+impl From<Language> for GraphQlLanguage {
+    fn from(language: Language) -> Self {
+        match language {
+            Language::Japanese => Self::Japanese,
+            Language::English => Self::English,
+        }
+    }
+}
+
+#[derive(juniper::GraphQLEnum)]
+#[graphql(name = "Element")]
+/// The element of a boss
+pub enum GraphQlElement {
+    Fire,
+    Water,
+    Earth,
+    Wind,
+    Light,
+    Dark,
+}
+
+impl From<Element> for GraphQlElement {
+    fn from(element: Element) -> Self {
+        match element {
+            Element::Fire => Self::Fire,
+            Element::Water => Self::Water,
+            Element::Earth => Self::Earth,
+            Element::Wind => Self::Wind,
+            Element::Light => Self::Light,
+            Element::Dark => Self::Dark,
+        }
+    }
+}
+
+#[derive(juniper::GraphQLEnum)]
+#[graphql(name = "MergeSource")]
+/// How a boss's Japanese and English names were matched
+pub enum GraphQlMergeSource {
+    /// The boss hasn't been matched with a boss in the other language
+    None,
+    /// Matched automatically, since bosses in each language had the same image hash
+    ImageHash,
+    /// Matched using the hardcoded list of known bosses
+    KnownBoss,
+    /// Matched by level and a similar name, since neither boss had an image
+    Name,
+}
+
+#[derive(juniper::GraphQLObject)]
+/// Diagnostic info about how a boss's names were matched, for debugging translations
+pub struct MergeInfo {
+    source: GraphQlMergeSource,
+    /// The matched image hash, if merged by image hash. 64-bit hashes are shown as a signed
+    /// integer, and other sizes in hexadecimal.
+    image_hash: Option<String>,
+}
+
+impl From<Option<MergeSource>> for MergeInfo {
+    fn from(source: Option<MergeSource>) -> Self {
+        match source {
+            None => Self {
+                source: GraphQlMergeSource::None,
+                image_hash: None,
+            },
+            Some(MergeSource::ImageHash(hash)) => Self {
+                source: GraphQlMergeSource::ImageHash,
+                image_hash: Some(hash.to_string()),
+            },
+            Some(MergeSource::KnownBoss) => Self {
+                source: GraphQlMergeSource::KnownBoss,
+                image_hash: None,
+            },
+            Some(MergeSource::Name) => Self {
+                source: GraphQlMergeSource::Name,
+                image_hash: None,
+            },
+        }
+    }
+}
+
+#[derive(juniper::GraphQLEnum, Clone, Copy, Debug, PartialEq)]
+/// Fields that bosses can be ordered by
+pub enum BossOrderField {
+    /// Boss level, then name
+    Level,
+    /// Boss name
+    Name,
+    /// When a tweet for the boss was last seen
+    LastSeen,
+    /// Total number of tweets seen for the boss
+    TweetCount,
+}
+
+#[derive(juniper::GraphQLEnum, Clone, Copy, Debug, PartialEq)]
+/// Sort direction
+pub enum OrderDirection {
+    Asc,
+    Desc,
+}
+
+#[derive(juniper::GraphQLInputObject, Debug)]
+/// Ordering options for bosses
+pub struct BossOrder {
+    field: BossOrderField,
+    /// Defaults to ascending
+    direction: Option<OrderDirection>,
+}
+
+impl BossOrder {
+    fn sort(&self, bosses: &mut [Arc<BossEntry>], preferred: Language) {
+        // Ties are broken by level, then name, so the order is deterministic. Keys are computed
+        // once per boss, since values like the tweet count can change while sorting.
+        bosses.sort_by_cached_key(|entry| {
+            let (level, name) = entry.sort_key(preferred);
+            match self.field {
+                BossOrderField::Level => (0, level, name),
+                BossOrderField::Name => (0, None, name),
+                BossOrderField::LastSeen => (entry.boss().last_seen_at.as_i64(), level, name),
+                BossOrderField::TweetCount => (entry.tweet_count() as i64, level, name),
+            }
+        });
+
+        if self.direction == Some(OrderDirection::Desc) {
+            bosses.reverse();
+        }
+    }
+}
+
+#[derive(juniper::GraphQLEnum, Clone, Copy)]
+#[graphql(name = "ImageSize")]
+/// Size variant of a Twitter media image
+pub enum GraphQlImageSize {
+    Thumb,
+    Small,
+    Medium,
+    Large,
+}
+
+impl From<GraphQlImageSize> for ImageSize {
+    fn from(size: GraphQlImageSize) -> Self {
+        match size {
+            GraphQlImageSize::Thumb => Self::Thumb,
+            GraphQlImageSize::Small => Self::Small,
+            GraphQlImageSize::Medium => Self::Medium,
+            GraphQlImageSize::Large => Self::Large,
+        }
+    }
+}
+
 pub struct Query;
 
-impl juniper::Context for RaidHandler {}
+/// Shared state available to all resolvers
+#[derive(Clone)]
+pub struct Context {
+    pub handler: RaidHandler,
+    pub page_size_limit: PageSizeLimit,
+}
+
+impl juniper::Context for Context {}
+
+impl Context {
+    // Subscriptions over websockets don't go through `graphql::execute`, so they're recorded
+    // when each subscription field is resolved
+    fn record_subscription(&self, field: &str) {
+        let operation = format!("subscription:{}", field);
+        self.handler
+            .metric_factory()
+            .record_graphql_request(Some(&operation), false);
+    }
+}
+
+// Malformed IDs are an error, as opposed to valid IDs for nodes that don't exist
+fn parse_node_id(id: &str) -> FieldResult<NodeId<'static>> {
+    id.parse()
+        .map_err(|()| format!("Invalid node ID: {}", id).into())
+}
 
-fn get_node(raid_handler: &RaidHandler, id: &str) -> Option<Node> {
-    match id.parse().ok()? {
+fn get_node(raid_handler: &RaidHandler, id: &str) -> FieldResult<Option<Node>> {
+    let node = match parse_node_id(id)? {
         NodeId::Boss(name) => raid_handler.boss(&name).map(Node::Boss),
         NodeId::Tweet { boss_name, id } => raid_handler.boss(&boss_name).and_then(|boss| {
             boss.history()
@@ -37,42 +247,93 @@ fn get_node(raid_handler: &RaidHandler, id: &str) -> Option<Node> {
                 .find(|tweet| tweet.tweet_id == id)
                 .map(|t| Node::Tweet(t.clone()))
         }),
+    };
+
+    Ok(node)
+}
+
+fn get_nodes(raid_handler: &RaidHandler, ids: &[Id]) -> FieldResult<Vec<Option<Node>>> {
+    let node_ids = ids
+        .iter()
+        .map(|id| parse_node_id(&id.0))
+        .collect::<FieldResult<Vec<_>>>()?;
+
+    // Group the requested tweets by boss, so that each boss's history is only read once
+    let mut tweets_by_boss = HashMap::<&BossName, HashMap<TweetId, Option<Arc<Raid>>>>::new();
+    for node_id in &node_ids {
+        if let NodeId::Tweet { boss_name, id } = node_id {
+            tweets_by_boss
+                .entry(boss_name.as_ref())
+                .or_default()
+                .insert(*id, None);
+        }
     }
+
+    for (boss_name, tweets) in tweets_by_boss.iter_mut() {
+        if let Some(boss) = raid_handler.boss(boss_name) {
+            for tweet in boss.history().read().iter() {
+                if let Some(slot) = tweets.get_mut(&tweet.tweet_id) {
+                    *slot = Some(tweet.clone());
+                }
+            }
+        }
+    }
+
+    let nodes = node_ids
+        .iter()
+        .map(|node_id| match node_id {
+            NodeId::Boss(name) => raid_handler.boss(name).map(Node::Boss),
+            NodeId::Tweet { boss_name, id } => tweets_by_boss
+                .get(boss_name.as_ref())
+                .and_then(|tweets| tweets.get(id))
+                .cloned()
+                .flatten()
+                .map(Node::Tweet),
+        })
+        .collect();
+
+    Ok(nodes)
 }
 
-#[juniper::graphql_object(Context = RaidHandler)]
+#[juniper::graphql_object(Context = Context)]
 impl Query {
     /// Fetches an object given its ID.
-    fn node(&self, ctx: &RaidHandler, id: Id) -> Option<Node> {
-        get_node(ctx, &id.0)
+    fn node(&self, ctx: &Context, id: Id) -> FieldResult<Option<Node>> {
+        get_node(&ctx.handler, &id.0)
     }
 
     /// Fetches a list of objects given their IDs.
-    fn nodes(&self, ctx: &RaidHandler, ids: Vec<Id>) -> Vec<Option<Node>> {
-        // TODO: Could be optimized more for tweets. The IDs requested could be multiple tweets
-        // from the same boss, but we currently iterate through the list once for each requested
-        // tweet node, when instead we could iterate once per unique boss.
-        ids.iter().map(|id| get_node(ctx, &id.0)).collect()
+    fn nodes(&self, ctx: &Context, ids: Vec<Id>) -> FieldResult<Vec<Option<Node>>> {
+        get_nodes(&ctx.handler, &ids)
     }
 
-    /// A list of bosses
+    /// A list of bosses, ordered by level, then name, unless `orderBy` is specified.
+    ///
+    /// Cursors identify a boss rather than a position, so they should only be used with the same
+    /// `orderBy` they were returned with. For orderings that change over time (e.g., `LAST_SEEN`),
+    /// bosses may move between pages as new tweets arrive.
     fn bosses(
         &self,
-        ctx: &RaidHandler,
+        ctx: &Context,
         first: Option<i32>,
         after: Option<BossCursor>,
         last: Option<i32>,
         before: Option<BossCursor>,
+        order_by: Option<BossOrder>,
     ) -> FieldResult<BossesConnection> {
-        let all_bosses = ctx.bosses().clone();
+        let mut all_bosses = ctx.handler.bosses().clone();
+        if let Some(order) = order_by {
+            order.sort(&mut all_bosses, ctx.handler.canonical_language());
+        }
+        let limit = ctx.page_size_limit;
 
         let (bosses, page_info) = BossCursor::paginate(
             all_bosses.iter(),
             all_bosses.len(),
             Arc::clone,
-            first,
+            limit.apply(first)?,
             after,
-            last,
+            limit.apply(last)?,
             before,
         )?;
 
@@ -80,31 +341,203 @@ impl Query {
     }
 
     /// An individual boss
-    fn boss(&self, ctx: &RaidHandler, name: String) -> Option<Arc<BossEntry>> {
-        ctx.boss(&name.into())
+    fn boss(&self, ctx: &Context, name: String) -> Option<Arc<BossEntry>> {
+        ctx.handler.boss(&name.into())
+    }
+
+    /// The most recent raid tweets across all bosses, newest first
+    fn recent_raids(&self, ctx: &Context, first: i32) -> FieldResult<Vec<Arc<Raid>>> {
+        let limit = match ctx.page_size_limit.apply(Some(first))? {
+            Some(n) if n < 0 => return Err("`first` must be non-negative".into()),
+            Some(n) => n as usize,
+            None => ctx.page_size_limit.max,
+        };
+
+        Ok(ctx.handler.recent_raids(limit))
+    }
+
+    /// Bosses with the most tweets created within the last `window`, most first
+    fn trending_bosses(
+        &self,
+        ctx: &Context,
+        window: GraphQlDuration,
+        first: Option<i32>,
+    ) -> FieldResult<Vec<TrendingBoss>> {
+        if window.0 < 0 {
+            return Err("`window` must be non-negative".into());
+        }
+
+        let limit = match ctx.page_size_limit.apply(first)? {
+            Some(n) if n < 0 => return Err("`first` must be non-negative".into()),
+            Some(n) => n as usize,
+            None => ctx.page_size_limit.max,
+        };
+        let since = chrono::Utc::now() - chrono::Duration::seconds(window.0.into());
+
+        Ok(ctx
+            .handler
+            .trending_bosses(&since, limit)
+            .into_iter()
+            .map(|(boss, tweet_count)| TrendingBoss { boss, tweet_count })
+            .collect())
+    }
+
+    /// Tweet stats for each language, across all bosses
+    fn stream_stats(&self, ctx: &Context) -> Vec<LanguageStats> {
+        ctx.handler.language_stats()
     }
 }
 
 pub struct Subscription;
 type SubscriptionStream<T> = Pin<Box<dyn Stream<Item = T> + Send>>;
 
-#[juniper::graphql_subscription(Context = RaidHandler)]
+#[juniper::graphql_subscription(Context = Context)]
 impl Subscription {
-    async fn bosses(&self, ctx: &RaidHandler) -> SubscriptionStream<Arc<BossEntry>> {
-        Box::pin(ctx.subscribe_boss_updates())
+    async fn bosses(&self, ctx: &Context) -> SubscriptionStream<Arc<BossEntry>> {
+        ctx.record_subscription("bosses");
+        Box::pin(ctx.handler.subscribe_boss_updates())
+    }
+
+    /// Bosses whose image hash was just set. If this caused the boss to be merged with a boss
+    /// in another language, the merged boss is returned.
+    async fn image_hash_updates(&self, ctx: &Context) -> SubscriptionStream<Arc<BossEntry>> {
+        ctx.record_subscription("imageHashUpdates");
+        Box::pin(ctx.handler.subscribe_image_hash_updates())
+    }
+
+    /// Raid tweets for a boss. If `withHistory` is specified, the stream will start with up to
+    /// that many of the boss's most recent tweets.
+    ///
+    /// If `minLevel` is specified, tweets for lower level bosses are skipped. Tweets whose
+    /// level can't be determined are always included.
+    ///
+    /// If the subscriber falls too far behind, some tweets are dropped, and an event with
+    /// `missedCount` set is sent in their place.
+    async fn tweets(
+        &self,
+        ctx: &Context,
+        boss_name: String,
+        with_history: Option<i32>,
+        min_level: Option<i32>,
+    ) -> SubscriptionStream<RaidUpdate> {
+        ctx.record_subscription("tweets");
+        match with_history {
+            Some(count) if count > 0 => filter_min_level(
+                ctx.handler
+                    .subscribe_updates_with_history(boss_name.into(), count as usize),
+                min_level,
+            ),
+            _ => filter_min_level(ctx.handler.subscribe_updates(boss_name.into()), min_level),
+        }
     }
 
-    async fn tweets(&self, ctx: &RaidHandler, boss_name: String) -> SubscriptionStream<Arc<Raid>> {
-        Box::pin(ctx.subscribe(boss_name.into()))
+    /// Raid tweets for all bosses. If `minLevel` is specified, tweets for lower level bosses
+    /// are skipped. Tweets whose level can't be determined are always included.
+    async fn raids(&self, ctx: &Context, min_level: Option<i32>) -> SubscriptionStream<Arc<Raid>> {
+        ctx.record_subscription("raids");
+        filter_min_level(ctx.handler.subscribe_all(), min_level)
     }
 }
 
-#[juniper::graphql_object]
+/// Stream items that may have a boss level
+trait MaybeLevel {
+    fn level(&self) -> Option<Level>;
+}
+
+impl MaybeLevel for Arc<Raid> {
+    fn level(&self) -> Option<Level> {
+        Raid::level(self)
+    }
+}
+
+impl MaybeLevel for RaidUpdate {
+    fn level(&self) -> Option<Level> {
+        match self {
+            RaidUpdate::Raid(raid) => raid.level(),
+            RaidUpdate::Lagged(_) => None,
+        }
+    }
+}
+
+fn filter_min_level<S, T>(stream: S, min_level: Option<i32>) -> SubscriptionStream<T>
+where
+    S: Stream<Item = T> + Send + 'static,
+    T: MaybeLevel,
+{
+    match min_level {
+        None => Box::pin(stream),
+        Some(min_level) => Box::pin(
+            stream.filter(move |item| item.level().map_or(true, |level| level >= min_level)),
+        ),
+    }
+}
+
+#[juniper::graphql_object(name = "TweetEvent", Context = Context)]
+/// Either a tweet, or a notice that some tweets were dropped because the subscriber fell behind
+impl RaidUpdate {
+    fn tweet(&self) -> Option<&Arc<Raid>> {
+        match self {
+            RaidUpdate::Raid(raid) => Some(raid),
+            RaidUpdate::Lagged(_) => None,
+        }
+    }
+
+    /// Number of tweets that were dropped, if the subscriber lagged
+    fn missed_count(&self) -> Option<i32> {
+        match self {
+            RaidUpdate::Raid(_) => None,
+            RaidUpdate::Lagged(count) => Some(*count as i32),
+        }
+    }
+}
+
+pub struct TrendingBoss {
+    boss: Arc<BossEntry>,
+    tweet_count: usize,
+}
+
+#[juniper::graphql_object(Context = Context)]
+/// A boss, along with how many of its tweets were created in the requested window
+impl TrendingBoss {
+    fn boss(&self) -> &Arc<BossEntry> {
+        &self.boss
+    }
+
+    /// Number of tweets created within the window. Only tweets still in the boss's history are
+    /// counted.
+    fn tweet_count(&self) -> i32 {
+        self.tweet_count as i32
+    }
+}
+
+#[juniper::graphql_object(name = "LanguageStat")]
+/// Tweet stats for a single language
+impl LanguageStats {
+    fn language(&self) -> GraphQlLanguage {
+        self.language.into()
+    }
+
+    /// Number of tweets received in this language
+    fn tweet_count(&self) -> i32 {
+        self.tweet_count as i32
+    }
+
+    /// When a tweet in this language was last received, if ever
+    fn last_seen_at(&self) -> Option<GraphQlDateTime> {
+        self.last_seen_at.map(|datetime| {
+            GraphQlDateTime(datetime.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+        })
+    }
+}
+
+#[juniper::graphql_object(Context = Context)]
 /// A string (name, URL, etc) that differs based on language
 impl LangString {
-    /// The Japanese string, if it exists. Otherwise, the English one.
-    fn canonical(&self) -> Option<&str> {
-        self.ja.as_deref().or_else(|| self.en.as_deref())
+    /// The string in the server's preferred language (Japanese by default), if it exists.
+    /// Otherwise, the other one.
+    fn canonical(&self, ctx: &Context) -> Option<&str> {
+        self.preferring(ctx.handler.canonical_language())
+            .map(|s| &**s)
     }
 
     /// Japanese string
@@ -118,7 +551,7 @@ impl LangString {
     }
 }
 
-#[juniper::graphql_object(name = "Boss", interfaces = [Node])]
+#[juniper::graphql_object(name = "Boss", Context = Context, interfaces = [Node])]
 /// A raid boss
 impl BossEntry {
     /// Node ID
@@ -131,9 +564,31 @@ impl BossEntry {
         &self.boss().name
     }
 
-    /// Twitter image URL
-    fn image(&self) -> &LangString {
-        &self.boss().image
+    /// Twitter image URL. If `size` is unspecified, the original URL is returned.
+    fn image(&self, size: Option<GraphQlImageSize>) -> LangString {
+        self.boss().image.map(|image| {
+            let url = image.as_url();
+            match size {
+                None => url.into(),
+                Some(size) => ImageSize::from(size).apply(&url).into(),
+            }
+        })
+    }
+
+    /// Whether the boss name is known in both Japanese and English
+    fn translated(&self) -> bool {
+        let name = &self.boss().name;
+        name.ja.is_some() && name.en.is_some()
+    }
+
+    /// How this boss's Japanese and English names were matched
+    fn merge_info(&self) -> MergeInfo {
+        self.boss().merge_source().into()
+    }
+
+    /// The element of the boss, if known
+    fn element(&self) -> Option<GraphQlElement> {
+        self.boss().element.map(GraphQlElement::from)
     }
 
     /// The level of the boss, if known
@@ -141,22 +596,66 @@ impl BossEntry {
         self.boss().level.map(|level| level as i32)
     }
 
-    /// A list of raid tweets for this boss
+    /// When this boss was first seen
+    fn first_seen_at(&self) -> GraphQlDateTime {
+        let first_seen_at = self.boss().first_seen_at.as_datetime();
+        GraphQlDateTime(first_seen_at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+    }
+
+    /// The game regions this boss has been seen in
+    fn regions(&self) -> Vec<GraphQlRegion> {
+        self.boss().regions().map(GraphQlRegion::from).collect()
+    }
+
+    /// A list of raid tweets for this boss, ordered by tweet ID (newest first).
+    ///
+    /// Cursors point to a specific tweet, so they remain valid as new tweets arrive, until the
+    /// tweet is evicted from the boss's history.
     fn tweets(
         &self,
+        ctx: &Context,
         first: Option<i32>,
         after: Option<TweetCursor>,
         last: Option<i32>,
         before: Option<TweetCursor>,
     ) -> FieldResult<BossTweetsConnection> {
-        let all_tweets = self.history().read();
+        let limit = ctx.page_size_limit;
+        tweets_page(self, limit.apply(first)?, after, limit.apply(last)?, before)
+    }
+}
+
+fn tweets_page(
+    entry: &BossEntry,
+    first: Option<i32>,
+    after: Option<TweetCursor>,
+    last: Option<i32>,
+    before: Option<TweetCursor>,
+) -> FieldResult<BossTweetsConnection> {
+    // Only hold the read lock while copying out the requested page, so that incoming tweets
+    // for this boss aren't blocked on the rest of query resolution
+    let (tweets, page_info) = {
+        let all_tweets = entry.history().read();
         let tweet_count = all_tweets.len();
-        let iter = all_tweets.iter();
-        let (tweets, page_info) =
-            TweetCursor::paginate(iter, tweet_count, Arc::clone, first, after, last, before)?;
 
-        Ok(BossTweetsConnection { tweets, page_info })
-    }
+        // History is in arrival order, which is usually (but not always, e.g., after reconnecting
+        // to the stream) the same as tweet ID order. Only sort if necessary.
+        let is_sorted = all_tweets
+            .iter()
+            .zip(all_tweets.iter().skip(1))
+            .all(|(newer, older)| newer.tweet_id >= older.tweet_id);
+
+        if is_sorted {
+            let iter = all_tweets.iter();
+            TweetCursor::paginate(iter, tweet_count, Arc::clone, first, after, last, before)?
+        } else {
+            let mut sorted = all_tweets.iter().collect::<Vec<_>>();
+            sorted.sort_by(|a, b| b.tweet_id.cmp(&a.tweet_id));
+            let iter = sorted.into_iter();
+            TweetCursor::paginate(iter, tweet_count, Arc::clone, first, after, last, before)?
+        }
+    };
+
+    Ok(BossTweetsConnection { tweets, page_info })
 }
 
 struct BossesConnection {
@@ -164,7 +663,7 @@ struct BossesConnection {
     page_info: PageInfo,
 }
 
-#[juniper::graphql_object]
+#[juniper::graphql_object(Context = Context)]
 impl BossesConnection {
     fn edges(&self) -> Vec<BossesEdge> {
         self.bosses
@@ -186,7 +685,7 @@ struct BossesEdge {
     node: Arc<BossEntry>,
 }
 
-#[juniper::graphql_object]
+#[juniper::graphql_object(Context = Context)]
 impl BossesEdge {
     fn node(&self) -> &Arc<BossEntry> {
         &self.node
@@ -202,7 +701,7 @@ struct BossTweetsConnection {
     page_info: PageInfo,
 }
 
-#[juniper::graphql_object]
+#[juniper::graphql_object(Context = Context)]
 impl BossTweetsConnection {
     fn edges(&self) -> Vec<BossTweetsEdge> {
         self.tweets
@@ -226,7 +725,7 @@ struct BossTweetsEdge {
     node: Arc<Raid>,
 }
 
-#[juniper::graphql_object]
+#[juniper::graphql_object(Context = Context)]
 impl BossTweetsEdge {
     fn node(&self) -> &Arc<Raid> {
         &self.node
@@ -246,7 +745,13 @@ fn raid_node_id(raid: &Raid) -> Id {
     Id(node_id.to_string())
 }
 
-#[juniper::graphql_object(name = "Tweet", interfaces = [Node])]
+// Tweets timestamped in the future (due to clock skew) are treated as brand new
+fn raid_age_seconds(raid: &Raid, now: &DateTime) -> i32 {
+    let age = now.signed_duration_since(*raid.created_at.as_datetime());
+    age.num_seconds().max(0).min(i32::MAX.into()) as i32
+}
+
+#[juniper::graphql_object(name = "Tweet", Context = Context, interfaces = [Node])]
 /// A tweet containing a raid invite
 impl Raid {
     /// Node ID
@@ -259,6 +764,16 @@ impl Raid {
         &self.id
     }
 
+    /// In-game URL for joining the raid
+    fn join_url(&self) -> String {
+        Raid::join_url(self)
+    }
+
+    /// The boss this raid is for. This may be null if the boss has since been removed.
+    fn boss(&self, ctx: &Context) -> Option<Arc<BossEntry>> {
+        ctx.handler.boss(&self.boss_name)
+    }
+
     /// Tweet ID
     fn tweet_id(&self) -> Id {
         Id(self.tweet_id.to_string())
@@ -269,9 +784,42 @@ impl Raid {
         self.text.as_deref()
     }
 
-    /// Tweet creation date
-    fn created_at(&self) -> GraphQlDateTime {
-        GraphQlDateTime(self.created_at.as_str().to_owned())
+    /// Tweet creation date, in RFC 3339 format. By default this is in UTC, but a different
+    /// offset (e.g., `540` for JST) can be requested with `utcOffsetMinutes`.
+    fn created_at(&self, utc_offset_minutes: Option<i32>) -> FieldResult<GraphQlDateTime> {
+        let offset = match utc_offset_minutes {
+            None => return Ok(GraphQlDateTime(self.created_at.to_string())),
+            Some(minutes) => minutes
+                .checked_mul(60)
+                .and_then(chrono::FixedOffset::east_opt)
+                .ok_or("`utcOffsetMinutes` must be between -1439 and 1439")?,
+        };
+
+        let datetime = self.created_at.as_datetime().with_timezone(&offset);
+        Ok(GraphQlDateTime(
+            datetime.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        ))
+    }
+
+    /// Seconds since the tweet was created, according to the server's clock
+    fn age_seconds(&self) -> i32 {
+        raid_age_seconds(self, &chrono::Utc::now())
+    }
+
+    /// The game region that the tweet was posted from
+    fn region(&self) -> GraphQlRegion {
+        self.region.into()
+    }
+
+    /// Twitter image URL. If `size` is unspecified, the original URL is returned.
+    fn image_url(&self, size: Option<GraphQlImageSize>) -> Option<String> {
+        self.image_url.as_ref().map(|image| {
+            let url = image.as_url();
+            match size {
+                None => url,
+                Some(size) => ImageSize::from(size).apply(&url),
+            }
+        })
     }
 
     /// Twitter username
@@ -295,7 +843,7 @@ enum Node {
     Tweet(Arc<Raid>),
 }
 
-juniper::graphql_interface!(Node: () |&self| {
+juniper::graphql_interface!(Node: Context |&self| {
     field id() -> Id {
         match self {
             Node::Boss(boss) => Id(boss.node_id().to_string()),
@@ -352,3 +900,788 @@ impl juniper::GraphQLTypeAsync<DefaultScalarValue> for Node {
         )))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::PrometheusMetricFactory;
+    use crate::raid_handler::{HistoryLimit, MergeOptions, RaidFilter};
+    use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+
+    fn execute(handler: &RaidHandler, query: &str) -> serde_json::Value {
+        let ctx = Context {
+            handler: handler.clone(),
+            page_size_limit: PageSizeLimit {
+                max: 100,
+                strict: false,
+            },
+        };
+
+        let request = juniper::http::GraphQLRequest::new(query.to_owned(), None, None);
+        let response = request.execute_sync(&crate::graphql::schema(), &ctx);
+        serde_json::to_value(&response).expect("failed to serialize response")
+    }
+
+    fn raid(tweet_id: TweetId) -> Raid {
+        Raid {
+            id: format!("{:08X}", tweet_id),
+            tweet_id,
+            user_name: "walfieee".into(),
+            user_image: None,
+            boss_name: "Lv60 オオゾラッコ".into(),
+            created_at: chrono::Utc::now().into(),
+            text: None,
+            language: Language::Japanese,
+            region: Region::Jp,
+            image_url: None,
+            extra_image_urls: Default::default(),
+        }
+    }
+
+    #[test]
+    fn paginate_tweets_while_pushing() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(50),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        handler.push(raid(0));
+        let boss_name = BossName::from("Lv60 オオゾラッコ");
+
+        let done = Arc::new(AtomicBool::new(false));
+        let writer = std::thread::spawn({
+            let handler = handler.clone();
+            let done = done.clone();
+            move || {
+                for tweet_id in 1..10_000 {
+                    handler.push(raid(tweet_id));
+                }
+                done.store(true, SeqCst);
+            }
+        });
+
+        while !done.load(SeqCst) {
+            let entry = handler.boss(&boss_name).expect("missing boss");
+            let first_page = tweets_page(&entry, Some(10), None, None, None).unwrap();
+            let cursor = TweetCursor::from_edge(first_page.tweets.last().unwrap());
+            let last_page = tweets_page(&entry, None, None, Some(10), Some(cursor)).unwrap();
+
+            for page in &[first_page, last_page] {
+                assert!(page.tweets.len() <= 10);
+
+                // Each page is a consistent snapshot, newest first
+                let ids = page.tweets.iter().map(|t| t.tweet_id).collect::<Vec<_>>();
+                let mut sorted = ids.clone();
+                sorted.sort_by(|a, b| b.cmp(a));
+                assert_eq!(ids, sorted);
+            }
+        }
+
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn nodes() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(50),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+
+        let other_boss = |tweet_id| Raid {
+            boss_name: "Lv100 ジ・オーダー・グランデ".into(),
+            ..raid(tweet_id)
+        };
+
+        handler.push(raid(1));
+        handler.push(raid(2));
+        handler.push(other_boss(3));
+        handler.push(other_boss(4));
+
+        let boss = handler.boss(&"Lv60 オオゾラッコ".into()).unwrap();
+        let unknown_tweet = Id(NodeId::Tweet {
+            boss_name: Cow::Owned("Lv60 オオゾラッコ".into()),
+            id: 100,
+        }
+        .to_string());
+
+        let ids = vec![
+            raid_node_id(&raid(2)),
+            Id(boss.node_id().to_string()),
+            raid_node_id(&other_boss(3)),
+            unknown_tweet,
+            raid_node_id(&raid(1)),
+            raid_node_id(&other_boss(4)),
+        ];
+
+        let describe = |node: &Option<Node>| match node {
+            Some(Node::Boss(entry)) => Some(entry.boss().name.canonical().unwrap().to_string()),
+            Some(Node::Tweet(tweet)) => Some(tweet.tweet_id.to_string()),
+            None => None,
+        };
+
+        let nodes = get_nodes(&handler, &ids)
+            .unwrap()
+            .iter()
+            .map(describe)
+            .collect::<Vec<_>>();
+
+        let expected = vec![
+            Some("2".to_owned()),
+            Some("Lv60 オオゾラッコ".to_owned()),
+            Some("3".to_owned()),
+            None,
+            Some("1".to_owned()),
+            Some("4".to_owned()),
+        ];
+
+        assert_eq!(nodes, expected);
+
+        // A single malformed ID fails the whole request
+        let mut with_invalid = ids;
+        with_invalid.push(Id("invalid".to_owned()));
+        assert!(get_nodes(&handler, &with_invalid).is_err());
+    }
+
+    #[test]
+    fn malformed_node_ids() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(50),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        handler.push(raid(1));
+
+        // Not valid base58
+        assert!(get_node(&handler, "invalid").is_err());
+        // Valid base58, but not a valid node ID
+        assert!(get_node(&handler, "1").is_err());
+
+        // Valid, but unknown boss
+        let unknown_boss = NodeId::Boss(Cow::Owned("Lvl 100 Unknown".into())).to_string();
+        assert!(get_node(&handler, &unknown_boss).unwrap().is_none());
+
+        // Valid and existing
+        let boss = handler.boss(&"Lv60 オオゾラッコ".into()).unwrap();
+        let existing = boss.node_id().to_string();
+        assert!(matches!(
+            get_node(&handler, &existing),
+            Ok(Some(Node::Boss(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn min_level_subscriptions() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(50),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+
+        let high_level = |tweet_id| Raid {
+            boss_name: "Lv150 プロトバハムート".into(),
+            ..raid(tweet_id)
+        };
+
+        let mut boss_tweets =
+            filter_min_level(handler.subscribe("Lv60 オオゾラッコ".into()), Some(100));
+        let all_raids = filter_min_level(handler.subscribe_all(), Some(100));
+
+        handler.push(raid(1));
+        handler.push(high_level(2));
+        handler.push(raid(3));
+        handler.push(high_level(4));
+
+        let ids = all_raids
+            .take(2)
+            .map(|raid| raid.tweet_id)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(ids, vec![2, 4]);
+
+        // Every tweet for this boss is below the minimum level
+        let timeout = std::time::Duration::from_millis(50);
+        assert!(tokio::time::timeout(timeout, boss_tweets.next())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn tweets_lagged() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(50),
+            1,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        handler.push(raid(1));
+
+        let mut tweets = filter_min_level(
+            handler.subscribe_updates("Lv60 オオゾラッコ".into()),
+            Some(100),
+        );
+        (2..=4).for_each(|id| handler.push(raid(id)));
+
+        // Lag notices are always sent, regardless of `minLevel`
+        assert_eq!(tweets.next().await, Some(RaidUpdate::Lagged(2)));
+    }
+
+    #[test]
+    fn tweet_boss() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(50),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+
+        let english = Raid {
+            boss_name: "Lvl 60 Ozorotter".into(),
+            language: Language::English,
+            region: Region::En,
+            ..raid(2)
+        };
+        handler.push(raid(1));
+        handler.push(english.clone());
+
+        // Navigate from the tweet node to its boss
+        let tweet = match get_node(&handler, &raid_node_id(&english).0) {
+            Ok(Some(Node::Tweet(tweet))) => tweet,
+            _ => panic!("missing tweet"),
+        };
+        let boss = handler.boss(&tweet.boss_name).unwrap();
+        assert_eq!(boss.boss().name.en, Some("Lvl 60 Ozorotter".into()));
+        assert_eq!(boss.boss().name.ja, None);
+
+        // After the Japanese and English bosses are merged, the tweet resolves to the merged boss
+        handler.update_image_hash(&"Lv60 オオゾラッコ".into(), ImageHash::from(123));
+        handler.update_image_hash(&"Lvl 60 Ozorotter".into(), ImageHash::from(123));
+
+        let boss = handler.boss(&tweet.boss_name).unwrap();
+        assert_eq!(boss.boss().name.ja, Some("Lv60 オオゾラッコ".into()));
+        assert_eq!(boss.boss().name.en, Some("Lvl 60 Ozorotter".into()));
+    }
+
+    #[test]
+    fn stable_tweet_cursors() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(50),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        let boss_name = BossName::from("Lv60 オオゾラッコ");
+        let page_ids = |page: &BossTweetsConnection| {
+            page.tweets.iter().map(|t| t.tweet_id).collect::<Vec<_>>()
+        };
+
+        // Tweet 5 arrives late, but is still ordered by tweet ID
+        (1..=4)
+            .chain(6..=8)
+            .chain(5..=5)
+            .for_each(|id| handler.push(raid(id)));
+
+        let entry = handler.boss(&boss_name).unwrap();
+        let first_page = tweets_page(&entry, Some(4), None, None, None).unwrap();
+        assert_eq!(page_ids(&first_page), vec![8, 7, 6, 5]);
+
+        // A new tweet arrives between reads, but the cursor still points to the same position
+        handler.push(raid(9));
+
+        let cursor = TweetCursor::from_edge(first_page.tweets.last().unwrap());
+        let entry = handler.boss(&boss_name).unwrap();
+        let second_page = tweets_page(&entry, Some(4), Some(cursor), None, None).unwrap();
+        assert_eq!(page_ids(&second_page), vec![4, 3, 2, 1]);
+        assert!(second_page.page_info.has_previous_page);
+        assert!(!second_page.page_info.has_next_page);
+    }
+
+    #[test]
+    fn translated() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(50),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        let query = "{ bosses(first: 10) { nodes { name { canonical } translated } } }";
+
+        handler.push(raid(1));
+        handler.push(Raid {
+            boss_name: "Lvl 60 Ozorotter".into(),
+            language: Language::English,
+            region: Region::En,
+            ..raid(2)
+        });
+
+        assert_eq!(
+            execute(&handler, query),
+            serde_json::json!({
+                "data": {
+                    "bosses": {
+                        "nodes": [
+                            { "name": { "canonical": "Lv60 オオゾラッコ" }, "translated": false },
+                            { "name": { "canonical": "Lvl 60 Ozorotter" }, "translated": false },
+                        ]
+                    }
+                }
+            })
+        );
+
+        handler.update_image_hash(&"Lv60 オオゾラッコ".into(), ImageHash::from(123));
+        handler.update_image_hash(&"Lvl 60 Ozorotter".into(), ImageHash::from(123));
+
+        assert_eq!(
+            execute(&handler, query),
+            serde_json::json!({
+                "data": {
+                    "bosses": {
+                        "nodes": [
+                            { "name": { "canonical": "Lv60 オオゾラッコ" }, "translated": true },
+                        ]
+                    }
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn canonical_language() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(50),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::English,
+        );
+        let query = "{ bosses(first: 10) { nodes { name { canonical ja en } } } }";
+
+        handler.push(raid(1));
+        handler.push(Raid {
+            boss_name: "Lvl 60 Ozorotter".into(),
+            language: Language::English,
+            region: Region::En,
+            ..raid(2)
+        });
+        handler.update_image_hash(&"Lv60 オオゾラッコ".into(), ImageHash::from(123));
+        handler.update_image_hash(&"Lvl 60 Ozorotter".into(), ImageHash::from(123));
+
+        assert_eq!(
+            execute(&handler, query),
+            serde_json::json!({
+                "data": {
+                    "bosses": {
+                        "nodes": [{
+                            "name": {
+                                "canonical": "Lvl 60 Ozorotter",
+                                "ja": "Lv60 オオゾラッコ",
+                                "en": "Lvl 60 Ozorotter",
+                            }
+                        }]
+                    }
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn bosses_order_by() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(50),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+
+        // Bosses are pushed in order of last seen
+        let start = chrono::Utc::now();
+        let mut tweet_id = 0;
+        for (boss_name, count) in &[
+            ("Lv60 オオゾラッコ", 1),
+            ("Lv100 アテナ", 3),
+            ("Lv75 スーペルヒガンテ", 2),
+        ] {
+            for _ in 0..*count {
+                tweet_id += 1;
+                handler.push(Raid {
+                    boss_name: (*boss_name).into(),
+                    created_at: (start + chrono::Duration::seconds(tweet_id as i64)).into(),
+                    ..raid(tweet_id)
+                });
+            }
+        }
+
+        let page = |order_by: &str, first: i32, after: Option<&str>| {
+            let after = after.map_or(String::new(), |a| format!(", after: \"{}\"", a));
+            let query = format!(
+                "{{ bosses(first: {}{}, orderBy: {}) {{ nodes {{ level }} pageInfo {{ endCursor }} }} }}",
+                first, after, order_by
+            );
+            let response = execute(&handler, &query);
+            let bosses = &response["data"]["bosses"];
+            let levels = bosses["nodes"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|node| node["level"].as_i64().unwrap())
+                .collect::<Vec<_>>();
+            let end_cursor = bosses["pageInfo"]["endCursor"].as_str().map(str::to_owned);
+            (levels, end_cursor)
+        };
+
+        let cases = vec![
+            ("{ field: LEVEL }", vec![60, 75, 100]),
+            ("{ field: LEVEL, direction: DESC }", vec![100, 75, 60]),
+            ("{ field: NAME }", vec![100, 60, 75]),
+            ("{ field: NAME, direction: DESC }", vec![75, 60, 100]),
+            ("{ field: LAST_SEEN }", vec![60, 100, 75]),
+            ("{ field: LAST_SEEN, direction: DESC }", vec![75, 100, 60]),
+            ("{ field: TWEET_COUNT }", vec![60, 75, 100]),
+            ("{ field: TWEET_COUNT, direction: DESC }", vec![100, 75, 60]),
+        ];
+
+        for (order_by, expected) in cases {
+            assert_eq!(page(order_by, 10, None).0, expected, "{}", order_by);
+
+            // Paginating with the same ordering returns the remaining bosses
+            let (first_page, cursor) = page(order_by, 2, None);
+            let (second_page, _) = page(order_by, 2, cursor.as_deref());
+            assert_eq!(first_page, expected[..2].to_vec(), "{}", order_by);
+            assert_eq!(second_page, expected[2..].to_vec(), "{}", order_by);
+        }
+    }
+
+    #[test]
+    fn stream_stats() {
+        use chrono::offset::TimeZone;
+
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(50),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+
+        let query = "{ streamStats { language tweetCount lastSeenAt } }";
+        assert_eq!(
+            execute(&handler, query),
+            serde_json::json!({
+                "data": {
+                    "streamStats": [
+                        { "language": "JAPANESE", "tweetCount": 0, "lastSeenAt": null },
+                        { "language": "ENGLISH", "tweetCount": 0, "lastSeenAt": null },
+                    ]
+                }
+            })
+        );
+
+        for tweet_id in 1..=3 {
+            handler.push(Raid {
+                created_at: chrono::Utc
+                    .timestamp(1_590_000_000 + tweet_id as i64, 0)
+                    .into(),
+                ..raid(tweet_id)
+            });
+        }
+
+        assert_eq!(
+            execute(&handler, query),
+            serde_json::json!({
+                "data": {
+                    "streamStats": [
+                        {
+                            "language": "JAPANESE",
+                            "tweetCount": 3,
+                            "lastSeenAt": "2020-05-20T18:40:03.000Z"
+                        },
+                        { "language": "ENGLISH", "tweetCount": 0, "lastSeenAt": null },
+                    ]
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn boss_element() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(50),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        handler.push(raid(1));
+        handler.push(Raid {
+            boss_name: "Lvl 100 Unknown".into(),
+            ..raid(2)
+        });
+
+        let query = r#"{
+            known: boss(name: "Lv60 オオゾラッコ") { element }
+            unknown: boss(name: "Lvl 100 Unknown") { element }
+        }"#;
+        assert_eq!(
+            execute(&handler, query),
+            serde_json::json!({
+                "data": {
+                    "known": { "element": "WATER" },
+                    "unknown": { "element": null }
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn raid_created_at() {
+        use chrono::TimeZone;
+
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(50),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        handler.push(Raid {
+            created_at: chrono::Utc.ymd(2020, 7, 1).and_hms(12, 34, 56).into(),
+            ..raid(1)
+        });
+
+        let query = r#"{
+            recentRaids(first: 1) {
+                utc: createdAt
+                jst: createdAt(utcOffsetMinutes: 540)
+                pst: createdAt(utcOffsetMinutes: -480)
+            }
+        }"#;
+        assert_eq!(
+            execute(&handler, query),
+            serde_json::json!({
+                "data": {
+                    "recentRaids": [{
+                        "utc": "2020-07-01T12:34:56Z",
+                        "jst": "2020-07-01T21:34:56+09:00",
+                        "pst": "2020-07-01T04:34:56-08:00"
+                    }]
+                }
+            })
+        );
+
+        let query = "{ recentRaids(first: 1) { createdAt(utcOffsetMinutes: 1440) } }";
+        assert!(execute(&handler, query)["errors"].is_array());
+    }
+
+    #[test]
+    fn raid_age_seconds() {
+        let now = chrono::Utc::now();
+        let raid_at = |seconds_ago| Raid {
+            created_at: (now - chrono::Duration::seconds(seconds_ago)).into(),
+            ..raid(1)
+        };
+
+        assert_eq!(super::raid_age_seconds(&raid_at(30), &now), 30);
+        assert_eq!(super::raid_age_seconds(&raid_at(0), &now), 0);
+        assert_eq!(super::raid_age_seconds(&raid_at(-5), &now), 0);
+        assert_eq!(
+            super::raid_age_seconds(&raid_at(i64::from(i32::MAX) + 1), &now),
+            i32::MAX
+        );
+
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(50),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        handler.push(raid_at(30));
+
+        let response = execute(&handler, "{ recentRaids(first: 1) { ageSeconds } }");
+        let age = response["data"]["recentRaids"][0]["ageSeconds"]
+            .as_i64()
+            .unwrap();
+        assert!((30..35).contains(&age), "unexpected age {}", age);
+    }
+
+    #[test]
+    fn recent_raids_page_size() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(50),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        handler.push(raid(1));
+        handler.push(raid(2));
+
+        // Large page sizes are capped, rather than allocated up front
+        let response = execute(&handler, "{ recentRaids(first: 2147483647) { tweetId } }");
+        let raids = response["data"]["recentRaids"].as_array().unwrap();
+        assert_eq!(raids.len(), 2);
+
+        let response = execute(&handler, "{ recentRaids(first: -1) { tweetId } }");
+        assert!(response["errors"].is_array());
+    }
+
+    #[test]
+    fn merge_info() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(50),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        handler.push(raid(1));
+        handler.push(Raid {
+            boss_name: "Lvl 60 Ozorotter".into(),
+            language: Language::English,
+            region: Region::En,
+            ..raid(2)
+        });
+
+        let query = r#"{
+            boss(name: "Lv60 オオゾラッコ") { mergeInfo { source imageHash } }
+        }"#;
+        assert_eq!(
+            execute(&handler, query),
+            serde_json::json!({
+                "data": { "boss": { "mergeInfo": { "source": "NONE", "imageHash": null } } }
+            })
+        );
+
+        handler.update_image_hash(&"Lv60 オオゾラッコ".into(), ImageHash::from(123));
+        handler.update_image_hash(&"Lvl 60 Ozorotter".into(), ImageHash::from(123));
+
+        assert_eq!(
+            execute(&handler, query),
+            serde_json::json!({
+                "data": {
+                    "boss": { "mergeInfo": { "source": "IMAGE_HASH", "imageHash": "123" } }
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn trending_bosses() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(50),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+
+        let old = chrono::Utc::now() - chrono::Duration::hours(2);
+        handler.push(Raid {
+            boss_name: "Lv100 ジ・オーダー・グランデ".into(),
+            created_at: old.into(),
+            ..raid(1)
+        });
+        handler.push(raid(2));
+        handler.push(raid(3));
+
+        let query = "{ trendingBosses(window: 3600) { boss { level } tweetCount } }";
+        assert_eq!(
+            execute(&handler, query),
+            serde_json::json!({
+                "data": { "trendingBosses": [{ "boss": { "level": 60 }, "tweetCount": 2 }] }
+            })
+        );
+    }
+
+    // Catches accidental changes to the schema. If the change is intentional, update the
+    // snapshot with `UPDATE_SNAPSHOTS=1 cargo test`.
+    #[test]
+    fn schema_snapshot() {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/graphql/testdata/schema.graphql"
+        );
+        let sdl = crate::graphql::schema().as_schema_language();
+
+        let snapshot = match std::fs::read_to_string(path) {
+            Ok(snapshot) if std::env::var_os("UPDATE_SNAPSHOTS").is_none() => snapshot,
+            // New snapshots are written rather than compared
+            _ => {
+                std::fs::write(path, &sdl).expect("failed to write schema snapshot");
+                return;
+            }
+        };
+
+        assert!(
+            sdl == snapshot,
+            "GraphQL schema doesn't match {}. If the change is intentional, rerun with \
+             `UPDATE_SNAPSHOTS=1`.\n\n{}",
+            path,
+            sdl
+        );
+    }
+}
@@ -2,15 +2,15 @@ pub(crate) mod phash;
 mod stream;
 mod updater;
 
-use crate::error::Result;
-pub use crate::image_hash::phash::ImageHash;
+use crate::client::HttpClient;
+use crate::error::{Error, Result};
+pub use crate::image_hash::phash::{HashSize, ImageHash};
 pub use crate::image_hash::updater::Updater;
+use crate::model::ImageSize;
 
 use async_trait::async_trait;
 use http::Uri;
 
-type HttpsClient = hyper::Client<hyper_tls::HttpsConnector<hyper::client::HttpConnector>>;
-
 #[async_trait]
 pub trait ImageHasher {
     async fn hash(&self, uri: Uri) -> Result<ImageHash>;
@@ -18,34 +18,92 @@ pub trait ImageHasher {
 
 #[derive(Clone, Debug)]
 pub struct HyperImageHasher {
-    client: HttpsClient,
+    client: HttpClient,
+    size: HashSize,
 }
 
 impl HyperImageHasher {
-    pub fn new(client: HttpsClient) -> Self {
-        Self { client }
+    pub fn new(client: HttpClient, size: HashSize) -> Self {
+        Self { client, size }
     }
 }
 
 #[async_trait]
 impl ImageHasher for HyperImageHasher {
     async fn hash(&self, uri: Uri) -> Result<ImageHash> {
-        let resp = self.client.get(uri).await?;
-        let body = hyper::body::to_bytes(resp).await?;
-        Ok(crop_and_hash(&body)?)
+        // Always hash the same size variant, so hashes are comparable
+        let uri = ImageSize::Large.apply(&uri.to_string()).parse::<Uri>()?;
+
+        let result = async {
+            let resp = self.client.get(uri.clone()).await?;
+            let status = resp.status();
+            if !status.is_success() {
+                return Err(Error::Http(status));
+            }
+
+            let body = hyper::body::to_bytes(resp).await?;
+            crop_and_hash(&body, self.size)
+        };
+
+        result.await.map_err(|e| e.with_uri(&uri))
+    }
+}
+
+// Only static JPEG/PNG images are hashed. Other formats (e.g., animated GIFs or WebP
+// promo images) would only have their first frame decoded, producing a meaningless hash.
+fn check_format(bytes: &[u8]) -> Result<image::ImageFormat> {
+    use image::ImageFormat;
+
+    match image::guess_format(bytes)? {
+        ImageFormat::Jpeg => Ok(ImageFormat::Jpeg),
+        ImageFormat::Png if is_animated_png(bytes) => {
+            Err(Error::UnsupportedImageFormat("animated PNG".to_owned()))
+        }
+        ImageFormat::Png => Ok(ImageFormat::Png),
+        other => Err(Error::UnsupportedImageFormat(format!("{:?}", other))),
+    }
+}
+
+// An APNG is a PNG with an `acTL` chunk before the first `IDAT` chunk
+fn is_animated_png(bytes: &[u8]) -> bool {
+    const SIGNATURE_LEN: usize = 8;
+
+    let mut offset = SIGNATURE_LEN;
+    while offset + 8 <= bytes.len() {
+        let len = u32::from_be_bytes([
+            bytes[offset],
+            bytes[offset + 1],
+            bytes[offset + 2],
+            bytes[offset + 3],
+        ]) as usize;
+
+        match &bytes[offset + 4..offset + 8] {
+            b"acTL" => return true,
+            b"IDAT" => return false,
+            _ => {}
+        }
+
+        // Chunk length + type + data + CRC
+        offset = match offset.checked_add(12 + len) {
+            Some(next) => next,
+            None => return false,
+        };
     }
+
+    false
 }
 
 // Specifically for raid boss images. Remove the lower 25% of the image
 // to get the boss image without the language-specific boss name.
-fn crop_and_hash(bytes: &[u8]) -> Result<ImageHash> {
+fn crop_and_hash(bytes: &[u8], size: HashSize) -> Result<ImageHash> {
     use image::GenericImageView;
 
-    let mut img = image::load_from_memory(bytes)?;
+    let format = check_format(bytes)?;
+    let mut img = image::load_from_memory_with_format(bytes, format)?;
     let (w, h) = img.dimensions();
     img = img.crop(0, 0, w, h * 3 / 4);
 
-    Ok(ImageHash::new(&img))
+    Ok(ImageHash::new(&img, size))
 }
 
 #[cfg(test)]
@@ -54,29 +112,100 @@ mod test {
     use crate::model::Language;
     use std::collections::HashMap;
 
+    // Two-frame 1x1 animated GIF
+    #[rustfmt::skip]
+    const ANIMATED_GIF: &[u8] = &[
+        0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0xff, 0xff, 0xff,
+        0x21, 0xf9, 0x04, 0x00, 0x0a, 0x00, 0x00, 0x00,
+        0x2c, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00,
+        0x21, 0xf9, 0x04, 0x00, 0x0a, 0x00, 0x00, 0x00,
+        0x2c, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x4c, 0x01, 0x00,
+        0x3b,
+    ];
+
+    #[test]
+    fn unsupported_formats() {
+        match crop_and_hash(ANIMATED_GIF, HashSize::default()) {
+            Err(Error::UnsupportedImageFormat(format)) => assert_eq!(format, "Gif"),
+            other => panic!("expected unsupported format error, got {:?}", other),
+        }
+
+        // PNG signature followed by an `acTL` chunk
+        let mut apng = b"\x89PNG\r\n\x1a\n".to_vec();
+        apng.extend_from_slice(&[0, 0, 0, 8]);
+        apng.extend_from_slice(b"acTL");
+        apng.extend_from_slice(&[0, 0, 0, 2, 0, 0, 0, 0]);
+        apng.extend_from_slice(&[0, 0, 0, 0]);
+        match crop_and_hash(&apng, HashSize::default()) {
+            Err(Error::UnsupportedImageFormat(format)) => assert_eq!(format, "animated PNG"),
+            other => panic!("expected unsupported format error, got {:?}", other),
+        }
+
+        let mut png = Vec::new();
+        image::DynamicImage::new_rgb8(4, 4)
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        assert!(crop_and_hash(&png, HashSize::default()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn user_agent() {
+        use hyper::service::{make_service_fn, service_fn};
+        use std::convert::Infallible;
+        use std::sync::{Arc, Mutex};
+
+        // Mock image server that records the `User-Agent` header
+        let user_agents = Arc::new(Mutex::new(Vec::new()));
+        let make_service = make_service_fn({
+            let user_agents = user_agents.clone();
+            move |_| {
+                let user_agents = user_agents.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: hyper::Request<hyper::Body>| {
+                        let user_agent = req.headers().get(http::header::USER_AGENT).cloned();
+                        user_agents.lock().unwrap().push(user_agent);
+                        async {
+                            hyper::Response::builder()
+                                .status(http::StatusCode::NOT_FOUND)
+                                .body(hyper::Body::empty())
+                        }
+                    }))
+                }
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let client =
+            HttpClient::new(http::HeaderValue::from_static("test-agent/1.0"), None).unwrap();
+        let hasher = HyperImageHasher::new(client, HashSize::default());
+        let uri = format!("http://{}/image.jpg", addr).parse().unwrap();
+        let error = hasher.hash(uri).await.unwrap_err();
+        assert_eq!(error.status_code(), Some(http::StatusCode::NOT_FOUND));
+
+        assert_eq!(
+            *user_agents.lock().unwrap(),
+            vec![Some(http::HeaderValue::from_static("test-agent/1.0"))]
+        );
+    }
+
     struct Item {
         name: &'static str,
         level: i32,
         language: Language,
+        url: &'static str,
         hash: ImageHash,
     }
 
-    // This test is slow to run, and is enabled with `--features integration`.
-    // To see console output as results come in, run with `--nocapture`.
-    // i.e., `cargo test --features integration -- --nocapture`
-    #[cfg_attr(not(feature = "integration"), ignore)]
-    #[tokio::test]
-    async fn raid_equality() -> anyhow::Result<()> {
+    #[rustfmt::skip]
+    fn boss_images() -> Vec<(&'static str, i32, Language, &'static str)> {
         use crate::model::Language::{English as En, Japanese as Ja};
 
-        let conn = hyper_tls::HttpsConnector::new();
-        let client = hyper::Client::builder().build::<_, hyper::Body>(conn);
-        let hasher = HyperImageHasher::new(client);
-
         // Copied from gbf-raidfinder tests:
         // https://github.com/walfie/gbf-raidfinder/blob/master/server/src/it/scala/com/pastebin/Pj9d8jt5/ImagePHashSpec.scala
-        #[rustfmt::skip]
-        let bosses = vec![
+        vec![
             // Japanese bosses
             ("Lv30 アーフラー", 30, Ja, "https://pbs.twimg.com/media/CeSO4quUYAAy8U1.jpg"),
             ("Lv40 アーフラー", 40, Ja, "https://pbs.twimg.com/media/CeSO6aAWAAAAOGe.jpg"),
@@ -188,45 +317,12 @@ mod test {
             ("Lvl 120 Nezha", 120, En, "https://pbs.twimg.com/media/CfqW4BYUEAAeYSR.jpg"),
             ("Lvl 120 Twin Elements", 120, En, "https://pbs.twimg.com/media/CfqZQ_pUEAAQFI4.jpg"),
             ("Lvl 150 Proto Bahamut", 150, En, "https://pbs.twimg.com/media/CfqZ-YtVAAAt5qd.jpg"),
-        ];
-
-        let futures = bosses.iter().map(move |(name, level, language, url)| {
-            let hasher = hasher.clone();
-            async move {
-                let uri = format!("{}:large", url).parse().unwrap();
-                let hash = hasher.hash(uri).await?;
-                eprintln!("{} -> {:?}", name, hash);
-                let result: anyhow::Result<Item> = Ok(Item {
-                    name,
-                    level: *level,
-                    language: *language,
-                    hash,
-                });
-                result
-            }
-        });
-
-        use futures::stream::StreamExt;
-        use futures::stream::TryStreamExt;
-        let results: Vec<Item> = futures::stream::iter(futures)
-            .buffer_unordered(bosses.len())
-            .try_collect()
-            .await?;
-
-        use itertools::Itertools;
-        let equivalent_bosses = results
-            .iter()
-            .tuple_combinations()
-            .filter_map(|(a, b)| {
-                if a.language != b.language && a.level == b.level && a.hash == b.hash {
-                    Some((a.name, b.name))
-                } else {
-                    None
-                }
-            })
-            .collect::<HashMap<&str, &str>>();
+        ]
+    }
 
-        let expected = maplit::hashmap! {
+    // Japanese and English bosses that should have matching image hashes
+    fn expected_matches() -> HashMap<&'static str, &'static str> {
+        maplit::hashmap! {
             "Lv30 アーフラー" => "Lvl 30 Ahura",
             "Lv40 アーフラー" => "Lvl 40 Ahura",
             "Lv50 ベオウルフ" => "Lvl 50 Grendel",
@@ -276,9 +372,92 @@ mod test {
             "Lv120 フラム＝グラス" => "Lvl 120 Twin Elements",
             "Lv120 マキュラ・マリウス" => "Lvl 120 Macula Marius",
             "Lv150 プロトバハムート" => "Lvl 150 Proto Bahamut",
-        };
+        }
+    }
+
+    async fn hash_boss_images(size: HashSize) -> anyhow::Result<Vec<Item>> {
+        let hasher = HyperImageHasher::new(HttpClient::default(), size);
+        let bosses = boss_images();
+
+        let futures = bosses.iter().map(move |&(name, level, language, url)| {
+            let hasher = hasher.clone();
+            async move {
+                let uri = url.parse().unwrap();
+                let hash = hasher.hash(uri).await?;
+                eprintln!("{} -> {}", name, hash);
+                let result: anyhow::Result<Item> = Ok(Item {
+                    name,
+                    level,
+                    language,
+                    url,
+                    hash,
+                });
+                result
+            }
+        });
+
+        use futures::stream::StreamExt;
+        use futures::stream::TryStreamExt;
+        futures::stream::iter(futures)
+            .buffer_unordered(bosses.len())
+            .try_collect()
+            .await
+    }
+
+    // These tests are slow to run, and are enabled with `--features integration`.
+    // To see console output as results come in, run with `--nocapture`.
+    // i.e., `cargo test --features integration -- --nocapture`
+    #[cfg_attr(not(feature = "integration"), ignore)]
+    #[tokio::test]
+    async fn raid_equality() -> anyhow::Result<()> {
+        let results = hash_boss_images(HashSize::default()).await?;
+
+        use itertools::Itertools;
+        let equivalent_bosses = results
+            .iter()
+            .tuple_combinations()
+            .filter_map(|(a, b)| {
+                if a.language != b.language && a.level == b.level && a.hash == b.hash {
+                    Some((a.name, b.name))
+                } else {
+                    None
+                }
+            })
+            .collect::<HashMap<&str, &str>>();
+
+        assert_eq!(equivalent_bosses, expected_matches());
+
+        Ok(())
+    }
+
+    #[cfg_attr(not(feature = "integration"), ignore)]
+    #[tokio::test]
+    async fn hash_size_collisions() -> anyhow::Result<()> {
+        // Pairs of different images with the same hash, that aren't known translations
+        async fn collisions(size: HashSize) -> anyhow::Result<usize> {
+            use itertools::Itertools;
+
+            let expected = expected_matches();
+            let results = hash_boss_images(size).await?;
+            let count = results
+                .iter()
+                .tuple_combinations()
+                .filter(|(a, b)| {
+                    a.hash == b.hash
+                        && a.url != b.url
+                        && expected.get(a.name) != Some(&b.name)
+                        && expected.get(b.name) != Some(&a.name)
+                })
+                .count();
+
+            Ok(count)
+        }
+
+        let small = collisions(HashSize::default()).await?;
+        let large = collisions(HashSize::new(64, 11)?).await?;
+        eprintln!("collisions: 64-bit -> {}, 121-bit -> {}", small, large);
 
-        assert_eq!(equivalent_bosses, expected);
+        assert!(large <= small);
 
         Ok(())
     }
@@ -1,26 +1,154 @@
+use crate::error::{Error, Result};
 use image::imageops::FilterType;
 use image::DynamicImage;
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
 
-const SIZE: usize = 32;
-const SMALL_SIZE: usize = 8;
+/// Dimensions used when computing an image hash. Images are resized to `size`x`size`, and the
+/// hash has one bit for each of the lowest `small_size`x`small_size` DCT frequencies.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HashSize {
+    size: u32,
+    small_size: u32,
+}
+
+impl HashSize {
+    /// Hashes are stored in a `u128`
+    pub const MAX_BITS: u32 = 128;
+
+    /// Images are resized to `size`x`size` and transformed in memory, so larger sizes are
+    /// rejected rather than allocating without limit
+    pub const MAX_SIZE: u32 = 256;
+
+    pub fn new(size: u32, small_size: u32) -> Result<Self> {
+        let bits = small_size.checked_mul(small_size);
+        if small_size < 2
+            || small_size > size
+            || size > Self::MAX_SIZE
+            || bits.map_or(true, |b| b > Self::MAX_BITS)
+        {
+            return Err(Error::ImageHashSize { size, small_size });
+        }
+
+        Ok(Self { size, small_size })
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn small_size(&self) -> u32 {
+        self.small_size
+    }
+
+    /// Width of the resulting hash, in bits
+    pub fn bits(&self) -> u8 {
+        (self.small_size * self.small_size) as u8
+    }
+}
+
+impl Default for HashSize {
+    fn default() -> Self {
+        Self {
+            size: 32,
+            small_size: 8,
+        }
+    }
+}
 
+/// A perceptual hash of an image. Hashes of different widths are never equal.
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
-pub struct ImageHash(pub(crate) i64);
+#[serde(into = "SerializedImageHash", try_from = "SerializedImageHash")]
+pub struct ImageHash {
+    bits: u128,
+    width: u8,
+}
 
 impl ImageHash {
-    pub fn new(img: &DynamicImage) -> Self {
-        ImageHash(get_hash(img))
+    pub fn new(img: &DynamicImage, size: HashSize) -> Self {
+        ImageHash {
+            bits: get_hash(img, size),
+            width: size.bits(),
+        }
+    }
+
+    /// Width of the hash, in bits
+    pub fn width(&self) -> u8 {
+        self.width
     }
 
-    pub fn as_i64(&self) -> i64 {
-        self.0
+    /// The number of bits that differ between the two hashes, or `None` if they have different
+    /// widths and can't be compared
+    pub fn distance(&self, other: &ImageHash) -> Option<u32> {
+        if self.width == other.width {
+            Some((self.bits ^ other.bits).count_ones())
+        } else {
+            None
+        }
     }
 }
 
+// A 64-bit hash, the default size
 impl From<i64> for ImageHash {
     fn from(value: i64) -> ImageHash {
-        ImageHash(value)
+        ImageHash {
+            bits: value as u64 as u128,
+            width: 64,
+        }
+    }
+}
+
+// 64-bit hashes are shown as signed integers, as they were before the size was configurable.
+// Other sizes are shown in hexadecimal.
+impl fmt::Display for ImageHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.width == 64 {
+            write!(f, "{}", self.bits as u64 as i64)
+        } else {
+            let digits = (self.width as usize + 3) / 4;
+            write!(f, "{:0digits$x}", self.bits, digits = digits)
+        }
+    }
+}
+
+// 64-bit hashes are serialized as integers, so previously persisted hashes can still be loaded
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum SerializedImageHash {
+    Integer(i64),
+    Sized { width: u8, hex: String },
+}
+
+impl From<ImageHash> for SerializedImageHash {
+    fn from(hash: ImageHash) -> Self {
+        if hash.width == 64 {
+            Self::Integer(hash.bits as u64 as i64)
+        } else {
+            Self::Sized {
+                width: hash.width,
+                hex: format!("{:x}", hash.bits),
+            }
+        }
+    }
+}
+
+impl TryFrom<SerializedImageHash> for ImageHash {
+    type Error = String;
+
+    fn try_from(value: SerializedImageHash) -> std::result::Result<Self, Self::Error> {
+        match value {
+            SerializedImageHash::Integer(value) => Ok(ImageHash::from(value)),
+            SerializedImageHash::Sized { width, hex } => {
+                let bits = u128::from_str_radix(&hex, 16).map_err(|e| e.to_string())?;
+                let fits = width >= 128 || bits >> width == 0;
+                if width as u32 > HashSize::MAX_BITS || !fits {
+                    return Err(format!("invalid {}-bit image hash `{}`", width, hex));
+                }
+
+                Ok(ImageHash { bits, width })
+            }
+        }
     }
 }
 
@@ -29,28 +157,26 @@ impl From<i64> for ImageHash {
 //
 // ...which was adapted from a Java implementation:
 // http://pastebin.com/Pj9d8jt5
-fn get_hash(img: &DynamicImage) -> i64 {
+fn get_hash(img: &DynamicImage, size: HashSize) -> u128 {
+    let small_size = size.small_size as usize;
+    let size = size.size as usize;
+
     let gray = img
-        .resize_exact(SIZE as u32, SIZE as u32, FilterType::Nearest)
+        .resize_exact(size as u32, size as u32, FilterType::Nearest)
         .to_luma();
 
-    let mut vals = [[0.0; SIZE]; SIZE];
+    let mut vals = vec![vec![0.0; size]; size];
     for (x, y, p) in gray.enumerate_pixels() {
         vals[x as usize][y as usize] = p.0[0] as f64;
     }
 
-    let dct_vals = apply_dct(&vals);
+    let dct_vals = apply_dct(&vals, small_size);
 
-    let dct_slice = dct_vals
-        .iter()
-        .take(SMALL_SIZE)
-        .flat_map(|arr| &arr[0..SMALL_SIZE])
-        .cloned()
-        .collect::<Vec<f64>>();
+    let dct_slice = dct_vals.into_iter().flatten().collect::<Vec<f64>>();
 
     let total: f64 = dct_slice.iter().skip(1).sum();
 
-    let average = total / (SMALL_SIZE * SMALL_SIZE - 1) as f64;
+    let average = total / (small_size * small_size - 1) as f64;
 
     let hash = dct_slice
         .into_iter()
@@ -70,18 +196,20 @@ fn get_hash(img: &DynamicImage) -> i64 {
     hash
 }
 
-fn apply_dct(f: &[[f64; SIZE]; SIZE]) -> [[f64; SIZE]; SIZE] {
+// Only the lowest `small_size`x`small_size` frequencies are used, so the rest aren't computed
+fn apply_dct(f: &[Vec<f64>], small_size: usize) -> Vec<Vec<f64>> {
     use std::f64::consts::{FRAC_1_SQRT_2, PI};
 
-    let mut out = [[0.0; SIZE]; SIZE];
+    let size = f.len();
+    let mut out = vec![vec![0.0; small_size]; small_size];
 
     for (u, out_arr) in out.iter_mut().enumerate() {
         for (v, out_val) in out_arr.iter_mut().enumerate() {
             for (i, arr) in f.iter().enumerate() {
                 for (j, val) in arr.iter().enumerate() {
                     *out_val += val
-                        * (PI * u as f64 * (2 * i + 1) as f64 / (2.0 * SIZE as f64)).cos()
-                        * (PI * v as f64 * (2 * j + 1) as f64 / (2.0 * SIZE as f64)).cos();
+                        * (PI * u as f64 * (2 * i + 1) as f64 / (2.0 * size as f64)).cos()
+                        * (PI * v as f64 * (2 * j + 1) as f64 / (2.0 * size as f64)).cos();
                 }
             }
 
@@ -99,3 +227,64 @@ fn apply_dct(f: &[[f64; SIZE]; SIZE]) -> [[f64; SIZE]; SIZE] {
 
     out
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hash_size() {
+        assert_eq!(HashSize::default(), HashSize::new(32, 8).unwrap());
+        assert_eq!(HashSize::default().bits(), 64);
+        assert_eq!(HashSize::new(64, 11).unwrap().bits(), 121);
+
+        assert!(HashSize::new(32, 1).is_err());
+        assert!(HashSize::new(8, 16).is_err());
+        assert!(HashSize::new(64, 12).is_err());
+        assert!(HashSize::new(u32::MAX, u32::MAX).is_err());
+
+        assert!(HashSize::new(HashSize::MAX_SIZE, 8).is_ok());
+        assert!(HashSize::new(HashSize::MAX_SIZE + 1, 8).is_err());
+        assert!(HashSize::new(u32::MAX, 8).is_err());
+    }
+
+    #[test]
+    fn hash_widths() {
+        let img = image::DynamicImage::ImageLuma8(image::ImageBuffer::from_fn(64, 64, |x, y| {
+            image::Luma([((x * 7 + y * 13) % 256) as u8])
+        }));
+
+        let small = ImageHash::new(&img, HashSize::default());
+        let large = ImageHash::new(&img, HashSize::new(64, 11).unwrap());
+        assert_eq!(small.width(), 64);
+        assert_eq!(large.width(), 121);
+        assert_ne!(small, large);
+        assert_eq!(small.distance(&large), None);
+        assert_eq!(small.distance(&small), Some(0));
+
+        let other = ImageHash::from(small.bits as u64 as i64 ^ 0b110);
+        assert_eq!(small.distance(&other), Some(2));
+    }
+
+    #[test]
+    fn serialization() {
+        // 64-bit hashes are serialized as integers, for compatibility with persisted data
+        let hash = ImageHash::from(-1234);
+        assert_eq!(serde_json::to_string(&hash).unwrap(), "-1234");
+        assert_eq!(hash.to_string(), "-1234");
+
+        let hash = ImageHash {
+            bits: 0x1ff,
+            width: 121,
+        };
+        let json = serde_json::to_string(&hash).unwrap();
+        assert_eq!(json, r#"{"width":121,"hex":"1ff"}"#);
+        assert_eq!(serde_json::from_str::<ImageHash>(&json).unwrap(), hash);
+        assert_eq!(hash.to_string(), "000000000000000000000000000001ff");
+
+        for (&bits, expected) in [1u128, u128::MAX].iter().zip(&[true, false]) {
+            let json = format!(r#"{{"width":121,"hex":"{:x}"}}"#, bits);
+            assert_eq!(serde_json::from_str::<ImageHash>(&json).is_ok(), *expected);
+        }
+    }
+}
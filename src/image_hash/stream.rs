@@ -1,4 +1,8 @@
+use std::collections::HashMap;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::error::Result;
 use crate::image_hash::{ImageHash, ImageHasher};
@@ -9,6 +13,8 @@ use futures::future::Either;
 use futures::stream::{Stream, StreamExt};
 use futures::FutureExt;
 use http::Uri;
+use parking_lot::Mutex;
+use smallvec::{smallvec, SmallVec};
 use tokio::sync::mpsc;
 
 #[derive(Debug)]
@@ -17,48 +23,110 @@ pub struct BossImageHash {
     pub image_hash: Result<ImageHash>,
 }
 
+type Fallbacks = SmallVec<[Uri; 1]>;
+
 /// Inbox for requesting image hashes
 #[derive(Debug, Clone)]
-pub struct Inbox(mpsc::UnboundedSender<(BossName, Uri)>);
+pub struct Inbox(mpsc::UnboundedSender<(BossName, Uri, Fallbacks)>);
 impl Inbox {
     pub fn request_hash(&self, boss_name: BossName, uri: Uri) {
-        let _ = self.0.send((boss_name, uri));
+        let _ = self.0.send((boss_name, uri, Fallbacks::new()));
+    }
+
+    /// Request a hash for `uri`, trying each of `fallbacks` in order if it fails
+    pub fn request_hash_with_fallbacks(
+        &self,
+        boss_name: BossName,
+        uri: Uri,
+        fallbacks: impl IntoIterator<Item = Uri>,
+    ) {
+        let _ = self
+            .0
+            .send((boss_name, uri, fallbacks.into_iter().collect()));
     }
 
     pub fn request_hash_for_boss(&self, boss: &Boss) {
+        // Persisted hashes are trusted, so there's no need to download the image again
+        if boss.image_hash.is_some() {
+            return;
+        }
+
         for lang in Language::VALUES {
             if let (Some(name), Some(image)) = (boss.name.get(*lang), boss.image.get(*lang)) {
-                if let Ok(url) = image.parse() {
-                    let _ = self.0.send((name.clone(), url));
+                if let Ok(url) = image.as_url().parse() {
+                    let _ = self.0.send((name.clone(), url, Fallbacks::new()));
                 }
             }
         }
     }
+
+    /// Request hashes for each boss, spreading the requests out to at most `per_sec` bosses per
+    /// second. If `per_sec` is `None`, all requests are sent immediately.
+    pub async fn request_hashes_for_bosses(&self, bosses: &[Boss], per_sec: Option<u32>) {
+        let mut interval = per_sec.map(|n| {
+            let period = Duration::from_secs_f64(1.0 / f64::from(n.max(1)));
+            tokio::time::interval(period)
+        });
+
+        for boss in bosses {
+            if let Some(interval) = &mut interval {
+                interval.tick().await;
+            }
+
+            self.request_hash_for_boss(boss);
+        }
+    }
 }
 
-pub fn stream<H>(image_hasher: H, concurrency: usize) -> (Inbox, impl Stream<Item = BossImageHash>)
+/// Creates a stream of image hash results for requests sent to the returned `Inbox`.
+///
+/// `on_pending_change` is called with the number of in-progress requests whenever it changes, and
+/// `on_attempt` is called with the result and duration of each image that gets hashed (including
+/// fallbacks).
+pub fn stream<H, F, G>(
+    image_hasher: H,
+    concurrency: usize,
+    on_pending_change: F,
+    on_attempt: G,
+) -> (Inbox, impl Stream<Item = BossImageHash>)
 where
     H: ImageHasher + Send + Sync + 'static,
+    F: Fn(usize) + Send + Sync + 'static,
+    G: Fn(bool, Duration) + Send + Sync + 'static,
 {
-    let (tx_in, mut rx_in) = mpsc::unbounded_channel::<(BossName, Uri)>();
+    let (tx_in, mut rx_in) = mpsc::unbounded_channel::<(BossName, Uri, Fallbacks)>();
     let (tx_out, rx_out) = mpsc::unbounded_channel();
 
     let image_hasher = Arc::new(image_hasher);
 
     // Represents whether we're still waiting for an image hash
+    #[derive(Clone)]
     enum State {
         Pending,
         Success(ImageHash),
-        Failure,
+        Failure { retryable: bool },
+    }
+
+    // Different bosses can share an image URL (e.g., English and Japanese bosses that haven't
+    // been merged yet), so requests are also deduplicated by URL. Failures aren't cached here,
+    // since retries are handled per boss.
+    enum UriState {
+        // Other bosses waiting on the in-flight request for this URL
+        Pending { waiters: Vec<BossName> },
+        Success(ImageHash),
     }
 
     let worker = async move {
         // On success, store the completed value in `requested`,
         // so that future requests can avoid having to recompute the hash
         let requested = Arc::new(DashMap::<BossName, State>::new());
+        let requested_uris = Arc::new(Mutex::new(HashMap::<Uri, UriState>::new()));
         let image_hasher = image_hasher.clone();
+        let pending_count = Arc::new(AtomicUsize::new(0));
+        let on_pending_change = Arc::new(on_pending_change);
+        let on_attempt = Arc::new(on_attempt);
 
-        while let Some((boss_name, uri)) = rx_in.recv().await {
+        while let Some((boss_name, uri, fallbacks)) = rx_in.recv().await {
             let requested = requested.clone();
 
             if let Some(guard) = requested.get(&boss_name) {
@@ -67,9 +135,14 @@ where
                         // There's already a pending request for this boss, don't re-submit
                         continue;
                     }
-                    State::Failure => {
+                    State::Failure { retryable: true } => {
                         // The last attempt failed, so we can retry
                     }
+                    State::Failure { retryable: false } => {
+                        // The last attempt failed in a way that won't succeed on retry
+                        // (e.g., the image couldn't be decoded), so don't re-submit
+                        continue;
+                    }
                     State::Success(image_hash) => {
                         // Reuse the previous successful result, don't re-submit
                         let hash = BossImageHash {
@@ -77,7 +150,7 @@ where
                             image_hash: Ok(*image_hash),
                         };
 
-                        let future = futures::future::ready(hash);
+                        let future = futures::future::ready(smallvec![hash]);
                         if let Err(_) = tx_out.send(Either::Left(future)) {
                             break; // Listener dropped
                         }
@@ -87,23 +160,95 @@ where
                 }
             }
 
+            match requested_uris.lock().get_mut(&uri) {
+                Some(UriState::Pending { waiters }) => {
+                    // Another boss is already waiting on this URL, so share its result
+                    requested.insert(boss_name.clone(), State::Pending);
+                    waiters.push(boss_name);
+                    continue;
+                }
+                Some(UriState::Success(image_hash)) => {
+                    requested.insert(boss_name.clone(), State::Success(*image_hash));
+                    let hash = BossImageHash {
+                        boss_name,
+                        image_hash: Ok(*image_hash),
+                    };
+
+                    let future = futures::future::ready(smallvec![hash]);
+                    if let Err(_) = tx_out.send(Either::Left(future)) {
+                        break; // Listener dropped
+                    }
+
+                    continue;
+                }
+                None => {}
+            }
+
             requested.insert(boss_name.clone(), State::Pending);
+            requested_uris.lock().insert(
+                uri.clone(),
+                UriState::Pending {
+                    waiters: Vec::new(),
+                },
+            );
+            on_pending_change(pending_count.fetch_add(1, Relaxed) + 1);
 
             let image_hasher = image_hasher.clone();
+            let requested_uris = requested_uris.clone();
+            let pending_count = pending_count.clone();
+            let on_pending_change = on_pending_change.clone();
+            let on_attempt = on_attempt.clone();
             let future = async move {
-                let image_hash = image_hasher.hash(uri).await;
+                let mut image_hash = timed_hash(&*image_hasher, uri.clone(), &*on_attempt).await;
+                for uri in fallbacks {
+                    if image_hash.is_ok() {
+                        break;
+                    }
+                    image_hash = timed_hash(&*image_hasher, uri, &*on_attempt).await;
+                }
+
+                let waiters = {
+                    let mut requested_uris = requested_uris.lock();
+                    let waiters = match requested_uris.remove(&uri) {
+                        Some(UriState::Pending { waiters }) => waiters,
+                        _ => Vec::new(),
+                    };
+                    if let Ok(hash) = image_hash {
+                        requested_uris.insert(uri, UriState::Success(hash));
+                    }
+                    waiters
+                };
 
                 let state = match image_hash {
                     Ok(hash) => State::Success(hash),
-                    Err(_) => State::Failure,
+                    Err(ref e) => State::Failure {
+                        retryable: e.is_retryable(),
+                    },
                 };
 
+                for waiter in &waiters {
+                    requested.insert(waiter.clone(), state.clone());
+                }
                 requested.insert(boss_name.clone(), state);
-
-                BossImageHash {
+                on_pending_change(pending_count.fetch_sub(1, Relaxed) - 1);
+
+                let shared = waiters
+                    .into_iter()
+                    .map(|waiter| BossImageHash {
+                        boss_name: waiter,
+                        image_hash: match &image_hash {
+                            Ok(hash) => Ok(*hash),
+                            Err(e) => Err(e.to_shared()),
+                        },
+                    })
+                    .collect::<Vec<_>>();
+
+                let mut results: SmallVec<[BossImageHash; 1]> = smallvec![BossImageHash {
                     boss_name,
                     image_hash,
-                }
+                }];
+                results.extend(shared);
+                results
             };
 
             if let Err(_) = tx_out.send(Either::Right(future)) {
@@ -113,7 +258,9 @@ where
     };
 
     let output = futures::stream::select(
-        rx_out.buffer_unordered(concurrency),
+        rx_out
+            .buffer_unordered(concurrency)
+            .flat_map(futures::stream::iter),
         worker
             .into_stream()
             .filter_map(|()| futures::future::ready(None)),
@@ -122,20 +269,32 @@ where
     (Inbox(tx_in), output)
 }
 
+async fn timed_hash<H, G>(image_hasher: &H, uri: Uri, on_attempt: &G) -> Result<ImageHash>
+where
+    H: ImageHasher,
+    G: Fn(bool, Duration),
+{
+    let start = Instant::now();
+    let image_hash = image_hasher.hash(uri).await;
+    on_attempt(image_hash.is_ok(), start.elapsed());
+    image_hash
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::error::{Error, Result};
+    use crate::model::{Raid, Region};
     use async_trait::async_trait;
     use http::StatusCode;
     use once_cell::sync::Lazy;
-    use std::sync::atomic::AtomicUsize;
     use std::sync::atomic::Ordering::SeqCst;
 
     struct MockImageHasher {
         image1_requested: AtomicUsize,
         image2_requested: AtomicUsize,
         image3_requested: AtomicUsize,
+        image4_requested: AtomicUsize,
     }
 
     impl MockImageHasher {
@@ -144,6 +303,7 @@ mod test {
                 image1_requested: AtomicUsize::new(0),
                 image2_requested: AtomicUsize::new(0),
                 image3_requested: AtomicUsize::new(0),
+                image4_requested: AtomicUsize::new(0),
             }
         }
     }
@@ -151,6 +311,7 @@ mod test {
     const IMAGE1: Lazy<Uri> = Lazy::new(|| "http://example.com/image1.png".parse().unwrap());
     const IMAGE2: Lazy<Uri> = Lazy::new(|| "http://example.com/image2.png".parse().unwrap());
     const IMAGE3: Lazy<Uri> = Lazy::new(|| "http://example.com/image3.png".parse().unwrap());
+    const IMAGE4: Lazy<Uri> = Lazy::new(|| "http://example.com/image4.png".parse().unwrap());
 
     #[async_trait]
     impl ImageHasher for MockImageHasher {
@@ -160,13 +321,13 @@ mod test {
             if uri == *IMAGE1 {
                 self.image1_requested.fetch_add(1, SeqCst);
                 match self.image1_requested.load(SeqCst) {
-                    1 => Ok(ImageHash(1)),
+                    1 => Ok(ImageHash::from(1)),
                     _ => unreachable!(),
                 }
             } else if uri == *IMAGE2 {
                 self.image2_requested.fetch_add(1, SeqCst);
                 match self.image2_requested.load(SeqCst) {
-                    1 => Ok(ImageHash(2)),
+                    1 => Ok(ImageHash::from(2)),
                     _ => unreachable!(),
                 }
             } else if uri == *IMAGE3 {
@@ -174,7 +335,14 @@ mod test {
                 match self.image3_requested.load(SeqCst) {
                     1 => Err(Error::Http(StatusCode::INTERNAL_SERVER_ERROR)),
                     2 => Err(Error::Http(StatusCode::SERVICE_UNAVAILABLE)),
-                    3 => Ok(ImageHash(3)),
+                    3 => Ok(ImageHash::from(3)),
+                    _ => unreachable!(),
+                }
+            } else if uri == *IMAGE4 {
+                // Non-retryable errors should only be attempted once
+                self.image4_requested.fetch_add(1, SeqCst);
+                match self.image4_requested.load(SeqCst) {
+                    1 => Err(Error::Http(StatusCode::NOT_FOUND)),
                     _ => unreachable!(),
                 }
             } else {
@@ -185,7 +353,7 @@ mod test {
 
     #[tokio::test]
     async fn test_stream() -> anyhow::Result<()> {
-        let (tx, rx) = stream(MockImageHasher::new(), 5);
+        let (tx, rx) = stream(MockImageHasher::new(), 5, |_| (), |_, _| ());
         let mut rx = Box::pin(rx);
 
         // Request each boss 3 times
@@ -198,11 +366,11 @@ mod test {
         // Should receive each successful hash result only once
         let next = rx.next().await.unwrap();
         assert_eq!(&next.boss_name, "Boss1");
-        assert_eq!(next.image_hash.unwrap(), ImageHash(1));
+        assert_eq!(next.image_hash.unwrap(), ImageHash::from(1));
 
         let next = rx.next().await.unwrap();
         assert_eq!(&next.boss_name, "Boss2");
-        assert_eq!(next.image_hash.unwrap(), ImageHash(2));
+        assert_eq!(next.image_hash.unwrap(), ImageHash::from(2));
 
         let next = rx.next().await.unwrap();
         assert_eq!(&next.boss_name, "Boss3");
@@ -219,11 +387,11 @@ mod test {
         // The hasher should reuse previously successful attempts
         let next = rx.next().await.unwrap();
         assert_eq!(&next.boss_name, "Boss1");
-        assert_eq!(next.image_hash.unwrap(), ImageHash(1));
+        assert_eq!(next.image_hash.unwrap(), ImageHash::from(1));
 
         let next = rx.next().await.unwrap();
         assert_eq!(&next.boss_name, "Boss2");
-        assert_eq!(next.image_hash.unwrap(), ImageHash(2));
+        assert_eq!(next.image_hash.unwrap(), ImageHash::from(2));
 
         let next = rx.next().await.unwrap();
         assert_eq!(&next.boss_name, "Boss3");
@@ -237,14 +405,14 @@ mod test {
 
         let next = rx.next().await.unwrap();
         assert_eq!(&next.boss_name, "Boss3");
-        assert_eq!(next.image_hash.unwrap(), ImageHash(3));
+        assert_eq!(next.image_hash.unwrap(), ImageHash::from(3));
 
         // Retry once more, and it should reuse the successful value
         tx.request_hash("Boss3".into(), IMAGE3.clone());
 
         let next = rx.next().await.unwrap();
         assert_eq!(&next.boss_name, "Boss3");
-        assert_eq!(next.image_hash.unwrap(), ImageHash(3));
+        assert_eq!(next.image_hash.unwrap(), ImageHash::from(3));
 
         // On drop, the stream should end
         drop(tx);
@@ -252,4 +420,244 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn fallbacks() -> anyhow::Result<()> {
+        let (tx, rx) = stream(MockImageHasher::new(), 5, |_| (), |_, _| ());
+        let mut rx = Box::pin(rx);
+
+        // The first image fails, so the fallback should be used
+        tx.request_hash_with_fallbacks("Boss1".into(), IMAGE3.clone(), vec![IMAGE1.clone()]);
+
+        let next = rx.next().await.unwrap();
+        assert_eq!(&next.boss_name, "Boss1");
+        assert_eq!(next.image_hash.unwrap(), ImageHash::from(1));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn non_retryable_failure() -> anyhow::Result<()> {
+        let (tx, rx) = stream(MockImageHasher::new(), 5, |_| (), |_, _| ());
+        let mut rx = Box::pin(rx);
+
+        tx.request_hash("Boss4".into(), IMAGE4.clone());
+
+        let next = rx.next().await.unwrap();
+        assert_eq!(&next.boss_name, "Boss4");
+        assert!(matches!(
+            next.image_hash,
+            Err(Error::Http(StatusCode::NOT_FOUND))
+        ));
+
+        // The retry is skipped, so the next result is for the other boss
+        tx.request_hash("Boss4".into(), IMAGE4.clone());
+        tx.request_hash("Boss1".into(), IMAGE1.clone());
+
+        let next = rx.next().await.unwrap();
+        assert_eq!(&next.boss_name, "Boss1");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skip_existing_hash() -> anyhow::Result<()> {
+        let (tx, rx) = stream(MockImageHasher::new(), 5, |_| (), |_, _| ());
+        let mut rx = Box::pin(rx);
+
+        let boss = |name: &str, image: &Uri| {
+            Boss::from(&Raid {
+                id: "ABCD1234".into(),
+                tweet_id: 1,
+                user_name: "walfieee".into(),
+                user_image: None,
+                boss_name: name.into(),
+                created_at: chrono::Utc::now().into(),
+                text: None,
+                language: Language::Japanese,
+                region: Region::Jp,
+                image_url: Some(image.to_string().into()),
+                extra_image_urls: Default::default(),
+            })
+        };
+
+        // The image for a boss with a persisted hash isn't downloaded
+        let mut persisted = boss("Boss1", &IMAGE1);
+        persisted.image_hash = Some(ImageHash::from(1));
+        tx.request_hash_for_boss(&persisted);
+        tx.request_hash_for_boss(&boss("Boss2", &IMAGE2));
+
+        let next = rx.next().await.unwrap();
+        assert_eq!(&next.boss_name, "Boss2");
+        assert_eq!(next.image_hash.unwrap(), ImageHash::from(2));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pending_count() {
+        // Hashes aren't computed until permits are added
+        struct BlockingImageHasher(Arc<tokio::sync::Semaphore>);
+
+        #[async_trait]
+        impl ImageHasher for BlockingImageHasher {
+            async fn hash(&self, _uri: Uri) -> Result<ImageHash> {
+                self.0.acquire().await.forget();
+                Ok(ImageHash::from(1))
+            }
+        }
+
+        let semaphore = Arc::new(tokio::sync::Semaphore::new(0));
+        let pending = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = stream(
+            BlockingImageHasher(semaphore.clone()),
+            5,
+            {
+                let pending = pending.clone();
+                move |count| pending.store(count, SeqCst)
+            },
+            |_, _| (),
+        );
+        let mut rx = Box::pin(rx);
+
+        tx.request_hash("Boss1".into(), IMAGE1.clone());
+        tx.request_hash("Boss2".into(), IMAGE2.clone());
+        tx.request_hash("Boss2".into(), IMAGE2.clone()); // Duplicate, not counted
+
+        // Poll the stream so that the requests are picked up
+        let timeout = tokio::time::timeout(Duration::from_millis(50), rx.next()).await;
+        assert!(timeout.is_err());
+        assert_eq!(pending.load(SeqCst), 2);
+
+        semaphore.add_permits(1);
+        rx.next().await.unwrap();
+        assert_eq!(pending.load(SeqCst), 1);
+
+        semaphore.add_permits(1);
+        rx.next().await.unwrap();
+        assert_eq!(pending.load(SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn shared_uri() {
+        // Hashes aren't computed until permits are added
+        struct CountingImageHasher {
+            semaphore: Arc<tokio::sync::Semaphore>,
+            count: Arc<AtomicUsize>,
+        }
+
+        #[async_trait]
+        impl ImageHasher for CountingImageHasher {
+            async fn hash(&self, uri: Uri) -> Result<ImageHash> {
+                self.count.fetch_add(1, SeqCst);
+                self.semaphore.acquire().await.forget();
+                if uri == *IMAGE2 {
+                    Err(Error::Http(StatusCode::NOT_FOUND).with_uri(uri))
+                } else {
+                    Ok(ImageHash::from(1))
+                }
+            }
+        }
+
+        let semaphore = Arc::new(tokio::sync::Semaphore::new(0));
+        let count = Arc::new(AtomicUsize::new(0));
+        let hasher = CountingImageHasher {
+            semaphore: semaphore.clone(),
+            count: count.clone(),
+        };
+        let (tx, rx) = stream(hasher, 5, |_| (), |_, _| ());
+        let mut rx = Box::pin(rx);
+
+        // Two bosses share the same image, while the first request is still in-flight
+        tx.request_hash("Lv100 Boss".into(), IMAGE1.clone());
+        tx.request_hash("Lvl 100 Boss".into(), IMAGE1.clone());
+
+        let timeout = tokio::time::timeout(Duration::from_millis(50), rx.next()).await;
+        assert!(timeout.is_err());
+        semaphore.add_permits(1);
+
+        let mut names = vec![rx.next().await.unwrap(), rx.next().await.unwrap()]
+            .into_iter()
+            .map(|item| {
+                assert_eq!(item.image_hash.unwrap(), ImageHash::from(1));
+                item.boss_name.to_string()
+            })
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["Lv100 Boss", "Lvl 100 Boss"]);
+
+        // Another boss with the same image reuses the completed hash
+        tx.request_hash("Other Boss".into(), IMAGE1.clone());
+        let next = rx.next().await.unwrap();
+        assert_eq!(&next.boss_name, "Other Boss");
+        assert_eq!(next.image_hash.unwrap(), ImageHash::from(1));
+
+        assert_eq!(count.load(SeqCst), 1);
+
+        // If the shared request fails, both bosses get the error
+        tx.request_hash("Lv120 Boss".into(), IMAGE2.clone());
+        tx.request_hash("Lvl 120 Boss".into(), IMAGE2.clone());
+        semaphore.add_permits(1);
+
+        let mut names = vec![rx.next().await.unwrap(), rx.next().await.unwrap()]
+            .into_iter()
+            .map(|item| {
+                let error = item.image_hash.unwrap_err();
+                assert_eq!(error.status_code(), Some(StatusCode::NOT_FOUND));
+                assert!(!error.is_retryable());
+                item.boss_name.to_string()
+            })
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["Lv120 Boss", "Lvl 120 Boss"]);
+
+        assert_eq!(count.load(SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn paced_requests() {
+        let boss = |n: usize| {
+            let raid = Raid {
+                id: n.to_string(),
+                tweet_id: n as u64,
+                user_name: "walfieee".into(),
+                user_image: None,
+                boss_name: format!("Lv{} Boss", n).into(),
+                created_at: chrono::Utc::now().into(),
+                text: None,
+                language: Language::Japanese,
+                region: Region::Jp,
+                image_url: Some(format!("http://example.com/image{}.png", n).into()),
+                extra_image_urls: Default::default(),
+            };
+            Boss::from(&raid)
+        };
+        let bosses = (1..=4).map(boss).collect::<Vec<_>>();
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let inbox = Inbox(tx);
+
+        // Unpaced requests are sent all at once
+        inbox.request_hashes_for_bosses(&bosses, None).await;
+        for _ in &bosses {
+            assert!(rx.try_recv().is_ok());
+        }
+
+        // 20 per second, so there should be 50ms between each request
+        let start = std::time::Instant::now();
+        tokio::spawn({
+            let bosses = bosses.clone();
+            async move { inbox.request_hashes_for_bosses(&bosses, Some(20)).await }
+        });
+
+        let mut received_at = Vec::new();
+        for n in 1..=4 {
+            let (name, _, _) = rx.recv().await.unwrap();
+            assert_eq!(name, boss(n).name.ja.unwrap());
+            received_at.push(start.elapsed());
+        }
+
+        assert!(received_at[0] < Duration::from_millis(50));
+        assert!(received_at[3] >= Duration::from_millis(140));
+    }
 }
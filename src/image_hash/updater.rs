@@ -2,8 +2,9 @@ use std::future::Future;
 
 use crate::image_hash::stream::{stream, Inbox};
 use crate::image_hash::ImageHasher;
+use crate::metrics::{Metric, MetricFactory};
 use crate::model::Language;
-use crate::raid_handler::RaidHandler;
+use crate::raid_handler::{BossUpdate, RaidHandler};
 
 use futures::stream::StreamExt;
 use futures::FutureExt;
@@ -29,22 +30,58 @@ where
     }
 
     pub fn run(self) -> (Inbox, impl Future<Output = ()>) {
-        let mut boss_stream = self.handler.subscribe_boss_updates();
+        let mut boss_stream = self.handler.subscribe_boss_updates_with_lag();
         let Updater {
             hasher,
             handler,
             log,
             ..
         } = self;
-        let (inbox, hashes) = stream(hasher, self.concurrency);
+        let pending_handler = handler.clone();
+        let attempt_handler = handler.clone();
+        let (inbox, hashes) = stream(
+            hasher,
+            self.concurrency,
+            move |count| {
+                pending_handler
+                    .metric_factory()
+                    .image_hash_pending_gauge()
+                    .set(count)
+            },
+            move |is_success, elapsed| {
+                attempt_handler
+                    .metric_factory()
+                    .record_image_hash(is_success, elapsed)
+            },
+        );
         let mut hashes = Box::pin(hashes);
 
         let hash_inbox = inbox.clone();
         let requester_log = log.clone();
+        let requester_handler = handler.clone();
         let hash_requester = async move {
             // As bosses get discovered, request image hashes for
             // those that have an image but no hash
-            while let Some(entry) = boss_stream.next().await {
+            while let Some(update) = boss_stream.next().await {
+                let entry = match update {
+                    BossUpdate::Updated(entry) => entry,
+                    BossUpdate::Removed(_) => continue,
+                    BossUpdate::Lagged(count) => {
+                        // Some updates were missed, so check all bosses in case
+                        // any of the missed ones still need an image hash
+                        slog::warn!(
+                            requester_log, "Boss updates lagged, requesting missing image hashes";
+                            "missedCount" => count
+                        );
+                        requester_handler.for_each_boss(|entry| {
+                            if entry.boss().needs_image_hash_update() {
+                                hash_inbox.request_hash_for_boss(entry.boss());
+                            }
+                        });
+                        continue;
+                    }
+                };
+
                 let boss = entry.boss();
                 if boss.image_hash.is_some() {
                     continue;
@@ -54,8 +91,21 @@ where
                     if let (Some(name), Some(image_url)) =
                         (boss.name.get(*lang), boss.image.get(*lang))
                     {
-                        if let Ok(uri) = image_url.parse() {
-                            hash_inbox.request_hash(name.clone(), uri);
+                        // If the tweet that the image came from had other images attached,
+                        // try those if the main image can't be hashed
+                        let fallbacks = entry
+                            .history()
+                            .read()
+                            .iter()
+                            .find(|raid| raid.image_url.as_ref() == Some(image_url))
+                            .map(|raid| raid.extra_image_urls.clone())
+                            .unwrap_or_default();
+
+                        if let Ok(uri) = image_url.as_url().parse() {
+                            let fallbacks = fallbacks
+                                .iter()
+                                .flat_map(|image| image.as_url().parse().ok());
+                            hash_inbox.request_hash_with_fallbacks(name.clone(), uri, fallbacks);
                         }
                     }
                 }
@@ -68,7 +118,7 @@ where
                 match item.image_hash {
                     Ok(image_hash) => {
                         slog::info!(
-                            requester_log, "Updated boss image hash";
+                            log, "Updated boss image hash";
                             "bossName" => %item.boss_name
                         );
                         handler.update_image_hash(&item.boss_name, image_hash);
@@ -85,3 +135,143 @@ where
         (inbox, worker)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::{Error, Result};
+    use crate::image_hash::ImageHash;
+    use crate::metrics::PrometheusMetricFactory;
+    use crate::model::{Raid, Region};
+    use crate::raid_handler::{HistoryLimit, MergeOptions, RaidFilter};
+    use async_trait::async_trait;
+    use chrono::offset::TimeZone;
+    use chrono::Utc;
+    use http::{StatusCode, Uri};
+    use std::time::Duration;
+
+    struct MockImageHasher;
+
+    #[async_trait]
+    impl ImageHasher for MockImageHasher {
+        async fn hash(&self, uri: Uri) -> Result<ImageHash> {
+            if uri.path() == "/missing.png" {
+                return Err(Error::Http(StatusCode::NOT_FOUND));
+            }
+
+            // Each boss gets a different hash, so that none of them get merged
+            let id = uri.path().trim_matches(|c: char| !c.is_ascii_digit());
+            Ok(ImageHash::from(id.parse::<i64>().unwrap()))
+        }
+    }
+
+    #[tokio::test]
+    async fn resync_on_lag() {
+        let metric_factory = PrometheusMetricFactory::new("petronel".to_owned());
+        let handler = RaidHandler::new(
+            metric_factory,
+            Vec::new(),
+            HistoryLimit::new(10),
+            1,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+
+        let (_inbox, worker) = Updater::new(log, MockImageHasher, handler.clone(), 5).run();
+
+        // With a broadcast capacity of 1, the updater will lag behind these new bosses
+        for id in 0..10u64 {
+            handler.push(Raid {
+                id: id.to_string(),
+                tweet_id: id,
+                user_name: "walfieee".into(),
+                user_image: None,
+                boss_name: format!("Lv{} Boss", id).into(),
+                created_at: Utc.ymd(2020, 5, 20).and_hms(1, 2, 3).into(),
+                text: None,
+                language: Language::Japanese,
+                region: Region::Jp,
+                image_url: Some(format!("http://example.com/{}.png", id).into()),
+                extra_image_urls: Default::default(),
+            });
+        }
+
+        tokio::spawn(worker);
+
+        let all_hashed = async {
+            loop {
+                let bosses = handler.bosses();
+                if bosses.len() == 10 && bosses.iter().all(|b| b.boss().image_hash.is_some()) {
+                    break;
+                }
+                drop(bosses);
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        };
+
+        tokio::time::timeout(Duration::from_secs(5), all_hashed)
+            .await
+            .expect("timed out waiting for image hashes");
+    }
+
+    #[tokio::test]
+    async fn image_hash_metrics() {
+        let metric_factory = PrometheusMetricFactory::new("petronel".to_owned());
+        let handler = RaidHandler::new(
+            metric_factory,
+            Vec::new(),
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+
+        let (_inbox, worker) = Updater::new(log, MockImageHasher, handler.clone(), 5).run();
+        tokio::spawn(worker);
+
+        for (id, image) in vec!["1.png", "2.png", "missing.png"]
+            .into_iter()
+            .enumerate()
+        {
+            handler.push(Raid {
+                id: id.to_string(),
+                tweet_id: id as u64,
+                user_name: "walfieee".into(),
+                user_image: None,
+                boss_name: format!("Lv{} Boss", id).into(),
+                created_at: Utc.ymd(2020, 5, 20).and_hms(1, 2, 3).into(),
+                text: None,
+                language: Language::Japanese,
+                region: Region::Jp,
+                image_url: Some(format!("http://example.com/{}", image).into()),
+                extra_image_urls: Default::default(),
+            });
+        }
+
+        let expected = [
+            r#"petronel_image_hash_total{result="success"} 2"#,
+            r#"petronel_image_hash_total{result="failure"} 1"#,
+            r#"petronel_image_hash_duration_seconds_count 3"#,
+        ];
+
+        let all_recorded = async {
+            loop {
+                let metrics = handler.metrics();
+                if expected.iter().all(|line| metrics.contains(line)) {
+                    break;
+                }
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        };
+
+        tokio::time::timeout(Duration::from_secs(5), all_recorded)
+            .await
+            .expect("timed out waiting for image hash metrics");
+    }
+}
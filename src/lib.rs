@@ -1,3 +1,6 @@
+pub mod build_info;
+pub mod cancellation;
+pub mod client;
 pub mod error;
 pub mod graphql;
 pub mod image_hash;
@@ -9,4 +12,7 @@ pub mod twitter;
 
 pub use crate::error::{Error, Result};
 pub use crate::persistence::Persistence;
-pub use crate::raid_handler::{BossEntry, RaidHandler};
+pub use crate::raid_handler::{
+    BossEntry, BossSnapshot, BossTtl, BossUpdate, HandlerEvent, HistoryLimit, HistorySampling,
+    LanguageStats, MergeOptions, RaidFilter, RaidHandler, RaidUpdate,
+};
@@ -1,14 +1,57 @@
 use slog::Drain;
 use std::fmt::Debug;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 pub enum Either<A, B> {
     A(A),
     B(B),
 }
 
-pub fn logger(json: bool) -> slog::Logger {
+/// Minimum level of logs to output, which can be changed at runtime
+#[derive(Clone, Debug)]
+pub struct LogLevel(Arc<AtomicUsize>);
+
+impl LogLevel {
+    pub fn new(level: slog::Level) -> Self {
+        Self(Arc::new(AtomicUsize::new(level.as_usize())))
+    }
+
+    pub fn get(&self) -> slog::Level {
+        slog::Level::from_usize(self.0.load(Ordering::Relaxed)).unwrap_or(slog::Level::Info)
+    }
+
+    pub fn set(&self, level: slog::Level) {
+        self.0.store(level.as_usize(), Ordering::Relaxed);
+    }
+}
+
+struct LevelFilter<D> {
+    drain: D,
+    level: LogLevel,
+}
+
+impl<D: Drain> Drain for LevelFilter<D> {
+    type Ok = Option<D::Ok>;
+    type Err = D::Err;
+
+    fn log(
+        &self,
+        record: &slog::Record,
+        values: &slog::OwnedKVList,
+    ) -> Result<Self::Ok, Self::Err> {
+        if record.level().is_at_least(self.level.get()) {
+            self.drain.log(record, values).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+pub fn logger(json: bool, level: LogLevel) -> slog::Logger {
     let drain = drain(json).fuse();
     let drain = slog_async::Async::new(drain).build().fuse();
+    let drain = LevelFilter { drain, level }.fuse();
     slog::Logger::root(drain, slog::o!())
 }
 
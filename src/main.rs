@@ -1,34 +1,71 @@
 mod log;
 mod opts;
 
-use std::net::SocketAddr;
+use std::fs::File;
+use std::io::BufReader;
+use std::num::NonZeroU32;
+use std::sync::Arc;
 use std::time::Duration;
 
+use anyhow::Context;
+use arc_swap::ArcSwap;
 use chrono::Utc;
+use futures::future::FutureExt;
 use futures::stream::StreamExt;
+use http::header::HeaderValue;
+use petronel_graphql::cancellation::CancellationToken;
+use petronel_graphql::client::{HttpClient, DEFAULT_USER_AGENT};
 use petronel_graphql::image_hash::HyperImageHasher;
 use petronel_graphql::metrics::PrometheusMetricFactory;
-use petronel_graphql::model::Boss;
+use petronel_graphql::model::{Boss, Language};
 use petronel_graphql::persistence::{JsonFile, Persistence, Redis};
-use petronel_graphql::{image_hash, twitter, RaidHandler};
+use petronel_graphql::{
+    image_hash, twitter, HistoryLimit, HistorySampling, MergeOptions, RaidFilter, RaidHandler,
+};
 use structopt::StructOpt;
+use tokio::signal::unix::{signal, SignalKind};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let opt = opts::Options::from_args();
 
-    let bind_addr: SocketAddr = format!("{}:{}", opt.bind_ip, opt.port).parse()?;
+    let bind_addr = opt.bind_addr()?;
 
-    let log = log::logger(opt.json_logs);
+    let log_level = log::LogLevel::new(opt.reloadable.log_level);
+    let log = log::logger(opt.json_logs, log_level.clone());
+    slog::info!(log, "Resolved configuration"; &opt);
 
-    let conn = hyper_tls::HttpsConnector::new();
-    let client = hyper::Client::builder().build::<_, hyper::Body>(conn);
+    let canonical_language = match opt.canonical_language.as_str() {
+        "en" => Language::English,
+        _ => Language::Japanese,
+    };
+
+    let user_agent = opt
+        .user_agent
+        .as_deref()
+        .unwrap_or(DEFAULT_USER_AGENT)
+        .parse::<HeaderValue>()
+        .context("invalid --user-agent")?;
+    let client = HttpClient::new(user_agent, opt.http_proxy.clone())
+        .context("failed to initialize HTTP client")?;
+
+    if opt.dry_run {
+        return dry_run(&log, &opt, &client).await;
+    }
+
+    // Only used for Twitter API v1.1
+    let token = if opt.twitter_api_version == "2" {
+        None
+    } else {
+        Some(twitter_token(&opt)?)
+    };
 
     // Get boss list from cache
+    let redis_layout = opt.storage_redis_layout();
     let json_file = opt.storage_file_path.map(JsonFile::new);
     let redis_client = match opt.storage_redis_uri {
         None => None,
-        Some(uri) => match Redis::new(uri, opt.storage_redis_key).await {
+        Some(uri) => match Redis::new(uri, redis_layout).await {
             Ok(client) => Some(client),
             Err(e) => {
                 slog::warn!(log, "Failed to connect to Redis"; "error" => %e);
@@ -37,8 +74,20 @@ async fn main() -> anyhow::Result<()> {
         },
     };
 
-    let initial_bosses =
+    let mut initial_bosses =
         get_initial_bosses(&log, json_file.as_ref(), redis_client.as_ref()).await?;
+
+    // Hashes of a different size never match, so they're recomputed
+    let hash_size = opt.hash_size()?;
+    for boss in initial_bosses.iter_mut() {
+        if boss
+            .image_hash
+            .map_or(false, |h| h.width() != hash_size.bits())
+        {
+            boss.image_hash = None;
+        }
+    }
+
     let bosses_to_request_hashes_for = initial_bosses
         .iter()
         .filter(|b| b.needs_image_hash_update())
@@ -49,49 +98,166 @@ async fn main() -> anyhow::Result<()> {
     let raid_handler = RaidHandler::new(
         PrometheusMetricFactory::new(opt.prometheus_prefix),
         initial_bosses,
-        opt.raid_history_size,
+        HistoryLimit {
+            size: opt.raid_history_size,
+            max_age: opt
+                .history_max_age
+                .map(chrono::Duration::from_std)
+                .transpose()?,
+            sampling: opt.history_sample_above.map(|max_per_sec| HistorySampling {
+                max_per_sec,
+                every: opt.history_sample_every,
+            }),
+        },
         opt.broadcast_capacity,
+        opt.max_broadcasts_per_sec_per_boss.map(NonZeroU32::get),
+        MergeOptions {
+            dedupe_history: opt.dedupe_merged_history,
+            ignore_level: opt.merge_ignoring_level,
+        },
+        RaidFilter {
+            min_level: opt.min_ingest_level,
+            allow: opt.boss_allow.into_iter().map(Into::into).collect(),
+            deny: opt.boss_deny.into_iter().map(Into::into).collect(),
+            max_age: opt
+                .max_tweet_age
+                .map(chrono::Duration::from_std)
+                .transpose()?,
+        },
+        canonical_language,
     );
 
+    // Shared by spawned tasks, so they can be stopped on shutdown
+    let cancel = CancellationToken::new();
+
     // Fetch boss images and calculate image hashes
     let hash_updater = image_hash::Updater::new(
         log.clone(),
-        HyperImageHasher::new(client.clone()),
+        HyperImageHasher::new(client.clone(), hash_size),
         raid_handler.clone(),
         opt.image_hash_concurrency,
     );
     let (hash_inbox, hash_worker) = hash_updater.run();
-    bosses_to_request_hashes_for
-        .iter()
-        .for_each(|boss| hash_inbox.request_hash_for_boss(boss));
-    tokio::spawn(hash_worker);
+    tokio::spawn({
+        let hash_inbox = hash_inbox.clone();
+        let per_sec = opt.image_hash_backfill_rate;
+        let cancel = cancel.clone();
+        async move {
+            let backfill =
+                hash_inbox.request_hashes_for_bosses(&bosses_to_request_hashes_for, per_sec);
+            cancel.run_until_cancelled(backfill).await
+        }
+    });
+    tokio::spawn({
+        let cancel = cancel.clone();
+        async move { cancel.run_until_cancelled(hash_worker).await }
+    });
 
     // Cleanup task that runs on startup and periodically:
     // * removes bosses that haven't been seen in a while
+    // * removes raids older than `--history-max-age` from boss histories
     // * drops broadcast channels for bosses that don't exist and have no subscribers
     // * requests image hashes for bosses that have an image but no hash
-    //   (possibly due to a failed HTTP request)
+    //   (possibly due to a failed HTTP request). This is skipped on startup, since the
+    //   backfill above already handles it.
+    // * if `--merge-imageless-by-name` is set, merges bosses that have no image by name
+    //
+    // Options used here can be changed at runtime by the reload task below.
+    opt.reloadable.boss_ttl()?;
+    let settings = Arc::new(ArcSwap::from_pointee(opt.reloadable.clone()));
+    let merge_imageless_by_name = opt.merge_imageless_by_name;
     tokio::spawn({
-        let ttl = chrono::Duration::from_std(opt.boss_ttl)?;
         let raid_handler = raid_handler.clone();
-        let mut interval = tokio::time::interval(opt.cleanup_interval);
+        let settings = settings.clone();
+        let cancel = cancel.clone();
+        let log = log.clone();
 
         async move {
+            let mut is_startup = true;
             loop {
-                interval.tick().await;
-                let long_ago = Utc::now() - ttl;
-                raid_handler.retain(|entry| {
+                let settings = settings.load_full();
+                let ttl = settings
+                    .boss_ttl()
+                    .expect("TTL is validated when options are loaded");
+
+                let started_at = std::time::Instant::now();
+                let now = Utc::now();
+                let removed = raid_handler.retain(|entry| {
                     let boss = entry.boss();
-                    if boss.needs_image_hash_update() {
+                    if !is_startup && boss.needs_image_hash_update() {
                         hash_inbox.request_hash_for_boss(boss);
                     }
 
-                    boss.last_seen_at.as_datetime() > long_ago
+                    !ttl.is_expired(boss, now)
                 });
+                let merged = if merge_imageless_by_name {
+                    raid_handler.merge_by_name()
+                } else {
+                    0
+                };
+                raid_handler.prune_histories();
+                slog::info!(
+                    log, "Finished cleanup";
+                    "removedBosses" => removed,
+                    "mergedBosses" => merged,
+                    "elapsedMs" => started_at.elapsed().as_millis() as u64,
+                );
+                is_startup = false;
+
+                tokio::select! {
+                    _ = tokio::time::delay_for(settings.cleanup_interval) => {}
+                    _ = cancel.cancelled() => break,
+                }
             }
         }
     });
 
+    // Reload runtime-tunable options on SIGHUP
+    if let Some(path) = opt.reload_file.clone() {
+        let mut hangups = signal(SignalKind::hangup()).context("failed to listen for SIGHUP")?;
+        let log = log.clone();
+        let cancel = cancel.clone();
+
+        tokio::spawn(async move {
+            while let Some(Some(())) = cancel.run_until_cancelled(hangups.recv()).await {
+                let result = tokio::fs::read_to_string(&path)
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|contents| settings.load().reload(&contents));
+
+                match result {
+                    Ok(reloaded) => {
+                        slog::info!(log, "Reloaded configuration"; &reloaded);
+                        log_level.set(reloaded.log_level);
+                        settings.store(Arc::new(reloaded));
+                    }
+                    Err(e) => {
+                        slog::warn!(
+                            log, "Failed to reload configuration";
+                            "error" => %e, "path" => &path
+                        );
+                    }
+                }
+            }
+        });
+    }
+
+    // Stop accepting new websocket connections on SIGUSR1 (same as `POST /drain`)
+    {
+        let mut drains =
+            signal(SignalKind::user_defined1()).context("failed to listen for SIGUSR1")?;
+        let raid_handler = raid_handler.clone();
+        let log = log.clone();
+        let cancel = cancel.clone();
+
+        tokio::spawn(async move {
+            while let Some(Some(())) = cancel.run_until_cancelled(drains.recv()).await {
+                slog::info!(log, "Draining, new websocket connections will be refused");
+                raid_handler.drain();
+            }
+        });
+    }
+
     // Periodically write boss data to JSON file
     if let Some(file) = json_file {
         let log = log.clone();
@@ -99,6 +265,7 @@ async fn main() -> anyhow::Result<()> {
             raid_handler.clone(),
             file,
             opt.storage_file_flush_interval,
+            cancel.clone(),
             move |file, result| match result {
                 Ok(count) => {
                     slog::debug!(log, "Saved boss data to file"; "path" => file.path(), "count" => count)
@@ -117,6 +284,7 @@ async fn main() -> anyhow::Result<()> {
             raid_handler.clone(),
             redis,
             opt.storage_redis_flush_interval,
+            cancel.clone(),
             move |_, result| match result {
                 Ok(count) => slog::debug!(log, "Saved boss data to Redis"; "count" => count),
                 Err(e) => slog::warn!(log, "Failed to save boss data to Redis"; "error" => %e),
@@ -125,30 +293,79 @@ async fn main() -> anyhow::Result<()> {
     };
 
     // Start Twitter stream
-    let token = twitter::Token::new(
-        opt.consumer_key,
-        opt.consumer_secret,
-        opt.access_token,
-        opt.access_token_secret,
-    );
-    let (mut tweet_stream, twitter_worker) = twitter::connect_with_retries(
-        log.clone(),
-        client,
-        token,
-        opt.connection_retry_delay,
-        opt.connection_timeout,
-    );
+    let (mut tweet_stream, twitter_worker) = if let Some(token) = token {
+        // Fail fast if the Twitter credentials are invalid
+        let screen_name = twitter::verify_credentials(&client, &token)
+            .await
+            .context("failed to verify Twitter credentials")?;
+        slog::info!(log, "Verified Twitter credentials"; "screenName" => screen_name);
+
+        let (stream, worker) = twitter::connect_with_retries(
+            log.clone(),
+            twitter::GzipService::new(client, opt.twitter_gzip),
+            token,
+            twitter::TweetSources::new(&opt.tweet_source_urls, &opt.tweet_source_names),
+            opt.connection_retry_delay,
+            opt.connection_timeout,
+            opt.twitter_connect_timeout,
+        );
+        (stream.right_stream(), worker.left_future())
+    } else {
+        let bearer_token = opt
+            .twitter_bearer_token
+            .context("--twitter-bearer-token is required for Twitter API v2")?;
 
-    let routes = petronel_graphql::graphql::routes(raid_handler.clone());
-    tokio::spawn(async move {
-        while let Some(item) = tweet_stream.next().await {
-            raid_handler.push(item);
+        let (stream, worker) = twitter::v2::connect_with_retries(
+            log.clone(),
+            client,
+            bearer_token,
+            opt.twitter_gzip,
+            twitter::TweetSources::new(&opt.tweet_source_urls, &opt.tweet_source_names),
+            opt.connection_retry_delay,
+            opt.connection_timeout,
+        );
+        (stream.left_stream(), worker.right_future())
+    };
+
+    let page_size_limit = petronel_graphql::graphql::PageSizeLimit {
+        max: opt.graphql_max_page_size,
+        strict: opt.graphql_strict_page_size,
+    };
+    let routes = petronel_graphql::graphql::routes(
+        raid_handler.clone(),
+        page_size_limit,
+        opt.graphql_max_body_bytes,
+        opt.admin_token.clone(),
+        opt.enable_admin_ui,
+        opt.enable_debug_events,
+        opt.max_websocket_connections,
+    );
+    let retain_all_media = opt.retain_all_media;
+    tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            while let Some(Some(mut item)) = cancel.run_until_cancelled(tweet_stream.next()).await {
+                if !retain_all_media {
+                    item.extra_image_urls = Default::default();
+                }
+                raid_handler.push(item);
+            }
         }
     });
 
     // Start HTTP listeners
-    slog::info!(log, "Starting HTTP server"; "port" => opt.port, "ip" => &opt.bind_ip);
-    let server = tokio::spawn(warp::serve(routes).try_bind(bind_addr));
+    let server = match (&opt.tls_cert, &opt.tls_key) {
+        (Some(cert), Some(key)) => {
+            check_tls_files(cert, key)?;
+            slog::info!(log, "Starting HTTPS server"; "port" => opt.port, "ip" => &opt.bind_ip);
+            let server = warp::serve(routes).tls().cert_path(cert).key_path(key);
+            tokio::spawn(server.bind(bind_addr))
+        }
+        _ => {
+            slog::info!(log, "Starting HTTP server"; "port" => opt.port, "ip" => &opt.bind_ip);
+            tokio::spawn(warp::serve(routes).try_bind(bind_addr))
+        }
+    };
 
     tokio::select! {
         _ = twitter_worker => {
@@ -162,24 +379,139 @@ async fn main() -> anyhow::Result<()> {
         }
     };
 
+    cancel.cancel();
     anyhow::bail!("could not start");
 }
 
+fn twitter_token(opt: &opts::Options) -> anyhow::Result<twitter::Token> {
+    match (
+        &opt.consumer_key,
+        &opt.consumer_secret,
+        &opt.access_token,
+        &opt.access_token_secret,
+    ) {
+        (Some(ck), Some(cs), Some(at), Some(ats)) => Ok(twitter::Token::new(
+            ck.clone(),
+            cs.clone(),
+            at.clone(),
+            ats.clone(),
+        )),
+        _ => anyhow::bail!(
+            "--consumer-key, --consumer-secret, --access-token, and --access-token-secret \
+             are required for Twitter API v1.1"
+        ),
+    }
+}
+
+// warp panics inside the server task if the TLS certificate or key can't be loaded, which would
+// only be logged as a failure to bind. They're loaded the same way up front to report the cause.
+fn check_tls_files(cert_path: &str, key_path: &str) -> anyhow::Result<()> {
+    use rustls::internal::pemfile;
+
+    let open = |path: &str| -> anyhow::Result<_> {
+        let file = File::open(path).with_context(|| format!("failed to open `{}`", path))?;
+        Ok(BufReader::new(file))
+    };
+
+    let certs = pemfile::certs(&mut open(cert_path)?)
+        .map_err(|()| anyhow::anyhow!("failed to parse TLS certificate `{}`", cert_path))?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificates found in `{}`", cert_path);
+    }
+
+    let key_error = || anyhow::anyhow!("failed to parse TLS private key `{}`", key_path);
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(key_path)?).map_err(|()| key_error())?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(key_path)?).map_err(|()| key_error())?;
+    }
+    let key = keys
+        .into_iter()
+        .next()
+        .with_context(|| format!("no private key found in `{}`", key_path))?;
+
+    rustls::ServerConfig::new(rustls::NoClientAuth::new())
+        .set_single_cert(certs, key)
+        .with_context(|| {
+            format!(
+                "invalid TLS certificate `{}` or private key `{}`",
+                cert_path, key_path
+            )
+        })
+}
+
+// Checks that storage is reachable, bosses can be loaded, and the Twitter credentials are valid,
+// without starting the Twitter stream or HTTP server
+async fn dry_run(
+    log: &slog::Logger,
+    opt: &opts::Options,
+    client: &HttpClient,
+) -> anyhow::Result<()> {
+    let json_file = opt.storage_file_path.clone().map(JsonFile::new);
+    let redis_client = match &opt.storage_redis_uri {
+        None => None,
+        Some(uri) => Some(
+            Redis::new(uri.as_str(), opt.storage_redis_layout())
+                .await
+                .context("failed to connect to Redis")?,
+        ),
+    };
+
+    let bosses = get_initial_bosses(log, json_file.as_ref(), redis_client.as_ref()).await?;
+
+    if opt.twitter_api_version == "2" {
+        let bearer_token = opt
+            .twitter_bearer_token
+            .as_deref()
+            .context("--twitter-bearer-token is required for Twitter API v2")?;
+        twitter::v2::verify_bearer_token(client, bearer_token)
+            .await
+            .context("failed to verify Twitter bearer token")?;
+    } else {
+        let screen_name = twitter::verify_credentials(client, &twitter_token(opt)?)
+            .await
+            .context("failed to verify Twitter credentials")?;
+        slog::info!(log, "Verified Twitter credentials"; "screenName" => screen_name);
+    }
+
+    slog::info!(log, "Dry run succeeded"; "bossCount" => bosses.len());
+    Ok(())
+}
+
 async fn save_bosses<P: Persistence>(
     raid_handler: RaidHandler,
     persistence: P,
     interval: Duration,
+    cancel: CancellationToken,
     mut on_complete: impl FnMut(&P, Result<usize, P::Error>),
 ) {
     let mut interval = tokio::time::interval(interval);
     interval.tick().await; // The first tick completes immediately
+    let mut saved_generation = None;
     loop {
-        interval.tick().await;
-        let guard = raid_handler.bosses();
-        let bosses = guard.iter().map(|entry| entry.boss()).collect::<Vec<_>>();
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = cancel.cancelled() => break,
+        }
 
-        let result = persistence.save_bosses(&bosses).await;
-        on_complete(&persistence, result.map(|()| bosses.len()));
+        // Skip the save if nothing changed since the last successful one
+        let generation = raid_handler.generation();
+        if saved_generation == Some(generation) {
+            continue;
+        }
+
+        let mut count = 0;
+        let save = persistence.save_bosses(|f| {
+            raid_handler.for_each_boss(|entry| {
+                count += 1;
+                f(entry.boss())
+            })
+        });
+
+        let result = save.await;
+        if result.is_ok() {
+            saved_generation = Some(generation);
+        }
+        on_complete(&persistence, result.map(|()| count));
     }
 }
 
@@ -239,14 +571,102 @@ async fn get_initial_bosses(
             }),
     };
 
-    // See comment on `Boss::LVL_120_MEDUSA` for the reasoning
-    if bosses
-        .iter()
-        .find(|b| b.name == Boss::LVL_120_MEDUSA.name)
-        .is_none()
-    {
-        bosses.push(Boss::LVL_120_MEDUSA.clone());
-    }
+    // See comment on `Boss::known_bosses` for the reasoning
+    Boss::ensure_known_bosses(&mut bosses);
 
     Ok(bosses)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::future::BoxFuture;
+    use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+    use structopt::StructOpt;
+
+    #[tokio::test]
+    async fn dry_run_redis_failure() {
+        let opt = opts::Options::from_iter_safe(&[
+            "petronel-graphql",
+            "--dry-run",
+            "--storage-redis-uri=redis://127.0.0.1:1",
+        ])
+        .unwrap();
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+
+        let error = dry_run(&log, &opt, &HttpClient::default())
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "failed to connect to Redis");
+    }
+
+    #[test]
+    fn invalid_tls_files() {
+        let error = check_tls_files("missing.pem", "key.pem").unwrap_err();
+        assert_eq!(error.to_string(), "failed to open `missing.pem`");
+
+        let error = check_tls_files("Cargo.toml", "Cargo.toml").unwrap_err();
+        assert_eq!(error.to_string(), "no certificates found in `Cargo.toml`");
+    }
+
+    #[derive(Clone, Default)]
+    struct CountingPersistence(Arc<AtomicUsize>);
+
+    #[async_trait::async_trait]
+    impl Persistence for CountingPersistence {
+        type Error = std::convert::Infallible;
+
+        async fn get_bosses(&self) -> Result<Vec<Boss>, Self::Error> {
+            Ok(Vec::new())
+        }
+
+        fn save_bosses(
+            &self,
+            _for_each_boss: impl FnOnce(&mut dyn FnMut(&Boss)),
+        ) -> BoxFuture<'static, Result<(), Self::Error>> {
+            self.0.fetch_add(1, SeqCst);
+            Box::pin(futures::future::ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn skip_unchanged_saves() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Boss::known_bosses().to_vec(),
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        let persistence = CountingPersistence::default();
+        let cancel = CancellationToken::new();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+        tokio::spawn(save_bosses(
+            handler.clone(),
+            persistence.clone(),
+            Duration::from_millis(10),
+            cancel.clone(),
+            move |_, result| {
+                let _ = tx.send(result.unwrap());
+            },
+        ));
+
+        // The first save always happens
+        assert_eq!(rx.recv().await, Some(Boss::known_bosses().len()));
+
+        // No writes while nothing changes
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        assert!(rx.try_recv().is_err());
+        assert_eq!(persistence.0.load(SeqCst), 1);
+
+        handler.retain(|_| false);
+        assert_eq!(rx.recv().await, Some(0));
+        assert_eq!(persistence.0.load(SeqCst), 2);
+
+        cancel.cancel();
+    }
+}
@@ -0,0 +1,29 @@
+use crate::model::LangString;
+use serde::Serialize;
+
+/// Metrics in a JSON-friendly format, for consumers that don't understand Prometheus
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JsonMetrics {
+    pub websocket_connections: usize,
+    pub subscriber_lagged_total: usize,
+    pub image_hash_pending: usize,
+    pub graphql_requests_total: usize,
+    pub graphql_errors_total: usize,
+    pub bosses: Vec<JsonBossMetrics>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JsonBossMetrics {
+    pub name: LangString,
+    pub tweets_total: JsonLangCount,
+    pub subscriptions: usize,
+    pub has_image_hash: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct JsonLangCount {
+    pub ja: usize,
+    pub en: usize,
+}
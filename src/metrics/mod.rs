@@ -1,11 +1,17 @@
+mod json;
 mod prometheus;
 
-pub use crate::metrics::prometheus::{PrometheusMetric, PrometheusMetricFactory};
+pub use crate::metrics::json::{JsonBossMetrics, JsonLangCount, JsonMetrics};
+pub use crate::metrics::prometheus::{
+    PrometheusHistogram, PrometheusMetric, PrometheusMetricFactory,
+};
 use crate::model::{LangString, Language};
+use std::time::Duration;
 
 pub trait Metric: Clone {
     fn get(&self) -> usize;
     fn inc(&self);
+    fn add(&self, value: usize);
     fn dec(&self);
     fn set(&self, value: usize);
 }
@@ -23,6 +29,16 @@ pub trait MetricFactory {
     fn boss_subscriptions_gauge(&self, name: &LangString) -> Self::Metric;
 
     fn websocket_connections_gauge(&self) -> &Self::Metric;
+    fn subscriber_lagged_counter(&self) -> &Self::Metric;
+    fn image_hash_pending_gauge(&self) -> &Self::Metric;
+    fn bosses_removed_counter(&self) -> &Self::Metric;
+    fn bosses_by_language_gauge(&self) -> &BossLanguageMetric<Self::Metric>;
+
+    /// Records a GraphQL operation. `operation` is the operation name, if any.
+    fn record_graphql_request(&self, operation: Option<&str>, is_error: bool);
+
+    /// Records an attempt to download and hash a boss image
+    fn record_image_hash(&self, is_success: bool, elapsed: Duration);
 
     fn write_per_boss_metrics(&self, metrics: &PerBossMetrics<'_, Self::Metric>) -> Self::Output;
 }
@@ -54,3 +70,41 @@ where
         }
     }
 }
+
+/// Number of bosses with names in only Japanese, only English, or both
+#[derive(Debug, Clone)]
+pub struct BossLanguageMetric<M> {
+    ja: M,
+    en: M,
+    both: M,
+}
+
+impl<M> BossLanguageMetric<M>
+where
+    M: Metric,
+{
+    pub fn new(ja: M, en: M, both: M) -> Self {
+        Self { ja, en, both }
+    }
+
+    /// Sets each count from the names of all bosses, which `for_each_name` passes to its callback
+    pub fn set_from(&self, for_each_name: impl FnOnce(&mut dyn FnMut(&LangString))) {
+        let (mut ja, mut en, mut both) = (0, 0, 0);
+        for_each_name(&mut |name| match (&name.ja, &name.en) {
+            (Some(_), Some(_)) => both += 1,
+            (Some(_), None) => ja += 1,
+            (None, Some(_)) => en += 1,
+            (None, None) => {}
+        });
+
+        self.ja.set(ja);
+        self.en.set(en);
+        self.both.set(both);
+    }
+
+    pub fn for_each(&self, mut f: impl FnMut(&M)) {
+        for metric in &[&self.ja, &self.en, &self.both] {
+            f(metric)
+        }
+    }
+}
@@ -1,9 +1,13 @@
-use crate::metrics::{LangMetric, Metric, MetricFactory, PerBossMetrics};
+use crate::build_info;
+use crate::metrics::{BossLanguageMetric, LangMetric, Metric, MetricFactory, PerBossMetrics};
 use crate::model::{LangString, Language};
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::fmt::Write;
-use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicU64, AtomicUsize};
+use std::time::Duration;
 
 #[derive(Debug)]
 pub struct PrometheusMetric {
@@ -27,6 +31,25 @@ impl PrometheusMetric {
             value: AtomicUsize::new(0),
         }
     }
+
+    /// Increments the value, unless it's already at least `max`. Returns whether it was
+    /// incremented.
+    pub fn inc_up_to(&self, max: usize) -> bool {
+        let mut current = self.value.load(Relaxed);
+        loop {
+            if current >= max {
+                return false;
+            }
+
+            match self
+                .value
+                .compare_exchange_weak(current, current + 1, Relaxed, Relaxed)
+            {
+                Ok(_) => return true,
+                Err(actual) => current = actual,
+            }
+        }
+    }
 }
 
 impl Metric for PrometheusMetric {
@@ -38,6 +61,10 @@ impl Metric for PrometheusMetric {
         self.value.fetch_add(1, Relaxed);
     }
 
+    fn add(&self, value: usize) {
+        self.value.fetch_add(value, Relaxed);
+    }
+
     fn dec(&self) {
         self.value.fetch_sub(1, Relaxed);
     }
@@ -53,6 +80,67 @@ impl fmt::Display for PrometheusMetric {
     }
 }
 
+/// A histogram of durations, in seconds
+#[derive(Debug)]
+pub struct PrometheusHistogram {
+    key: String,
+    // Upper bounds, each with the number of observations at or below it
+    buckets: Vec<(f64, AtomicUsize)>,
+    sum_micros: AtomicU64,
+    count: AtomicUsize,
+}
+
+impl PrometheusHistogram {
+    pub fn new(key: String, bounds: &[f64]) -> Self {
+        Self {
+            key,
+            buckets: bounds
+                .iter()
+                .map(|&bound| (bound, AtomicUsize::new(0)))
+                .collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bound, count) in &self.buckets {
+            if secs <= *bound {
+                count.fetch_add(1, Relaxed);
+            }
+        }
+
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Relaxed);
+        self.count.fetch_add(1, Relaxed);
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Relaxed)
+    }
+}
+
+impl fmt::Display for PrometheusHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (bound, count) in &self.buckets {
+            writeln!(
+                f,
+                "{}_bucket{{le=\"{}\"}} {}",
+                self.key,
+                bound,
+                count.load(Relaxed)
+            )?;
+        }
+
+        let count = self.count();
+        let sum = self.sum_micros.load(Relaxed) as f64 / 1_000_000.0;
+        writeln!(f, "{}_bucket{{le=\"+Inf\"}} {}", self.key, count)?;
+        writeln!(f, "{}_sum {}", self.key, sum)?;
+        write!(f, "{}_count {}", self.key, count)
+    }
+}
+
 #[derive(Debug)]
 pub struct PrometheusMetricFactory {
     prefix: String,
@@ -60,9 +148,37 @@ pub struct PrometheusMetricFactory {
     boss_subscriptions_gauge_header: String,
     websocket_connections_gauge_header: String,
     websocket_connections_gauge: PrometheusMetric,
+    subscriber_lagged_counter_header: String,
+    subscriber_lagged_counter: PrometheusMetric,
+    image_hash_pending_gauge_header: String,
+    image_hash_pending_gauge: PrometheusMetric,
+    image_hash_counter_header: String,
+    image_hash_success_counter: PrometheusMetric,
+    image_hash_failure_counter: PrometheusMetric,
+    image_hash_duration_histogram_header: String,
+    image_hash_duration_histogram: PrometheusHistogram,
+    bosses_removed_counter_header: String,
+    bosses_removed_counter: PrometheusMetric,
+    bosses_by_language_gauge_header: String,
+    bosses_by_language_gauge: BossLanguageMetric<PrometheusMetric>,
+    graphql_requests_counter_header: String,
+    // Keyed by operation name
+    graphql_requests_counters: Mutex<BTreeMap<String, PrometheusMetric>>,
+    graphql_errors_counter_header: String,
+    graphql_errors_counter: PrometheusMetric,
+    build_info_gauge_header: String,
+    build_info_gauge: PrometheusMetric,
 }
 
 impl PrometheusMetricFactory {
+    /// Operation names are provided by clients, so only this many are tracked separately, to
+    /// avoid unbounded label cardinality. Any others are counted under `other`.
+    const MAX_GRAPHQL_OPERATIONS: usize = 100;
+
+    /// Histogram buckets for image hash durations, in seconds
+    const IMAGE_HASH_DURATION_BUCKETS: &'static [f64] =
+        &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
     pub fn new(prefix: String) -> Self {
         let header = |name, description, kind| {
             format!(
@@ -89,19 +205,244 @@ impl PrometheusMetricFactory {
             "gauge",
         );
 
+        let subscriber_lagged_counter_header = header(
+            "subscriber_lagged_total",
+            "Number of messages dropped due to lagging subscribers",
+            "counter",
+        );
+
+        let image_hash_pending_gauge_header = header(
+            "image_hash_pending",
+            "Number of boss image hash requests in progress",
+            "gauge",
+        );
+
+        let image_hash_counter_header = header(
+            "image_hash_total",
+            "Number of attempts to download and hash a boss image, by result",
+            "counter",
+        );
+
+        let image_hash_duration_histogram_header = header(
+            "image_hash_duration_seconds",
+            "Time taken to download and hash a boss image",
+            "histogram",
+        );
+
+        let bosses_removed_counter_header = header(
+            "bosses_removed_total",
+            "Number of bosses removed during cleanup tasks",
+            "counter",
+        );
+
+        let bosses_by_language_gauge_header = header(
+            "bosses_by_language",
+            "Number of bosses with names in only Japanese, only English, or both",
+            "gauge",
+        );
+
+        let graphql_requests_counter_header = header(
+            "graphql_requests_total",
+            "Number of GraphQL operations, by operation name",
+            "counter",
+        );
+
+        let graphql_errors_counter_header = header(
+            "graphql_errors_total",
+            "Number of GraphQL operations that returned errors",
+            "counter",
+        );
+
+        let build_info_gauge_header = header(
+            "build_info",
+            "Version and git commit of the running build, always 1",
+            "gauge",
+        );
+
         let websocket_connections_gauge = {
             let key = format!("{}_websocket_connections", prefix);
             PrometheusMetric::new(key)
         };
 
+        let subscriber_lagged_counter = {
+            let key = format!("{}_subscriber_lagged_total", prefix);
+            PrometheusMetric::new(key)
+        };
+
+        let image_hash_pending_gauge = {
+            let key = format!("{}_image_hash_pending", prefix);
+            PrometheusMetric::new(key)
+        };
+
+        let image_hash_counter = |result| {
+            let key = format!("{}_image_hash_total{{result=\"{}\"}}", prefix, result);
+            PrometheusMetric::new(key)
+        };
+        let image_hash_success_counter = image_hash_counter("success");
+        let image_hash_failure_counter = image_hash_counter("failure");
+
+        let image_hash_duration_histogram = PrometheusHistogram::new(
+            format!("{}_image_hash_duration_seconds", prefix),
+            Self::IMAGE_HASH_DURATION_BUCKETS,
+        );
+
+        let bosses_removed_counter = {
+            let key = format!("{}_bosses_removed_total", prefix);
+            PrometheusMetric::new(key)
+        };
+
+        let bosses_by_language_gauge = {
+            let make = |lang| {
+                let key = format!("{}_bosses_by_language{{lang=\"{}\"}}", prefix, lang);
+                PrometheusMetric::new(key)
+            };
+            BossLanguageMetric::new(make("ja"), make("en"), make("both"))
+        };
+
+        let graphql_errors_counter = {
+            let key = format!("{}_graphql_errors_total", prefix);
+            PrometheusMetric::new(key)
+        };
+
+        let build_info_gauge = {
+            let key = format!(
+                "{}_build_info{{version=\"{}\",commit=\"{}\"}}",
+                prefix,
+                Label::new(build_info::VERSION),
+                Label::new(build_info::COMMIT),
+            );
+            let metric = PrometheusMetric::new(key);
+            metric.set(1);
+            metric
+        };
+
         Self {
             prefix,
             boss_tweets_counter_header,
             boss_subscriptions_gauge_header,
             websocket_connections_gauge_header,
             websocket_connections_gauge,
+            subscriber_lagged_counter_header,
+            subscriber_lagged_counter,
+            image_hash_pending_gauge_header,
+            image_hash_pending_gauge,
+            image_hash_counter_header,
+            image_hash_success_counter,
+            image_hash_failure_counter,
+            image_hash_duration_histogram_header,
+            image_hash_duration_histogram,
+            bosses_removed_counter_header,
+            bosses_removed_counter,
+            bosses_by_language_gauge_header,
+            bosses_by_language_gauge,
+            graphql_requests_counter_header,
+            graphql_requests_counters: Mutex::new(BTreeMap::new()),
+            graphql_errors_counter_header,
+            graphql_errors_counter,
+            build_info_gauge_header,
+            build_info_gauge,
         }
     }
+
+    /// Total number of GraphQL operations, across all operation names
+    pub fn graphql_requests_total(&self) -> usize {
+        self.graphql_requests_counters
+            .lock()
+            .values()
+            .map(|metric| metric.get())
+            .sum()
+    }
+
+    pub fn graphql_errors_counter(&self) -> &PrometheusMetric {
+        &self.graphql_errors_counter
+    }
+
+    /// Like `write_per_boss_metrics`, but appends to `out` and gets the per-boss metrics from
+    /// callbacks, so they don't need to be collected first
+    pub fn write_metrics<T, S>(
+        &self,
+        out: &mut String,
+        for_each_tweets_counter: T,
+        for_each_subscriptions_gauge: S,
+    ) where
+        T: FnOnce(&mut dyn FnMut(&LangMetric<PrometheusMetric>)),
+        S: FnOnce(&mut dyn FnMut(&PrometheusMetric)),
+    {
+        writeln!(
+            out,
+            "{}\n{}",
+            self.websocket_connections_gauge_header, self.websocket_connections_gauge
+        )
+        .unwrap();
+
+        writeln!(
+            out,
+            "\n{}\n{}",
+            self.subscriber_lagged_counter_header, self.subscriber_lagged_counter
+        )
+        .unwrap();
+
+        writeln!(
+            out,
+            "\n{}\n{}",
+            self.image_hash_pending_gauge_header, self.image_hash_pending_gauge
+        )
+        .unwrap();
+
+        writeln!(
+            out,
+            "\n{}\n{}\n{}",
+            self.image_hash_counter_header,
+            self.image_hash_success_counter,
+            self.image_hash_failure_counter
+        )
+        .unwrap();
+
+        writeln!(
+            out,
+            "\n{}\n{}",
+            self.image_hash_duration_histogram_header, self.image_hash_duration_histogram
+        )
+        .unwrap();
+
+        writeln!(
+            out,
+            "\n{}\n{}",
+            self.bosses_removed_counter_header, self.bosses_removed_counter
+        )
+        .unwrap();
+
+        writeln!(out, "\n{}", self.bosses_by_language_gauge_header).unwrap();
+        self.bosses_by_language_gauge
+            .for_each(|m| writeln!(out, "{}", m).unwrap());
+
+        writeln!(out, "\n{}", self.graphql_requests_counter_header).unwrap();
+        for metric in self.graphql_requests_counters.lock().values() {
+            writeln!(out, "{}", metric).unwrap();
+        }
+
+        writeln!(
+            out,
+            "\n{}\n{}",
+            self.graphql_errors_counter_header, self.graphql_errors_counter
+        )
+        .unwrap();
+
+        writeln!(out, "\n{}", self.boss_tweets_counter_header).unwrap();
+        for_each_tweets_counter(&mut |metric| {
+            metric.for_each(|m| writeln!(out, "{}", m).unwrap());
+        });
+
+        writeln!(out, "\n{}", self.boss_subscriptions_gauge_header).unwrap();
+        for_each_subscriptions_gauge(&mut |metric| writeln!(out, "{}", metric).unwrap());
+
+        writeln!(
+            out,
+            "\n{}\n{}",
+            self.build_info_gauge_header, self.build_info_gauge
+        )
+        .unwrap();
+    }
 }
 
 impl MetricFactory for PrometheusMetricFactory {
@@ -139,26 +480,73 @@ impl MetricFactory for PrometheusMetricFactory {
         &self.websocket_connections_gauge
     }
 
-    fn write_per_boss_metrics(&self, metrics: &PerBossMetrics<'_, Self::Metric>) -> Self::Output {
-        let mut out = String::new();
+    fn subscriber_lagged_counter(&self) -> &PrometheusMetric {
+        &self.subscriber_lagged_counter
+    }
 
-        writeln!(
-            &mut out,
-            "{}\n{}",
-            self.websocket_connections_gauge_header, self.websocket_connections_gauge
-        )
-        .unwrap();
+    fn image_hash_pending_gauge(&self) -> &PrometheusMetric {
+        &self.image_hash_pending_gauge
+    }
+
+    fn bosses_removed_counter(&self) -> &PrometheusMetric {
+        &self.bosses_removed_counter
+    }
 
-        writeln!(&mut out, "\n{}", self.boss_tweets_counter_header).unwrap();
-        for metric in &metrics.boss_tweets_counters {
-            metric.for_each(|m| writeln!(&mut out, "{}", m).unwrap());
+    fn bosses_by_language_gauge(&self) -> &BossLanguageMetric<PrometheusMetric> {
+        &self.bosses_by_language_gauge
+    }
+
+    fn record_graphql_request(&self, operation: Option<&str>, is_error: bool) {
+        let operation = operation.unwrap_or("anonymous");
+
+        let mut counters = self.graphql_requests_counters.lock();
+        let key =
+            if counters.contains_key(operation) || counters.len() < Self::MAX_GRAPHQL_OPERATIONS {
+                operation
+            } else {
+                "other"
+            };
+
+        counters
+            .entry(key.to_owned())
+            .or_insert_with(|| {
+                let key = format!(
+                    "{}_graphql_requests_total{{operation=\"{}\"}}",
+                    self.prefix,
+                    Label::new(key)
+                );
+                PrometheusMetric::new(key)
+            })
+            .inc();
+
+        if is_error {
+            self.graphql_errors_counter.inc();
         }
+    }
 
-        writeln!(&mut out, "\n{}", self.boss_subscriptions_gauge_header).unwrap();
-        for metric in &metrics.boss_subscriptions_gauges {
-            writeln!(&mut out, "{}", metric).unwrap();
+    fn record_image_hash(&self, is_success: bool, elapsed: Duration) {
+        if is_success {
+            self.image_hash_success_counter.inc();
+        } else {
+            self.image_hash_failure_counter.inc();
         }
 
+        self.image_hash_duration_histogram.observe(elapsed);
+    }
+
+    fn write_per_boss_metrics(&self, metrics: &PerBossMetrics<'_, Self::Metric>) -> Self::Output {
+        let mut out = String::new();
+        self.write_metrics(
+            &mut out,
+            |f| metrics.boss_tweets_counters.iter().copied().for_each(f),
+            |f| {
+                metrics
+                    .boss_subscriptions_gauges
+                    .iter()
+                    .copied()
+                    .for_each(f)
+            },
+        );
         out
     }
 }
@@ -219,6 +607,22 @@ mod test {
         gauge.set(100);
 
         factory.websocket_connections_gauge().set(10);
+        factory.subscriber_lagged_counter().add(3);
+        factory.image_hash_pending_gauge().set(2);
+        factory.record_image_hash(true, Duration::from_millis(80));
+        factory.record_image_hash(true, Duration::from_millis(400));
+        factory.record_image_hash(false, Duration::from_secs(30));
+        factory.bosses_removed_counter().add(4);
+        let names = [
+            name.clone(),
+            LangString::new(Language::English, "Lvl 100 Huanglong".into()),
+        ];
+        factory
+            .bosses_by_language_gauge()
+            .set_from(|f| names.iter().for_each(f));
+        factory.record_graphql_request(Some("Bosses"), false);
+        factory.record_graphql_request(Some("Bosses"), true);
+        factory.record_graphql_request(None, false);
 
         let metrics = PerBossMetrics {
             boss_tweets_counters: vec![&counter],
@@ -232,6 +636,52 @@ mod test {
             # TYPE petronel_websocket_connections gauge
             petronel_websocket_connections 10
 
+            # HELP petronel_subscriber_lagged_total Number of messages dropped due to lagging subscribers
+            # TYPE petronel_subscriber_lagged_total counter
+            petronel_subscriber_lagged_total 3
+
+            # HELP petronel_image_hash_pending Number of boss image hash requests in progress
+            # TYPE petronel_image_hash_pending gauge
+            petronel_image_hash_pending 2
+
+            # HELP petronel_image_hash_total Number of attempts to download and hash a boss image, by result
+            # TYPE petronel_image_hash_total counter
+            petronel_image_hash_total{result="success"} 2
+            petronel_image_hash_total{result="failure"} 1
+
+            # HELP petronel_image_hash_duration_seconds Time taken to download and hash a boss image
+            # TYPE petronel_image_hash_duration_seconds histogram
+            petronel_image_hash_duration_seconds_bucket{le="0.05"} 0
+            petronel_image_hash_duration_seconds_bucket{le="0.1"} 1
+            petronel_image_hash_duration_seconds_bucket{le="0.25"} 1
+            petronel_image_hash_duration_seconds_bucket{le="0.5"} 2
+            petronel_image_hash_duration_seconds_bucket{le="1"} 2
+            petronel_image_hash_duration_seconds_bucket{le="2.5"} 2
+            petronel_image_hash_duration_seconds_bucket{le="5"} 2
+            petronel_image_hash_duration_seconds_bucket{le="10"} 2
+            petronel_image_hash_duration_seconds_bucket{le="+Inf"} 3
+            petronel_image_hash_duration_seconds_sum 30.48
+            petronel_image_hash_duration_seconds_count 3
+
+            # HELP petronel_bosses_removed_total Number of bosses removed during cleanup tasks
+            # TYPE petronel_bosses_removed_total counter
+            petronel_bosses_removed_total 4
+
+            # HELP petronel_bosses_by_language Number of bosses with names in only Japanese, only English, or both
+            # TYPE petronel_bosses_by_language gauge
+            petronel_bosses_by_language{lang="ja"} 0
+            petronel_bosses_by_language{lang="en"} 1
+            petronel_bosses_by_language{lang="both"} 1
+
+            # HELP petronel_graphql_requests_total Number of GraphQL operations, by operation name
+            # TYPE petronel_graphql_requests_total counter
+            petronel_graphql_requests_total{operation="Bosses"} 2
+            petronel_graphql_requests_total{operation="anonymous"} 1
+
+            # HELP petronel_graphql_errors_total Number of GraphQL operations that returned errors
+            # TYPE petronel_graphql_errors_total counter
+            petronel_graphql_errors_total 1
+
             # HELP petronel_tweets_total Number of tweets seen for boss
             # TYPE petronel_tweets_total counter
             petronel_tweets_total{name_ja="Lv60 オオゾラッコ",name_en="Lvl 60 Ozorotter",lang="ja"} 35
@@ -240,8 +690,43 @@ mod test {
             # HELP petronel_subscriptions Number of active subscriptions for boss
             # TYPE petronel_subscriptions gauge
             petronel_subscriptions{name_ja="Lv60 オオゾラッコ",name_en="Lvl 60 Ozorotter"} 100
+
+            # HELP petronel_build_info Version and git commit of the running build, always 1
+            # TYPE petronel_build_info gauge
             "#
         );
+        let expected = format!(
+            "{}petronel_build_info{{version=\"{}\",commit=\"{}\"}} 1\n",
+            expected,
+            build_info::VERSION,
+            build_info::COMMIT
+        );
         assert_eq!(output, expected);
     }
+
+    #[test]
+    fn graphql_operation_cardinality() {
+        let factory = PrometheusMetricFactory::new("petronel".to_owned());
+
+        for i in 0..PrometheusMetricFactory::MAX_GRAPHQL_OPERATIONS + 5 {
+            factory.record_graphql_request(Some(&format!("Operation{}", i)), false);
+        }
+        // Already-tracked operations are still counted separately
+        factory.record_graphql_request(Some("Operation0"), false);
+
+        let counters = factory.graphql_requests_counters.lock();
+        // One counter per tracked operation, plus `other`
+        assert_eq!(
+            counters.len(),
+            PrometheusMetricFactory::MAX_GRAPHQL_OPERATIONS + 1
+        );
+        assert_eq!(counters["Operation0"].get(), 2);
+        assert_eq!(counters["other"].get(), 5);
+        drop(counters);
+
+        assert_eq!(
+            factory.graphql_requests_total(),
+            PrometheusMetricFactory::MAX_GRAPHQL_OPERATIONS + 6
+        );
+    }
 }
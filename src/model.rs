@@ -2,8 +2,10 @@ use chrono::offset::{TimeZone, Utc};
 use once_cell::sync::Lazy;
 use regex::Regex;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use smallvec::SmallVec;
 use std::borrow::Cow;
 use std::cmp::Ordering;
+use std::fmt;
 use std::str;
 use std::sync::atomic::AtomicI64;
 use std::sync::atomic::Ordering::Relaxed;
@@ -44,13 +46,19 @@ impl<'a> str::FromStr for NodeId<'a> {
 }
 
 impl<'a> NodeId<'a> {
-    pub fn from_boss_name(name: &'a LangString) -> Self {
+    /// Node ID for a boss. `preferred` is the canonical language, which breaks ties between
+    /// names of equal length.
+    pub fn from_boss_name(name: &'a LangString, preferred: Language) -> Self {
         // Use whichever boss name is smaller (in terms of bytes),
-        // since both names resolve to the same boss anyway
-        let shorter_name = Language::VALUES
-            .iter()
-            .flat_map(|lang| name.get(*lang))
-            .min_by_key(|n| n.len());
+        // since both names resolve to the same boss anyway.
+        //
+        // If both names are the same length, prefer the canonical language. The node ID needs to
+        // stay the same across restarts, or clients' cached IDs will break.
+        let shorter_name = match (name.get(preferred), name.get(preferred.other())) {
+            (Some(a), Some(b)) if b.len() < a.len() => Some(b),
+            (Some(a), _) => Some(a),
+            (None, b) => b,
+        };
 
         Self::Boss(match shorter_name {
             Some(name) => Cow::Borrowed(name),
@@ -59,39 +67,241 @@ impl<'a> NodeId<'a> {
     }
 }
 
-#[serde(rename_all = "camelCase")]
+#[serde(rename_all = "camelCase", from = "BossFields")]
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Boss {
     pub name: LangString,
-    pub image: LangString,
+    pub image: LangString<MediaImage>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub level: Option<Level>,
+    pub first_seen_at: AtomicDateTime,
     pub last_seen_at: AtomicDateTime,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub image_hash: Option<ImageHash>,
+    /// If this boss was merged from bosses in each language by image hash, the matched hash
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub merged_image_hash: Option<ImageHash>,
+    /// Whether this boss was merged from image-less bosses in each language, by level and name
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub merged_by_name: bool,
+    /// Looked up from `boss_elements.json` by name, so it isn't persisted
+    #[serde(skip)]
+    pub element: Option<Element>,
+}
+
+/// Boss element, for the bosses listed in `boss_elements.json`
+#[serde(rename_all = "lowercase")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum Element {
+    Fire,
+    Water,
+    Earth,
+    Wind,
+    Light,
+    Dark,
+}
+
+static BOSS_ELEMENTS: Lazy<Vec<(LangString, Element)>> = Lazy::new(|| {
+    #[derive(Deserialize)]
+    struct BossElement {
+        name: LangString,
+        element: Element,
+    }
+
+    serde_json::from_str::<Vec<BossElement>>(include_str!("boss_elements.json"))
+        .expect("invalid boss elements JSON")
+        .into_iter()
+        .map(|entry| (entry.name, entry.element))
+        .collect()
+});
+
+impl Element {
+    /// Looks up the element of a boss by name. A boss matches an entry if any of its names match.
+    pub fn for_boss_name(name: &LangString) -> Option<Element> {
+        BOSS_ELEMENTS
+            .iter()
+            .find(|(known, _)| {
+                Language::VALUES.iter().any(|lang| {
+                    let boss_name = name.get(*lang);
+                    boss_name.is_some() && boss_name == known.get(*lang)
+                })
+            })
+            .map(|(_, element)| *element)
+    }
+}
+
+/// How a boss's Japanese and English names were matched
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MergeSource {
+    /// Merged automatically, since bosses in each language had the same image hash
+    ImageHash(ImageHash),
+    /// Hardcoded in `known_bosses.json`
+    KnownBoss,
+    /// Merged by level and a similar name, since neither boss had an image
+    Name,
+}
+
+// Bosses persisted before `first_seen_at` was added don't have that field,
+// so it defaults to `last_seen_at`
+#[serde(rename_all = "camelCase")]
+#[derive(Deserialize)]
+struct BossFields {
+    name: LangString,
+    image: LangString<MediaImage>,
+    level: Option<Level>,
+    first_seen_at: Option<AtomicDateTime>,
+    last_seen_at: AtomicDateTime,
+    image_hash: Option<ImageHash>,
+    merged_image_hash: Option<ImageHash>,
+    #[serde(default)]
+    merged_by_name: bool,
 }
 
+impl From<BossFields> for Boss {
+    fn from(fields: BossFields) -> Self {
+        Self {
+            image: fields.image,
+            level: fields.level,
+            first_seen_at: fields
+                .first_seen_at
+                .unwrap_or_else(|| fields.last_seen_at.clone()),
+            last_seen_at: fields.last_seen_at,
+            image_hash: fields.image_hash,
+            merged_image_hash: fields.merged_image_hash,
+            merged_by_name: fields.merged_by_name,
+            element: Element::for_boss_name(&fields.name),
+            name: fields.name,
+        }
+    }
+}
+
+// Unfortunately, some bosses have to be hardcoded because the boss
+// image hashes are different between the English and Japanese versions.
+// https://github.com/walfie/gbf-raidfinder/blob/master/docs/implementation.md#automatic-translations
+static KNOWN_BOSSES: Lazy<Vec<Boss>> = Lazy::new(|| {
+    #[derive(Deserialize)]
+    struct KnownBoss {
+        name: LangString,
+        level: Option<Level>,
+    }
+
+    serde_json::from_str::<Vec<KnownBoss>>(include_str!("known_bosses.json"))
+        .expect("invalid known bosses JSON")
+        .into_iter()
+        .map(|known| Boss {
+            element: Element::for_boss_name(&known.name),
+            name: known.name,
+            image: LangString::default(),
+            level: known.level,
+            first_seen_at: AtomicDateTime::now(),
+            last_seen_at: AtomicDateTime::now(),
+            image_hash: None,
+            merged_image_hash: None,
+            merged_by_name: false,
+        })
+        .collect()
+});
+
 impl Boss {
-    // Unfortunately, this has to be hardcoded somewhere because the boss
-    // image hashes are different between the English and Japanese versions.
-    // https://github.com/walfie/gbf-raidfinder/blob/master/docs/implementation.md#automatic-translations
-    pub const LVL_120_MEDUSA: Lazy<Boss> = Lazy::new(|| Boss {
-        name: LangString {
-            ja: Some("Lv120 メドゥーサ".into()),
-            en: Some("Lvl 120 Medusa".into()),
-        },
-        image: LangString::default(),
-        level: Some(120),
-        last_seen_at: AtomicDateTime::now(),
-        image_hash: None,
-    });
+    /// Bosses whose names in each language can't be matched automatically, loaded from
+    /// `known_bosses.json`. These are always present, even if they haven't been seen yet.
+    pub fn known_bosses() -> &'static [Boss] {
+        &KNOWN_BOSSES
+    }
+
+    pub fn is_known_boss(&self) -> bool {
+        KNOWN_BOSSES.iter().any(|known| known.name == self.name)
+    }
+
+    /// Adds any known bosses that are missing from `bosses`
+    pub fn ensure_known_bosses(bosses: &mut Vec<Boss>) {
+        for known in KNOWN_BOSSES.iter() {
+            if !bosses.iter().any(|boss| boss.name == known.name) {
+                bosses.push(known.clone());
+            }
+        }
+    }
+
+    /// How this boss's names in each language were matched, if they were
+    pub fn merge_source(&self) -> Option<MergeSource> {
+        match self.merged_image_hash {
+            Some(hash) => Some(MergeSource::ImageHash(hash)),
+            None if self.is_known_boss() => Some(MergeSource::KnownBoss),
+            None if self.merged_by_name => Some(MergeSource::Name),
+            None => None,
+        }
+    }
 
     pub fn needs_image_hash_update(&self) -> bool {
         self.image_hash.is_none() && self.image.canonical().is_some()
     }
+
+    /// Regions this boss has been seen in, based on which names are known
+    pub fn regions(&self) -> impl Iterator<Item = Region> + '_ {
+        Language::VALUES
+            .iter()
+            .filter(move |lang| self.name.get(**lang).is_some())
+            .map(|lang| Region::from(*lang))
+    }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+/// Size variants for images hosted on Twitter's media server
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageSize {
+    Thumb,
+    Small,
+    Medium,
+    Large,
+}
+
+impl ImageSize {
+    const MEDIA_HOST: &'static str = "://pbs.twimg.com/media/";
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImageSize::Thumb => "thumb",
+            ImageSize::Small => "small",
+            ImageSize::Medium => "medium",
+            ImageSize::Large => "large",
+        }
+    }
+
+    /// Returns the URL for this size variant of a Twitter media image, replacing any existing
+    /// `:size` suffix. URLs not hosted on Twitter's media server are returned unchanged.
+    pub fn apply(&self, url: &str) -> String {
+        if !url.contains(Self::MEDIA_HOST) {
+            return url.to_owned();
+        }
+
+        let path_start = url.rfind('/').map_or(0, |i| i + 1);
+        let base = match url[path_start..].find(':') {
+            Some(i) => &url[..path_start + i],
+            None => url,
+        };
+
+        format!("{}:{}", base, self.as_str())
+    }
+}
+
+// Twitter image URLs are stored as paths under `prefix`, with a leading slash. URLs that aren't
+// under `prefix` are stored whole.
+fn url_to_prefixed_path<'a>(url: &'a str, prefix: &str) -> &'a str {
+    match url.get(prefix.len()..) {
+        Some(path) if url.starts_with(prefix) && path.starts_with('/') => path,
+        _ => url,
+    }
+}
+
+fn prefixed_path_to_url(path: &str, prefix: &str) -> String {
+    if path.starts_with('/') {
+        format!("{}{}", prefix, path)
+    } else {
+        path.to_owned()
+    }
+}
+
+#[serde(transparent)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct UserImage {
     path: String,
 }
@@ -101,7 +311,7 @@ impl UserImage {
 
     pub fn from_url(url: &str) -> Self {
         Self {
-            path: url.trim_start_matches(Self::PREFIX).to_owned(),
+            path: url_to_prefixed_path(url, Self::PREFIX).to_owned(),
         }
     }
 
@@ -110,74 +320,136 @@ impl UserImage {
     }
 
     pub fn as_url(&self) -> String {
-        if self.path.starts_with('/') {
-            format!("{}{}", Self::PREFIX, self.path)
+        prefixed_path_to_url(&self.path, Self::PREFIX)
+    }
+}
+
+/// An image hosted on Twitter's media server. Only the path is stored, to keep persisted and
+/// broadcast payloads small. URLs on other hosts are stored whole.
+#[serde(from = "CachedString", into = "CachedString")]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MediaImage {
+    path: CachedString,
+}
+
+impl MediaImage {
+    const PREFIX: &'static str = "https://pbs.twimg.com/media";
+
+    /// `http://` URLs are rewritten to `https://` first, so that the same image is always stored
+    /// with the same path
+    pub fn from_url(url: &str) -> Self {
+        const HTTP: &str = "http://";
+        let url = if url.starts_with(HTTP) {
+            Cow::Owned(format!("https://{}", &url[HTTP.len()..]))
         } else {
-            self.path.clone()
+            Cow::Borrowed(url)
+        };
+
+        Self {
+            path: url_to_prefixed_path(&url, Self::PREFIX).into(),
         }
     }
+
+    pub fn as_path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn as_url(&self) -> String {
+        prefixed_path_to_url(&self.path, Self::PREFIX)
+    }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+impl From<&str> for MediaImage {
+    fn from(url: &str) -> Self {
+        Self::from_url(url)
+    }
+}
+
+impl From<String> for MediaImage {
+    fn from(url: String) -> Self {
+        Self::from_url(&url)
+    }
+}
+
+impl From<CachedString> for MediaImage {
+    fn from(url: CachedString) -> Self {
+        Self::from_url(&url)
+    }
+}
+
+impl From<MediaImage> for CachedString {
+    fn from(image: MediaImage) -> Self {
+        image.path
+    }
+}
+
+#[serde(rename_all = "camelCase")]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Raid {
     pub id: RaidId,
     pub tweet_id: TweetId,
     pub user_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub user_image: Option<UserImage>,
     pub boss_name: BossName,
     pub created_at: DateTimeString,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub text: Option<String>,
     pub language: Language,
-    pub image_url: Option<CachedString>,
+    pub region: Region,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_url: Option<MediaImage>,
+    /// Any additional images attached to the tweet, after `image_url`
+    #[serde(default, skip_serializing_if = "SmallVec::is_empty")]
+    pub extra_image_urls: SmallVec<[MediaImage; 1]>,
 }
 
-// A premature optimization to avoid needing to stringify a `DateTime` multiple times
-#[derive(Debug, Clone)]
-pub struct DateTimeString {
-    string: String,
-    datetime: DateTime,
-}
+impl Raid {
+    const JOIN_URL_PREFIX: &'static str = "https://game.granbluefantasy.jp/#quest/assist/multi/0/";
 
-impl DateTimeString {
-    pub fn as_str(&self) -> &str {
-        &self.string
+    /// The in-game URL for joining this raid
+    pub fn join_url(&self) -> String {
+        format!("{}{}", Self::JOIN_URL_PREFIX, self.id)
     }
 
-    pub fn as_datetime(&self) -> &DateTime {
-        &self.datetime
+    /// The boss level, as parsed from the boss name
+    pub fn level(&self) -> Option<Level> {
+        parse_level(&self.boss_name)
     }
 }
 
-impl PartialEq for DateTimeString {
-    fn eq(&self, other: &Self) -> bool {
-        self.as_datetime() == other.as_datetime()
-    }
+/// A timestamp that's displayed in RFC 3339 format, e.g. `2020-05-20T01:02:03Z`.
+///
+/// Only the `DateTime` is stored, and the string is formatted on demand, since most raids in
+/// history are never displayed.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct DateTimeString {
+    datetime: DateTime,
 }
 
-impl Eq for DateTimeString {}
-
-impl PartialOrd for DateTimeString {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.as_datetime().partial_cmp(other.as_datetime())
+impl DateTimeString {
+    pub fn as_datetime(&self) -> &DateTime {
+        &self.datetime
     }
 }
 
-impl Ord for DateTimeString {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.as_datetime().cmp(other.as_datetime())
+impl From<DateTime> for DateTimeString {
+    fn from(datetime: DateTime) -> Self {
+        Self { datetime }
     }
 }
 
-impl From<DateTime> for DateTimeString {
-    fn from(value: DateTime) -> Self {
-        Self {
-            string: value.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
-            datetime: value,
-        }
+impl fmt::Display for DateTimeString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let formatted = self
+            .datetime
+            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        f.write_str(&formatted)
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Language {
     Japanese,
     English,
@@ -186,6 +458,13 @@ pub enum Language {
 impl Language {
     pub const VALUES: &'static [Language] = &[Self::Japanese, Self::English];
 
+    pub fn other(&self) -> Self {
+        match self {
+            Self::Japanese => Self::English,
+            Self::English => Self::Japanese,
+        }
+    }
+
     pub fn as_metric_label(&self) -> &'static str {
         match self {
             Self::Japanese => "ja",
@@ -194,38 +473,72 @@ impl Language {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
-pub struct LangString {
+/// The game region that a raid tweet was posted from.
+///
+/// Currently there is a single game with a language setting, so this always corresponds to the
+/// tweet language. It exists separately so that region-specific data (e.g., image hashes, which
+/// differ between the Japanese and English versions) can be handled in the future.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Region {
+    Jp,
+    En,
+}
+
+impl From<Language> for Region {
+    fn from(language: Language) -> Self {
+        match language {
+            Language::Japanese => Self::Jp,
+            Language::English => Self::En,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LangString<T = CachedString> {
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub en: Option<CachedString>,
+    pub en: Option<T>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub ja: Option<CachedString>,
+    pub ja: Option<T>,
+}
+
+// Derived `Default` would require `T: Default`
+impl<T> Default for LangString<T> {
+    fn default() -> Self {
+        Self::empty()
+    }
 }
 
-impl LangString {
+impl<T> LangString<T> {
     pub fn empty() -> Self {
         Self { en: None, ja: None }
     }
 
-    pub fn get(&self, lang: Language) -> Option<&CachedString> {
+    pub fn get(&self, lang: Language) -> Option<&T> {
         match lang {
             Language::English => self.en.as_ref(),
             Language::Japanese => self.ja.as_ref(),
         }
     }
 
-    pub fn canonical(&self) -> Option<&CachedString> {
-        self.ja.as_ref().or_else(|| self.en.as_ref())
+    /// The Japanese value, falling back to the English one if missing. Unlike `preferring`, this
+    /// doesn't depend on configuration, so it can be used for lookups and storage keys.
+    pub fn canonical(&self) -> Option<&T> {
+        self.preferring(Language::Japanese)
     }
 
-    pub fn set(&mut self, lang: Language, value: Option<CachedString>) {
+    /// The value for the `preferred` language, falling back to the other language if missing
+    pub fn preferring(&self, preferred: Language) -> Option<&T> {
+        self.get(preferred).or_else(|| self.get(preferred.other()))
+    }
+
+    pub fn set(&mut self, lang: Language, value: Option<T>) {
         match lang {
             Language::English => self.en = value,
             Language::Japanese => self.ja = value,
         }
     }
 
-    pub fn for_each(&self, mut f: impl FnMut(&BossName)) {
+    pub fn for_each(&self, mut f: impl FnMut(&T)) {
         for opt in &[self.ja.as_ref(), self.en.as_ref()] {
             if let Some(value) = opt {
                 f(value)
@@ -233,14 +546,24 @@ impl LangString {
         }
     }
 
-    pub fn merge(&self, other: &LangString) -> Self {
+    pub fn map<U>(&self, mut f: impl FnMut(&T) -> U) -> LangString<U> {
+        LangString {
+            en: self.en.as_ref().map(&mut f),
+            ja: self.ja.as_ref().map(&mut f),
+        }
+    }
+
+    pub fn merge(&self, other: &Self) -> Self
+    where
+        T: Clone,
+    {
         Self {
             en: self.en.as_ref().or(other.en.as_ref()).cloned(),
             ja: self.ja.as_ref().or(other.ja.as_ref()).cloned(),
         }
     }
 
-    pub fn new(lang: Language, value: CachedString) -> Self {
+    pub fn new(lang: Language, value: T) -> Self {
         match lang {
             Language::English => Self {
                 en: Some(value),
@@ -254,6 +577,11 @@ impl LangString {
     }
 }
 
+/// A `DateTime` that can be updated atomically.
+///
+/// The value is stored as milliseconds since the Unix epoch, so any sub-millisecond precision is
+/// truncated (this is also the format used when serializing). Use `truncate_to_millis` to get the
+/// value that would be returned by `as_datetime` after a round trip.
 #[derive(Debug)]
 pub struct AtomicDateTime(AtomicI64);
 impl AtomicDateTime {
@@ -261,6 +589,10 @@ impl AtomicDateTime {
         Self::from(&Utc::now())
     }
 
+    pub fn truncate_to_millis(value: &DateTime) -> DateTime {
+        Utc.timestamp_millis(value.timestamp_millis())
+    }
+
     pub fn replace(&self, value: &DateTime) {
         self.0.store(value.timestamp_millis(), Relaxed)
     }
@@ -336,16 +668,47 @@ impl Serialize for AtomicDateTime {
 static REGEX_LEVEL: Lazy<Regex> =
     Lazy::new(|| Regex::new("^Lv(?:l )?(?P<level>[0-9]+) ").expect("invalid level regex"));
 
+// Levels too large to fit in a `Level` are treated as unknown
 fn parse_level(name: &str) -> Option<Level> {
     REGEX_LEVEL
         .captures(name)
         .and_then(|c| c.name("level"))
-        .map(|level| {
-            level
-                .as_str()
-                .parse()
-                .expect("Somehow failed to parse level")
-        })
+        .and_then(|level| level.as_str().parse().ok())
+}
+
+/// Whether two boss names are probably for the same boss, e.g., `Lv100 Huanglong` and
+/// `Lvl 100 Huanglong`. Level prefixes, case, and punctuation are ignored, and the rest of the
+/// names can differ by up to 20% of their length.
+pub fn is_similar_boss_name(a: &str, b: &str) -> bool {
+    let (a, b) = (normalize_boss_name(a), normalize_boss_name(b));
+    let max_len = a.len().max(b.len());
+    max_len > 0 && edit_distance(&a, &b) * 5 <= max_len
+}
+
+fn normalize_boss_name(name: &str) -> Vec<char> {
+    REGEX_LEVEL
+        .replace(name, "")
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+// Levenshtein distance
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    let mut curr = vec![0; b.len() + 1];
+
+    for (i, char_a) in a.iter().enumerate() {
+        curr[0] = i + 1;
+        for (j, char_b) in b.iter().enumerate() {
+            let substitution = prev[j] + if char_a == char_b { 0 } else { 1 };
+            curr[j + 1] = substitution.min(prev[j + 1] + 1).min(curr[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut curr);
+    }
+
+    prev[b.len()]
 }
 
 impl From<&Raid> for Boss {
@@ -356,12 +719,17 @@ impl From<&Raid> for Boss {
             None => Default::default(),
             Some(ref url) => LangString::new(lang, url.clone()),
         };
+        let name = LangString::new(lang, raid.boss_name.clone());
 
         Self {
             image,
             image_hash: None,
-            level: parse_level(&raid.boss_name),
-            name: LangString::new(lang, raid.boss_name.clone()),
+            merged_image_hash: None,
+            merged_by_name: false,
+            level: raid.level(),
+            element: Element::for_boss_name(&name),
+            name,
+            first_seen_at: raid.created_at.as_datetime().into(),
             last_seen_at: raid.created_at.as_datetime().into(),
         }
     }
@@ -375,6 +743,189 @@ mod test {
     fn parse_level() {
         assert_eq!(super::parse_level("Lv75 セレスト・マグナ").unwrap(), 75);
         assert_eq!(super::parse_level("Lvl 75 Celeste Omega").unwrap(), 75);
+        assert_eq!(super::parse_level("Lv999999999999999999 Foo"), None);
+        assert_eq!(super::parse_level("Lvl 2147483648 Foo"), None);
+        assert_eq!(super::parse_level("Lvl 2147483647 Foo"), Some(2147483647));
+    }
+
+    #[test]
+    fn similar_boss_names() {
+        assert!(is_similar_boss_name("Lv100 Huanglong", "Lvl 100 Huanglong"));
+        assert!(is_similar_boss_name(
+            "Lv150 Proto-Bahamut",
+            "Lvl 150 Proto Bahamut"
+        ));
+        assert!(is_similar_boss_name(
+            "Lv120 Shiva (Impossible)",
+            "Lvl 120 Shiva Impossible!"
+        ));
+        assert!(!is_similar_boss_name(
+            "Lv60 オオゾラッコ",
+            "Lvl 60 Ozorotter"
+        ));
+        assert!(!is_similar_boss_name("Lv100 Huanglong", "Lvl 100 Qilin"));
+        assert!(!is_similar_boss_name("Lv100 ", "Lvl 100 "));
+
+        assert_eq!(edit_distance(&['a', 'b', 'c'], &['a', 'c']), 1);
+        assert_eq!(edit_distance(&['k', 'i', 't'], &['s', 'i', 't', 's']), 2);
+    }
+
+    #[test]
+    fn node_id_from_boss_name() {
+        let name = |ja: Option<&str>, en: Option<&str>| LangString {
+            ja: ja.map(Into::into),
+            en: en.map(Into::into),
+        };
+        let node_id =
+            |name: &LangString| NodeId::from_boss_name(name, Language::Japanese).to_string();
+        let boss_node_id = |n: &str| NodeId::Boss(Cow::Owned(n.into())).to_string();
+
+        // Equal lengths prefer the Japanese name, regardless of field order
+        let equal = name(Some("Lv60 ABCDEFGH"), Some("Lvl 60 ABCDEF"));
+        assert_eq!(node_id(&equal), boss_node_id("Lv60 ABCDEFGH"));
+
+        let shorter_en = name(Some("Lv60 オオゾラッコ"), Some("Lvl 60 Ozorotter"));
+        assert_eq!(node_id(&shorter_en), boss_node_id("Lvl 60 Ozorotter"));
+
+        assert_eq!(
+            node_id(&name(None, Some("Lvl 60 Ozorotter"))),
+            boss_node_id("Lvl 60 Ozorotter")
+        );
+        assert_eq!(
+            node_id(&name(Some("Lv60 オオゾラッコ"), None)),
+            boss_node_id("Lv60 オオゾラッコ")
+        );
+
+        // If English is preferred, equal lengths prefer the English name instead
+        let node_id_en =
+            |name: &LangString| NodeId::from_boss_name(name, Language::English).to_string();
+        assert_eq!(node_id_en(&equal), boss_node_id("Lvl 60 ABCDEF"));
+        assert_eq!(node_id_en(&shorter_en), boss_node_id("Lvl 60 Ozorotter"));
+    }
+
+    #[test]
+    fn canonical_preference() {
+        let both = LangString {
+            ja: Some("Lv60 オオゾラッコ".into()),
+            en: Some("Lvl 60 Ozorotter".into()),
+        };
+        let ja_only = LangString::new(Language::Japanese, "Lv60 オオゾラッコ".into());
+        let en_only = LangString::new(Language::English, "Lvl 60 Ozorotter".into());
+
+        let ja = |s: &LangString| s.preferring(Language::Japanese).cloned();
+        assert_eq!(ja(&both), Some("Lv60 オオゾラッコ".into()));
+        assert_eq!(ja(&en_only), Some("Lvl 60 Ozorotter".into()));
+
+        let en = |s: &LangString| s.preferring(Language::English).cloned();
+        assert_eq!(en(&both), Some("Lvl 60 Ozorotter".into()));
+        assert_eq!(en(&ja_only), Some("Lv60 オオゾラッコ".into()));
+
+        assert_eq!(
+            LangString::<CachedString>::default().preferring(Language::English),
+            None
+        );
+    }
+
+    #[test]
+    fn join_url() {
+        let raid = Raid {
+            id: "ABCD1234".into(),
+            tweet_id: 1,
+            user_name: "walfieee".into(),
+            user_image: None,
+            boss_name: "Lv60 オオゾラッコ".into(),
+            created_at: Utc::now().into(),
+            text: None,
+            language: Language::Japanese,
+            region: Region::Jp,
+            image_url: None,
+            extra_image_urls: Default::default(),
+        };
+
+        assert_eq!(
+            raid.join_url(),
+            "https://game.granbluefantasy.jp/#quest/assist/multi/0/ABCD1234"
+        );
+    }
+
+    #[test]
+    fn date_time_string() {
+        let datetime = Utc.ymd(2020, 5, 20).and_hms_milli(1, 2, 3, 456);
+        let string = DateTimeString::from(datetime);
+
+        // Same format as when the string was stored eagerly
+        assert_eq!(string.to_string(), "2020-05-20T01:02:03Z");
+        assert_eq!(
+            string.to_string(),
+            datetime.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+        );
+
+        // No heap-allocated string is kept alongside the datetime
+        assert_eq!(
+            std::mem::size_of::<DateTimeString>(),
+            std::mem::size_of::<DateTime>()
+        );
+    }
+
+    #[test]
+    fn media_image() {
+        let url = "https://pbs.twimg.com/media/CVL2EBHUwAA8nUj.jpg";
+        let image = MediaImage::from_url(url);
+        assert_eq!(image.as_path(), "/CVL2EBHUwAA8nUj.jpg");
+        assert_eq!(image.as_url(), url);
+
+        // Only the path is serialized
+        let json = serde_json::to_string(&image).unwrap();
+        assert_eq!(json, r#""/CVL2EBHUwAA8nUj.jpg""#);
+        assert_eq!(serde_json::from_str::<MediaImage>(&json).unwrap(), image);
+
+        // Full URLs (e.g., persisted before paths were stored) are also accepted
+        let json = format!("\"{}\"", url);
+        assert_eq!(serde_json::from_str::<MediaImage>(&json).unwrap(), image);
+
+        // `http://` URLs are normalized to `https://`
+        assert_eq!(
+            MediaImage::from_url("http://pbs.twimg.com/media/CVL2EBHUwAA8nUj.jpg"),
+            image
+        );
+    }
+
+    #[test]
+    fn media_image_other_host() {
+        let url = "https://example.com/media/image.png";
+        let image = MediaImage::from_url(url);
+        assert_eq!(image.as_path(), url);
+        assert_eq!(image.as_url(), url);
+
+        let json = serde_json::to_string(&image).unwrap();
+        assert_eq!(serde_json::from_str::<MediaImage>(&json).unwrap(), image);
+
+        // URLs that only share a prefix with the media path are also stored whole
+        let url = "https://pbs.twimg.com/mediaX/CVL2EBHUwAA8nUj.jpg";
+        let image = MediaImage::from_url(url);
+        assert_eq!(image.as_path(), url);
+        assert_eq!(image.as_url(), url);
+    }
+
+    #[test]
+    fn image_size() {
+        let url = "https://pbs.twimg.com/media/CVL2EBHUwAA8nUj.jpg";
+        assert_eq!(
+            ImageSize::Large.apply(url),
+            "https://pbs.twimg.com/media/CVL2EBHUwAA8nUj.jpg:large"
+        );
+        assert_eq!(
+            ImageSize::Thumb.apply("https://pbs.twimg.com/media/CVL2EBHUwAA8nUj.jpg:large"),
+            "https://pbs.twimg.com/media/CVL2EBHUwAA8nUj.jpg:thumb"
+        );
+        assert_eq!(
+            ImageSize::Small.apply("http://pbs.twimg.com/media/CVL2EBHUwAA8nUj.jpg:orig"),
+            "http://pbs.twimg.com/media/CVL2EBHUwAA8nUj.jpg:small"
+        );
+
+        // Non-Twitter URLs are left alone
+        let other = "http://example.com:8080/image.png";
+        assert_eq!(ImageSize::Medium.apply(other), other);
     }
 
     #[test]
@@ -406,11 +957,162 @@ mod test {
                 ja: Some("http://example.com/image_ja.png".into()),
             },
             level: Some(60),
+            first_seen_at: AtomicDateTime::from(1234),
             last_seen_at: AtomicDateTime::from(1234),
             image_hash: Some(ImageHash::from(6789)),
+            merged_image_hash: None,
+            merged_by_name: false,
+            element: Some(Element::Water),
         };
 
+        // `firstSeenAt` defaults to `lastSeenAt` if missing
         assert_eq!(json, boss);
+
+        let mut boss = boss;
+        boss.first_seen_at = AtomicDateTime::from(1000);
+        let round_trip =
+            serde_json::from_str::<Boss>(&serde_json::to_string(&boss).unwrap()).unwrap();
+        assert_eq!(round_trip, boss);
+    }
+
+    #[test]
+    fn raid_serde() {
+        let raid = Raid {
+            id: "ABCD1234".into(),
+            tweet_id: 1234,
+            user_name: "walfieee".into(),
+            user_image: Some(UserImage::from_url(
+                "https://pbs.twimg.com/profile_images/1234/image_normal.jpg",
+            )),
+            boss_name: "Lv60 オオゾラッコ".into(),
+            created_at: Utc.ymd(2020, 5, 20).and_hms(1, 2, 3).into(),
+            text: Some("Help".into()),
+            language: Language::Japanese,
+            region: Region::Jp,
+            image_url: Some("https://pbs.twimg.com/media/image.jpg".into()),
+            extra_image_urls: vec!["https://pbs.twimg.com/media/extra.jpg".into()].into(),
+        };
+
+        let json = serde_json::to_string(&raid).unwrap();
+        let round_trip = serde_json::from_str::<Raid>(&json).unwrap();
+        assert_eq!(round_trip, raid);
+        assert_eq!(round_trip.created_at.to_string(), "2020-05-20T01:02:03Z");
+        assert_eq!(
+            round_trip.user_image.unwrap().as_url(),
+            "https://pbs.twimg.com/profile_images/1234/image_normal.jpg"
+        );
+
+        // Optional fields are omitted when empty
+        let raid = Raid {
+            user_image: None,
+            text: None,
+            image_url: None,
+            extra_image_urls: SmallVec::new(),
+            language: Language::English,
+            region: Region::En,
+            ..raid
+        };
+
+        let json = serde_json::to_value(&raid).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "id": "ABCD1234",
+                "tweetId": 1234,
+                "userName": "walfieee",
+                "bossName": "Lv60 オオゾラッコ",
+                "createdAt": "2020-05-20T01:02:03Z",
+                "language": "English",
+                "region": "En",
+            })
+        );
+        assert_eq!(serde_json::from_value::<Raid>(json).unwrap(), raid);
+    }
+
+    #[test]
+    fn atomic_datetime_truncation() {
+        // Millisecond precision survives a round trip
+        let millis = Utc.timestamp_millis(1_590_000_000_123);
+        assert_eq!(AtomicDateTime::from(&millis).as_datetime(), millis);
+        assert_eq!(AtomicDateTime::truncate_to_millis(&millis), millis);
+
+        // Anything more precise than that is truncated
+        let nanos = Utc.timestamp(1_590_000_000, 123_999_999);
+        assert_eq!(AtomicDateTime::from(&nanos).as_datetime(), millis);
+        assert_eq!(AtomicDateTime::truncate_to_millis(&nanos), millis);
+
+        // Values before the epoch are truncated towards negative infinity
+        let before_epoch = Utc.timestamp(-1, 999_999_999);
+        assert_eq!(
+            AtomicDateTime::from(&before_epoch).as_datetime(),
+            Utc.timestamp_millis(-1)
+        );
+    }
+
+    #[test]
+    fn known_bosses() {
+        let medusa = LangString {
+            ja: Some("Lv120 メドゥーサ".into()),
+            en: Some("Lvl 120 Medusa".into()),
+        };
+        assert!(Boss::known_bosses()
+            .iter()
+            .any(|boss| boss.name == medusa && boss.level == Some(120)));
+
+        let mut bosses = Vec::new();
+        Boss::ensure_known_bosses(&mut bosses);
+        assert_eq!(bosses.len(), Boss::known_bosses().len());
+
+        // Existing bosses are kept, and known bosses aren't duplicated
+        let mut existing = Boss::known_bosses()[0].clone();
+        existing.image_hash = Some(ImageHash::from(1234));
+        let mut bosses = vec![existing.clone()];
+        Boss::ensure_known_bosses(&mut bosses);
+        assert_eq!(bosses.len(), Boss::known_bosses().len());
+        assert_eq!(bosses[0], existing);
+        for known in Boss::known_bosses() {
+            assert!(bosses.iter().any(|boss| boss.name == known.name));
+            assert!(known.is_known_boss());
+        }
+    }
+
+    #[test]
+    fn boss_element() {
+        let element =
+            |lang, name: &str| Element::for_boss_name(&LangString::new(lang, name.into()));
+        assert_eq!(
+            element(Language::Japanese, "Lv60 オオゾラッコ"),
+            Some(Element::Water)
+        );
+        assert_eq!(
+            element(Language::English, "Lvl 120 Medusa"),
+            Some(Element::Earth)
+        );
+        assert_eq!(
+            element(Language::English, "Lvl 100 Colossus Omega"),
+            Some(Element::Fire)
+        );
+        assert_eq!(element(Language::English, "Lvl 100 Unknown"), None);
+        assert_eq!(Element::for_boss_name(&LangString::empty()), None);
+
+        // Populated when creating bosses
+        let medusa = Boss::known_bosses()
+            .iter()
+            .find(|boss| boss.level == Some(120))
+            .unwrap();
+        assert_eq!(medusa.element, Some(Element::Earth));
+    }
+
+    #[test]
+    fn boss_regions() {
+        let mut boss = Boss::known_bosses()[0].clone();
+        assert_eq!(
+            boss.regions().collect::<Vec<_>>(),
+            vec![Region::Jp, Region::En]
+        );
+
+        boss.name.ja = None;
+        assert_eq!(boss.regions().collect::<Vec<_>>(), vec![Region::En]);
     }
 
     #[test]
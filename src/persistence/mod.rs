@@ -2,15 +2,44 @@ use crate::error::Error;
 use crate::model::Boss;
 
 use async_trait::async_trait;
+use futures::future::BoxFuture;
+use parking_lot::Mutex;
 use redis::aio::ConnectionManager;
 use redis::AsyncCommands;
+use serde::ser::{SerializeSeq, Serializer};
+use std::collections::HashMap;
+use std::sync::Arc;
 
 #[async_trait]
 pub trait Persistence {
     type Error;
 
     async fn get_bosses(&self) -> Result<Vec<Boss>, Self::Error>;
-    async fn save_bosses(&self, bosses: &[&Boss]) -> Result<(), Self::Error>;
+
+    /// Saves the bosses that `for_each_boss` passes to its callback. Bosses are serialized before
+    /// this returns, so the caller doesn't need to keep them borrowed while they're written.
+    fn save_bosses(
+        &self,
+        for_each_boss: impl FnOnce(&mut dyn FnMut(&Boss)),
+    ) -> BoxFuture<'static, Result<(), Self::Error>>;
+}
+
+// Serializes bosses as a JSON array, without collecting them first
+fn to_json_array(for_each_boss: impl FnOnce(&mut dyn FnMut(&Boss))) -> serde_json::Result<Vec<u8>> {
+    let mut json = Vec::new();
+    let mut serializer = serde_json::Serializer::new(&mut json);
+    let mut seq = serializer.serialize_seq(None)?;
+
+    let mut result = Ok(());
+    for_each_boss(&mut |boss| {
+        if result.is_ok() {
+            result = seq.serialize_element(boss);
+        }
+    });
+    result?;
+
+    seq.end()?;
+    Ok(json)
 }
 
 #[derive(Debug, Clone)]
@@ -37,25 +66,99 @@ impl Persistence for JsonFile {
         Ok(serde_json::from_slice(&contents)?)
     }
 
-    async fn save_bosses(&self, bosses: &[&Boss]) -> Result<(), Self::Error> {
-        let json = serde_json::to_string(bosses)?;
-        Ok(tokio::fs::write(&self.path, &json).await?)
+    fn save_bosses(
+        &self,
+        for_each_boss: impl FnOnce(&mut dyn FnMut(&Boss)),
+    ) -> BoxFuture<'static, Result<(), Self::Error>> {
+        let json = to_json_array(for_each_boss);
+        let path = self.path.clone();
+        Box::pin(async move {
+            tokio::fs::write(&path, &json?).await?;
+            Ok::<_, Error>(())
+        })
+    }
+}
+
+/// How boss data is laid out in Redis
+#[derive(Debug, Clone, PartialEq)]
+pub enum RedisLayout {
+    /// All bosses are stored as a single JSON array under one key
+    SingleKey(String),
+    /// Each boss is stored as JSON under its own key, `<prefix>:boss:<canonical name>`, so a
+    /// boss that fails to save doesn't affect the others
+    KeyPerBoss(String),
+}
+
+impl RedisLayout {
+    fn boss_key(prefix: &str, boss: &Boss) -> Option<String> {
+        boss.name
+            .canonical()
+            .map(|name| format!("{}:boss:{}", prefix, name))
+    }
+
+    // `SCAN` pattern matching all boss keys, with glob characters in the prefix escaped
+    fn boss_key_pattern(prefix: &str) -> String {
+        let mut pattern = String::with_capacity(prefix.len() + 7);
+        for c in prefix.chars() {
+            if let '*' | '?' | '[' | ']' | '\\' = c {
+                pattern.push('\\');
+            }
+            pattern.push(c);
+        }
+        pattern.push_str(":boss:*");
+        pattern
     }
 }
 
 #[derive(Clone)]
 pub struct Redis {
-    key: String,
+    layout: RedisLayout,
     manager: ConnectionManager,
+    // For `RedisLayout::KeyPerBoss`, the JSON last loaded or saved for each boss key, so that
+    // only changed bosses are written. `None` until the existing keys are known.
+    saved: Arc<Mutex<Option<HashMap<String, String>>>>,
 }
 
 impl Redis {
-    pub async fn new<T>(uri: T, key: String) -> redis::RedisResult<Self>
+    pub async fn new<T>(uri: T, layout: RedisLayout) -> redis::RedisResult<Self>
     where
         T: redis::IntoConnectionInfo,
     {
         let manager = ConnectionManager::new(uri.into_connection_info()?).await?;
-        Ok(Self { manager, key })
+        Ok(Self {
+            manager,
+            layout,
+            saved: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    async fn scan_boss_keys(&self, prefix: &str) -> redis::RedisResult<Vec<String>> {
+        let pattern = RedisLayout::boss_key_pattern(prefix);
+        let mut conn = self.manager.clone();
+        let mut keys = Vec::new();
+        let mut cursor = 0u64;
+
+        loop {
+            let (next, mut batch): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("COUNT")
+                .arg(100)
+                .query_async(&mut conn)
+                .await?;
+            keys.append(&mut batch);
+
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+
+        // `SCAN` may return the same key more than once
+        keys.sort_unstable();
+        keys.dedup();
+        Ok(keys)
     }
 }
 
@@ -64,15 +167,183 @@ impl Persistence for Redis {
     type Error = Error;
 
     async fn get_bosses(&self) -> Result<Vec<Boss>, Self::Error> {
-        let value: Option<Vec<u8>> = self.manager.clone().get(&self.key).await?;
-        match value {
-            None => Ok(Vec::new()),
-            Some(contents) => Ok(serde_json::from_slice(&contents)?),
+        match &self.layout {
+            RedisLayout::SingleKey(key) => {
+                let value: Option<Vec<u8>> = self.manager.clone().get(key).await?;
+                match value {
+                    None => Ok(Vec::new()),
+                    Some(contents) => Ok(serde_json::from_slice(&contents)?),
+                }
+            }
+            RedisLayout::KeyPerBoss(prefix) => {
+                let keys = self.scan_boss_keys(prefix).await?;
+                if keys.is_empty() {
+                    *self.saved.lock() = Some(HashMap::new());
+                    return Ok(Vec::new());
+                }
+
+                let values: Vec<Option<Vec<u8>>> = redis::cmd("MGET")
+                    .arg(&keys)
+                    .query_async(&mut self.manager.clone())
+                    .await?;
+
+                // Keys may have been deleted between `SCAN` and `MGET`. Bosses that fail to parse
+                // are skipped, rather than failing the whole load, and are removed on the next save.
+                let mut saved = HashMap::with_capacity(keys.len());
+                let mut bosses = Vec::with_capacity(keys.len());
+                for (key, contents) in keys.into_iter().zip(values) {
+                    if let Some(contents) = contents {
+                        if let Ok(boss) = serde_json::from_slice::<Boss>(&contents) {
+                            bosses.push(boss);
+                        }
+                        saved.insert(key, String::from_utf8_lossy(&contents).into_owned());
+                    }
+                }
+
+                *self.saved.lock() = Some(saved);
+                Ok(bosses)
+            }
         }
     }
 
-    async fn save_bosses(&self, bosses: &[&Boss]) -> Result<(), Self::Error> {
-        let json = serde_json::to_string(bosses)?;
-        Ok(self.manager.clone().set(&self.key, json).await?)
+    fn save_bosses(
+        &self,
+        for_each_boss: impl FnOnce(&mut dyn FnMut(&Boss)),
+    ) -> BoxFuture<'static, Result<(), Self::Error>> {
+        let this = self.clone();
+        match &self.layout {
+            RedisLayout::SingleKey(key) => {
+                let json = to_json_array(for_each_boss);
+                let key = key.clone();
+                Box::pin(async move {
+                    let () = this.manager.clone().set(key, json?).await?;
+                    Ok::<_, Error>(())
+                })
+            }
+            RedisLayout::KeyPerBoss(prefix) => {
+                let mut current = HashMap::new();
+                let mut result = Ok(());
+                for_each_boss(&mut |boss| {
+                    if result.is_err() {
+                        return;
+                    }
+                    if let Some(key) = RedisLayout::boss_key(prefix, boss) {
+                        match serde_json::to_string(boss) {
+                            Ok(json) => {
+                                current.insert(key, json);
+                            }
+                            Err(e) => result = Err(e),
+                        }
+                    }
+                });
+                let prefix = prefix.clone();
+
+                Box::pin(async move {
+                    result?;
+
+                    let previous = this.saved.lock().clone();
+                    let previous = match previous {
+                        Some(previous) => previous,
+                        // If nothing was loaded yet, existing keys are found once with `SCAN`.
+                        // Their contents are unknown, so any bosses that still exist are rewritten.
+                        None => this
+                            .scan_boss_keys(&prefix)
+                            .await?
+                            .into_iter()
+                            .map(|key| (key, String::new()))
+                            .collect(),
+                    };
+
+                    let mut pipe = redis::pipe();
+                    let mut changed = false;
+                    for (key, json) in &current {
+                        if previous.get(key) != Some(json) {
+                            pipe.set(key, json).ignore();
+                            changed = true;
+                        }
+                    }
+
+                    // Remove bosses that no longer exist (e.g., removed during cleanup)
+                    let stale_keys = previous
+                        .keys()
+                        .filter(|key| !current.contains_key(*key))
+                        .collect::<Vec<_>>();
+                    if !stale_keys.is_empty() {
+                        pipe.del(stale_keys).ignore();
+                        changed = true;
+                    }
+
+                    if changed {
+                        let () = pipe.query_async(&mut this.manager.clone()).await?;
+                    }
+
+                    *this.saved.lock() = Some(current);
+                    Ok::<_, Error>(())
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{LangString, Language};
+
+    fn boss(name: &str) -> Boss {
+        Boss {
+            name: LangString::new(Language::Japanese, name.into()),
+            element: None,
+            ..Boss::known_bosses()[0].clone()
+        }
+    }
+
+    #[test]
+    fn redis_boss_keys() {
+        assert_eq!(
+            RedisLayout::boss_key("petronel", &boss("Lv60 オオゾラッコ")),
+            Some("petronel:boss:Lv60 オオゾラッコ".to_owned())
+        );
+        assert_eq!(
+            RedisLayout::boss_key_pattern("petronel[1]*"),
+            r"petronel\[1\]\*:boss:*"
+        );
+    }
+
+    // Requires a Redis server, and is enabled with `--features integration`.
+    // The server can be specified with the `REDIS_URI` environment variable.
+    #[cfg_attr(not(feature = "integration"), ignore)]
+    #[tokio::test]
+    async fn redis_key_per_boss() -> anyhow::Result<()> {
+        let uri = std::env::var("REDIS_URI").unwrap_or_else(|_| "redis://127.0.0.1".to_owned());
+        let prefix = format!("petronel-test:{}", std::process::id());
+        let redis = Redis::new(uri.as_str(), RedisLayout::KeyPerBoss(prefix.clone())).await?;
+
+        let bosses = vec![boss("Boss1"), boss("Boss2")];
+        redis.save_bosses(|f| bosses.iter().for_each(f)).await?;
+
+        let mut loaded = redis.get_bosses().await?;
+        loaded.sort_by(|a, b| a.name.ja.as_deref().cmp(&b.name.ja.as_deref()));
+        assert_eq!(loaded, bosses);
+
+        // Unchanged bosses aren't rewritten
+        let key = RedisLayout::boss_key(&prefix, &bosses[0]).unwrap();
+        let mut conn = redis.manager.clone();
+        let () = conn.set(&key, "not a boss").await?;
+        redis.save_bosses(|f| bosses.iter().for_each(f)).await?;
+        let value: String = conn.get(&key).await?;
+        assert_eq!(value, "not a boss");
+
+        // Malformed bosses are skipped when loading
+        assert_eq!(redis.get_bosses().await?, vec![bosses[1].clone()]);
+
+        // Bosses missing from the next save are deleted
+        redis.save_bosses(|f| f(&bosses[1])).await?;
+        assert_eq!(redis.get_bosses().await?, vec![bosses[1].clone()]);
+        assert_eq!(redis.scan_boss_keys(&prefix).await?.len(), 1);
+
+        redis.save_bosses(|_| {}).await?;
+        assert!(redis.scan_boss_keys(&prefix).await?.is_empty());
+        Ok(())
     }
 }
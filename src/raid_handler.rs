@@ -1,26 +1,53 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::ops::Deref;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering as AtomicOrdering};
 use std::sync::{Arc, Weak};
 use std::task::{Context, Poll};
+use std::time::Instant;
 
+use crate::error::{Error, Result};
 use crate::metrics::{
-    LangMetric, Metric, MetricFactory, PerBossMetrics, PrometheusMetric, PrometheusMetricFactory,
+    JsonBossMetrics, JsonLangCount, JsonMetrics, LangMetric, Metric, MetricFactory,
+    PrometheusMetric, PrometheusMetricFactory,
+};
+use crate::model::{
+    is_similar_boss_name, AtomicDateTime, Boss, BossName, CachedString, DateTime, Element,
+    ImageHash, LangString, Language, Level, MergeSource, NodeId, Raid, TweetId,
 };
-use crate::model::{Boss, BossName, CachedString, ImageHash, NodeId, Raid};
 
 use arc_swap::ArcSwap;
 use circular_queue::CircularQueue;
 use dashmap::{DashMap, ElementGuard};
 use futures::stream::Stream;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, MutexGuard, RwLock};
+use serde::{Deserialize, Serialize};
 use tokio::stream::StreamExt;
 use tokio::sync::broadcast;
 
 #[derive(Clone, Debug)]
 pub struct RaidHandler(Arc<RaidHandlerInner>);
 
+/// An item in a boss's raid stream
+#[derive(Clone, Debug, PartialEq)]
+pub enum RaidUpdate {
+    Raid(Arc<Raid>),
+    /// The subscriber was too slow, and missed this many raids
+    Lagged(u64),
+}
+
+impl RaidUpdate {
+    pub fn into_raid(self) -> Option<Arc<Raid>> {
+        match self {
+            RaidUpdate::Raid(raid) => Some(raid),
+            RaidUpdate::Lagged(_) => None,
+        }
+    }
+}
+
 pin_project_lite::pin_project! {
-    pub struct Subscription {
+    pub struct UpdateSubscription {
         #[pin]
         rx: broadcast::Receiver<Arc<Raid>>,
         boss_name: BossName,
@@ -28,16 +55,23 @@ pin_project_lite::pin_project! {
     }
 }
 
-impl Stream for Subscription {
-    type Item = Arc<Raid>;
+impl Stream for UpdateSubscription {
+    type Item = RaidUpdate;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
         let mut this = self.project();
 
         loop {
             match futures::ready!(this.rx.as_mut().poll_next(cx)) {
-                Some(Ok(item)) => return Poll::Ready(Some(item)),
-                Some(Err(broadcast::RecvError::Lagged(_))) => continue,
+                Some(Ok(item)) => return Poll::Ready(Some(RaidUpdate::Raid(item))),
+                Some(Err(broadcast::RecvError::Lagged(count))) => {
+                    // The subscriber is too slow to keep up, and missed some messages
+                    this.handler
+                        .metric_factory
+                        .subscriber_lagged_counter()
+                        .add(count as usize);
+                    return Poll::Ready(Some(RaidUpdate::Lagged(count)));
+                }
                 Some(Err(broadcast::RecvError::Closed)) => (),
                 None => (),
             }
@@ -49,30 +83,169 @@ impl Stream for Subscription {
     }
 }
 
+pin_project_lite::pin_project! {
+    /// Like `UpdateSubscription`, but silently skips lag notifications
+    pub struct Subscription {
+        #[pin]
+        inner: UpdateSubscription,
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Arc<Raid>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+
+        loop {
+            match futures::ready!(this.inner.as_mut().poll_next(cx)) {
+                Some(RaidUpdate::Raid(raid)) => return Poll::Ready(Some(raid)),
+                Some(RaidUpdate::Lagged(_)) => continue,
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
 impl RaidHandler {
+    /// `canonical_language` determines boss sort order and node IDs. Changing it between
+    /// restarts invalidates any node IDs that clients have cached.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         metric_factory: PrometheusMetricFactory,
         bosses: Vec<Boss>,
-        history_size: usize,
+        history: HistoryLimit,
+        broadcast_capacity: usize,
+        max_broadcasts_per_sec: Option<u32>,
+        merge: MergeOptions,
+        filter: RaidFilter,
+        canonical_language: Language,
+    ) -> Self {
+        Self::from_snapshot(
+            metric_factory,
+            bosses.into_iter().map(BossSnapshot::from).collect(),
+            history,
+            broadcast_capacity,
+            max_broadcasts_per_sec,
+            merge,
+            filter,
+            canonical_language,
+        )
+    }
+
+    /// Like `new`, but with each boss's history populated from the snapshot
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_snapshot(
+        metric_factory: PrometheusMetricFactory,
+        snapshot: Vec<BossSnapshot>,
+        history: HistoryLimit,
         broadcast_capacity: usize,
+        max_broadcasts_per_sec: Option<u32>,
+        merge: MergeOptions,
+        filter: RaidFilter,
+        canonical_language: Language,
     ) -> Self {
         Self(Arc::new(RaidHandlerInner::new(
             metric_factory,
-            bosses,
-            history_size,
+            snapshot,
+            history,
             broadcast_capacity,
+            max_broadcasts_per_sec,
+            merge,
+            filter,
+            canonical_language,
         )))
     }
 
+    /// The language preferred for boss sort order and node IDs
+    pub fn canonical_language(&self) -> Language {
+        self.0.bosses.canonical_language
+    }
+
     pub fn subscribe(&self, boss_name: BossName) -> Subscription {
+        Subscription {
+            inner: self.subscribe_updates(boss_name),
+        }
+    }
+
+    /// Like `subscribe`, but also notifies the subscriber when raids were dropped due to lag
+    pub fn subscribe_updates(&self, boss_name: BossName) -> UpdateSubscription {
         let inner = self.0.clone();
 
-        Subscription {
+        UpdateSubscription {
             rx: inner.subscribe(&boss_name),
             boss_name,
             handler: inner.clone(),
         }
     }
+
+    /// Subscribe to raids for a boss, starting with (up to) the last `count` raids from its
+    /// history, oldest first
+    pub fn subscribe_with_history(
+        &self,
+        boss_name: BossName,
+        count: usize,
+    ) -> impl Stream<Item = Arc<Raid>> {
+        self.subscribe_updates_with_history(boss_name, count)
+            .filter_map(RaidUpdate::into_raid)
+    }
+
+    /// Like `subscribe_with_history`, but also notifies the subscriber when raids were dropped
+    /// due to lag
+    pub fn subscribe_updates_with_history(
+        &self,
+        boss_name: BossName,
+        count: usize,
+    ) -> impl Stream<Item = RaidUpdate> {
+        // Subscribe before reading history, so that no raids are missed in between.
+        // Any raids that end up in both are skipped in the live stream.
+        let live = self.subscribe_updates(boss_name.clone());
+
+        let mut history = match self.boss(&boss_name) {
+            Some(entry) => entry
+                .history
+                .read()
+                .iter()
+                .take(count)
+                .cloned()
+                .map(RaidUpdate::Raid)
+                .collect::<Vec<_>>(),
+            None => Vec::new(),
+        };
+        history.reverse();
+
+        let seen = history
+            .iter()
+            .filter_map(|update| match update {
+                RaidUpdate::Raid(raid) => Some(raid.tweet_id),
+                RaidUpdate::Lagged(_) => None,
+            })
+            .collect::<HashSet<_>>();
+
+        tokio::stream::iter(history).chain(live.filter(move |update| match update {
+            RaidUpdate::Raid(raid) => !seen.contains(&raid.tweet_id),
+            RaidUpdate::Lagged(_) => true,
+        }))
+    }
+
+    /// Subscribe to raids for all bosses
+    pub fn subscribe_all(&self) -> impl Stream<Item = Arc<Raid>> {
+        let inner = self.0.clone();
+
+        self.raid_broadcast
+            .subscribe()
+            .filter_map(move |result| match result {
+                Ok(raid) => Some(raid),
+                Err(broadcast::RecvError::Lagged(count)) => {
+                    inner
+                        .metric_factory
+                        .subscriber_lagged_counter()
+                        .add(count as usize);
+                    None
+                }
+                Err(broadcast::RecvError::Closed) => None,
+            })
+    }
 }
 
 impl Deref for RaidHandler {
@@ -83,12 +256,46 @@ impl Deref for RaidHandler {
     }
 }
 
+// A boss's raid channel, shared by every entry for the boss. The sender can be replaced (e.g., to
+// change its capacity), which closes the old channel so that subscribers resubscribe.
+#[derive(Debug)]
+struct RaidBroadcast(ArcSwap<broadcast::Sender<Arc<Raid>>>);
+
+impl RaidBroadcast {
+    fn new(tx: broadcast::Sender<Arc<Raid>>) -> Arc<Self> {
+        Arc::new(Self(ArcSwap::from_pointee(tx)))
+    }
+
+    fn load(&self) -> arc_swap::Guard<'static, Arc<broadcast::Sender<Arc<Raid>>>> {
+        self.0.load()
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Arc<Raid>> {
+        self.load().subscribe()
+    }
+
+    fn receiver_count(&self) -> usize {
+        self.load().receiver_count()
+    }
+
+    // Existing subscribers receive any raids still buffered in the old channel, then get a
+    // `Closed` error and resubscribe to the new one
+    fn replace(&self, tx: broadcast::Sender<Arc<Raid>>) {
+        let old = self.0.swap(Arc::new(tx));
+        // This is normally the last reference to the old sender, unless a raid is being sent
+        // concurrently, in which case the channel closes once that send finishes
+        drop(old);
+    }
+}
+
 #[derive(Debug)]
 pub struct BossEntry {
     node_id: CachedString,
     boss: Boss,
     history: RwLock<CircularQueue<Arc<Raid>>>,
-    broadcast: broadcast::Sender<Arc<Raid>>,
+    broadcast: Arc<RaidBroadcast>,
+    broadcast_limiter: Option<Arc<RateLimiter>>,
+    history_sampler: Option<Arc<HistorySampler>>,
     tweet_count: LangMetric<PrometheusMetric>,
     subscriber_count: PrometheusMetric,
 }
@@ -104,6 +311,8 @@ impl Clone for BossEntry {
             boss,
             history,
             broadcast: self.broadcast.clone(),
+            broadcast_limiter: self.broadcast_limiter.clone(),
+            history_sampler: self.history_sampler.clone(),
             tweet_count: self.tweet_count.clone(),
             subscriber_count: self.subscriber_count.clone(),
         }
@@ -126,6 +335,359 @@ impl BossEntry {
     pub fn history(&self) -> &RwLock<CircularQueue<Arc<Raid>>> {
         &self.history
     }
+
+    /// The boss along with a copy of its history
+    pub fn snapshot(&self) -> BossSnapshot {
+        BossSnapshot {
+            boss: self.boss.clone(),
+            history: self
+                .history
+                .read()
+                .asc_iter()
+                .map(|raid| Raid::clone(raid))
+                .collect(),
+        }
+    }
+
+    // Removes raids created before `cutoff` from history. Only the oldest raid is checked up
+    // front, so pushes don't need to scan the whole history when nothing has expired.
+    fn prune_history(&self, cutoff: &DateTime) {
+        let is_expired = |raid: &Arc<Raid>| raid.created_at.as_datetime() < cutoff;
+
+        if !self
+            .history
+            .read()
+            .asc_iter()
+            .next()
+            .map_or(false, is_expired)
+        {
+            return;
+        }
+
+        let mut history = self.history.write();
+        let mut pruned = CircularQueue::with_capacity(history.capacity());
+        history
+            .asc_iter()
+            .filter(|raid| !is_expired(*raid))
+            .for_each(|raid| pruned.push(raid.clone()));
+        *history = pruned;
+    }
+
+    /// Total number of tweets seen for this boss, across both languages
+    pub fn tweet_count(&self) -> usize {
+        self.tweet_count.get(Language::Japanese).get()
+            + self.tweet_count.get(Language::English).get()
+    }
+
+    // Bosses are sorted by level, then name in the canonical language
+    pub(crate) fn sort_key(&self, preferred: Language) -> (Option<Level>, Option<CachedString>) {
+        (
+            self.boss.level,
+            self.boss.name.preferring(preferred).cloned(),
+        )
+    }
+
+    // Broadcasts the raid to subscribers, unless the boss is over its rate limit.
+    // Returns whether the raid was broadcast.
+    fn broadcast(&self, raid: Arc<Raid>) -> bool {
+        let allowed = match &self.broadcast_limiter {
+            Some(limiter) => limiter.try_acquire(Instant::now()),
+            None => true,
+        };
+
+        if allowed {
+            let _ = self.broadcast.load().send(raid);
+        }
+
+        allowed
+    }
+
+    // Whether the raid should be recorded in history, if the boss is over its sampling rate
+    fn should_record(&self, now: Instant) -> bool {
+        match &self.history_sampler {
+            Some(sampler) => sampler.should_record(now),
+            None => true,
+        }
+    }
+}
+
+// Token bucket rate limiter, used to throttle broadcasts for a single boss.
+// Raids that exceed the limit are still recorded in history, but not broadcast.
+#[derive(Debug)]
+struct RateLimiter {
+    per_sec: f64,
+    state: Mutex<RateLimiterState>,
+}
+
+#[derive(Debug)]
+struct RateLimiterState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    fn new(per_sec: u32) -> Self {
+        let per_sec = per_sec as f64;
+
+        Self {
+            per_sec,
+            state: Mutex::new(RateLimiterState {
+                tokens: per_sec,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    fn try_acquire(&self, now: Instant) -> bool {
+        let mut state = self.state.lock();
+
+        let elapsed = now.saturating_duration_since(state.last_refill);
+        state.tokens = (state.tokens + elapsed.as_secs_f64() * self.per_sec).min(self.per_sec);
+        state.last_refill = now;
+
+        if state.tokens >= 1.0 {
+            state.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// Above the rate limit, only every `every`th raid is recorded in history
+#[derive(Debug)]
+struct HistorySampler {
+    limiter: RateLimiter,
+    every: u64,
+    over_limit: AtomicU64,
+}
+
+impl HistorySampler {
+    fn new(sampling: HistorySampling) -> Self {
+        Self {
+            limiter: RateLimiter::new(sampling.max_per_sec),
+            every: u64::from(sampling.every.max(1)),
+            over_limit: AtomicU64::new(0),
+        }
+    }
+
+    fn should_record(&self, now: Instant) -> bool {
+        self.limiter.try_acquire(now)
+            || self.over_limit.fetch_add(1, AtomicOrdering::Relaxed) % self.every == 0
+    }
+}
+
+/// Tweet stats for a single language, across all bosses
+#[derive(Clone, Debug, PartialEq)]
+pub struct LanguageStats {
+    pub language: Language,
+    pub tweet_count: usize,
+    /// When a tweet in this language was last received, if ever
+    pub last_seen_at: Option<DateTime>,
+}
+
+// When a tweet was last received in each language, where 0 means never
+#[derive(Debug)]
+struct LanguageLastSeen {
+    ja: AtomicDateTime,
+    en: AtomicDateTime,
+}
+
+impl LanguageLastSeen {
+    fn new() -> Self {
+        Self {
+            ja: AtomicDateTime::from(0),
+            en: AtomicDateTime::from(0),
+        }
+    }
+
+    fn get(&self, lang: Language) -> &AtomicDateTime {
+        match lang {
+            Language::Japanese => &self.ja,
+            Language::English => &self.en,
+        }
+    }
+}
+
+/// A boss along with its recent raids, oldest first
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BossSnapshot {
+    pub boss: Boss,
+    pub history: Vec<Raid>,
+}
+
+impl From<Boss> for BossSnapshot {
+    fn from(boss: Boss) -> Self {
+        Self {
+            boss,
+            history: Vec::new(),
+        }
+    }
+}
+
+/// Limits on which raids are kept in each boss's history
+#[derive(Clone, Copy, Debug)]
+pub struct HistoryLimit {
+    /// Maximum number of raids to keep
+    pub size: usize,
+    /// Raids created longer ago than this are pruned from history
+    pub max_age: Option<chrono::Duration>,
+    /// Limits how often raids are recorded for bosses with very high traffic
+    pub sampling: Option<HistorySampling>,
+}
+
+impl HistoryLimit {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            max_age: None,
+            sampling: None,
+        }
+    }
+}
+
+/// Above `max_per_sec` raids per second for a single boss, only every `every`th raid is recorded
+/// in the boss's history. All raids are still broadcast (subject to the broadcast rate limit) and
+/// counted in metrics.
+#[derive(Clone, Copy, Debug)]
+pub struct HistorySampling {
+    pub max_per_sec: u32,
+    pub every: u32,
+}
+
+/// How Japanese and English bosses are merged into a single boss
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MergeOptions {
+    /// When merging histories, keep only the earliest tweet for each raid ID
+    pub dedupe_history: bool,
+    /// Merge bosses with the same image hash even if their levels differ, as long as the match
+    /// is unambiguous
+    pub ignore_level: bool,
+}
+
+/// How long a boss can go unseen before it's removed during cleanup
+#[derive(Clone, Debug)]
+pub struct BossTtl {
+    pub default: chrono::Duration,
+    /// Overrides for bosses of specific levels, e.g. to keep permanent bosses around longer
+    /// than event bosses
+    pub by_level: HashMap<Level, chrono::Duration>,
+}
+
+impl BossTtl {
+    pub fn new(default: chrono::Duration) -> Self {
+        Self {
+            default,
+            by_level: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, boss: &Boss) -> chrono::Duration {
+        boss.level
+            .and_then(|level| self.by_level.get(&level))
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    pub fn is_expired(&self, boss: &Boss, now: DateTime) -> bool {
+        boss.last_seen_at.as_datetime() <= now - self.get(boss)
+    }
+}
+
+/// Determines which incoming raids are tracked by the `RaidHandler`
+#[derive(Clone, Debug, Default)]
+pub struct RaidFilter {
+    /// Ignore raids for bosses below this level. Bosses with unknown levels are always allowed.
+    pub min_level: Option<Level>,
+    /// If non-empty, only bosses with one of these names (in any language) are allowed
+    pub allow: HashSet<BossName>,
+    /// Bosses with any of these names (in any language) are ignored
+    pub deny: HashSet<BossName>,
+    /// Ignore raids created longer ago than this. This prevents old tweets delivered after a
+    /// reconnect from resurrecting bosses that were already removed by cleanup.
+    pub max_age: Option<chrono::Duration>,
+}
+
+impl RaidFilter {
+    // `boss` is the existing boss for this raid, if any. A boss may be known by
+    // both its Japanese and English names, and either can match the lists.
+    fn allows(&self, raid: &Raid, boss: Option<&Boss>) -> bool {
+        if let Some(max_age) = self.max_age {
+            if *raid.created_at.as_datetime() < chrono::Utc::now() - max_age {
+                return false;
+            }
+        }
+
+        let level_allowed = match (self.min_level, raid.level()) {
+            (Some(min_level), Some(level)) => level >= min_level,
+            _ => true,
+        };
+
+        let is_listed = |list: &HashSet<BossName>| {
+            list.contains(&raid.boss_name)
+                || boss.map_or(false, |boss| {
+                    Language::VALUES
+                        .iter()
+                        .filter_map(|lang| boss.name.get(*lang))
+                        .any(|name| list.contains(name))
+                })
+        };
+
+        level_allowed && !is_listed(&self.deny) && (self.allow.is_empty() || is_listed(&self.allow))
+    }
+}
+
+#[derive(Clone, Debug)]
+enum BossEvent {
+    Updated(Weak<BossEntry>),
+    Removed(LangString),
+    // Sent in addition to `Updated` when a boss's image hash is set
+    ImageHashUpdated(Weak<BossEntry>),
+}
+
+/// A high-level change to the set of bosses, for debugging
+#[serde(tag = "type", rename_all = "camelCase")]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum HandlerEvent {
+    /// A tweet was seen for a boss that didn't exist yet
+    BossCreated { name: LangString },
+    /// Bosses in each language were merged, since they had the same image hash, or by name if
+    /// neither had an image
+    #[serde(rename_all = "camelCase")]
+    BossMerged {
+        name: LangString,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        image_hash: Option<ImageHash>,
+    },
+    /// A boss was removed, e.g., during cleanup
+    BossRemoved { name: LangString },
+    /// A boss's image hash was set, without being merged with another boss
+    #[serde(rename_all = "camelCase")]
+    ImageHashComputed {
+        name: LangString,
+        image_hash: ImageHash,
+    },
+}
+
+impl HandlerEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::BossCreated { .. } => "bossCreated",
+            Self::BossMerged { .. } => "bossMerged",
+            Self::BossRemoved { .. } => "bossRemoved",
+            Self::ImageHashComputed { .. } => "imageHashComputed",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BossUpdate {
+    /// A boss was added or updated
+    Updated(Arc<BossEntry>),
+    /// A boss with these names was removed
+    Removed(LangString),
+    /// The subscriber was too slow, and missed this many updates
+    Lagged(u64),
 }
 
 pub struct Bosses(arc_swap::Guard<'static, Arc<Vec<Arc<BossEntry>>>>);
@@ -141,9 +703,18 @@ impl Deref for Bosses {
 pub struct RaidHandlerInner {
     metric_factory: PrometheusMetricFactory,
     bosses: BossMap,
-    boss_broadcast: broadcast::Sender<Weak<BossEntry>>,
+    boss_broadcast: broadcast::Sender<BossEvent>,
+    raid_broadcast: broadcast::Sender<Arc<Raid>>,
+    event_broadcast: broadcast::Sender<HandlerEvent>,
     history_size: usize,
+    history_max_age: Option<chrono::Duration>,
     broadcast_capacity: usize,
+    merge: MergeOptions,
+    filter: RaidFilter,
+    language_last_seen_at: LanguageLastSeen,
+    // Incremented whenever boss data changes
+    generation: AtomicU64,
+    draining: AtomicBool,
 }
 
 #[derive(Debug)]
@@ -151,30 +722,66 @@ struct BossMap {
     map: DashMap<CachedString, Arc<BossEntry>>,
     // Bosses sorted by level, then name
     vec: ArcSwap<Vec<Arc<BossEntry>>>,
+    // Whether `vec` is out of date, and should be rebuilt on the next read.
+    // Rebuilding lazily avoids repeatedly re-sorting the list during bursts of inserts.
+    vec_dirty: AtomicBool,
+    // Held while rebuilding `vec` or marking it dirty, so that the flag is only cleared once the
+    // rebuilt list has been stored, and marks made during a rebuild aren't lost
+    vec_lock: Mutex<()>,
     // Bosses that don't exist yet, but are subscribed to
     waiting: DashMap<CachedString, broadcast::Sender<Arc<Raid>>>,
     history_size: usize,
+    history_sampling: Option<HistorySampling>,
     broadcast_capacity: usize,
+    max_broadcasts_per_sec: Option<u32>,
+    // Determines sort order and node IDs
+    canonical_language: Language,
 }
 
 impl BossMap {
     fn new(
         metric_factory: &PrometheusMetricFactory,
-        mut bosses: Vec<Boss>,
+        mut snapshot: Vec<BossSnapshot>,
         history_size: usize,
+        history_sampling: Option<HistorySampling>,
         broadcast_capacity: usize,
+        max_broadcasts_per_sec: Option<u32>,
+        canonical_language: Language,
     ) -> Self {
-        bosses.sort_by_key(|boss| boss.name.canonical().cloned());
-        bosses.dedup_by(|a, b| a.name == b.name);
+        // If multiple snapshots share a name (e.g., if loaded from different sources), keep the
+        // most recently seen one. Ties are broken by keeping whichever came first.
+        snapshot.sort_by(|a, b| b.boss.last_seen_at.cmp(&a.boss.last_seen_at));
+        let mut claimed = HashSet::new();
+        snapshot.retain(|s| {
+            let mut conflict = false;
+            s.boss
+                .name
+                .for_each(|name| conflict |= claimed.contains(name));
+            if !conflict {
+                s.boss.name.for_each(|name| {
+                    claimed.insert(name.clone());
+                });
+            }
+            !conflict
+        });
 
         let mut init = Vec::new();
 
-        for boss in bosses {
+        for BossSnapshot { boss, history } in snapshot {
+            let mut queue = CircularQueue::with_capacity(history_size);
+            history
+                .into_iter()
+                .for_each(|raid| queue.push(Arc::new(raid)));
+
             let (tx, _) = broadcast::channel(broadcast_capacity);
             let entry = Arc::new(BossEntry {
-                node_id: NodeId::from_boss_name(&boss.name).to_string().into(),
-                history: RwLock::new(CircularQueue::with_capacity(history_size)),
-                broadcast: tx,
+                node_id: NodeId::from_boss_name(&boss.name, canonical_language)
+                    .to_string()
+                    .into(),
+                history: RwLock::new(queue),
+                broadcast: RaidBroadcast::new(tx),
+                broadcast_limiter: max_broadcasts_per_sec.map(|n| Arc::new(RateLimiter::new(n))),
+                history_sampler: history_sampling.map(|s| Arc::new(HistorySampler::new(s))),
                 tweet_count: metric_factory.boss_tweets_counter(&boss.name),
                 subscriber_count: metric_factory.boss_subscriptions_gauge(&boss.name),
                 boss,
@@ -189,12 +796,17 @@ impl BossMap {
         let this = Self {
             map: DashMap::from_iter(init),
             vec: ArcSwap::from_pointee(Vec::new()),
+            vec_dirty: AtomicBool::new(false),
+            vec_lock: Mutex::new(()),
             waiting: DashMap::new(),
             history_size,
+            history_sampling,
             broadcast_capacity,
+            max_broadcasts_per_sec,
+            canonical_language,
         };
 
-        this.update_vec();
+        this.rebuild_vec();
         this
     }
 
@@ -202,24 +814,38 @@ impl BossMap {
         self.map.get(name)
     }
 
-    fn update_vec(&self) {
+    fn update_vec(&self, _lock: &MutexGuard<'_, ()>) {
         let mut vec = self
             .map
             .iter()
             .map(|guard| guard.value().clone())
             .collect::<Vec<_>>();
 
-        vec.sort_by_key(|entry| (entry.boss.level, entry.boss.name.canonical().cloned()));
+        // Bosses appear once per name in the map. Sorting by pointer after the sort key ensures
+        // duplicates are adjacent, even if distinct bosses have the same sort key.
+        vec.sort_by(|a, b| {
+            a.sort_key(self.canonical_language)
+                .cmp(&b.sort_key(self.canonical_language))
+                .then_with(|| Arc::as_ptr(a).cmp(&Arc::as_ptr(b)))
+        });
         vec.dedup_by(|a, b| Arc::ptr_eq(a, b));
 
         self.vec.store(Arc::new(vec));
+        self.vec_dirty.store(false, AtomicOrdering::Release);
+    }
+
+    // Rebuilds the sorted list immediately. This is used when bosses are removed, so that the list
+    // doesn't keep the removed entries (and their broadcast channels) alive until the next read.
+    fn rebuild_vec(&self) {
+        let lock = self.vec_lock.lock();
+        self.update_vec(&lock);
     }
 
     fn retain(&self, predicate: impl FnMut(&CachedString, &Arc<BossEntry>) -> bool) {
         let len = self.map.len();
         self.map.retain(predicate);
         if self.map.len() != len {
-            self.update_vec();
+            self.rebuild_vec();
         }
 
         self.waiting.retain(|_k, v| v.receiver_count() > 0);
@@ -232,17 +858,72 @@ impl BossMap {
         self.map.iter().find(predicate)
     }
 
+    fn filter<'a>(
+        &'a self,
+        predicate: impl FnMut(&ElementGuard<CachedString, Arc<BossEntry>>) -> bool + 'a,
+    ) -> impl Iterator<Item = ElementGuard<CachedString, Arc<BossEntry>>> + 'a {
+        self.map.iter().filter(predicate)
+    }
+
     fn as_vec(&self) -> &ArcSwap<Vec<Arc<BossEntry>>> {
+        if self.vec_dirty.load(AtomicOrdering::Acquire) {
+            let lock = self.vec_lock.lock();
+
+            // Another reader may have rebuilt it while we were waiting for the lock
+            if self.vec_dirty.load(AtomicOrdering::Acquire) {
+                self.update_vec(&lock);
+            }
+        }
+
         &self.vec
     }
 
     fn insert(&self, entry: &Arc<BossEntry>) {
+        let previous = entry
+            .boss
+            .name
+            .canonical()
+            .and_then(|name| self.map.get(name))
+            .map(|guard| guard.value().clone());
+
         entry.boss.name.for_each(|name| {
             self.map.insert(name.clone(), entry.clone());
             self.waiting.remove(&name);
         });
 
-        self.update_vec();
+        let lock = self.vec_lock.lock();
+
+        // If this replaces an existing entry without changing its position in the sorted list
+        // (e.g., when updating a boss image), swap it in directly instead of re-sorting
+        match previous {
+            Some(previous)
+                if previous.boss.name == entry.boss.name
+                    && previous.sort_key(self.canonical_language)
+                        == entry.sort_key(self.canonical_language) =>
+            {
+                let mut replaced = false;
+                self.vec.rcu(|vec| {
+                    let mut vec = Vec::clone(vec);
+                    replaced = match vec.iter_mut().find(|e| Arc::ptr_eq(e, &previous)) {
+                        Some(slot) => {
+                            *slot = entry.clone();
+                            true
+                        }
+                        None => false,
+                    };
+                    vec
+                });
+
+                if !replaced {
+                    self.vec_dirty.store(true, AtomicOrdering::Release);
+                }
+            }
+            // Replaced entries (e.g., when merging bosses) are removed from the list right away,
+            // so that their subscribers can move to the new entry's channel
+            Some(_) => self.update_vec(&lock),
+            // New bosses are added lazily, on the next read
+            None => self.vec_dirty.store(true, AtomicOrdering::Release),
+        }
     }
 
     fn subscribe(&self, key: &CachedString) -> broadcast::Receiver<Arc<Raid>> {
@@ -257,31 +938,46 @@ impl BossMap {
         }
     }
 
+    // The broadcast channel for a boss that doesn't exist yet, which may already have subscribers
+    fn take_waiting(&self, names: &LangString) -> broadcast::Sender<Arc<Raid>> {
+        let mut waiting = None;
+        names.for_each(|name| {
+            if waiting.is_none() {
+                waiting = self.waiting.remove_take(name).map(|tx| tx.value().clone());
+            }
+        });
+
+        waiting.unwrap_or_else(|| broadcast::channel(self.broadcast_capacity).0)
+    }
+
     fn new_entry_from_raid(
         &self,
         metric_factory: &PrometheusMetricFactory,
-        raid: Raid,
+        raid: Arc<Raid>,
     ) -> Arc<BossEntry> {
-        let boss = Boss::from(&raid);
-        let broadcast = if let Some(tx) = self.waiting.remove_take(&raid.boss_name) {
-            tx.value().clone()
-        } else {
-            let (tx, _) = broadcast::channel(self.broadcast_capacity);
-            tx
-        };
+        let boss = Boss::from(raid.as_ref());
+        let broadcast = RaidBroadcast::new(self.take_waiting(&boss.name));
 
         let entry = BossEntry {
-            node_id: NodeId::from_boss_name(&boss.name).to_string().into(),
+            node_id: NodeId::from_boss_name(&boss.name, self.canonical_language)
+                .to_string()
+                .into(),
             history: RwLock::new(CircularQueue::with_capacity(self.history_size)),
             broadcast,
+            broadcast_limiter: self
+                .max_broadcasts_per_sec
+                .map(|n| Arc::new(RateLimiter::new(n))),
+            history_sampler: self
+                .history_sampling
+                .map(|s| Arc::new(HistorySampler::new(s))),
             tweet_count: metric_factory.boss_tweets_counter(&boss.name),
             subscriber_count: metric_factory.boss_subscriptions_gauge(&boss.name),
             boss,
         };
 
-        let raid = Arc::new(raid);
-        let _ = entry.broadcast.send(raid.clone());
-        entry.history.write().push(raid.clone());
+        entry.broadcast(raid.clone());
+        entry.tweet_count.get(raid.language).inc();
+        entry.history.write().push(raid);
 
         let entry = Arc::new(entry);
         self.insert(&entry);
@@ -290,20 +986,69 @@ impl BossMap {
 }
 
 impl RaidHandlerInner {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         metric_factory: PrometheusMetricFactory,
-        bosses: Vec<Boss>,
-        history_size: usize,
+        snapshot: Vec<BossSnapshot>,
+        history: HistoryLimit,
         broadcast_capacity: usize,
+        max_broadcasts_per_sec: Option<u32>,
+        merge: MergeOptions,
+        filter: RaidFilter,
+        canonical_language: Language,
     ) -> Self {
         let (tx, _) = broadcast::channel(broadcast_capacity);
+        let (raid_tx, _) = broadcast::channel(broadcast_capacity);
+        let (event_tx, _) = broadcast::channel(broadcast_capacity);
 
-        Self {
-            bosses: BossMap::new(&metric_factory, bosses, history_size, broadcast_capacity),
+        let this = Self {
+            bosses: BossMap::new(
+                &metric_factory,
+                snapshot,
+                history.size,
+                history.sampling,
+                broadcast_capacity,
+                max_broadcasts_per_sec,
+                canonical_language,
+            ),
             boss_broadcast: tx,
-            history_size,
+            raid_broadcast: raid_tx,
+            event_broadcast: event_tx,
+            history_size: history.size,
+            history_max_age: history.max_age,
             broadcast_capacity,
+            merge,
+            filter,
+            language_last_seen_at: LanguageLastSeen::new(),
+            generation: AtomicU64::new(0),
+            draining: AtomicBool::new(false),
             metric_factory,
+        };
+
+        this.merge_persisted_image_hashes();
+        this
+    }
+
+    // Persisted image hashes are trusted, so bosses that share a hash are merged on load, rather
+    // than waiting for their images to be downloaded and hashed again
+    fn merge_persisted_image_hashes(&self) {
+        for entry in self.bosses().iter() {
+            let name = &entry.boss.name;
+            let image_hash = match entry.boss.image_hash {
+                Some(image_hash) if name.ja.is_none() || name.en.is_none() => image_hash,
+                _ => continue,
+            };
+
+            // Skip entries that were already replaced by an earlier merge
+            let is_current = name.canonical().map_or(false, |canonical| {
+                self.bosses
+                    .get(canonical)
+                    .map_or(false, |guard| Arc::ptr_eq(guard.value(), entry))
+            });
+
+            if is_current {
+                self.merge_by_image_hash(entry, image_hash);
+            }
         }
     }
 
@@ -311,46 +1056,493 @@ impl RaidHandlerInner {
         self.bosses.subscribe(boss_name)
     }
 
-    pub fn retain(&self, mut predicate: impl FnMut(&Arc<BossEntry>) -> bool) {
-        self.bosses.retain(|_k, v| predicate(v));
+    /// A counter that changes whenever boss data changes. If it's the same as it was during the
+    /// last save, there's nothing new to save.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(AtomicOrdering::SeqCst)
     }
 
-    pub fn subscribe_boss_updates(&self) -> impl Stream<Item = Arc<BossEntry>> {
-        self.boss_broadcast
-            .subscribe()
-            .filter_map(|entry| entry.ok().and_then(|w| w.upgrade()))
+    fn mark_changed(&self) {
+        self.generation.fetch_add(1, AtomicOrdering::SeqCst);
     }
 
-    pub fn boss(&self, name: &CachedString) -> Option<Arc<BossEntry>> {
-        self.bosses.get(name).map(|guard| guard.value().clone())
+    /// A feed of high-level boss events (see `HandlerEvent`). Events sent while the subscriber is
+    /// lagging are skipped.
+    pub fn subscribe_events(&self) -> impl Stream<Item = HandlerEvent> {
+        self.event_broadcast.subscribe().filter_map(Result::ok)
     }
 
-    pub fn bosses(&self) -> Bosses {
-        Bosses(self.bosses.as_vec().load())
+    fn send_event(&self, event: HandlerEvent) {
+        // Fails only if there are no subscribers
+        let _ = self.event_broadcast.send(event);
     }
 
-    pub fn metric_factory(&self) -> &PrometheusMetricFactory {
-        &self.metric_factory
-    }
+    /// Removes bosses that don't match the predicate. Returns the number of bosses removed.
+    pub fn retain(&self, mut predicate: impl FnMut(&Arc<BossEntry>) -> bool) -> usize {
+        let mut removed = Vec::<Arc<BossEntry>>::new();
 
-    pub fn metrics(&self) -> <PrometheusMetricFactory as MetricFactory>::Output {
-        let bosses = self.bosses();
+        self.bosses.retain(|_k, entry| {
+            if predicate(entry) {
+                return true;
+            }
 
-        let mut metrics = PerBossMetrics {
-            boss_tweets_counters: Vec::with_capacity(bosses.len()),
-            boss_subscriptions_gauges: Vec::with_capacity(bosses.len()),
-        };
+            // Bosses with multiple names are in the map once per name
+            if !removed.iter().any(|e| Arc::ptr_eq(e, entry)) {
+                removed.push(entry.clone());
+            }
 
-        for boss in bosses.iter() {
-            metrics.boss_tweets_counters.push(&boss.tweet_count);
-            boss.subscriber_count.set(boss.broadcast.receiver_count());
-            metrics
-                .boss_subscriptions_gauges
-                .push(&boss.subscriber_count);
-        }
+            false
+        });
 
-        self.metric_factory.write_per_boss_metrics(&metrics)
-    }
+        if !removed.is_empty() {
+            self.mark_changed();
+        }
+        for entry in &removed {
+            self.send_event(HandlerEvent::BossRemoved {
+                name: entry.boss.name.clone(),
+            });
+        }
+
+        self.metric_factory
+            .bosses_removed_counter()
+            .add(removed.len());
+        removed.len()
+    }
+
+    /// Removes all bosses, except for known ones (see `Boss::known_bosses`)
+    pub fn clear(&self) {
+        let mut removed = Vec::<Arc<BossEntry>>::new();
+
+        self.bosses.retain(|_k, entry| {
+            if entry.boss.is_known_boss() {
+                return true;
+            }
+
+            // Bosses with multiple names are in the map once per name
+            if !removed.iter().any(|e| Arc::ptr_eq(e, entry)) {
+                removed.push(entry.clone());
+            }
+
+            false
+        });
+
+        if !removed.is_empty() {
+            self.mark_changed();
+        }
+
+        for entry in removed {
+            let _ = self
+                .boss_broadcast
+                .send(BossEvent::Removed(entry.boss.name.clone()));
+            self.send_event(HandlerEvent::BossRemoved {
+                name: entry.boss.name.clone(),
+            });
+        }
+    }
+
+    /// Merges bosses from a snapshot (e.g., exported from another instance) into the current
+    /// state. An imported boss only replaces existing bosses with the same names if it was seen
+    /// more recently, and any names, images, and history from the existing bosses are kept.
+    /// Imported histories are only merged in if `include_history` is set.
+    ///
+    /// The snapshot is validated before anything is imported. Returns the number of bosses that
+    /// were added or updated.
+    pub fn import(&self, snapshot: Vec<BossSnapshot>, include_history: bool) -> Result<usize> {
+        for BossSnapshot { boss, history } in &snapshot {
+            if boss.name.canonical().is_none() {
+                return Err(Error::InvalidSnapshot("boss has no name".to_owned()));
+            }
+
+            let mut names = Vec::new();
+            boss.name.for_each(|name| names.push(name));
+            if let Some(raid) = history
+                .iter()
+                .find(|raid| !names.contains(&&raid.boss_name))
+            {
+                return Err(Error::InvalidSnapshot(format!(
+                    "history for {:?} contains a raid for {:?}",
+                    names, raid.boss_name
+                )));
+            }
+        }
+
+        let mut imported = 0;
+        for BossSnapshot { mut boss, history } in snapshot {
+            // Bosses with multiple names are in the map once per name
+            let mut existing = Vec::<Arc<BossEntry>>::new();
+            boss.name.for_each(|name| {
+                if let Some(guard) = self.bosses.get(name) {
+                    let entry = guard.value();
+                    if !existing.iter().any(|e| Arc::ptr_eq(e, entry)) {
+                        existing.push(entry.clone());
+                    }
+                }
+            });
+
+            if existing
+                .iter()
+                .any(|entry| entry.boss.last_seen_at >= boss.last_seen_at)
+            {
+                continue;
+            }
+
+            let mut combined_history = Vec::new();
+            for entry in &existing {
+                boss.name = boss.name.merge(&entry.boss.name);
+                boss.image = boss.image.merge(&entry.boss.image);
+                boss.image_hash = boss.image_hash.or(entry.boss.image_hash);
+                boss.first_seen_at =
+                    std::cmp::min(boss.first_seen_at.clone(), entry.boss.first_seen_at.clone());
+                combined_history.extend(entry.history.read().asc_iter().cloned());
+            }
+            boss.element = Element::for_boss_name(&boss.name);
+
+            if include_history {
+                combined_history.extend(history.into_iter().map(Arc::new));
+            }
+            combined_history.sort_by_key(|raid| (*raid.created_at.as_datetime(), raid.tweet_id));
+            combined_history.dedup_by_key(|raid| raid.tweet_id);
+
+            let mut new_history = CircularQueue::with_capacity(self.history_size);
+            combined_history
+                .into_iter()
+                .for_each(|raid| new_history.push(raid));
+
+            let (broadcast, broadcast_limiter, history_sampler) = match existing.first() {
+                Some(entry) => (
+                    entry.broadcast.clone(),
+                    entry.broadcast_limiter.clone(),
+                    entry.history_sampler.clone(),
+                ),
+                None => (
+                    RaidBroadcast::new(self.bosses.take_waiting(&boss.name)),
+                    self.bosses
+                        .max_broadcasts_per_sec
+                        .map(|n| Arc::new(RateLimiter::new(n))),
+                    self.bosses
+                        .history_sampling
+                        .map(|s| Arc::new(HistorySampler::new(s))),
+                ),
+            };
+
+            let entry = Arc::new(BossEntry {
+                node_id: NodeId::from_boss_name(&boss.name, self.bosses.canonical_language)
+                    .to_string()
+                    .into(),
+                history: RwLock::new(new_history),
+                broadcast,
+                broadcast_limiter,
+                history_sampler,
+                tweet_count: self.metric_factory.boss_tweets_counter(&boss.name),
+                subscriber_count: self.metric_factory.boss_subscriptions_gauge(&boss.name),
+                boss,
+            });
+            if let Some(max_age) = self.history_max_age {
+                entry.prune_history(&(chrono::Utc::now() - max_age));
+            }
+
+            self.bosses.insert(&entry);
+            if existing.is_empty() {
+                self.send_event(HandlerEvent::BossCreated {
+                    name: entry.boss.name.clone(),
+                });
+            }
+            let _ = self
+                .boss_broadcast
+                .send(BossEvent::Updated(Arc::downgrade(&entry)));
+            imported += 1;
+        }
+
+        if imported > 0 {
+            self.mark_changed();
+        }
+        Ok(imported)
+    }
+
+    /// Replaces a boss's broadcast channel with one that buffers up to `capacity` raids, so that
+    /// popular bosses can tolerate slower subscribers without lagging.
+    ///
+    /// The old channel is closed, so existing subscribers receive any raids buffered in it, then
+    /// resubscribe to the new one the next time they're polled. Returns `false` if the boss
+    /// doesn't exist.
+    pub fn set_broadcast_capacity_for(
+        &self,
+        boss_name: &BossName,
+        capacity: usize,
+    ) -> Result<bool> {
+        if capacity == 0 {
+            return Err(Error::BroadcastCapacity(capacity));
+        }
+
+        let entry = match self.bosses.get(boss_name) {
+            Some(guard) => guard.value().clone(),
+            None => return Ok(false),
+        };
+
+        let (tx, _) = broadcast::channel(capacity);
+        entry.broadcast.replace(tx);
+        Ok(true)
+    }
+
+    pub fn subscribe_boss_updates(&self) -> impl Stream<Item = Arc<BossEntry>> {
+        self.boss_broadcast
+            .subscribe()
+            .filter_map(|event| match event {
+                Ok(BossEvent::Updated(w)) => w.upgrade(),
+                _ => None,
+            })
+    }
+
+    /// Notifies the subscriber when a boss's image hash is set. If this caused the boss to be
+    /// merged with another, the merged boss is returned.
+    pub fn subscribe_image_hash_updates(&self) -> impl Stream<Item = Arc<BossEntry>> {
+        self.boss_broadcast
+            .subscribe()
+            .filter_map(|event| match event {
+                Ok(BossEvent::ImageHashUpdated(w)) => w.upgrade(),
+                _ => None,
+            })
+    }
+
+    /// Like `subscribe_boss_updates`, but also notifies the subscriber when updates were dropped
+    /// due to lag, so that it can resync from `bosses()`
+    pub fn subscribe_boss_updates_with_lag(&self) -> impl Stream<Item = BossUpdate> {
+        self.boss_broadcast
+            .subscribe()
+            .filter_map(|event| match event {
+                Ok(BossEvent::Updated(weak)) => weak.upgrade().map(BossUpdate::Updated),
+                Ok(BossEvent::Removed(name)) => Some(BossUpdate::Removed(name)),
+                Ok(BossEvent::ImageHashUpdated(_)) => None,
+                Err(broadcast::RecvError::Lagged(count)) => Some(BossUpdate::Lagged(count)),
+                Err(broadcast::RecvError::Closed) => None,
+            })
+    }
+
+    pub fn boss(&self, name: &CachedString) -> Option<Arc<BossEntry>> {
+        self.bosses.get(name).map(|guard| guard.value().clone())
+    }
+
+    pub fn bosses(&self) -> Bosses {
+        Bosses(self.bosses.as_vec().load())
+    }
+
+    /// Calls `f` on each boss (sorted by level, then name) without cloning the list
+    pub fn for_each_boss(&self, mut f: impl FnMut(&BossEntry)) {
+        self.bosses
+            .as_vec()
+            .load()
+            .iter()
+            .for_each(|entry| f(entry))
+    }
+
+    /// The most recent raids across all bosses, newest first
+    pub fn recent_raids(&self, limit: usize) -> Vec<Arc<Raid>> {
+        // Each boss's history is already sorted newest first, so we can do
+        // a k-way merge rather than collecting and sorting every raid
+        struct Head<'a> {
+            raid: &'a Arc<Raid>,
+            index: usize,
+        }
+
+        impl<'a> Head<'a> {
+            fn key(&self) -> (&DateTime, TweetId) {
+                (self.raid.created_at.as_datetime(), self.raid.tweet_id)
+            }
+        }
+
+        impl<'a> PartialEq for Head<'a> {
+            fn eq(&self, other: &Self) -> bool {
+                self.key() == other.key()
+            }
+        }
+
+        impl<'a> Eq for Head<'a> {}
+
+        impl<'a> PartialOrd for Head<'a> {
+            fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+
+        impl<'a> Ord for Head<'a> {
+            fn cmp(&self, other: &Self) -> Ordering {
+                self.key().cmp(&other.key())
+            }
+        }
+
+        let bosses = self.bosses();
+        let histories = bosses
+            .iter()
+            .map(|entry| entry.history.read())
+            .collect::<Vec<_>>();
+        let mut iters = histories
+            .iter()
+            .map(|history| history.iter())
+            .collect::<Vec<_>>();
+
+        let mut heap = iters
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(index, iter)| iter.next().map(|raid| Head { raid, index }))
+            .collect::<BinaryHeap<_>>();
+
+        let total = histories.iter().map(|history| history.len()).sum::<usize>();
+        let mut out = Vec::with_capacity(limit.min(total));
+        while out.len() < limit {
+            let head = match heap.pop() {
+                Some(head) => head,
+                None => break,
+            };
+
+            out.push(head.raid.clone());
+
+            if let Some(raid) = iters[head.index].next() {
+                heap.push(Head {
+                    raid,
+                    index: head.index,
+                });
+            }
+        }
+
+        out
+    }
+
+    /// Bosses with raids created after `since`, along with the number of such raids, ordered by
+    /// that count (most first), then by level and name
+    pub fn trending_bosses(&self, since: &DateTime, limit: usize) -> Vec<(Arc<BossEntry>, usize)> {
+        let mut trending = self
+            .bosses()
+            .iter()
+            .filter_map(|entry| {
+                let count = entry
+                    .history
+                    .read()
+                    .iter()
+                    .filter(|raid| raid.created_at.as_datetime() > since)
+                    .count();
+
+                if count > 0 {
+                    Some((entry.clone(), count))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // Stable sort, so ties keep the default boss ordering
+        trending.sort_by(|(_, a), (_, b)| b.cmp(a));
+        trending.truncate(limit);
+        trending
+    }
+
+    /// The current state of every boss, including history
+    pub fn snapshot(&self) -> Vec<BossSnapshot> {
+        let mut snapshot = Vec::new();
+        self.for_each_boss(|entry| snapshot.push(entry.snapshot()));
+        snapshot
+    }
+
+    /// Removes raids older than the history's max age (if any) from every boss's history.
+    /// Histories are also pruned on each push, but this handles bosses that are rarely seen.
+    pub fn prune_histories(&self) {
+        if let Some(max_age) = self.history_max_age {
+            let cutoff = chrono::Utc::now() - max_age;
+            self.for_each_boss(|entry| entry.prune_history(&cutoff));
+        }
+    }
+
+    /// Tweet counts and last-seen times for each language, e.g. for detecting when one of the
+    /// language's keyword tracks has stopped producing tweets
+    pub fn language_stats(&self) -> Vec<LanguageStats> {
+        let bosses = self.bosses();
+        Language::VALUES
+            .iter()
+            .map(|&language| {
+                let last_seen_at = self.language_last_seen_at.get(language);
+                let last_seen_at = if last_seen_at.as_i64() == 0 {
+                    None
+                } else {
+                    Some(last_seen_at.as_datetime())
+                };
+
+                LanguageStats {
+                    language,
+                    tweet_count: bosses
+                        .iter()
+                        .map(|boss| boss.tweet_count.get(language).get())
+                        .sum(),
+                    last_seen_at,
+                }
+            })
+            .collect()
+    }
+
+    pub fn metric_factory(&self) -> &PrometheusMetricFactory {
+        &self.metric_factory
+    }
+
+    /// Marks the server as draining (e.g., before shutting down during a deploy). New websocket
+    /// connections are refused, but existing subscriptions continue.
+    pub fn drain(&self) {
+        self.draining.store(true, AtomicOrdering::Release);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(AtomicOrdering::Acquire)
+    }
+
+    pub fn metrics(&self) -> <PrometheusMetricFactory as MetricFactory>::Output {
+        let mut out = String::new();
+        self.write_metrics(&mut out);
+        out
+    }
+
+    /// Like `metrics`, but appends to `out` while iterating over bosses, without collecting
+    /// per-boss metrics first
+    pub fn write_metrics(&self, out: &mut String) {
+        self.metric_factory
+            .bosses_by_language_gauge()
+            .set_from(|f| {
+                self.for_each_boss(|boss| {
+                    boss.subscriber_count.set(boss.broadcast.receiver_count());
+                    f(&boss.boss.name)
+                })
+            });
+
+        self.metric_factory.write_metrics(
+            out,
+            |f| self.for_each_boss(|boss| f(&boss.tweet_count)),
+            |f| self.for_each_boss(|boss| f(&boss.subscriber_count)),
+        );
+    }
+
+    pub fn metrics_json(&self) -> JsonMetrics {
+        let bosses = self
+            .bosses()
+            .iter()
+            .map(|boss| {
+                boss.subscriber_count.set(boss.broadcast.receiver_count());
+                JsonBossMetrics {
+                    name: boss.boss.name.clone(),
+                    tweets_total: JsonLangCount {
+                        ja: boss.tweet_count.get(Language::Japanese).get(),
+                        en: boss.tweet_count.get(Language::English).get(),
+                    },
+                    subscriptions: boss.subscriber_count.get(),
+                    has_image_hash: boss.boss.image_hash.is_some(),
+                }
+            })
+            .collect();
+
+        JsonMetrics {
+            websocket_connections: self.metric_factory.websocket_connections_gauge().get(),
+            subscriber_lagged_total: self.metric_factory.subscriber_lagged_counter().get(),
+            image_hash_pending: self.metric_factory.image_hash_pending_gauge().get(),
+            graphql_requests_total: self.metric_factory.graphql_requests_total(),
+            graphql_errors_total: self.metric_factory.graphql_errors_counter().get(),
+            bosses,
+        }
+    }
 
     pub fn update_image_hash(&self, boss_name: &BossName, image_hash: ImageHash) {
         let guard = match self.bosses.get(boss_name) {
@@ -360,78 +1552,233 @@ impl RaidHandlerInner {
 
         let boss_entry = guard.value();
 
-        let this_boss = &boss_entry.boss;
-
-        if this_boss.image_hash.is_some() {
+        if boss_entry.boss.image_hash.is_some() {
             return; // Do nothing, it's already set
         }
 
-        let is_japanese = this_boss.name.ja.is_some();
+        self.mark_changed();
+        if !self.merge_by_image_hash(boss_entry, image_hash) {
+            let mut new_entry = BossEntry::clone(boss_entry);
+            new_entry.boss.image_hash = Some(image_hash);
+            let new_entry = Arc::new(new_entry);
+            self.bosses.insert(&new_entry);
+            self.send_event(HandlerEvent::ImageHashComputed {
+                name: new_entry.boss.name.clone(),
+                image_hash,
+            });
+
+            let _ = self
+                .boss_broadcast
+                .send(BossEvent::ImageHashUpdated(Arc::downgrade(&new_entry)));
+        }
+    }
 
-        let matching_entry_opt = self.bosses.find(|item| {
-            let value = item.value();
-            let other_boss = &value.boss;
+    // Merges the boss with a boss in the other language that has the same image hash and level,
+    // if there is one. Returns whether a match was found.
+    fn merge_by_image_hash(&self, boss_entry: &Arc<BossEntry>, image_hash: ImageHash) -> bool {
+        let this_boss = &boss_entry.boss;
 
-            other_boss.image_hash == Some(image_hash)
-                && other_boss.level == this_boss.level
-                && other_boss.name != this_boss.name
-        });
+        let matching_entry_opt = self
+            .bosses
+            .find(|item| {
+                let value = item.value();
+                let other_boss = &value.boss;
+
+                other_boss.image_hash == Some(image_hash)
+                    && other_boss.level == this_boss.level
+                    && other_boss.name != this_boss.name
+            })
+            .or_else(|| {
+                if self.merge.ignore_level {
+                    self.find_translation_ignoring_level(this_boss, image_hash)
+                } else {
+                    None
+                }
+            });
 
         if let Some(matching_entry) = matching_entry_opt {
-            let other_entry = matching_entry.value();
+            let new_entry = self.merge_entries(
+                boss_entry,
+                matching_entry.value(),
+                MergeSource::ImageHash(image_hash),
+            );
 
-            // Merge the two entries, keeping values from the Japanese one
-            let (entry_to_keep, entry_to_discard) = if is_japanese {
-                (boss_entry, other_entry)
-            } else {
-                (other_entry, boss_entry)
-            };
+            let _ = self
+                .boss_broadcast
+                .send(BossEvent::ImageHashUpdated(Arc::downgrade(&new_entry)));
 
-            let mut merged_boss = Boss::clone(&entry_to_keep.boss);
-            merged_boss.name = entry_to_keep.boss.name.merge(&entry_to_discard.boss.name);
-            merged_boss.image = entry_to_keep.boss.image.merge(&entry_to_discard.boss.image);
-            merged_boss.image_hash = Some(image_hash);
-            merged_boss.last_seen_at = std::cmp::max(
-                entry_to_keep.boss.last_seen_at.clone(),
-                entry_to_discard.boss.last_seen_at.clone(),
-            );
+            true
+        } else {
+            false
+        }
+    }
 
-            let mut new_history = CircularQueue::with_capacity(self.history_size);
-            let mut combined_history = entry_to_discard
-                .history
-                .read()
-                .asc_iter()
-                .cloned()
-                .collect::<Vec<_>>();
-            combined_history.extend(entry_to_keep.history.read().asc_iter().cloned());
-            combined_history.sort_by_key(|raid| *raid.created_at.as_datetime());
-            combined_history
-                .drain(..)
-                .for_each(|raid| new_history.push(raid));
+    // Levels are ignored here, so to avoid false merges, both bosses must be untranslated and in
+    // different languages, and there must be only one candidate
+    fn find_translation_ignoring_level(
+        &self,
+        this_boss: &Boss,
+        image_hash: ImageHash,
+    ) -> Option<ElementGuard<CachedString, Arc<BossEntry>>> {
+        let is_untranslated_pair = |a: &LangString, b: &LangString| {
+            a.ja.is_some() && a.en.is_none() && b.ja.is_none() && b.en.is_some()
+        };
 
-            let new_entry = Arc::new(BossEntry {
-                node_id: NodeId::from_boss_name(&merged_boss.name).to_string().into(),
-                history: RwLock::new(new_history),
-                broadcast: entry_to_keep.broadcast.clone(),
-                tweet_count: self.metric_factory.boss_tweets_counter(&merged_boss.name),
-                subscriber_count: self
-                    .metric_factory
-                    .boss_subscriptions_gauge(&merged_boss.name),
-                boss: merged_boss,
+        let mut candidates = self.bosses.filter(|item| {
+            let other_boss = &item.value().boss;
+
+            other_boss.image_hash == Some(image_hash)
+                && (is_untranslated_pair(&this_boss.name, &other_boss.name)
+                    || is_untranslated_pair(&other_boss.name, &this_boss.name))
+        });
+
+        match (candidates.next(), candidates.next()) {
+            (Some(candidate), None) => Some(candidate),
+            _ => None,
+        }
+    }
+
+    /// Merges bosses that have no image in either language with a boss in the other language
+    /// that has the same level and a similar name (see `model::is_similar_boss_name`), since
+    /// they can't be matched by image hash. Returns the number of bosses merged.
+    pub fn merge_by_name(&self) -> usize {
+        let is_candidate = |boss: &Boss| {
+            boss.image_hash.is_none()
+                && boss.image.canonical().is_none()
+                && boss.level.is_some()
+                && !boss.is_known_boss()
+        };
+
+        let (japanese, english): (Vec<_>, Vec<_>) = self
+            .bosses()
+            .iter()
+            .filter(|entry| is_candidate(&entry.boss))
+            .filter(|entry| entry.boss.name.ja.is_none() != entry.boss.name.en.is_none())
+            .cloned()
+            .partition(|entry| entry.boss.name.ja.is_some());
+
+        let mut merged = 0;
+        let mut english = english;
+        for ja_entry in japanese {
+            let ja_name = ja_entry.boss.name.ja.as_deref().unwrap_or("");
+            let position = english.iter().position(|en_entry| {
+                let en_name = en_entry.boss.name.en.as_deref().unwrap_or("");
+                en_entry.boss.level == ja_entry.boss.level && is_similar_boss_name(ja_name, en_name)
             });
 
-            self.bosses.insert(&new_entry);
+            if let Some(position) = position {
+                let en_entry = english.swap_remove(position);
+                self.merge_entries(&ja_entry, &en_entry, MergeSource::Name);
+                merged += 1;
+            }
+        }
+
+        if merged > 0 {
+            self.mark_changed();
+        }
+        merged
+    }
 
-            let _ = self.boss_broadcast.send(Arc::downgrade(&new_entry));
+    // Merges the entries for a boss in each language into one, keeping values from the Japanese
+    // one, and returns the merged entry
+    fn merge_entries(
+        &self,
+        boss_entry: &Arc<BossEntry>,
+        other_entry: &Arc<BossEntry>,
+        source: MergeSource,
+    ) -> Arc<BossEntry> {
+        let (entry_to_keep, entry_to_discard) = if boss_entry.boss.name.ja.is_some() {
+            (boss_entry, other_entry)
         } else {
-            let mut new_entry = BossEntry::clone(boss_entry);
-            new_entry.boss.image_hash = Some(image_hash);
-            self.bosses.insert(&Arc::new(new_entry));
+            (other_entry, boss_entry)
+        };
+
+        let mut merged_boss = Boss::clone(&entry_to_keep.boss);
+        merged_boss.name = entry_to_keep.boss.name.merge(&entry_to_discard.boss.name);
+        merged_boss.element = Element::for_boss_name(&merged_boss.name);
+        merged_boss.image = entry_to_keep.boss.image.merge(&entry_to_discard.boss.image);
+        match source {
+            MergeSource::ImageHash(image_hash) => {
+                merged_boss.image_hash = Some(image_hash);
+                merged_boss.merged_image_hash = Some(image_hash);
+            }
+            MergeSource::Name => merged_boss.merged_by_name = true,
+            MergeSource::KnownBoss => {}
+        }
+        merged_boss.first_seen_at = std::cmp::min(
+            entry_to_keep.boss.first_seen_at.clone(),
+            entry_to_discard.boss.first_seen_at.clone(),
+        );
+        merged_boss.last_seen_at = std::cmp::max(
+            entry_to_keep.boss.last_seen_at.clone(),
+            entry_to_discard.boss.last_seen_at.clone(),
+        );
+
+        let mut new_history = CircularQueue::with_capacity(self.history_size);
+        let mut combined_history = entry_to_discard
+            .history
+            .read()
+            .asc_iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        combined_history.extend(entry_to_keep.history.read().asc_iter().cloned());
+        // Break ties by tweet ID, so the order doesn't depend on which entry was kept
+        combined_history.sort_by_key(|raid| (*raid.created_at.as_datetime(), raid.tweet_id));
+
+        // The same raid may have been tweeted in both languages. If so, keep the earliest.
+        if self.merge.dedupe_history {
+            let mut seen = HashSet::new();
+            combined_history.retain(|raid| seen.insert(raid.id.clone()));
         }
+        combined_history
+            .drain(..)
+            .for_each(|raid| new_history.push(raid));
+
+        let new_entry = Arc::new(BossEntry {
+            node_id: NodeId::from_boss_name(&merged_boss.name, self.bosses.canonical_language)
+                .to_string()
+                .into(),
+            history: RwLock::new(new_history),
+            broadcast: entry_to_keep.broadcast.clone(),
+            broadcast_limiter: entry_to_keep.broadcast_limiter.clone(),
+            history_sampler: entry_to_keep.history_sampler.clone(),
+            tweet_count: self.metric_factory.boss_tweets_counter(&merged_boss.name),
+            subscriber_count: self
+                .metric_factory
+                .boss_subscriptions_gauge(&merged_boss.name),
+            boss: merged_boss,
+        });
+
+        self.bosses.insert(&new_entry);
+        self.send_event(HandlerEvent::BossMerged {
+            name: new_entry.boss.name.clone(),
+            image_hash: new_entry.boss.merged_image_hash,
+        });
+
+        let _ = self
+            .boss_broadcast
+            .send(BossEvent::Updated(Arc::downgrade(&new_entry)));
+
+        new_entry
     }
 
     pub fn push(&self, raid: Raid) {
-        if let Some(guard) = self.bosses.get(&raid.boss_name) {
+        let existing = self.bosses.get(&raid.boss_name);
+        if !self
+            .filter
+            .allows(&raid, existing.as_ref().map(|guard| &guard.value().boss))
+        {
+            return;
+        }
+
+        // Even for existing bosses, `last_seen_at` changes
+        self.mark_changed();
+
+        self.language_last_seen_at
+            .get(raid.language)
+            .replace(raid.created_at.as_datetime());
+
+        if let Some(guard) = existing {
             let entry = guard.value();
 
             entry
@@ -442,8 +1789,15 @@ impl RaidHandlerInner {
             let raid = Arc::new(raid);
 
             // Broadcast the raid to all listeners of this boss and update history
-            let _ = entry.broadcast.send(raid.clone());
-            entry.history.write().push(raid.clone());
+            if entry.broadcast(raid.clone()) {
+                let _ = self.raid_broadcast.send(raid.clone());
+            }
+            if entry.should_record(Instant::now()) {
+                entry.history.write().push(raid.clone());
+                if let Some(max_age) = self.history_max_age {
+                    entry.prune_history(&(chrono::Utc::now() - max_age));
+                }
+            }
 
             // Update metrics
             entry.tweet_count.get(raid.language).inc();
@@ -459,11 +1813,25 @@ impl RaidHandlerInner {
                 let new_entry = Arc::new(new_entry);
 
                 self.bosses.insert(&new_entry);
-                let _ = self.boss_broadcast.send(Arc::downgrade(&new_entry));
+                let _ = self
+                    .boss_broadcast
+                    .send(BossEvent::Updated(Arc::downgrade(&new_entry)));
             }
         } else {
-            let entry = self.bosses.new_entry_from_raid(&self.metric_factory, raid);
-            let _ = self.boss_broadcast.send(Arc::downgrade(&entry));
+            let raid = Arc::new(raid);
+            let entry = self
+                .bosses
+                .new_entry_from_raid(&self.metric_factory, raid.clone());
+
+            self.send_event(HandlerEvent::BossCreated {
+                name: entry.boss.name.clone(),
+            });
+
+            // The first raid for a boss is never rate limited
+            let _ = self.raid_broadcast.send(raid);
+            let _ = self
+                .boss_broadcast
+                .send(BossEvent::Updated(Arc::downgrade(&entry)));
         }
     }
 }
@@ -471,7 +1839,7 @@ impl RaidHandlerInner {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::model::{LangString, Language};
+    use crate::model::Region;
     use chrono::offset::TimeZone;
     use chrono::Utc;
     use futures::stream::StreamExt;
@@ -495,6 +1863,27 @@ mod test {
             .collect()
     }
 
+    fn raid(tweet_id: TweetId, language: Language, seconds: i64) -> Raid {
+        Raid {
+            id: tweet_id.to_string(),
+            tweet_id,
+            user_name: "walfieee".into(),
+            user_image: None,
+            boss_name: match language {
+                Language::Japanese => BOSS_NAME_JA.clone(),
+                Language::English => BOSS_NAME_EN.clone(),
+            },
+            created_at: (Utc.ymd(2020, 5, 20).and_hms(1, 2, 3)
+                + chrono::Duration::seconds(seconds))
+            .into(),
+            text: None,
+            language,
+            region: language.into(),
+            image_url: None,
+            extra_image_urls: Default::default(),
+        }
+    }
+
     #[tokio::test]
     async fn scenario() {
         use Language::{English, Japanese};
@@ -503,8 +1892,16 @@ mod test {
         let broadcast_capacity = 10;
         let metric_factory = PrometheusMetricFactory::new("petronel".to_owned());
 
-        let handler =
-            RaidHandler::new(metric_factory, Vec::new(), history_size, broadcast_capacity);
+        let handler = RaidHandler::new(
+            metric_factory,
+            Vec::new(),
+            HistoryLimit::new(history_size),
+            broadcast_capacity,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
 
         let mut subscriber_ja = handler.subscribe(BOSS_NAME_JA.clone());
         let mut subscriber_en = handler.subscribe(BOSS_NAME_EN.clone());
@@ -517,6 +1914,7 @@ mod test {
             raid.created_at =
                 (raid.created_at.as_datetime().clone() + chrono::Duration::seconds(1)).into();
             raid.language = language;
+            raid.region = language.into();
             raid.boss_name = match language {
                 Japanese => BOSS_NAME_JA.clone(),
                 English => BOSS_NAME_EN.clone(),
@@ -533,7 +1931,9 @@ mod test {
             created_at: Utc.ymd(2020, 5, 20).and_hms(1, 2, 3).into(),
             text: Some("Help".into()),
             language: Language::Japanese,
+            region: Region::Jp,
             image_url: None,
+            extra_image_urls: Default::default(),
         };
 
         assert!(handler.boss(&BOSS_NAME_JA).is_none());
@@ -585,8 +1985,8 @@ mod test {
         );
 
         // Merge the two bosses. The history should be merged, as well as the boss entries and broadcast.
-        handler.update_image_hash(&BOSS_NAME_EN, ImageHash(123));
-        handler.update_image_hash(&BOSS_NAME_JA, ImageHash(123));
+        handler.update_image_hash(&BOSS_NAME_EN, ImageHash::from(123));
+        handler.update_image_hash(&BOSS_NAME_JA, ImageHash::from(123));
 
         let expected_boss = Boss {
             name: LangString {
@@ -597,7 +1997,9 @@ mod test {
                 en: raid4.image_url.as_ref().cloned(),
                 ja: raid1.image_url.as_ref().cloned(),
             },
-            image_hash: Some(ImageHash(123)),
+            image_hash: Some(ImageHash::from(123)),
+            merged_image_hash: Some(ImageHash::from(123)),
+            first_seen_at: raid1.created_at.as_datetime().into(),
             ..Boss::from(&raid4)
         };
         assert_eq!(
@@ -642,4 +2044,1453 @@ mod test {
         assert_eq!(subscriber_ja.next().await, expected);
         assert_eq!(subscriber_ja2.next().await, expected);
     }
+
+    #[tokio::test]
+    async fn broadcast_rate_limit() {
+        use futures::FutureExt;
+
+        let metric_factory = PrometheusMetricFactory::new("petronel".to_owned());
+        let handler = RaidHandler::new(
+            metric_factory,
+            Vec::new(),
+            HistoryLimit::new(10),
+            10,
+            Some(3),
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        let mut subscriber = handler.subscribe(BOSS_NAME_JA.clone());
+
+        let raids = (1..=10)
+            .map(|id| raid(id, Language::Japanese, id as i64))
+            .collect::<Vec<_>>();
+
+        raids.iter().cloned().for_each(|raid| handler.push(raid));
+
+        // Only the first few raids in the burst should be broadcast
+        for raid in raids.iter().take(3) {
+            assert_eq!(subscriber.next().await, Some(Arc::new(raid.clone())));
+        }
+        assert!(subscriber.next().now_or_never().is_none());
+
+        // ...but all of them should still be recorded in history
+        assert_eq!(get_history(&handler, &BOSS_NAME_JA).len(), raids.len());
+    }
+
+    #[tokio::test]
+    async fn set_broadcast_capacity() {
+        use futures::FutureExt;
+
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(10),
+            2,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        let lagged = || handler.metric_factory().subscriber_lagged_counter().get();
+
+        assert!(!handler
+            .set_broadcast_capacity_for(&BOSS_NAME_JA, 20)
+            .unwrap());
+        handler.push(raid(0, Language::Japanese, 0));
+        let mut subscriber = handler.subscribe(BOSS_NAME_JA.clone());
+
+        let burst = |start: u64| {
+            (start..start + 10)
+                .map(|id| raid(id, Language::Japanese, id as i64))
+                .collect::<Vec<_>>()
+        };
+
+        // With the initial capacity, the subscriber lags and only receives the last 2 raids
+        let raids = burst(1);
+        raids.iter().cloned().for_each(|raid| handler.push(raid));
+        for raid in &raids[8..] {
+            assert_eq!(subscriber.next().await, Some(Arc::new(raid.clone())));
+        }
+        assert_eq!(lagged(), 8);
+
+        // Holding onto the entry doesn't keep the old channel open
+        let entry = handler.boss(&BOSS_NAME_JA).unwrap();
+        assert!(handler
+            .set_broadcast_capacity_for(&BOSS_NAME_JA, 20)
+            .unwrap());
+        assert!(handler
+            .set_broadcast_capacity_for(&BOSS_NAME_JA, 0)
+            .is_err());
+        assert!(Arc::ptr_eq(&entry, &handler.boss(&BOSS_NAME_JA).unwrap()));
+
+        // Polling causes the subscriber to resubscribe, since the old channel was closed
+        assert!(subscriber.next().now_or_never().is_none());
+
+        // With the increased capacity, the subscriber receives the whole burst
+        let raids = burst(11);
+        raids.iter().cloned().for_each(|raid| handler.push(raid));
+        for raid in &raids {
+            assert_eq!(subscriber.next().await, Some(Arc::new(raid.clone())));
+        }
+        assert_eq!(lagged(), 8);
+
+        // History and other state are kept
+        assert_eq!(get_history(&handler, &BOSS_NAME_JA).len(), 10);
+    }
+
+    #[tokio::test]
+    async fn subscribe_updates_lagged() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(10),
+            2,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        handler.push(raid(0, Language::Japanese, 0));
+        let mut subscriber = handler.subscribe_updates(BOSS_NAME_JA.clone());
+
+        let raids = (1..=10)
+            .map(|id| raid(id, Language::Japanese, id as i64))
+            .collect::<Vec<_>>();
+        raids.iter().cloned().for_each(|raid| handler.push(raid));
+
+        // The subscriber is notified of the missed raids before receiving the rest
+        assert_eq!(subscriber.next().await, Some(RaidUpdate::Lagged(8)));
+        for raid in &raids[8..] {
+            assert_eq!(
+                subscriber.next().await,
+                Some(RaidUpdate::Raid(Arc::new(raid.clone())))
+            );
+        }
+        assert_eq!(
+            handler.metric_factory().subscriber_lagged_counter().get(),
+            8
+        );
+    }
+
+    #[test]
+    fn rate_limiter_refill() {
+        let limiter = RateLimiter::new(2);
+        let now = std::time::Instant::now();
+
+        assert!(limiter.try_acquire(now));
+        assert!(limiter.try_acquire(now));
+        assert!(!limiter.try_acquire(now));
+
+        // Tokens are refilled over time, but never above the limit
+        let later = now + std::time::Duration::from_millis(500);
+        assert!(limiter.try_acquire(later));
+        assert!(!limiter.try_acquire(later));
+
+        let much_later = now + std::time::Duration::from_secs(60);
+        assert!(limiter.try_acquire(much_later));
+        assert!(limiter.try_acquire(much_later));
+        assert!(!limiter.try_acquire(much_later));
+    }
+
+    #[test]
+    fn merged_history_tie_breaker() {
+        let metric_factory = PrometheusMetricFactory::new("petronel".to_owned());
+        let handler = RaidHandler::new(
+            metric_factory,
+            Vec::new(),
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+
+        // Tweets with the same timestamp should be ordered by tweet ID after merging
+        let raid_ja = raid(2, Language::Japanese, 0);
+        let raid_en = raid(1, Language::English, 0);
+        handler.push(raid_ja.clone());
+        handler.push(raid_en.clone());
+
+        handler.update_image_hash(&BOSS_NAME_JA, ImageHash::from(123));
+        handler.update_image_hash(&BOSS_NAME_EN, ImageHash::from(123));
+
+        let expected = vec![Arc::new(raid_ja), Arc::new(raid_en)];
+        assert_eq!(get_history(&handler, &BOSS_NAME_JA), expected);
+        assert_eq!(get_history(&handler, &BOSS_NAME_EN), expected);
+    }
+
+    #[test]
+    fn merged_history_dedupe() {
+        let metric_factory = PrometheusMetricFactory::new("petronel".to_owned());
+        let handler = RaidHandler::new(
+            metric_factory,
+            Vec::new(),
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions {
+                dedupe_history: true,
+                ..Default::default()
+            },
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+
+        // The same battle ID, tweeted in both languages
+        let raid_ja = Raid {
+            id: "ABCD1234".into(),
+            ..raid(1, Language::Japanese, 0)
+        };
+        let raid_en = Raid {
+            id: "ABCD1234".into(),
+            ..raid(2, Language::English, 1)
+        };
+        let raid_other = raid(3, Language::English, 2);
+        handler.push(raid_ja.clone());
+        handler.push(raid_en.clone());
+        handler.push(raid_other.clone());
+
+        handler.update_image_hash(&BOSS_NAME_JA, ImageHash::from(123));
+        handler.update_image_hash(&BOSS_NAME_EN, ImageHash::from(123));
+
+        let expected = vec![Arc::new(raid_other), Arc::new(raid_ja)];
+        assert_eq!(get_history(&handler, &BOSS_NAME_JA), expected);
+        assert_eq!(get_history(&handler, &BOSS_NAME_EN), expected);
+    }
+
+    #[test]
+    fn recent_raids() {
+        let metric_factory = PrometheusMetricFactory::new("petronel".to_owned());
+        let handler = RaidHandler::new(
+            metric_factory,
+            Vec::new(),
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+
+        let other_boss = |tweet_id, seconds| Raid {
+            boss_name: "Lv100 ジ・オーダー・グランデ".into(),
+            ..raid(tweet_id, Language::Japanese, seconds)
+        };
+
+        let raids = vec![
+            raid(1, Language::Japanese, 0),
+            raid(2, Language::English, 1),
+            other_boss(3, 2),
+            raid(4, Language::Japanese, 3),
+            other_boss(5, 4),
+            other_boss(6, 5),
+            raid(7, Language::English, 6),
+        ];
+        raids.iter().cloned().for_each(|raid| handler.push(raid));
+
+        let tweet_ids = |limit| {
+            handler
+                .recent_raids(limit)
+                .iter()
+                .map(|raid| raid.tweet_id)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(tweet_ids(0), Vec::<TweetId>::new());
+        assert_eq!(tweet_ids(4), vec![7, 6, 5, 4]);
+        assert_eq!(tweet_ids(100), vec![7, 6, 5, 4, 3, 2, 1]);
+    }
+
+    #[test]
+    fn trending_bosses() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+
+        let boss = |name: &str, tweet_id, seconds| Raid {
+            boss_name: name.into(),
+            ..raid(tweet_id, Language::Japanese, seconds)
+        };
+
+        // Lots of old tweets, but only one recent one
+        for tweet_id in 1..=5 {
+            handler.push(boss("Lv100 ジ・オーダー・グランデ", tweet_id, 0));
+        }
+        handler.push(boss("Lv100 ジ・オーダー・グランデ", 6, 100));
+        // Several recent tweets
+        for tweet_id in 7..=9 {
+            handler.push(boss(
+                "Lv150 プロトバハムート",
+                tweet_id,
+                100 + tweet_id as i64,
+            ));
+        }
+        // Tied with the first boss, but lower level
+        handler.push(boss("Lv60 オオゾラッコ", 10, 100));
+        // No recent tweets
+        handler.push(boss("Lv120 メドゥーサ", 11, 0));
+
+        let since = *raid(0, Language::Japanese, 50).created_at.as_datetime();
+        let trending = |limit| {
+            handler
+                .trending_bosses(&since, limit)
+                .into_iter()
+                .map(|(entry, count)| (entry.boss().level, count))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            trending(10),
+            vec![(Some(150), 3), (Some(60), 1), (Some(100), 1)]
+        );
+        assert_eq!(trending(1), vec![(Some(150), 3)]);
+    }
+
+    #[test]
+    fn bosses_sorted_after_many_inserts() {
+        let metric_factory = PrometheusMetricFactory::new("petronel".to_owned());
+        let handler = RaidHandler::new(
+            metric_factory,
+            Vec::new(),
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+
+        for tweet_id in 0..100 {
+            let level = 100 - tweet_id % 10;
+            handler.push(Raid {
+                boss_name: format!("Lv{} Boss {}", level, tweet_id % 3).into(),
+                ..raid(tweet_id, Language::Japanese, tweet_id as i64)
+            });
+        }
+
+        let keys = handler
+            .bosses()
+            .iter()
+            .map(|entry| (entry.boss.level, entry.boss.name.canonical().cloned()))
+            .collect::<Vec<_>>();
+
+        let mut expected = keys.clone();
+        expected.sort();
+        expected.dedup();
+
+        assert_eq!(keys.len(), 30);
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn canonical_language() {
+        let boss = |ja: &str, en: &str| Boss {
+            name: LangString {
+                ja: Some(ja.into()),
+                en: Some(en.into()),
+            },
+            level: Some(60),
+            ..Boss::from(&raid(0, Language::Japanese, 0))
+        };
+        let bosses = vec![
+            boss("Lv60 ABCDEFGH", "Lvl 60 ZZZZZZ"),
+            boss("Lv60 ンンン", "Lvl 60 AAAAAA"),
+        ];
+
+        let sorted = |canonical_language| {
+            let handler = RaidHandler::new(
+                PrometheusMetricFactory::new("petronel".to_owned()),
+                bosses.clone(),
+                HistoryLimit::new(10),
+                10,
+                None,
+                MergeOptions::default(),
+                RaidFilter::default(),
+                canonical_language,
+            );
+
+            handler
+                .bosses()
+                .iter()
+                .map(|entry| {
+                    (
+                        entry.boss.name.en.as_deref().unwrap().to_owned(),
+                        entry.node_id.to_string(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let node_id = |name: &str| NodeId::Boss(std::borrow::Cow::Owned(name.into())).to_string();
+
+        // Names of equal length prefer the canonical language in node IDs
+        assert_eq!(
+            sorted(Language::Japanese),
+            vec![
+                ("Lvl 60 ZZZZZZ".to_owned(), node_id("Lv60 ABCDEFGH")),
+                ("Lvl 60 AAAAAA".to_owned(), node_id("Lvl 60 AAAAAA")),
+            ]
+        );
+        assert_eq!(
+            sorted(Language::English),
+            vec![
+                ("Lvl 60 AAAAAA".to_owned(), node_id("Lvl 60 AAAAAA")),
+                ("Lvl 60 ZZZZZZ".to_owned(), node_id("Lvl 60 ZZZZZZ")),
+            ]
+        );
+    }
+
+    #[test]
+    fn insert_without_resort() {
+        let metric_factory = PrometheusMetricFactory::new("petronel".to_owned());
+        let handler = RaidHandler::new(
+            metric_factory,
+            Vec::new(),
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        let is_dirty = || handler.bosses.vec_dirty.load(AtomicOrdering::Acquire);
+
+        handler.push(raid(1, Language::Japanese, 0));
+        handler.push(raid(2, Language::English, 1));
+        assert_eq!(handler.bosses().len(), 2);
+        assert!(!is_dirty());
+
+        // Repeated raids for existing bosses don't change the list
+        handler.push(raid(3, Language::Japanese, 2));
+        handler.push(raid(4, Language::English, 3));
+        assert!(!is_dirty());
+        assert_eq!(handler.bosses().len(), 2);
+
+        // Adding an image to an existing boss replaces the entry, but doesn't change its position
+        handler.push(Raid {
+            image_url: Some("http://example.com/image.png".into()),
+            ..raid(5, Language::Japanese, 4)
+        });
+        assert!(!is_dirty());
+        assert_eq!(handler.bosses().len(), 2);
+        assert!(handler
+            .bosses()
+            .iter()
+            .any(|entry| entry.boss.image.ja.is_some()));
+
+        // Adding a new boss requires a re-sort
+        handler.push(Raid {
+            boss_name: "Lv100 ジ・オーダー・グランデ".into(),
+            ..raid(6, Language::Japanese, 5)
+        });
+        assert!(is_dirty());
+        assert_eq!(handler.bosses().len(), 3);
+        assert!(!is_dirty());
+    }
+
+    #[test]
+    fn for_each_boss() {
+        let metric_factory = PrometheusMetricFactory::new("petronel".to_owned());
+        let handler = RaidHandler::new(
+            metric_factory,
+            Vec::new(),
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+
+        handler.push(raid(1, Language::Japanese, 0));
+        handler.push(raid(2, Language::English, 1));
+
+        let mut names = Vec::new();
+        handler.for_each_boss(|entry| names.push(entry.boss().name.canonical().cloned()));
+
+        let expected = handler
+            .bosses()
+            .iter()
+            .map(|entry| entry.boss().name.canonical().cloned())
+            .collect::<Vec<_>>();
+
+        assert_eq!(names.len(), 2);
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn write_metrics() {
+        let metric_factory = PrometheusMetricFactory::new("petronel".to_owned());
+        let handler = RaidHandler::new(
+            metric_factory,
+            Vec::new(),
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+
+        handler.push(raid(1, Language::Japanese, 0));
+        handler.push(raid(2, Language::Japanese, 1));
+        handler.push(raid(3, Language::English, 2));
+        let _subscription = handler.subscribe(BOSS_NAME_JA.clone());
+
+        let mut out = String::new();
+        handler.write_metrics(&mut out);
+        assert_eq!(out, handler.metrics());
+        assert!(out.contains(r#"petronel_subscriptions{name_ja="Lv60 オオゾラッコ",name_en=""} 1"#));
+    }
+
+    #[test]
+    fn bosses_by_language_metrics() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        let push = |tweet_id, language, boss_name: &str| {
+            handler.push(Raid {
+                boss_name: boss_name.into(),
+                ..raid(tweet_id, language, tweet_id as i64)
+            })
+        };
+
+        push(1, Language::Japanese, "Lv100 Huanglong");
+        push(2, Language::Japanese, "Lv100 Qilin");
+        push(3, Language::English, "Lvl 100 Huanglong");
+        push(4, Language::English, "Lvl 120 Qilin");
+        push(5, Language::English, "Lvl 150 Qilin");
+        handler.update_image_hash(&"Lv100 Huanglong".into(), ImageHash::from(1));
+        handler.update_image_hash(&"Lvl 100 Huanglong".into(), ImageHash::from(1));
+
+        let metrics = handler.metrics();
+        assert!(metrics.contains(r#"petronel_bosses_by_language{lang="ja"} 1"#));
+        assert!(metrics.contains(r#"petronel_bosses_by_language{lang="en"} 2"#));
+        assert!(metrics.contains(r#"petronel_bosses_by_language{lang="both"} 1"#));
+    }
+
+    #[test]
+    fn metrics_json() {
+        let metric_factory = PrometheusMetricFactory::new("petronel".to_owned());
+        let handler = RaidHandler::new(
+            metric_factory,
+            Vec::new(),
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+
+        handler.push(raid(1, Language::Japanese, 0));
+        handler.push(raid(2, Language::Japanese, 1));
+        let _subscription = handler.subscribe(BOSS_NAME_JA.clone());
+        handler
+            .metric_factory()
+            .websocket_connections_gauge()
+            .set(3);
+
+        let json = serde_json::to_value(handler.metrics_json()).unwrap();
+        let expected = serde_json::json!({
+            "websocketConnections": 3,
+            "subscriberLaggedTotal": 0,
+            "imageHashPending": 0,
+            "graphqlRequestsTotal": 0,
+            "graphqlErrorsTotal": 0,
+            "bosses": [{
+                "name": { "ja": "Lv60 オオゾラッコ" },
+                "tweetsTotal": { "ja": 2, "en": 0 },
+                "subscriptions": 1,
+                "hasImageHash": false
+            }]
+        });
+
+        assert_eq!(json, expected);
+    }
+
+    #[tokio::test]
+    async fn subscribe_with_history() {
+        use futures::FutureExt;
+
+        let metric_factory = PrometheusMetricFactory::new("petronel".to_owned());
+        let handler = RaidHandler::new(
+            metric_factory,
+            Vec::new(),
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+
+        let raids = (1..=5)
+            .map(|id| Arc::new(raid(id, Language::Japanese, id as i64)))
+            .collect::<Vec<_>>();
+        raids[..3]
+            .iter()
+            .for_each(|raid| handler.push(Raid::clone(raid)));
+
+        let mut subscriber = handler.subscribe_with_history(BOSS_NAME_JA.clone(), 2);
+        let mut no_history = handler.subscribe_with_history(BOSS_NAME_JA.clone(), 0);
+        let mut unknown_boss = handler.subscribe_with_history("Unknown".into(), 2);
+
+        raids[3..]
+            .iter()
+            .for_each(|raid| handler.push(Raid::clone(raid)));
+
+        // History is returned oldest first, followed by live raids, with no duplicates
+        for raid in &raids[1..] {
+            assert_eq!(subscriber.next().await.as_ref(), Some(raid));
+        }
+        for raid in &raids[3..] {
+            assert_eq!(no_history.next().await.as_ref(), Some(raid));
+        }
+
+        assert!(subscriber.next().now_or_never().is_none());
+        assert!(unknown_boss.next().now_or_never().is_none());
+    }
+
+    #[tokio::test]
+    async fn clear() {
+        use futures::FutureExt;
+
+        let metric_factory = PrometheusMetricFactory::new("petronel".to_owned());
+        let handler = RaidHandler::new(
+            metric_factory,
+            Boss::known_bosses().to_vec(),
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+
+        handler.push(raid(1, Language::Japanese, 0));
+        handler.push(raid(2, Language::English, 1));
+        assert_eq!(handler.bosses().len(), Boss::known_bosses().len() + 2);
+
+        let mut subscriber = handler.subscribe(BOSS_NAME_JA.clone());
+        let mut boss_updates = handler.subscribe_boss_updates_with_lag();
+
+        handler.clear();
+
+        // The old channel is closed as soon as the boss is removed, without waiting for the boss
+        // list to be read. Polling here lets the subscription notice, and resubscribe.
+        assert!(subscriber.next().now_or_never().is_none());
+
+        // Hardcoded bosses are kept
+        assert_eq!(get_bosses(&handler), Boss::known_bosses().to_vec());
+        assert!(get_history(&handler, &BOSS_NAME_JA).is_empty());
+
+        let mut removed = Vec::new();
+        for _ in 0..2 {
+            match boss_updates.next().await {
+                Some(BossUpdate::Removed(name)) => removed.push(name),
+                other => panic!("unexpected boss update {:?}", other),
+            }
+        }
+        removed.sort_by_key(|name| name.canonical().cloned());
+        assert_eq!(
+            removed,
+            vec![
+                LangString::new(Language::Japanese, BOSS_NAME_JA.clone()),
+                LangString::new(Language::English, BOSS_NAME_EN.clone()),
+            ]
+        );
+
+        // Existing subscriptions should continue to work after the boss is re-added
+        let raid3 = raid(3, Language::Japanese, 2);
+        handler.push(raid3.clone());
+        assert_eq!(subscriber.next().await, Some(Arc::new(raid3)));
+    }
+
+    #[test]
+    fn min_level_filter() {
+        let metric_factory = PrometheusMetricFactory::new("petronel".to_owned());
+        let filter = RaidFilter {
+            min_level: Some(100),
+            ..RaidFilter::default()
+        };
+        let handler = RaidHandler::new(
+            metric_factory,
+            Vec::new(),
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            filter,
+            Language::Japanese,
+        );
+
+        let with_name = |tweet_id, name: &str| Raid {
+            boss_name: name.into(),
+            ..raid(tweet_id, Language::Japanese, 0)
+        };
+
+        handler.push(with_name(1, "Lv60 オオゾラッコ"));
+        handler.push(with_name(2, "Lv100 ジ・オーダー・グランデ"));
+        handler.push(with_name(3, "Lv120 メドゥーサ"));
+        handler.push(with_name(4, "Huanglong & Qilin (Impossible)"));
+
+        let names = handler
+            .bosses()
+            .iter()
+            .flat_map(|entry| entry.boss.name.canonical().cloned())
+            .collect::<Vec<_>>();
+
+        let expected: Vec<BossName> = vec![
+            "Huanglong & Qilin (Impossible)".into(),
+            "Lv100 ジ・オーダー・グランデ".into(),
+            "Lv120 メドゥーサ".into(),
+        ];
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn allow_deny_filter() {
+        let known_boss = Boss {
+            name: LangString {
+                ja: Some(BOSS_NAME_JA.clone()),
+                en: Some(BOSS_NAME_EN.clone()),
+            },
+            ..Boss::from(&raid(0, Language::Japanese, 0))
+        };
+        let other_boss = |tweet_id| Raid {
+            boss_name: "Lv100 ジ・オーダー・グランデ".into(),
+            ..raid(tweet_id, Language::Japanese, 0)
+        };
+
+        let handler_with_filter = |filter| {
+            let metric_factory = PrometheusMetricFactory::new("petronel".to_owned());
+            let bosses = vec![known_boss.clone()];
+            RaidHandler::new(
+                metric_factory,
+                bosses,
+                HistoryLimit::new(10),
+                10,
+                None,
+                MergeOptions::default(),
+                filter,
+                Language::Japanese,
+            )
+        };
+
+        // The allowlist only contains the Japanese name, but English tweets for the
+        // same boss are allowed, since the boss is known by both names
+        let handler = handler_with_filter(RaidFilter {
+            allow: vec![BOSS_NAME_JA.clone()].into_iter().collect(),
+            ..RaidFilter::default()
+        });
+        handler.push(raid(1, Language::English, 1));
+        handler.push(other_boss(2));
+        assert_eq!(get_history(&handler, &BOSS_NAME_EN).len(), 1);
+        assert_eq!(handler.bosses().len(), 1);
+
+        // Likewise, denying the Japanese name also denies English tweets
+        let handler = handler_with_filter(RaidFilter {
+            deny: vec![BOSS_NAME_JA.clone()].into_iter().collect(),
+            ..RaidFilter::default()
+        });
+        handler.push(raid(1, Language::English, 1));
+        handler.push(other_boss(2));
+        assert!(get_history(&handler, &BOSS_NAME_EN).is_empty());
+        assert_eq!(handler.bosses().len(), 2);
+
+        // Unknown bosses are matched only by the name in the tweet
+        let handler = handler_with_filter(RaidFilter {
+            deny: vec!["Lv100 ジ・オーダー・グランデ".into()]
+                .into_iter()
+                .collect(),
+            ..RaidFilter::default()
+        });
+        handler.push(other_boss(1));
+        assert_eq!(handler.bosses().len(), 1);
+    }
+
+    #[test]
+    fn max_age_filter() {
+        let metric_factory = PrometheusMetricFactory::new("petronel".to_owned());
+        let filter = RaidFilter {
+            max_age: Some(chrono::Duration::hours(1)),
+            ..RaidFilter::default()
+        };
+        let handler = RaidHandler::new(
+            metric_factory,
+            Vec::new(),
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            filter,
+            Language::Japanese,
+        );
+
+        // Created long before the max age
+        handler.push(raid(1, Language::Japanese, 0));
+        assert!(handler.bosses().is_empty());
+
+        let recent = Raid {
+            created_at: Utc::now().into(),
+            ..raid(2, Language::Japanese, 0)
+        };
+        handler.push(recent);
+        let history = get_history(&handler, &BOSS_NAME_JA);
+        assert_eq!(
+            history.iter().map(|raid| raid.tweet_id).collect::<Vec<_>>(),
+            vec![2]
+        );
+    }
+
+    #[tokio::test]
+    async fn image_hash_updates() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        let mut updates = handler.subscribe_image_hash_updates();
+
+        handler.push(raid(1, Language::Japanese, 0));
+        handler.push(raid(2, Language::English, 1));
+        handler.update_image_hash(&BOSS_NAME_EN, ImageHash::from(123));
+        handler.update_image_hash(&BOSS_NAME_JA, ImageHash::from(123));
+
+        // The first boss to be hashed has nothing to merge with yet
+        let boss = updates.next().await.unwrap().boss.clone();
+        assert_eq!(boss.name.en, Some(BOSS_NAME_EN.clone()));
+        assert_eq!(boss.name.ja, None);
+        assert_eq!(boss.image_hash, Some(ImageHash::from(123)));
+
+        let boss = updates.next().await.unwrap().boss.clone();
+        assert_eq!(boss.name.en, Some(BOSS_NAME_EN.clone()));
+        assert_eq!(boss.name.ja, Some(BOSS_NAME_JA.clone()));
+        assert_eq!(boss.image_hash, Some(ImageHash::from(123)));
+    }
+
+    #[test]
+    fn history_max_age() {
+        let metric_factory = PrometheusMetricFactory::new("petronel".to_owned());
+        let history = HistoryLimit {
+            max_age: Some(chrono::Duration::hours(1)),
+            ..HistoryLimit::new(10)
+        };
+        let handler = RaidHandler::new(
+            metric_factory,
+            Vec::new(),
+            history,
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+
+        let created_ago = |tweet_id, minutes| Raid {
+            created_at: (Utc::now() - chrono::Duration::minutes(minutes)).into(),
+            ..raid(tweet_id, Language::Japanese, 0)
+        };
+        let history_ids = |handler: &RaidHandler| {
+            get_history(handler, &BOSS_NAME_JA)
+                .iter()
+                .map(|raid| raid.tweet_id)
+                .collect::<Vec<_>>()
+        };
+
+        // Raid 1 is pruned once the boss receives another raid
+        handler.push(created_ago(1, 90));
+        handler.push(created_ago(2, 30));
+        handler.push(created_ago(3, 10));
+        assert_eq!(history_ids(&handler), vec![3, 2]);
+
+        // Pruning without a push
+        handler
+            .boss(&BOSS_NAME_JA)
+            .unwrap()
+            .history()
+            .write()
+            .push(Arc::new(created_ago(4, 120)));
+        assert_eq!(history_ids(&handler), vec![4, 3, 2]);
+        handler.prune_histories();
+        assert_eq!(history_ids(&handler), vec![3, 2]);
+    }
+
+    #[test]
+    fn from_snapshot() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        for tweet_id in 1..=3 {
+            handler.push(raid(tweet_id, Language::Japanese, tweet_id as i64));
+        }
+        handler.push(raid(4, Language::English, 4));
+
+        let snapshot = handler.snapshot();
+        assert_eq!(snapshot.len(), 2);
+
+        // Restored histories are subject to the new history size
+        let restored = RaidHandler::from_snapshot(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            snapshot,
+            HistoryLimit::new(2),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+
+        assert_eq!(get_bosses(&restored), get_bosses(&handler));
+        assert_eq!(
+            get_history(&restored, &BOSS_NAME_JA),
+            vec![
+                Arc::new(raid(3, Language::Japanese, 3)),
+                Arc::new(raid(2, Language::Japanese, 2)),
+            ]
+        );
+        assert_eq!(
+            get_history(&restored, &BOSS_NAME_EN),
+            vec![Arc::new(raid(4, Language::English, 4))]
+        );
+
+        // New raids are appended to the restored history
+        restored.push(raid(5, Language::Japanese, 5));
+        let ids = get_history(&restored, &BOSS_NAME_JA)
+            .iter()
+            .map(|raid| raid.tweet_id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![5, 3]);
+    }
+
+    #[test]
+    fn import() {
+        use futures::FutureExt;
+
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        handler.push(raid(1, Language::Japanese, 1));
+        handler.push(raid(2, Language::English, 2));
+        let mut boss_updates = handler.subscribe_boss_updates();
+
+        let other = handler.snapshot();
+        let source = RaidHandler::from_snapshot(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            other,
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        // Seen more recently in the other instance, and translated there
+        source.push(raid(3, Language::Japanese, 3));
+        source.update_image_hash(&BOSS_NAME_JA, ImageHash::from(123));
+        source.update_image_hash(&BOSS_NAME_EN, ImageHash::from(123));
+        let mut snapshot = source.snapshot();
+        // Older than the existing boss, so it's ignored
+        let mut older = BossSnapshot::from(Boss::from(&Raid {
+            boss_name: "Lv100 Huanglong".into(),
+            ..raid(4, Language::Japanese, 0)
+        }));
+        handler.push(Raid {
+            boss_name: "Lv100 Huanglong".into(),
+            ..raid(5, Language::Japanese, 5)
+        });
+        older.boss.image_hash = Some(ImageHash::from(456));
+        snapshot.push(older);
+
+        // Invalid snapshots aren't partially imported
+        let mut invalid = snapshot.clone();
+        invalid[0].history.push(Raid {
+            boss_name: "Lvl 100 Unrelated".into(),
+            ..raid(6, Language::English, 6)
+        });
+        assert!(handler.import(invalid, true).is_err());
+        assert_eq!(get_bosses(&handler).len(), 3);
+
+        assert_eq!(handler.import(snapshot, true).unwrap(), 1);
+        assert_eq!(get_bosses(&handler).len(), 2);
+        let boss = handler.boss(&BOSS_NAME_JA).unwrap().boss.clone();
+        assert_eq!(boss.name.en, Some(BOSS_NAME_EN.clone()));
+        assert_eq!(boss.image_hash, Some(ImageHash::from(123)));
+        let other = handler.boss(&"Lv100 Huanglong".into()).unwrap();
+        assert_eq!(other.boss.image_hash, None);
+
+        // Histories are merged without duplicates
+        let ids = get_history(&handler, &BOSS_NAME_EN)
+            .iter()
+            .map(|raid| raid.tweet_id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![3, 2, 1]);
+
+        // Existing subscribers are notified
+        assert!(boss_updates.next().now_or_never().flatten().is_some());
+    }
+
+    #[test]
+    fn from_snapshot_conflicts() {
+        let new_handler = |bosses: Vec<Boss>| {
+            RaidHandler::new(
+                PrometheusMetricFactory::new("petronel".to_owned()),
+                bosses,
+                HistoryLimit::new(10),
+                10,
+                None,
+                MergeOptions::default(),
+                RaidFilter::default(),
+                Language::Japanese,
+            )
+        };
+
+        let older = Boss::from(&raid(1, Language::Japanese, 1));
+        let mut newer = Boss::from(&raid(2, Language::Japanese, 2));
+        newer
+            .name
+            .set(Language::English, Some(BOSS_NAME_EN.clone()));
+        let unrelated = Boss::from(&Raid {
+            boss_name: "Lv100 ティアマト・マグナ".into(),
+            ..raid(3, Language::Japanese, 0)
+        });
+
+        // The most recently seen boss wins, regardless of input order
+        for bosses in vec![
+            vec![older.clone(), newer.clone(), unrelated.clone()],
+            vec![unrelated.clone(), newer.clone(), older.clone()],
+        ] {
+            let handler = new_handler(bosses);
+            let mut bosses = get_bosses(&handler);
+            bosses.sort_by_key(|boss| boss.last_seen_at.as_i64());
+            assert_eq!(bosses, vec![unrelated.clone(), newer.clone()]);
+            assert!(Arc::ptr_eq(
+                &handler.boss(&BOSS_NAME_JA).unwrap(),
+                &handler.boss(&BOSS_NAME_EN).unwrap()
+            ));
+        }
+
+        // Ties keep whichever came first
+        let tied = Boss {
+            level: Some(61),
+            ..older.clone()
+        };
+        let handler = new_handler(vec![tied.clone(), older.clone()]);
+        assert_eq!(get_bosses(&handler), vec![tied]);
+    }
+
+    #[test]
+    fn boss_ttl_by_level() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        let long_ttl_boss = Raid {
+            boss_name: "Lv150 プロトバハムート".into(),
+            ..raid(2, Language::Japanese, 0)
+        };
+        handler.push(raid(1, Language::Japanese, 0));
+        handler.push(long_ttl_boss.clone());
+
+        let mut ttl = BossTtl::new(chrono::Duration::days(1));
+        ttl.by_level.insert(150, chrono::Duration::days(30));
+
+        // Both bosses were last seen at the same time
+        let now =
+            *raid(0, Language::Japanese, 0).created_at.as_datetime() + chrono::Duration::days(7);
+        assert_eq!(
+            handler.retain(|entry| !ttl.is_expired(entry.boss(), now)),
+            1
+        );
+
+        assert_eq!(get_bosses(&handler), vec![Boss::from(&long_ttl_boss)]);
+    }
+
+    #[test]
+    fn retain_removed_count() {
+        let mut translated = Boss::from(&raid(1, Language::Japanese, 0));
+        translated
+            .name
+            .set(Language::English, Some(BOSS_NAME_EN.clone()));
+        let other = Boss::from(&Raid {
+            boss_name: "Lv150 プロトバハムート".into(),
+            ..raid(2, Language::Japanese, 0)
+        });
+
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            vec![translated, other.clone()],
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+
+        // The translated boss is only counted once, even though it has two names
+        let removed = handler.retain(|entry| entry.boss().level == Some(150));
+        assert_eq!(removed, 1);
+        assert_eq!(get_bosses(&handler), vec![other]);
+
+        assert_eq!(handler.retain(|_| true), 0);
+        assert_eq!(handler.retain(|_| false), 1);
+        assert_eq!(handler.metric_factory().bosses_removed_counter().get(), 2);
+    }
+
+    #[tokio::test]
+    async fn handler_events() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        let mut events = handler.subscribe_events();
+
+        handler.push(raid(1, Language::Japanese, 0));
+        handler.push(raid(2, Language::Japanese, 1)); // Existing boss
+        handler.push(raid(3, Language::English, 2));
+        handler.update_image_hash(&BOSS_NAME_JA, ImageHash::from(123));
+        handler.update_image_hash(&BOSS_NAME_EN, ImageHash::from(123));
+        handler.retain(|_| false);
+
+        let ja = LangString::new(Language::Japanese, BOSS_NAME_JA.clone());
+        let en = LangString::new(Language::English, BOSS_NAME_EN.clone());
+        let merged = ja.merge(&en);
+        let expected = vec![
+            HandlerEvent::BossCreated { name: ja.clone() },
+            HandlerEvent::BossCreated { name: en },
+            HandlerEvent::ImageHashComputed {
+                name: ja,
+                image_hash: ImageHash::from(123),
+            },
+            HandlerEvent::BossMerged {
+                name: merged.clone(),
+                image_hash: Some(ImageHash::from(123)),
+            },
+            HandlerEvent::BossRemoved { name: merged },
+        ];
+        for event in expected {
+            assert_eq!(events.next().await, Some(event));
+        }
+        assert!(events.next().now_or_never().is_none());
+
+        let json = serde_json::to_value(&HandlerEvent::BossMerged {
+            name: LangString::new(Language::English, BOSS_NAME_EN.clone()),
+            image_hash: Some(ImageHash::from(123)),
+        })
+        .unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "type": "bossMerged",
+                "name": { "en": "Lvl 60 Ozorotter" },
+                "imageHash": 123
+            })
+        );
+    }
+
+    #[test]
+    fn merge_by_name() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        let imageless = |tweet_id, language, boss_name: &str| Raid {
+            boss_name: boss_name.into(),
+            ..raid(tweet_id, language, tweet_id as i64)
+        };
+
+        handler.push(imageless(1, Language::Japanese, "Lv100 Huanglong"));
+        handler.push(imageless(2, Language::English, "Lvl 100 Huanglong"));
+        // Different level
+        handler.push(imageless(3, Language::English, "Lvl 120 Huanglong"));
+        // Has an image, so it should be matched by image hash instead
+        handler.push(Raid {
+            boss_name: "Lv100 Qilin".into(),
+            image_url: Some("https://pbs.twimg.com/media/qilin.jpg".into()),
+            ..raid(4, Language::Japanese, 4)
+        });
+        handler.push(imageless(5, Language::English, "Lvl 100 Qilin"));
+
+        assert_eq!(handler.merge_by_name(), 1);
+        assert_eq!(handler.merge_by_name(), 0);
+
+        let merged = handler.boss(&"Lvl 100 Huanglong".into()).unwrap();
+        assert_eq!(merged.boss.name.ja, Some("Lv100 Huanglong".into()));
+        assert_eq!(merged.boss.merge_source(), Some(MergeSource::Name));
+        assert!(Arc::ptr_eq(
+            &merged,
+            &handler.boss(&"Lv100 Huanglong".into()).unwrap()
+        ));
+        assert_eq!(
+            get_history(&handler, &"Lv100 Huanglong".into())
+                .iter()
+                .map(|raid| raid.tweet_id)
+                .collect::<Vec<_>>(),
+            vec![2, 1]
+        );
+
+        let translated = get_bosses(&handler)
+            .into_iter()
+            .filter(|boss| boss.name.ja.is_some() && boss.name.en.is_some())
+            .count();
+        assert_eq!(translated, 1);
+    }
+
+    #[test]
+    fn merge_ignoring_level() {
+        let run = |ignore_level| {
+            let handler = RaidHandler::new(
+                PrometheusMetricFactory::new("petronel".to_owned()),
+                Vec::new(),
+                HistoryLimit::new(10),
+                10,
+                None,
+                MergeOptions {
+                    ignore_level,
+                    ..Default::default()
+                },
+                RaidFilter::default(),
+                Language::Japanese,
+            );
+            let push = |tweet_id, language, boss_name: &str| {
+                handler.push(Raid {
+                    boss_name: boss_name.into(),
+                    ..raid(tweet_id, language, tweet_id as i64)
+                })
+            };
+
+            push(1, Language::Japanese, "Lv100 Huanglong");
+            push(2, Language::English, "Lvl 120 Huanglong");
+            handler.update_image_hash(&"Lv100 Huanglong".into(), ImageHash::from(1));
+            handler.update_image_hash(&"Lvl 120 Huanglong".into(), ImageHash::from(1));
+
+            // Ambiguous, since two English bosses share the Japanese boss's image
+            push(3, Language::English, "Lvl 100 Qilin");
+            push(4, Language::English, "Lvl 120 Qilin");
+            push(5, Language::Japanese, "Lv150 Qilin");
+            handler.update_image_hash(&"Lvl 100 Qilin".into(), ImageHash::from(2));
+            handler.update_image_hash(&"Lvl 120 Qilin".into(), ImageHash::from(2));
+            handler.update_image_hash(&"Lv150 Qilin".into(), ImageHash::from(2));
+
+            handler
+        };
+
+        let handler = run(false);
+        assert_eq!(get_bosses(&handler).len(), 5);
+
+        let handler = run(true);
+        assert_eq!(get_bosses(&handler).len(), 4);
+        let merged = handler.boss(&"Lvl 120 Huanglong".into()).unwrap();
+        assert_eq!(merged.boss.name.ja, Some("Lv100 Huanglong".into()));
+        assert_eq!(
+            merged.boss.merge_source(),
+            Some(MergeSource::ImageHash(ImageHash::from(1)))
+        );
+        assert!(handler
+            .boss(&"Lv150 Qilin".into())
+            .unwrap()
+            .boss
+            .name
+            .en
+            .is_none());
+    }
+
+    #[test]
+    fn history_sampling() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit {
+                sampling: Some(HistorySampling {
+                    max_per_sec: 1,
+                    every: 5,
+                }),
+                ..HistoryLimit::new(100)
+            },
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+
+        // The first raid creates the boss, and is always recorded
+        handler.push(raid(0, Language::Japanese, 0));
+        let initial_count = handler.boss(&BOSS_NAME_JA).unwrap().tweet_count();
+
+        // Simulate a burst of 20 raids at once
+        (1..=20).for_each(|id| handler.push(raid(id, Language::Japanese, 0)));
+
+        // One raid is within the rate limit, and every 5th of the remaining 19 is recorded
+        let history = get_history(&handler, &BOSS_NAME_JA);
+        let ids = history.iter().map(|raid| raid.tweet_id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![17, 12, 7, 2, 1, 0]);
+
+        // All raids are still counted
+        let entry = handler.boss(&BOSS_NAME_JA).unwrap();
+        assert_eq!(entry.tweet_count(), initial_count + 20);
+    }
+
+    #[test]
+    fn generation() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        let mut generation = handler.generation();
+        let mut changed = || {
+            let previous = std::mem::replace(&mut generation, handler.generation());
+            previous != generation
+        };
+
+        // Reads don't count as changes
+        get_bosses(&handler);
+        assert!(!changed());
+
+        handler.push(raid(1, Language::Japanese, 0));
+        assert!(changed());
+        handler.push(raid(2, Language::Japanese, 1));
+        assert!(changed());
+
+        handler.update_image_hash(&BOSS_NAME_JA, ImageHash::from(123));
+        assert!(changed());
+        handler.update_image_hash(&BOSS_NAME_JA, ImageHash::from(456));
+        assert!(!changed());
+
+        assert_eq!(handler.retain(|_| true), 0);
+        assert!(!changed());
+        assert_eq!(handler.retain(|_| false), 1);
+        assert!(changed());
+    }
+
+    #[test]
+    fn merge_persisted_image_hashes() {
+        let with_hash = |raid: &Raid| Boss {
+            image_hash: Some(ImageHash::from(123)),
+            ..Boss::from(raid)
+        };
+        let ja = with_hash(&raid(1, Language::Japanese, 0));
+        let en = with_hash(&raid(2, Language::English, 1));
+
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            vec![en, ja.clone()],
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+
+        // Bosses with matching persisted hashes are merged without rehashing their images
+        let bosses = get_bosses(&handler);
+        assert_eq!(bosses.len(), 1);
+        assert_eq!(bosses[0].name.en, Some(BOSS_NAME_EN.clone()));
+        assert_eq!(bosses[0].name.ja, Some(BOSS_NAME_JA.clone()));
+        assert_eq!(bosses[0].merged_image_hash, Some(ImageHash::from(123)));
+        assert!(!bosses[0].needs_image_hash_update());
+        assert!(Arc::ptr_eq(
+            &handler.boss(&BOSS_NAME_JA).unwrap(),
+            &handler.boss(&BOSS_NAME_EN).unwrap()
+        ));
+    }
+
+    #[tokio::test]
+    async fn normalize_image_urls() {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(10),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+            Language::Japanese,
+        );
+        let with_image = |tweet_id, url: &str| Raid {
+            image_url: Some(url.into()),
+            ..raid(tweet_id, Language::Japanese, 0)
+        };
+        let https_url = "https://pbs.twimg.com/media/CVL2EBHUwAA8nUj.jpg";
+
+        handler.push(with_image(1, https_url));
+        let mut boss_updates = handler.subscribe_boss_updates();
+        handler.push(with_image(
+            2,
+            "http://pbs.twimg.com/media/CVL2EBHUwAA8nUj.jpg",
+        ));
+
+        let entry = handler.boss(&BOSS_NAME_JA).unwrap();
+        assert_eq!(entry.boss().image.ja, Some(https_url.into()));
+        assert!(get_history(&handler, &BOSS_NAME_JA)
+            .iter()
+            .all(|raid| raid.image_url == Some(https_url.into())));
+
+        // The boss's image wasn't updated
+        let timeout = std::time::Duration::from_millis(50);
+        assert!(tokio::time::timeout(timeout, boss_updates.next())
+            .await
+            .is_err());
+    }
 }
@@ -1,6 +1,10 @@
 mod model;
 mod parse;
 mod stream;
+pub mod v2;
+mod verify;
 
-pub use stream::{connect, connect_with_retries};
+pub use parse::TweetSources;
+pub use stream::{connect, connect_with_retries, GzipService};
 pub use twitter_stream::Token;
+pub use verify::verify_credentials;
@@ -1,6 +1,7 @@
 use crate::model::{CachedString, DateTime, TweetId};
 use serde::de::{SeqAccess, Visitor};
 use serde::{Deserialize, Deserializer};
+use smallvec::SmallVec;
 use std::fmt;
 
 #[derive(Deserialize, PartialEq, Debug)]
@@ -16,11 +17,10 @@ pub struct Tweet {
 
 #[derive(Deserialize, PartialEq, Debug)]
 pub struct Entities {
-    // In most cases there should only be one item in the `media` array.
-    // We can avoid allocating a `Vec` by using a custom deserializer
-    // that only cares about one of the media items.
+    // In most cases there should only be one item in the `media` array,
+    // which a `SmallVec` can store without allocating.
     #[serde(default, deserialize_with = "deserialize_media")]
-    pub media: Option<Media>,
+    pub media: SmallVec<[Media; 1]>,
 }
 
 #[derive(Deserialize, PartialEq, Debug)]
@@ -36,14 +36,14 @@ pub struct User {
     pub profile_image_url_https: String,
 }
 
-fn deserialize_media<'de, D>(deserializer: D) -> Result<Option<Media>, D::Error>
+fn deserialize_media<'de, D>(deserializer: D) -> Result<SmallVec<[Media; 1]>, D::Error>
 where
     D: Deserializer<'de>,
 {
     struct MediaVisitor;
 
     impl<'de> Visitor<'de> for MediaVisitor {
-        type Value = Option<Media>;
+        type Value = SmallVec<[Media; 1]>;
 
         fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
             formatter.write_str("an array of URL objects")
@@ -53,10 +53,10 @@ where
         where
             S: SeqAccess<'de>,
         {
-            let mut out = None;
+            let mut out = SmallVec::new();
 
             while let Ok(Some(item)) = seq.next_element() {
-                out = item;
+                out.push(item);
             }
 
             Ok(out)
@@ -146,9 +146,9 @@ mod test {
                         .to_owned(),
             },
             entities: Entities {
-                media: Some(Media {
+                media: smallvec::smallvec![Media {
                     media_url_https: "https://pbs.twimg.com/media/CVL2EBHUwAA8nUj.jpg".into(),
-                }),
+                }],
             },
             source:
                 "<a href=\"http://granbluefantasy.jp/\" rel=\"nofollow\">グランブルー ファンタジー</a>"
@@ -159,4 +159,27 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn parse_multiple_media() -> anyhow::Result<()> {
+        let input = include_str!("../../tests/tweet_multiple_media.json");
+
+        let parsed = serde_json::from_str::<Tweet>(input)?;
+
+        let urls = parsed
+            .entities
+            .media
+            .iter()
+            .map(|media| media.media_url_https.as_ref())
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            urls,
+            vec![
+                "https://pbs.twimg.com/media/CVL2EBHUwAA8nUj.jpg",
+                "https://pbs.twimg.com/media/CVL2EBHUwAE9xYz.jpg",
+            ]
+        );
+
+        Ok(())
+    }
 }
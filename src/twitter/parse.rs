@@ -1,16 +1,18 @@
-use crate::model::{Language, Raid, UserImage};
+use crate::model::{Language, MediaImage, Raid, UserImage};
 use crate::twitter::model::Tweet;
 use once_cell::sync::Lazy;
 use regex::Regex;
 use std::borrow::Cow;
 use std::convert::TryFrom;
 
+/// The parts of a raid tweet's text, borrowed from the text where possible
 #[derive(Clone, Debug, PartialEq)]
-struct TextParts<'a> {
-    language: Language,
-    text: Option<Cow<'a, str>>,
-    raid_id: &'a str,
-    boss_name: Cow<'a, str>,
+pub(crate) struct TextParts<'a> {
+    pub language: Language,
+    /// Any text the user added before the raid ID
+    pub text: Option<Cow<'a, str>>,
+    pub raid_id: &'a str,
+    pub boss_name: Cow<'a, str>,
 }
 
 #[cfg(test)]
@@ -30,16 +32,75 @@ impl<'a> TextParts<'a> {
     }
 }
 
-const GRANBLUE_APP_SOURCE: &'static str =
-    r#"<a href="http://granbluefantasy.jp/" rel="nofollow">グランブルー ファンタジー</a>"#;
+const GRANBLUE_APP_URL: &'static str = "http://granbluefantasy.jp/";
+const GRANBLUE_APP_NAME: &'static str = "グランブルー ファンタジー";
+
+static REGEX_SOURCE_HREF: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"href="(?P<href>[^"]*)""#).expect("invalid source href regex"));
+
+/// Apps that raid tweets are accepted from, based on the tweet's `source`
+///
+/// API v1.1 returns the `source` as an HTML link, which is matched against the app URLs. API v2
+/// returns only the app name, which is matched against the app names.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TweetSources {
+    urls: Vec<String>,
+    names: Vec<String>,
+}
+
+impl TweetSources {
+    pub fn new<I, S, J, T>(urls: I, names: J) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+        J: IntoIterator<Item = T>,
+        T: AsRef<str>,
+    {
+        Self {
+            urls: urls
+                .into_iter()
+                .map(|url| url.as_ref().to_lowercase())
+                .collect(),
+            names: names
+                .into_iter()
+                .map(|name| name.as_ref().to_owned())
+                .collect(),
+        }
+    }
+
+    pub fn matches(&self, source: &str) -> bool {
+        if self.names.iter().any(|name| name == source) {
+            return true;
+        }
+
+        REGEX_SOURCE_HREF
+            .captures(source)
+            .and_then(|c| c.name("href"))
+            .map_or(false, |href| {
+                let href = href.as_str().to_lowercase();
+                self.urls.iter().any(|url| *url == href)
+            })
+    }
+}
+
+impl Default for TweetSources {
+    fn default() -> Self {
+        Self::new(&[GRANBLUE_APP_URL], &[GRANBLUE_APP_NAME])
+    }
+}
+
+// The line between the raid ID and the boss name. It's matched loosely by the regexes below, and
+// then checked exactly, so that tweets with extra content on that line are rejected.
+const SENTINEL_JAPANESE: &'static str = "参加者募集！";
+const SENTINEL_ENGLISH: &'static str = "I need backup!";
 
 static REGEX_JAPANESE: Lazy<Regex> = Lazy::new(|| {
     Regex::new(
         "\
         (?P<text>(?s).*)(?P<id>[0-9A-F]{8}) :参戦ID\n\
-        参加者募集！\n\
+        (?P<sentinel>.*)\n\
         (?P<boss>.+)\n?\
-        (?P<url>.*)\
+        (?P<url>.*)\\s*\\z\
     ",
     )
     .expect("invalid Japanese raid tweet regex")
@@ -49,9 +110,9 @@ static REGEX_ENGLISH: Lazy<Regex> = Lazy::new(|| {
     Regex::new(
         "\
         (?P<text>(?s).*)(?P<id>[0-9A-F]{8}) :Battle ID\n\
-        I need backup!\n\
+        (?P<sentinel>.*)\n\
         (?P<boss>.+)\n?\
-        (?P<url>.*)\
+        (?P<url>.*)\\s*\\z\
     ",
     )
     .expect("invalid English raid tweet regex")
@@ -60,7 +121,8 @@ static REGEX_ENGLISH: Lazy<Regex> = Lazy::new(|| {
 static REGEX_IMAGE_URL: Lazy<Regex> =
     Lazy::new(|| Regex::new("^https?://[^ ]+$").expect("invalid image URL regex"));
 
-fn parse_text<'a>(tweet_text: &'a str) -> Option<TextParts<'a>> {
+/// Parses the text of a raid tweet, returning `None` for any text that isn't one
+pub(crate) fn parse_text<'a>(tweet_text: &'a str) -> Option<TextParts<'a>> {
     REGEX_JAPANESE
         .captures(tweet_text)
         .map(|c| (Language::Japanese, c))
@@ -70,13 +132,19 @@ fn parse_text<'a>(tweet_text: &'a str) -> Option<TextParts<'a>> {
                 .map(|c| (Language::English, c))
         })
         .and_then(|(lang, c)| {
-            if let (Some(text), Some(id), Some(boss), Some(url)) =
-                (c.name("text"), c.name("id"), c.name("boss"), c.name("url"))
-            {
+            if let (Some(text), Some(id), Some(sentinel), Some(boss), Some(url)) = (
+                c.name("text"),
+                c.name("id"),
+                c.name("sentinel"),
+                c.name("boss"),
+                c.name("url"),
+            ) {
                 let boss_name_raw = boss.as_str().trim();
                 let url_str = url.as_str();
 
-                if boss_name_raw.contains("http")
+                if !is_valid_sentinel(lang, sentinel.as_str())
+                    || boss_name_raw.contains("http")
+                    || contains_sentinel(boss_name_raw)
                     || !url_str.is_empty() && !REGEX_IMAGE_URL.is_match(url_str)
                 {
                     return None;
@@ -101,6 +169,20 @@ fn parse_text<'a>(tweet_text: &'a str) -> Option<TextParts<'a>> {
         })
 }
 
+fn is_valid_sentinel(language: Language, line: &str) -> bool {
+    match language {
+        Language::Japanese => line == SENTINEL_JAPANESE,
+        Language::English => line == SENTINEL_ENGLISH,
+    }
+}
+
+// Promotional tweets sometimes repeat the raid tweet format on the boss line
+fn contains_sentinel(text: &str) -> bool {
+    [SENTINEL_JAPANESE, SENTINEL_ENGLISH, "参戦ID", "Battle ID"]
+        .iter()
+        .any(|s| text.contains(s))
+}
+
 fn html_decode(text: &str) -> Cow<'_, str> {
     if text.contains('&') {
         match escaper::decode_html_sloppy(text) {
@@ -115,11 +197,8 @@ fn html_decode(text: &str) -> Cow<'_, str> {
 impl TryFrom<Tweet> for Raid {
     type Error = ();
 
+    // This doesn't check the tweet's `source`, which should be done with `TweetSources`
     fn try_from(mut tweet: Tweet) -> Result<Raid, Self::Error> {
-        if tweet.source != GRANBLUE_APP_SOURCE {
-            return Err(());
-        }
-
         let text = std::mem::replace(&mut tweet.text, String::new());
 
         let parsed = match parse_text(&text) {
@@ -138,6 +217,12 @@ impl TryFrom<Tweet> for Raid {
             Some(UserImage::from_url(&tweet.user.profile_image_url_https))
         };
 
+        let mut image_urls = tweet
+            .entities
+            .media
+            .into_iter()
+            .map(|media| MediaImage::from_url(&media.media_url_https));
+
         let raid = Raid {
             id: parsed.raid_id.to_owned(),
             tweet_id: tweet.id,
@@ -147,7 +232,9 @@ impl TryFrom<Tweet> for Raid {
             text: parsed.text.map(Cow::into_owned),
             created_at: tweet.created_at.into(),
             language: parsed.language,
-            image_url: tweet.entities.media.map(|media| media.media_url_https),
+            region: parsed.language.into(),
+            image_url: image_urls.next(),
+            extra_image_urls: image_urls.collect(),
         };
 
         Ok(raid)
@@ -199,6 +286,44 @@ mod test {
         );
     }
 
+    #[test]
+    fn tweet_sources() {
+        let jp_source =
+            r#"<a href="http://granbluefantasy.jp/" rel="nofollow">グランブルー ファンタジー</a>"#;
+        let en_source =
+            r#"<a href="https://GranblueFantasy.jp/en/" rel="nofollow">Granblue Fantasy</a>"#;
+
+        let default = TweetSources::default();
+        assert!(default.matches(jp_source));
+        assert!(default.matches(GRANBLUE_APP_NAME));
+        assert!(!default.matches(en_source));
+        assert!(!default
+            .matches(r#"<a href="https://mobile.twitter.com" rel="nofollow">Twitter Web App</a>"#));
+
+        // Matching is case-insensitive
+        let sources = TweetSources::new(
+            &[
+                "http://granbluefantasy.jp/",
+                "https://granbluefantasy.jp/EN/",
+            ],
+            &["Granblue Fantasy"],
+        );
+        assert!(sources.matches(jp_source));
+        assert!(sources.matches(en_source));
+
+        // App names are configured separately from URLs
+        assert!(sources.matches("Granblue Fantasy"));
+        assert!(!sources.matches(GRANBLUE_APP_NAME));
+    }
+
+    #[test]
+    fn ignore_known_false_positives() {
+        let fixtures = include_str!("../../tests/parse_false_positives.txt");
+        for text in fixtures.split("\n---\n") {
+            assert_eq!(parse_text(text), None, "parsed false positive:\n{}", text);
+        }
+    }
+
     #[test]
     fn ignore_extra_space_in_image_url() {
         // First two lines are user input
@@ -398,4 +523,129 @@ mod test {
             ))
         );
     }
+
+    mod prop {
+        use super::*;
+        use proptest::prelude::*;
+
+        /// The shape of a raid tweet, for generating its text and the expected parse result
+        #[derive(Clone, Debug)]
+        struct RaidTweet {
+            language: Language,
+            text: Vec<String>,
+            separator: &'static str,
+            raid_id: String,
+            boss_name: String,
+            image_url: Option<String>,
+            trailing_newline: bool,
+        }
+
+        impl RaidTweet {
+            fn to_text(&self) -> String {
+                let sentinel = match self.language {
+                    Japanese => SENTINEL_JAPANESE,
+                    English => SENTINEL_ENGLISH,
+                };
+                self.to_text_with_sentinel(sentinel)
+            }
+
+            fn to_text_with_sentinel(&self, sentinel: &str) -> String {
+                let id_label = match self.language {
+                    Japanese => "参戦ID",
+                    English => "Battle ID",
+                };
+
+                let mut out = String::new();
+                if !self.text.is_empty() {
+                    out.push_str(&self.text.join("\n"));
+                    out.push_str(self.separator);
+                }
+                out.push_str(&format!(
+                    "{} :{}\n{}\n{}",
+                    self.raid_id, id_label, sentinel, self.boss_name
+                ));
+                if let Some(url) = &self.image_url {
+                    out.push('\n');
+                    out.push_str(url);
+                }
+                if self.trailing_newline {
+                    out.push('\n');
+                }
+                out
+            }
+
+            fn expected_text(&self) -> Option<String> {
+                let text = self.text.join("\n");
+                let text = text.trim();
+                if text.is_empty() {
+                    None
+                } else {
+                    Some(text.to_owned())
+                }
+            }
+        }
+
+        fn language() -> impl Strategy<Value = Language> {
+            prop_oneof![Just(Japanese), Just(English)]
+        }
+
+        // Kana and ASCII only, so generated names never contain the sentinel lines
+        fn boss_name() -> impl Strategy<Value = String> {
+            "Lvl? ?[0-9]{1,3} [A-Za-zぁ-ゖァ-ヺ]{1,20}( \\([A-Za-z]{1,10}\\))?"
+                .prop_filter("boss names can't contain URLs", |name| {
+                    !name.contains("http")
+                })
+        }
+
+        prop_compose! {
+            fn raid_tweet()(
+                language in language(),
+                text in prop::collection::vec("[A-Za-z0-9 !?ぁ-ゖァ-ヺ]{0,30}", 0..4),
+                separator in prop_oneof![Just(" "), Just("\n")],
+                raid_id in "[0-9A-F]{8}",
+                boss_name in boss_name(),
+                image_url in prop::option::of("https://t\\.co/[A-Za-z0-9]{10}"),
+                trailing_newline in any::<bool>(),
+            ) -> RaidTweet {
+                RaidTweet {
+                    language,
+                    text,
+                    separator,
+                    raid_id,
+                    boss_name,
+                    image_url,
+                    trailing_newline,
+                }
+            }
+        }
+
+        proptest! {
+            #[test]
+            fn parses_raid_tweets(tweet in raid_tweet()) {
+                let expected_text = tweet.expected_text();
+                let expected = TextParts::new(
+                    tweet.language,
+                    expected_text.as_deref(),
+                    &tweet.raid_id,
+                    &tweet.boss_name,
+                );
+                prop_assert_eq!(parse_text(&tweet.to_text()), Some(expected));
+            }
+
+            #[test]
+            fn ignores_extra_text_on_sentinel_line(tweet in raid_tweet(), extra in "[^\n]+") {
+                let sentinel = match tweet.language {
+                    Japanese => format!("{}{}", SENTINEL_JAPANESE, extra),
+                    English => format!("{}{}", SENTINEL_ENGLISH, extra),
+                };
+                prop_assert_eq!(parse_text(&tweet.to_text_with_sentinel(&sentinel)), None);
+            }
+
+            #[test]
+            fn ignores_non_raid_text(text in "(\\PC|\n)*") {
+                prop_assume!(!text.contains(" :参戦ID\n") && !text.contains(" :Battle ID\n"));
+                prop_assert_eq!(parse_text(&text), None);
+            }
+        }
+    }
 }
@@ -3,28 +3,42 @@ use std::time::Duration;
 use crate::error::{Error, Result};
 use crate::model::Raid;
 use crate::twitter::model::Tweet;
+use crate::twitter::TweetSources;
 
-use futures::future::ready;
-use futures::stream::{Stream, StreamExt};
-use http::{Response, StatusCode};
+use async_compression::stream::GzipDecoder;
+use futures::future::{ready, FutureExt};
+use futures::stream::{Stream, StreamExt, TryStreamExt};
+use http::header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH};
+use http::{Request, Response, StatusCode};
 use hyper::body::HttpBody;
+use hyper::Body;
 use std::convert::TryFrom;
 use std::fmt;
 use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::task::{Context, Poll};
 use tokio::sync::mpsc;
 use twitter_stream::service::HttpService;
 use twitter_stream::Token;
 
 const TRACK: &'static str = "参加者募集！,:参戦ID,I need backup!,:Battle ID";
 
-fn handle_msg(msg: &str) -> Result<Option<Raid>> {
+// The endpoint used by `twitter_stream`, for logging purposes
+const STREAM_URI: &'static str = "https://stream.twitter.com/1.1/statuses/filter.json";
+
+fn handle_msg(msg: &str, sources: &TweetSources) -> Result<Option<Raid>> {
     let tweet = serde_json::from_str::<Tweet>(msg)?;
+    if !sources.matches(&tweet.source) {
+        return Ok(None);
+    }
     Ok(Raid::try_from(tweet).ok())
 }
 
 pub async fn connect<S, B>(
     service: S,
     token: Token,
+    sources: Arc<TweetSources>,
 ) -> Result<impl Stream<Item = Result<Raid>>, twitter_stream::Error<S::Error>>
 where
     S: HttpService<B, Response = Response<B>>,
@@ -35,10 +49,10 @@ where
         .track(TRACK)
         .listen_with_client(service)
         .await?
-        .filter_map(|result| {
+        .filter_map(move |result| {
             ready({
                 match result {
-                    Ok(msg) => handle_msg(&msg).transpose(),
+                    Ok(msg) => handle_msg(&msg, &sources).transpose(),
                     Err(e) => Some(Err(e.into())),
                 }
             })
@@ -47,7 +61,66 @@ where
     Ok(stream)
 }
 
-fn is_retryable(status: StatusCode) -> bool {
+/// Wraps an HTTP service to request gzip-compressed responses if `enabled`, and decompresses
+/// them before they're read by `twitter_stream`
+#[derive(Clone, Debug)]
+pub struct GzipService<S> {
+    inner: S,
+    enabled: bool,
+}
+
+impl<S> GzipService<S> {
+    pub fn new(inner: S, enabled: bool) -> Self {
+        Self { inner, enabled }
+    }
+}
+
+type DecodeFn<E> = fn(Result<Response<Body>, E>) -> Result<Response<Body>, E>;
+
+fn decode_gzip<E>(result: Result<Response<Body>, E>) -> Result<Response<Body>, E> {
+    let mut resp = result?;
+
+    // Even if requested, the server isn't obligated to compress the response
+    if resp
+        .headers()
+        .get(CONTENT_ENCODING)
+        .map_or(false, |encoding| encoding == "gzip")
+    {
+        resp.headers_mut().remove(CONTENT_ENCODING);
+        resp.headers_mut().remove(CONTENT_LENGTH);
+        let body =
+            std::mem::take(resp.body_mut()).map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+        *resp.body_mut() = Body::wrap_stream(GzipDecoder::new(body));
+    }
+
+    Ok(resp)
+}
+
+impl<S> tower_service::Service<Request<Body>> for GzipService<S>
+where
+    S: tower_service::Service<Request<Body>, Response = Response<Body>>,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = futures::future::Map<S::Future, DecodeFn<S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<Body>) -> Self::Future {
+        if self.enabled {
+            request
+                .headers_mut()
+                .insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        }
+        self.inner
+            .call(request)
+            .map(decode_gzip as DecodeFn<S::Error>)
+    }
+}
+
+pub(super) fn is_retryable(status: StatusCode) -> bool {
     // 4xx errors should not be retried unless it's due to rate limiting (status 420 or 429)
     if status.is_client_error() {
         status == StatusCode::TOO_MANY_REQUESTS || status.as_u16() == 420
@@ -60,8 +133,10 @@ pub fn connect_with_retries<S, B>(
     log: slog::Logger,
     service: S,
     token: Token,
+    sources: TweetSources,
     retry_delay: Duration,
     timeout: Duration,
+    connect_timeout: Duration,
 ) -> (impl Stream<Item = Raid>, impl Future<Output = Error>)
 where
     S: HttpService<B, Response = Response<B>> + Clone,
@@ -71,15 +146,30 @@ where
 {
     let (tx, rx) = mpsc::unbounded_channel();
 
+    let sources = Arc::new(sources);
+
     let worker = async move {
         let mut retry_count = 0;
 
         // Loop per connection attempt
         loop {
             use twitter_stream::Error::Http;
-            match connect(service.clone(), token.clone()).await {
+            // Bound the time spent on DNS resolution, TLS handshakes, etc.
+            let connection = tokio::time::timeout(
+                connect_timeout,
+                connect(service.clone(), token.clone(), sources.clone()),
+            );
+
+            match connection.await {
+                Err(_) => {
+                    slog::warn!(
+                        log, "Timed out connecting to Twitter stream";
+                        "duration" => ?connect_timeout, "uri" => STREAM_URI
+                    );
+                }
+
                 // Loop per message
-                Ok(mut stream) => loop {
+                Ok(Ok(mut stream)) => loop {
                     match tokio::time::timeout(timeout, stream.next()).await {
                         Err(_) => {
                             slog::warn!(log, "Twitter stream timed out"; "duration" => ?timeout);
@@ -101,21 +191,33 @@ where
                     }
                 },
 
-                Err(Http(status)) if is_retryable(status) => {
-                    slog::warn!(log, "Twitter HTTP error"; "statusCode" => status.as_u16());
+                Ok(Err(Http(status))) if is_retryable(status) => {
+                    slog::warn!(
+                        log, "Twitter HTTP error";
+                        "statusCode" => status.as_u16(), "uri" => STREAM_URI
+                    );
                 }
-                Err(Http(status)) => {
+                Ok(Err(Http(status))) => {
                     // Sometimes a 401 can be returned even on valid credentials. If this is our
                     // first attempt, fail immediately. Otherwise, if we've successfully connected
                     // before, retry.
                     if retry_count == 0 {
-                        slog::error!(log, "Non-retryable Twitter HTTP error code"; "error" => %status);
+                        slog::error!(
+                            log, "Non-retryable Twitter HTTP error code";
+                            "error" => %status, "uri" => STREAM_URI
+                        );
                         return Error::Http(status);
                     }
-                    slog::warn!(log, "Twitter HTTP error code"; "error" => %status);
+                    slog::warn!(
+                        log, "Twitter HTTP error code";
+                        "error" => %status, "uri" => STREAM_URI
+                    );
                 }
-                Err(e) => {
-                    slog::warn!(log, "Twitter stream connection error"; "error" => %e);
+                Ok(Err(e)) => {
+                    slog::warn!(
+                        log, "Twitter stream connection error";
+                        "error" => %e, "uri" => STREAM_URI
+                    );
                 }
             };
 
@@ -127,3 +229,103 @@ where
 
     (rx, worker)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use http::Request;
+    use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+    use std::task::{Context, Poll};
+
+    // A service whose requests never complete, e.g., due to a hung TLS handshake
+    #[derive(Clone)]
+    struct HangingService(Arc<AtomicUsize>);
+
+    impl tower_service::Service<Request<hyper::Body>> for HangingService {
+        type Response = Response<hyper::Body>;
+        type Error = hyper::Error;
+        type Future = futures::future::Pending<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _request: Request<hyper::Body>) -> Self::Future {
+            self.0.fetch_add(1, SeqCst);
+            futures::future::pending()
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_timeout() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let attempts = Arc::new(AtomicUsize::new(0));
+
+        let (_stream, worker) = connect_with_retries(
+            log,
+            HangingService(attempts.clone()),
+            Token::new(
+                "ck".to_owned(),
+                "cs".to_owned(),
+                "at".to_owned(),
+                "ats".to_owned(),
+            ),
+            TweetSources::default(),
+            Duration::from_millis(10),
+            Duration::from_secs(60),
+            Duration::from_millis(20),
+        );
+        tokio::spawn(worker);
+
+        // Timed out connection attempts are retried
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        assert!(attempts.load(SeqCst) >= 2);
+    }
+
+    // Returns a gzip-compressed tweet if the request accepts it
+    #[derive(Clone)]
+    struct GzipTweetService;
+
+    impl tower_service::Service<Request<hyper::Body>> for GzipTweetService {
+        type Response = Response<hyper::Body>;
+        type Error = hyper::Error;
+        type Future = futures::future::Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: Request<hyper::Body>) -> Self::Future {
+            let accepts_gzip = request
+                .headers()
+                .get(ACCEPT_ENCODING)
+                .map_or(false, |encoding| encoding == "gzip");
+            let resp = if accepts_gzip {
+                Response::builder()
+                    .header(CONTENT_ENCODING, "gzip")
+                    .body(include_bytes!("../../tests/tweet.json.gz").to_vec().into())
+            } else {
+                Response::builder().body(include_str!("../../tests/tweet.json").into())
+            };
+            futures::future::ok(resp.unwrap())
+        }
+    }
+
+    #[tokio::test]
+    async fn gzip_service() -> anyhow::Result<()> {
+        use tower_service::Service;
+
+        let expected = include_str!("../../tests/tweet.json");
+        for &enabled in &[true, false] {
+            let resp = GzipService::new(GzipTweetService, enabled)
+                .call(Request::new(hyper::Body::empty()))
+                .await?;
+            assert!(resp.headers().get(CONTENT_ENCODING).is_none());
+
+            let body = hyper::body::to_bytes(resp.into_body()).await?;
+            assert_eq!(std::str::from_utf8(&body)?, expected);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,477 @@
+//! Twitter API v2 filtered stream, as an alternative to the deprecated v1.1 streaming API
+
+use std::time::Duration;
+
+use crate::client::HttpClient;
+use crate::error::{Error, Result};
+use crate::model::{CachedString, DateTime, Raid};
+use crate::twitter::model::{Entities, Media, Tweet, User};
+use crate::twitter::stream::is_retryable;
+use crate::twitter::TweetSources;
+
+use async_compression::stream::GzipDecoder;
+use bytes::Bytes;
+use futures::future::ready;
+use futures::stream::{self, Stream, StreamExt, TryStreamExt};
+use http::{header, Method, Request};
+use hyper::Body;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+const STREAM_URI: &str = "https://api.twitter.com/2/tweets/search/stream\
+    ?tweet.fields=created_at,source\
+    &expansions=author_id,attachments.media_keys\
+    &user.fields=profile_image_url\
+    &media.fields=url";
+const RULES_URI: &str = "https://api.twitter.com/2/tweets/search/stream/rules";
+
+// Rules created by this app are tagged, so that rules added by other apps sharing the same bearer
+// token are left alone
+const RULE_TAG: &str = "petronel";
+const RULES: &[&str] = &[
+    r#""参加者募集！" "参戦ID""#,
+    r#""I need backup!" "Battle ID""#,
+];
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+struct Rule {
+    #[serde(default, skip_serializing)]
+    id: String,
+    value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tag: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Rules {
+    #[serde(default)]
+    data: Vec<Rule>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum RulesUpdate {
+    Add(Vec<Rule>),
+    Delete { ids: Vec<String> },
+}
+
+#[derive(Deserialize, Debug)]
+struct Message {
+    data: TweetData,
+    #[serde(default)]
+    includes: Includes,
+}
+
+#[derive(Deserialize, Debug)]
+struct TweetData {
+    id: String,
+    created_at: DateTime,
+    text: String,
+    author_id: String,
+    #[serde(default)]
+    source: String,
+    #[serde(default)]
+    attachments: Attachments,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct Attachments {
+    #[serde(default)]
+    media_keys: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct Includes {
+    #[serde(default)]
+    users: Vec<IncludedUser>,
+    #[serde(default)]
+    media: Vec<IncludedMedia>,
+}
+
+#[derive(Deserialize, Debug)]
+struct IncludedUser {
+    id: String,
+    username: String,
+    #[serde(default)]
+    profile_image_url: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct IncludedMedia {
+    media_key: String,
+    url: Option<CachedString>,
+}
+
+impl TryFrom<Message> for Tweet {
+    type Error = ();
+
+    fn try_from(msg: Message) -> Result<Tweet, Self::Error> {
+        let Message { data, includes } = msg;
+
+        let user = includes
+            .users
+            .into_iter()
+            .find(|user| user.id == data.author_id)
+            .ok_or(())?;
+
+        let mut included_media = includes.media;
+        let media = data
+            .attachments
+            .media_keys
+            .iter()
+            .filter_map(|key| {
+                let index = included_media
+                    .iter()
+                    .position(|media| &media.media_key == key)?;
+                included_media.swap_remove(index).url
+            })
+            .map(|url| Media {
+                media_url_https: url,
+            })
+            .collect();
+
+        Ok(Tweet {
+            id: data.id.parse().map_err(|_| ())?,
+            created_at: data.created_at,
+            text: data.text,
+            user: User {
+                id: user.id.parse().map_err(|_| ())?,
+                screen_name: user.username,
+                // Not available in v2. Default images are detected by URL instead.
+                default_profile_image: false,
+                profile_image_url_https: user.profile_image_url,
+            },
+            entities: Entities { media },
+            source: data.source,
+        })
+    }
+}
+
+fn handle_msg(msg: &str, sources: &TweetSources) -> Result<Option<Raid>> {
+    let msg = serde_json::from_str::<Message>(msg)?;
+    Ok(Tweet::try_from(msg)
+        .ok()
+        .filter(|tweet| sources.matches(&tweet.source))
+        .and_then(|tweet| Raid::try_from(tweet).ok()))
+}
+
+async fn request_json<T: DeserializeOwned>(
+    client: &HttpClient,
+    bearer_token: &str,
+    method: Method,
+    uri: &str,
+    body: Option<Vec<u8>>,
+) -> Result<T> {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::AUTHORIZATION, format!("Bearer {}", bearer_token))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body.map(Body::from).unwrap_or_else(Body::empty))
+        .expect("invalid Twitter v2 request");
+
+    let result = async {
+        let resp = client.request(request).await?;
+        let status = resp.status();
+        if !status.is_success() {
+            return Err(Error::Http(status));
+        }
+
+        let body = hyper::body::to_bytes(resp).await?;
+        Ok(serde_json::from_slice(&body)?)
+    };
+
+    result.await.map_err(|e| e.with_uri(uri))
+}
+
+async fn update_rules(client: &HttpClient, bearer_token: &str, update: &RulesUpdate) -> Result<()> {
+    let body = serde_json::to_vec(update)?;
+    request_json::<serde_json::Value>(client, bearer_token, Method::POST, RULES_URI, Some(body))
+        .await?;
+    Ok(())
+}
+
+/// Checks that the bearer token is valid, by listing the stream's rules without modifying them
+pub async fn verify_bearer_token(client: &HttpClient, bearer_token: &str) -> Result<()> {
+    request_json::<serde_json::Value>(client, bearer_token, Method::GET, RULES_URI, None).await?;
+    Ok(())
+}
+
+/// Replaces any outdated rules previously created by this app, and adds any missing rules
+pub async fn sync_rules(client: &HttpClient, bearer_token: &str) -> Result<()> {
+    let existing =
+        request_json::<Rules>(client, bearer_token, Method::GET, RULES_URI, None).await?;
+
+    let outdated = existing
+        .data
+        .iter()
+        .filter(|rule| rule.tag.as_deref() == Some(RULE_TAG))
+        .filter(|rule| !RULES.contains(&rule.value.as_str()))
+        .map(|rule| rule.id.clone())
+        .collect::<Vec<_>>();
+
+    let missing = RULES
+        .iter()
+        .filter(|value| !existing.data.iter().any(|rule| &rule.value == *value))
+        .map(|value| Rule {
+            id: String::new(),
+            value: value.to_string(),
+            tag: Some(RULE_TAG.to_owned()),
+        })
+        .collect::<Vec<_>>();
+
+    if !outdated.is_empty() {
+        update_rules(client, bearer_token, &RulesUpdate::Delete { ids: outdated }).await?;
+    }
+
+    if !missing.is_empty() {
+        update_rules(client, bearer_token, &RulesUpdate::Add(missing)).await?;
+    }
+
+    Ok(())
+}
+
+// Splits the response body into newline-delimited messages, skipping keep-alive blank lines.
+// If `gzip` is set, the body is decompressed before being split.
+fn lines<S>(body: S, gzip: bool) -> impl Stream<Item = Result<String>>
+where
+    S: Stream<Item = Result<Bytes, hyper::Error>> + Send + 'static,
+{
+    let chunks = if gzip {
+        let body = body.map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+        GzipDecoder::new(body).map_err(Error::from).left_stream()
+    } else {
+        body.map_err(Error::from).right_stream()
+    };
+
+    stream::unfold(
+        (Box::pin(chunks), Vec::new()),
+        |(mut chunks, mut buf)| async move {
+            loop {
+                if let Some(index) = buf.iter().position(|b| *b == b'\n') {
+                    let line = buf.drain(..=index).collect::<Vec<u8>>();
+                    let line = String::from_utf8_lossy(&line).trim().to_owned();
+                    if line.is_empty() {
+                        continue;
+                    }
+                    return Some((Ok(line), (chunks, buf)));
+                }
+
+                match chunks.next().await {
+                    Some(Ok(chunk)) => buf.extend_from_slice(&chunk),
+                    Some(Err(e)) => return Some((Err(e), (chunks, buf))),
+                    None => return None,
+                }
+            }
+        },
+    )
+}
+
+pub async fn connect(
+    client: &HttpClient,
+    bearer_token: &str,
+    gzip: bool,
+    sources: Arc<TweetSources>,
+) -> Result<impl Stream<Item = Result<Raid>>> {
+    let mut request =
+        Request::get(STREAM_URI).header(header::AUTHORIZATION, format!("Bearer {}", bearer_token));
+    if gzip {
+        request = request.header(header::ACCEPT_ENCODING, "gzip");
+    }
+    let request = request
+        .body(Body::empty())
+        .expect("invalid Twitter v2 stream request");
+
+    let resp = client
+        .request(request)
+        .await
+        .map_err(|e| Error::from(e).with_uri(STREAM_URI))?;
+    let status = resp.status();
+    if !status.is_success() {
+        return Err(Error::Http(status).with_uri(STREAM_URI));
+    }
+
+    // Even if requested, the server isn't obligated to compress the response
+    let is_gzipped = resp
+        .headers()
+        .get(header::CONTENT_ENCODING)
+        .map_or(false, |encoding| encoding == "gzip");
+
+    let stream = lines(resp.into_body(), is_gzipped).filter_map(move |result| {
+        ready({
+            match result {
+                Ok(msg) => handle_msg(&msg, &sources).transpose(),
+                Err(e) => Some(Err(e)),
+            }
+        })
+    });
+
+    Ok(stream)
+}
+
+pub fn connect_with_retries(
+    log: slog::Logger,
+    client: HttpClient,
+    bearer_token: String,
+    gzip: bool,
+    sources: TweetSources,
+    retry_delay: Duration,
+    timeout: Duration,
+) -> (impl Stream<Item = Raid>, impl Future<Output = Error>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let sources = Arc::new(sources);
+
+    let worker = async move {
+        if let Err(e) = sync_rules(&client, &bearer_token).await {
+            slog::error!(log, "Failed to update Twitter stream rules"; "error" => %e);
+            return e;
+        }
+
+        let mut retry_count = 0;
+
+        // Loop per connection attempt
+        loop {
+            match connect(&client, &bearer_token, gzip, sources.clone()).await {
+                // Loop per message
+                Ok(stream) => {
+                    futures::pin_mut!(stream);
+                    loop {
+                        match tokio::time::timeout(timeout, stream.next()).await {
+                            Err(_) => {
+                                slog::warn!(log, "Twitter stream timed out"; "duration" => ?timeout);
+                                break;
+                            }
+                            Ok(Some(Ok(msg))) => {
+                                if let Err(_) = tx.send(msg) {
+                                    // Stream closed by receiver
+                                    return Error::StreamClosed;
+                                }
+                            }
+                            Ok(Some(Err(e))) => {
+                                slog::warn!(log, "Error reading message from Twitter stream"; "error" => %e);
+                            }
+                            Ok(None) => {
+                                slog::warn!(log, "Twitter stream ended");
+                                break;
+                            }
+                        }
+                    }
+                }
+
+                Err(e) => match e.status_code() {
+                    // Same as v1: only fail immediately if we've never connected successfully
+                    Some(status) if !is_retryable(status) && retry_count == 0 => {
+                        slog::error!(log, "Non-retryable Twitter HTTP error code"; "error" => %e);
+                        return e;
+                    }
+                    _ => {
+                        slog::warn!(log, "Twitter stream connection error"; "error" => %e);
+                    }
+                },
+            };
+
+            tokio::time::delay_for(retry_delay).await;
+            slog::info!(log, "Reconnecting to Twitter stream");
+            retry_count += 1;
+        }
+    };
+
+    (rx, worker)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{Language, UserImage};
+    use chrono::offset::TimeZone;
+
+    #[test]
+    fn parse_message() -> anyhow::Result<()> {
+        let input = include_str!("../../tests/tweet_v2.json");
+
+        let raid = handle_msg(input, &TweetSources::default())?.expect("failed to parse raid");
+
+        assert_eq!(raid.id, "457BAF34");
+        assert_eq!(raid.tweet_id, 1240718100460273665);
+        assert_eq!(raid.boss_name, "Lv120 フラム＝グラス".into());
+        assert_eq!(raid.user_name, "walfieee");
+        assert_eq!(
+            raid.user_image,
+            Some(UserImage::from_url(
+                "https://pbs.twimg.com/profile_images/1/abc_normal.png"
+            ))
+        );
+        assert_eq!(raid.language, Language::Japanese);
+        assert_eq!(
+            *raid.created_at.as_datetime(),
+            chrono::Utc.ymd(2020, 5, 9).and_hms(22, 55, 25)
+        );
+        assert_eq!(
+            raid.image_url,
+            Some("https://pbs.twimg.com/media/CVL2EBHUwAA8nUj.jpg".into())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_other_sources() -> anyhow::Result<()> {
+        let input = r#"{
+            "data": {
+                "id": "1",
+                "created_at": "2020-05-09T22:55:25.000Z",
+                "text": "457BAF34 :参戦ID\n参加者募集！\nLv120 フラム＝グラス",
+                "author_id": "2",
+                "source": "Twitter Web App"
+            },
+            "includes": { "users": [{ "id": "2", "username": "someone" }] }
+        }"#;
+
+        assert!(handle_msg(input, &TweetSources::default())?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn configured_source_names() -> anyhow::Result<()> {
+        let input = include_str!("../../tests/tweet_v2.json");
+
+        let sources = TweetSources::new(&["http://granbluefantasy.jp/"], &["Granblue Fantasy"]);
+        assert!(handle_msg(input, &sources)?.is_none());
+
+        let sources = TweetSources::new(&["https://example.com/"], &["グランブルー ファンタジー"]);
+        assert!(handle_msg(input, &sources)?.is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parse_gzipped_stream() -> anyhow::Result<()> {
+        let input = include_bytes!("../../tests/stream_v2.ndjson.gz");
+
+        // Split into small chunks, so that compressed blocks span multiple chunks
+        let chunks = input
+            .chunks(16)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect::<Vec<_>>();
+
+        let messages = lines(stream::iter(chunks), true)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+
+        // The blank keep-alive line should be skipped
+        assert_eq!(messages.len(), 2);
+        for msg in messages {
+            let raid = handle_msg(&msg, &TweetSources::default())?.expect("failed to parse raid");
+            assert_eq!(raid.id, "457BAF34");
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,39 @@
+use crate::error::{Error, Result};
+
+use crate::client::HttpClient;
+
+use http::{header, Request};
+use serde::Deserialize;
+use twitter_stream::Token;
+
+const VERIFY_CREDENTIALS_URI: &str = "https://api.twitter.com/1.1/account/verify_credentials.json";
+
+#[derive(Deserialize)]
+struct Account {
+    screen_name: String,
+}
+
+/// Checks that the token is valid, and returns the authenticated user's screen name
+pub async fn verify_credentials(client: &HttpClient, token: &Token) -> Result<String> {
+    let authorization =
+        oauth1_request::get(VERIFY_CREDENTIALS_URI, &(), token, oauth1_request::HmacSha1);
+
+    let request = Request::get(VERIFY_CREDENTIALS_URI)
+        .header(header::AUTHORIZATION, authorization)
+        .body(hyper::Body::empty())
+        .expect("invalid verify_credentials request");
+
+    let result = async {
+        let resp = client.request(request).await?;
+        let status = resp.status();
+        if !status.is_success() {
+            return Err(Error::Http(status));
+        }
+
+        let body = hyper::body::to_bytes(resp).await?;
+        let account = serde_json::from_slice::<Account>(&body)?;
+        Ok(account.screen_name)
+    };
+
+    result.await.map_err(|e| e.with_uri(VERIFY_CREDENTIALS_URI))
+}
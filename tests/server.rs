@@ -0,0 +1,205 @@
+// End-to-end tests that run the full HTTP server on an ephemeral port, without connecting to
+// Twitter. These are enabled with `cargo test --features integration`.
+
+use hyper::body::HttpBody;
+use hyper::{Body, Request, StatusCode};
+use petronel_graphql::cancellation::CancellationToken;
+use petronel_graphql::graphql::{routes, PageSizeLimit};
+use petronel_graphql::metrics::PrometheusMetricFactory;
+use petronel_graphql::model::{Language, Raid, Region, TweetId};
+use petronel_graphql::{HistoryLimit, MergeOptions, RaidFilter, RaidHandler};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+const BOSS_NAME: &str = "Lv60 オオゾラッコ";
+
+/// A server with an in-memory `RaidHandler`, where raids are pushed directly rather than read
+/// from a tweet stream
+#[derive(Clone)]
+struct TestServer {
+    handler: RaidHandler,
+    addr: SocketAddr,
+}
+
+impl TestServer {
+    fn start() -> Self {
+        let handler = RaidHandler::new(
+            PrometheusMetricFactory::new("petronel".to_owned()),
+            Vec::new(),
+            HistoryLimit::new(50),
+            10,
+            None,
+            MergeOptions::default(),
+            RaidFilter::default(),
+        );
+
+        let routes = routes(
+            handler.clone(),
+            PageSizeLimit {
+                max: 100,
+                strict: false,
+            },
+            16 * 1024,
+            None,
+            false,
+            false,
+            None,
+        );
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        Self { handler, addr }
+    }
+
+    fn push(&self, tweet_id: TweetId) {
+        self.handler.push(Raid {
+            id: format!("{:08X}", tweet_id),
+            tweet_id,
+            user_name: "walfieee".into(),
+            user_image: None,
+            boss_name: BOSS_NAME.into(),
+            created_at: chrono::Utc::now().into(),
+            text: None,
+            language: Language::Japanese,
+            region: Region::Jp,
+            image_url: None,
+            extra_image_urls: Default::default(),
+        });
+    }
+
+    /// Pushes a new raid every few milliseconds until cancelled, for subscriptions that may not
+    /// have started yet
+    fn push_until_cancelled(&self, first_tweet_id: TweetId) -> CancellationToken {
+        let cancel = CancellationToken::new();
+        let server = self.clone();
+
+        tokio::spawn({
+            let cancel = cancel.clone();
+            async move {
+                cancel
+                    .run_until_cancelled(async move {
+                        for tweet_id in first_tweet_id.. {
+                            server.push(tweet_id);
+                            tokio::time::delay_for(Duration::from_millis(10)).await;
+                        }
+                    })
+                    .await
+            }
+        });
+
+        cancel
+    }
+
+    async fn query(&self, query: &str) -> serde_json::Value {
+        let request = Request::post(format!("http://{}/graphql", self.addr))
+            .header("content-type", "application/json")
+            .body(Body::from(
+                serde_json::json!({ "query": query }).to_string(),
+            ))
+            .unwrap();
+
+        let resp = hyper::Client::new().request(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// Subscribes over server-sent events, and returns the data of the first event
+    async fn first_subscription_event(&self, query: &str) -> serde_json::Value {
+        let uri = format!(
+            "http://{}/graphql/stream?query={}",
+            self.addr,
+            percent_encode(query)
+        );
+        let mut resp = hyper::Client::new()
+            .get(uri.parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let mut body = String::new();
+        while !body.contains("\n\n") {
+            let chunk = resp.body_mut().data().await.unwrap().unwrap();
+            body.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+
+        let event = body.split("\n\n").next().unwrap();
+        serde_json::from_str(event.trim_start_matches("data:")).unwrap()
+    }
+}
+
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[tokio::test]
+#[cfg_attr(not(feature = "integration"), ignore)]
+async fn query_bosses() {
+    let server = TestServer::start();
+    server.push(1);
+    server.push(2);
+
+    let response = server
+        .query(
+            r#"{
+                bosses(first: 10) {
+                    nodes {
+                        name { ja en }
+                        tweets(first: 10) { nodes { raidId } }
+                    }
+                }
+            }"#,
+        )
+        .await;
+
+    assert_eq!(
+        response,
+        serde_json::json!({
+            "data": {
+                "bosses": {
+                    "nodes": [{
+                        "name": { "ja": BOSS_NAME, "en": null },
+                        "tweets": {
+                            "nodes": [{ "raidId": "00000002" }, { "raidId": "00000001" }]
+                        }
+                    }]
+                }
+            }
+        })
+    );
+}
+
+#[tokio::test]
+#[cfg_attr(not(feature = "integration"), ignore)]
+async fn subscribe_to_tweets() {
+    let server = TestServer::start();
+
+    let cancel = server.push_until_cancelled(1);
+    let event = server
+        .first_subscription_event(&format!(
+            r#"subscription {{ tweets(bossName: "{}") {{ tweet {{ raidId username }} }} }}"#,
+            BOSS_NAME
+        ))
+        .await;
+    cancel.cancel();
+
+    let tweet = &event["data"]["tweets"]["tweet"];
+    assert_eq!(tweet["username"], "walfieee");
+    assert!(tweet["raidId"].is_string());
+
+    // The raid is also visible to queries, now that the boss exists
+    let response = server
+        .query(&format!(
+            r#"{{ boss(name: "{}") {{ tweets(first: 1) {{ nodes {{ raidId }} }} }} }}"#,
+            BOSS_NAME
+        ))
+        .await;
+    assert!(response["data"]["boss"]["tweets"]["nodes"][0]["raidId"].is_string());
+}